@@ -1,8 +1,190 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::{path::Path, sync::Arc};
 
-use crab_tv::{Canvas, WHITE};
-use glam::IVec2;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+use crab_tv::{
+    look_at_transform, render_scene, viewport_transform, BlendMode, Canvas, DepthShader,
+    GouraudShader, Model, NormalMap, PhongShader, PhongShadowInput, RenderScene, SceneNode, WHITE,
+};
+use glam::{const_vec3, IVec2, Mat4, Vec3};
 use rgb::RGBA8;
+use strum::IntoEnumIterator;
+
+/// Size of the images rendered by the model benchmarks, matching the UI's default.
+const IMAGE_SIZE: usize = 1000;
+const MODELS: [&str; 2] = ["head", "diablo"];
+
+const LIGHT_DIR: Vec3 = const_vec3!([0.0, 0.0, 1.0]);
+const CAMERA_DISTANCE: f32 = 3.0;
+const CAMERA_LOOK_FROM: Vec3 = const_vec3!([0.0, 0.0, 3.0]);
+const CAMERA_LOOK_AT: Vec3 = Vec3::ZERO;
+const CAMERA_UP: Vec3 = const_vec3!([0.0, 1.0, 0.0]);
+const PHONG_LIGHTING_WEIGHTS: Vec3 = const_vec3!([1.0, 1.0, 0.6]);
+
+fn load_model(name: &str) -> Model {
+    let path = format!("assets/{}.obj", name);
+    Model::load_obj_file(&Model::validate(Path::new(&path)).expect("model should be valid"))
+        .expect("model load should succeed")
+}
+
+/// The viewport and projection * modelview transforms used by the scenes with a movable camera.
+fn transforms() -> (Mat4, Mat4) {
+    let size = IMAGE_SIZE as f32;
+    let viewport = viewport_transform(size / 8.0, size / 8.0, size * 3.0 / 4.0, size * 3.0 / 4.0);
+    let mut projection = Mat4::IDENTITY;
+    projection.z_axis.w = -1.0 / CAMERA_DISTANCE;
+    let uniform_m = projection * look_at_transform(CAMERA_LOOK_FROM, CAMERA_LOOK_AT, CAMERA_UP);
+    (viewport, uniform_m)
+}
+
+/// A shadow buffer rendered from the light's point of view, as the shadowed scenes do.
+fn shadows(model: &Model, viewport: Mat4, uniform_m: Mat4) -> PhongShadowInput {
+    let light_m = look_at_transform(LIGHT_DIR, CAMERA_LOOK_AT, CAMERA_UP);
+    let mut shadow_buffer = Canvas::new(IMAGE_SIZE, IMAGE_SIZE);
+    shadow_buffer.model_shader(model, &DepthShader::new(viewport, light_m));
+    PhongShadowInput::new(
+        viewport * light_m * (viewport * uniform_m).inverse(),
+        Arc::new(shadow_buffer),
+        0.7,
+        5.0,
+    )
+}
+
+fn phong_shader<'m>(
+    model: &'m Model,
+    viewport: Mat4,
+    uniform_m: Mat4,
+    shadows: Option<&'m PhongShadowInput>,
+) -> PhongShader<'m> {
+    PhongShader::new(
+        viewport,
+        uniform_m,
+        Mat4::IDENTITY,
+        LIGHT_DIR,
+        PHONG_LIGHTING_WEIGHTS,
+        &model.diffuse_texture,
+        NormalMap::TangentSpace(&model.normal_texture_darboux),
+        &model.specular_texture,
+        shadows,
+        model.glow_texture.as_deref(),
+        5.0,
+        1.0,
+        Vec3::ONE,
+    )
+}
+
+fn model_shaders(c: &mut Criterion) {
+    let mut group = c.benchmark_group("model-shader");
+    group.sample_size(20);
+    let (viewport, uniform_m) = transforms();
+    let new_image = || Canvas::new(IMAGE_SIZE, IMAGE_SIZE);
+
+    for name in MODELS {
+        let model = load_model(name);
+
+        let gouraud = GouraudShader::new(
+            viewport,
+            uniform_m,
+            LIGHT_DIR,
+            Some(&model.diffuse_texture),
+            false,
+        );
+        group.bench_function(format!("{}/gouraud", name), |b| {
+            b.iter_batched_ref(
+                new_image,
+                |image| image.model_shader(&model, &gouraud),
+                BatchSize::LargeInput,
+            );
+        });
+
+        let phong = phong_shader(&model, viewport, uniform_m, None);
+        group.bench_function(format!("{}/phong", name), |b| {
+            b.iter_batched_ref(
+                new_image,
+                |image| image.model_shader(&model, &phong),
+                BatchSize::LargeInput,
+            );
+        });
+
+        let shadows = shadows(&model, viewport, uniform_m);
+        let phong_shadowed = phong_shader(&model, viewport, uniform_m, Some(&shadows));
+        group.bench_function(format!("{}/phong-shadowed", name), |b| {
+            b.iter_batched_ref(
+                new_image,
+                |image| image.model_shader(&model, &phong_shadowed),
+                BatchSize::LargeInput,
+            );
+        });
+    }
+
+    group.finish();
+}
+
+fn ambient_occlusion(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ambient-occlusion");
+    group.sample_size(10);
+    let (viewport, uniform_m) = transforms();
+
+    for name in MODELS {
+        let model = load_model(name);
+        let mut rendered = Canvas::new(IMAGE_SIZE, IMAGE_SIZE);
+        rendered.model_shader(&model, &phong_shader(&model, viewport, uniform_m, None));
+
+        for passes in [1, 5] {
+            group.bench_function(format!("{}/{}-passes", name, passes), |b| {
+                b.iter_batched_ref(
+                    || rendered.clone(),
+                    |image| image.apply_ambient_occlusion(2.0, passes),
+                    BatchSize::LargeInput,
+                );
+            });
+        }
+    }
+
+    group.finish();
+}
+
+fn scenes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("render-scene");
+    group.sample_size(10);
+
+    for name in MODELS {
+        let nodes = [SceneNode::new(Arc::new(load_model(name)))];
+        for scene in RenderScene::iter() {
+            group.bench_function(format!("{}/{:?}", name, scene), |b| {
+                b.iter_batched_ref(
+                    || Canvas::new(IMAGE_SIZE, IMAGE_SIZE),
+                    |image| {
+                        render_scene(
+                            image,
+                            &scene,
+                            &nodes,
+                            LIGHT_DIR,
+                            CAMERA_DISTANCE,
+                            CAMERA_LOOK_FROM,
+                            CAMERA_LOOK_AT,
+                            CAMERA_UP,
+                            PHONG_LIGHTING_WEIGHTS,
+                            true,
+                            0.7,
+                            5.0,
+                            5,
+                            2.0,
+                            true,
+                            5.0,
+                            0.5,
+                            BlendMode::Alpha,
+                        )
+                        .expect("scene should render")
+                    },
+                    BatchSize::LargeInput,
+                );
+            });
+        }
+    }
+
+    group.finish();
+}
 
 fn line_drawing(c: &mut Criterion) {
     let mut group = c.benchmark_group("line-drawing");
@@ -10,7 +192,7 @@ fn line_drawing(c: &mut Criterion) {
     group.bench_function("v1-slow", |b| {
         let mut image = Canvas::new(100, 100);
         b.iter(|| {
-            image.line_slow(0, 0, 99, 99, RGBA8::new(255, 0, 0));
+            image.line_slow(0, 0, 99, 99, RGBA8::new(255, 0, 0, 255));
         });
         black_box(image);
     });
@@ -18,7 +200,7 @@ fn line_drawing(c: &mut Criterion) {
     group.bench_function("v2-faster", |b| {
         let mut image = Canvas::new(100, 100);
         b.iter(|| {
-            image.line_faster(0, 0, 99, 99, RGBA8::new(255, 0, 0));
+            image.line_faster(0, 0, 99, 99, RGBA8::new(255, 0, 0, 255));
         });
         black_box(image);
     });
@@ -26,7 +208,7 @@ fn line_drawing(c: &mut Criterion) {
     group.bench_function("v3-integer maths", |b| {
         let mut image = Canvas::new(100, 100);
         b.iter(|| {
-            image.line_fastest(0, 0, 99, 99, RGBA8::new(255, 0, 0));
+            image.line_fastest(0, 0, 99, 99, RGBA8::new(255, 0, 0, 255));
         });
         black_box(image);
     });
@@ -66,5 +248,12 @@ fn triangle_drawing(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, line_drawing, triangle_drawing);
+criterion_group!(
+    benches,
+    line_drawing,
+    triangle_drawing,
+    model_shaders,
+    ambient_occlusion,
+    scenes
+);
 criterion_main!(benches);
@@ -0,0 +1,449 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use crab_tv::{Canvas, ProgressSink, RenderScene};
+use glam::{Quat, Vec3};
+use rgb::{ComponentBytes, RGBA8};
+
+use crate::{assets::AssetCache, worker, RenderConfig, RenderInput};
+
+/// How the camera and light move over the course of an animation.
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::EnumIter,
+    PartialEq,
+    Eq,
+    strum::Display,
+)]
+pub enum AnimationPath {
+    /// Orbit the camera once around the point it's looking at
+    #[default]
+    Turntable,
+    /// Move the camera and light between keyframes
+    Keyframes,
+}
+
+/// What to write the frames of an animation out as.
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::EnumIter,
+    PartialEq,
+    Eq,
+    strum::Display,
+)]
+pub enum AnimationFormat {
+    /// One numbered PNG per frame
+    PngSequence,
+    #[default]
+    Gif,
+    Apng,
+}
+
+impl AnimationFormat {
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            AnimationFormat::PngSequence | AnimationFormat::Apng => "png",
+            AnimationFormat::Gif => "gif",
+        }
+    }
+}
+
+/// The camera and light at a moment in an animation.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, PartialEq)]
+pub struct Keyframe {
+    /// Seconds since the start of the animation
+    pub(crate) time: f32,
+    pub(crate) camera_look_from: Vec3,
+    pub(crate) camera_look_at: Vec3,
+    pub(crate) light_dir: Vec3,
+}
+
+impl Keyframe {
+    pub(crate) fn from_config(time: f32, config: &RenderConfig) -> Self {
+        Keyframe {
+            time,
+            camera_look_from: config.camera_look_from,
+            camera_look_at: config.camera_look_at,
+            light_dir: config.light_dir,
+        }
+    }
+
+    /// Blend between this keyframe and the next one, moving the camera around the point it's
+    /// looking at (rather than straight through the model) and turning the light around.
+    fn interpolate(&self, next: &Keyframe, s: f32) -> Keyframe {
+        let camera_look_at = self.camera_look_at.lerp(next.camera_look_at, s);
+        let offset = self.camera_look_from - self.camera_look_at;
+        let next_offset = next.camera_look_from - next.camera_look_at;
+        let offset_length = offset.length() + (next_offset.length() - offset.length()) * s;
+        Keyframe {
+            time: self.time + (next.time - self.time) * s,
+            camera_look_from: camera_look_at
+                + slerp_direction(offset, next_offset, s) * offset_length,
+            camera_look_at,
+            light_dir: slerp_direction(self.light_dir, next.light_dir, s),
+        }
+    }
+}
+
+/// Rotate from one direction towards another, returning a unit vector.
+fn slerp_direction(from: Vec3, to: Vec3, s: f32) -> Vec3 {
+    let (from, to) = (from.normalize_or_zero(), to.normalize_or_zero());
+    if from == Vec3::ZERO || to == Vec3::ZERO {
+        return from.lerp(to, s).normalize_or_zero();
+    }
+    Quat::IDENTITY.slerp(Quat::from_rotation_arc(from, to), s) * from
+}
+
+/// How to render an animation and where to save it.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AnimationSettings {
+    pub(crate) path: AnimationPath,
+    pub(crate) turntable_frames: usize,
+    pub(crate) keyframes: Vec<Keyframe>,
+    pub(crate) frames_per_second: f32,
+    pub(crate) format: AnimationFormat,
+    /// File to save to; for PNG sequences, each frame's number is added to the file name
+    pub(crate) output: PathBuf,
+}
+
+impl Default for AnimationSettings {
+    fn default() -> Self {
+        Self {
+            path: AnimationPath::default(),
+            turntable_frames: 36,
+            keyframes: Vec::new(),
+            frames_per_second: 12.0,
+            format: AnimationFormat::default(),
+            output: PathBuf::from("target/animation.gif"),
+        }
+    }
+}
+
+impl AnimationSettings {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.frames_per_second <= 0.0 {
+            bail!("Frames per second must be greater than 0");
+        }
+        match self.path {
+            AnimationPath::Turntable => {
+                if self.turntable_frames < 1 {
+                    bail!("Turntable must have at least 1 frame");
+                }
+            }
+            AnimationPath::Keyframes => {
+                if self.keyframes.len() < 2 {
+                    bail!("Keyframed animation must have at least 2 keyframes");
+                }
+                if self.keyframes.iter().any(|k| k.time < 0.0) {
+                    bail!("Keyframe times must be 0 or greater");
+                }
+            }
+        }
+        if self.output.file_stem().is_none() {
+            bail!("Animation output path must include a file name");
+        }
+        Ok(())
+    }
+
+    /// The keyframes in the order they happen.
+    fn sorted_keyframes(&self) -> Vec<Keyframe> {
+        let mut keyframes = self.keyframes.clone();
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        keyframes
+    }
+
+    pub(crate) fn frame_count(&self) -> usize {
+        match self.path {
+            AnimationPath::Turntable => self.turntable_frames,
+            AnimationPath::Keyframes => {
+                let keyframes = self.sorted_keyframes();
+                let duration = match (keyframes.first(), keyframes.last()) {
+                    (Some(first), Some(last)) => last.time - first.time,
+                    _ => 0.0,
+                };
+                (duration * self.frames_per_second).round() as usize + 1
+            }
+        }
+    }
+
+    /// Work out what to render for each frame of the animation, starting from `config`.
+    pub(crate) fn frame_inputs(&self, config: &RenderConfig) -> Result<Vec<RenderInput>> {
+        self.validate()?;
+        let frame_count = self.frame_count();
+        let keyframes = self.sorted_keyframes();
+
+        (0..frame_count)
+            .map(|frame| {
+                let mut frame_config = config.clone();
+                // a playing skeletal animation clip carries on playing from where it's up to
+                if config.animation_playing {
+                    frame_config.animation_time +=
+                        config.animation_speed * frame as f32 / self.frames_per_second;
+                }
+                match self.path {
+                    AnimationPath::Turntable => {
+                        let angle = std::f32::consts::TAU * frame as f32 / frame_count as f32;
+                        frame_config.orbit_camera(angle, 0.0);
+                    }
+                    AnimationPath::Keyframes => {
+                        let time = keyframes[0].time + frame as f32 / self.frames_per_second;
+                        let next = keyframes
+                            .iter()
+                            .position(|k| k.time > time)
+                            .unwrap_or(keyframes.len() - 1)
+                            .max(1);
+                        let (a, b) = (&keyframes[next - 1], &keyframes[next]);
+                        let s = if b.time > a.time {
+                            ((time - a.time) / (b.time - a.time)).clamp(0.0, 1.0)
+                        } else {
+                            1.0
+                        };
+                        let keyframe = a.interpolate(b, s);
+                        frame_config.camera_look_from = keyframe.camera_look_from;
+                        frame_config.camera_look_at = keyframe.camera_look_at;
+                        frame_config.light_dir = keyframe.light_dir;
+                    }
+                }
+                frame_config
+                    .validate()
+                    .with_context(|| format!("Frame {} can't be rendered", frame))
+            })
+            .collect()
+    }
+}
+
+/// Render each frame of an animation in turn and save them, calling `on_frame` with the index of
+/// each frame once it has been saved. Stops with an error if `progress` is cancelled.
+pub(crate) fn export_animation(
+    settings: &AnimationSettings,
+    frames: Vec<RenderInput>,
+    assets: &AssetCache,
+    progress: Arc<dyn ProgressSink>,
+    mut on_frame: impl FnMut(usize, &Canvas),
+) -> Result<()> {
+    let mut writer = None;
+    let frame_count = frames.len();
+    for (index, input) in frames.iter().enumerate() {
+        let finished = worker::render(input, assets, progress.clone(), true)?;
+        if progress.is_cancelled() {
+            bail!("Animation export was cancelled");
+        }
+        let image = &finished.image;
+        if writer.is_none() {
+            writer = Some(AnimationWriter::new(
+                settings,
+                image.width(),
+                image.height(),
+                frame_count,
+            )?);
+        }
+        writer
+            .as_mut()
+            .expect("writer was just created")
+            .write_frame(index, image.pixels())?;
+        on_frame(index, image);
+    }
+    match writer {
+        Some(writer) => writer.finish(),
+        None => bail!("Animation has no frames"),
+    }
+}
+
+/// Saves frames of an animation as they're rendered.
+enum AnimationWriter {
+    PngSequence {
+        output: PathBuf,
+        width: usize,
+        height: usize,
+    },
+    Gif {
+        encoder: gif::Encoder<BufWriter<File>>,
+        width: u16,
+        height: u16,
+        /// Time to show each frame for, in hundredths of a second
+        delay: u16,
+    },
+    Apng(png::Writer<BufWriter<File>>),
+}
+
+impl AnimationWriter {
+    fn new(
+        settings: &AnimationSettings,
+        width: usize,
+        height: usize,
+        frame_count: usize,
+    ) -> Result<Self> {
+        let create = |path: &Path| {
+            File::create(path)
+                .map(BufWriter::new)
+                .with_context(|| format!("Failed to create {}", path.display()))
+        };
+
+        Ok(match settings.format {
+            AnimationFormat::PngSequence => AnimationWriter::PngSequence {
+                output: settings.output.clone(),
+                width,
+                height,
+            },
+            AnimationFormat::Gif => {
+                let (width, height) = (width as u16, height as u16);
+                let mut encoder = gif::Encoder::new(create(&settings.output)?, width, height, &[])
+                    .context("Failed to start GIF")?;
+                encoder.set_repeat(gif::Repeat::Infinite)?;
+                AnimationWriter::Gif {
+                    encoder,
+                    width,
+                    height,
+                    delay: (100.0 / settings.frames_per_second).round().max(1.0) as u16,
+                }
+            }
+            AnimationFormat::Apng => {
+                let mut encoder =
+                    png::Encoder::new(create(&settings.output)?, width as u32, height as u32);
+                encoder.set_color(png::ColorType::Rgba);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.set_animated(frame_count as u32, 0)?;
+                encoder.set_frame_delay(
+                    (1000.0 / settings.frames_per_second).round().max(1.0) as u16,
+                    1000,
+                )?;
+                AnimationWriter::Apng(encoder.write_header().context("Failed to start APNG")?)
+            }
+        })
+    }
+
+    fn write_frame(&mut self, index: usize, pixels: &[RGBA8]) -> Result<()> {
+        match self {
+            AnimationWriter::PngSequence {
+                output,
+                width,
+                height,
+            } => {
+                let path = numbered_frame_path(output, index);
+                lodepng::encode_file(&path, pixels, *width, *height, lodepng::ColorType::RGBA, 8)
+                    .with_context(|| format!("Failed to save frame to {}", path.display()))?;
+            }
+            AnimationWriter::Gif {
+                encoder,
+                width,
+                height,
+                delay,
+            } => {
+                let mut bytes = pixels.as_bytes().to_vec();
+                let mut frame = gif::Frame::from_rgba_speed(*width, *height, &mut bytes, 10);
+                frame.delay = *delay;
+                encoder.write_frame(&frame)?;
+            }
+            AnimationWriter::Apng(writer) => writer.write_image_data(pixels.as_bytes())?,
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            AnimationWriter::PngSequence { .. } => {}
+            // the GIF trailer is written when its encoder is dropped
+            AnimationWriter::Gif { .. } => {}
+            AnimationWriter::Apng(writer) => writer.finish()?,
+        }
+        Ok(())
+    }
+}
+
+/// Where to save a frame of a PNG sequence, e.g. `target/animation_0007.png`.
+fn numbered_frame_path(output: &Path, index: usize) -> PathBuf {
+    let stem = output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    output.with_file_name(format!("{}_{:04}.png", stem, index))
+}
+
+/// An animation to render without the UI.
+#[derive(Debug, serde::Deserialize)]
+struct AnimationFile {
+    scene: RenderScene,
+    #[serde(default)]
+    render: RenderConfig,
+    #[serde(default)]
+    animation: AnimationSettings,
+}
+
+/// Render and save the animation described in a RON file, printing progress as it goes.
+pub(crate) fn export_from_file(path: &Path) -> Result<()> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read animation from {}", path.display()))?;
+    let file: AnimationFile = ron::from_str(&text)
+        .with_context(|| format!("Failed to parse animation from {}", path.display()))?;
+
+    let config = RenderConfig {
+        scene: file.scene,
+        ..file.render
+    };
+    let frames = file.animation.frame_inputs(&config)?;
+    let frame_count = frames.len();
+    println!(
+        "Exporting {} frame {} animation to {}",
+        frame_count,
+        file.animation.format,
+        file.animation.output.display()
+    );
+    export_animation(
+        &file.animation,
+        frames,
+        &AssetCache::default(),
+        Arc::new(NoProgress),
+        |index, _| println!("Saved frame {}/{}", index + 1, frame_count),
+    )
+}
+
+/// Progress sink for renders that nobody is watching.
+pub(crate) struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn report(&self, _pass: &str, _fraction: f32, _canvas: &Canvas) {}
+
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyframes_should_interpolate_around_the_look_at_point() {
+        let keyframe = |time, camera_look_from, light_dir| Keyframe {
+            time,
+            camera_look_from,
+            camera_look_at: Vec3::ZERO,
+            light_dir,
+        };
+        let start = keyframe(0.0, Vec3::new(0.0, 0.0, 3.0), Vec3::Z);
+        let end = keyframe(2.0, Vec3::new(3.0, 0.0, 0.0), Vec3::X);
+
+        let middle = start.interpolate(&end, 0.5);
+
+        let diagonal = Vec3::new(1.0, 0.0, 1.0).normalize();
+        assert_eq!(middle.time, 1.0);
+        assert!(middle.camera_look_from.abs_diff_eq(diagonal * 3.0, 1e-5));
+        assert!(middle.light_dir.abs_diff_eq(diagonal, 1e-5));
+    }
+}
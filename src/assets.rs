@@ -0,0 +1,562 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, MutexGuard, Weak},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use crab_tv::{MeshReport, Model, ModelFile, ModelInput, SubdivisionScheme, Texture};
+use eframe::epi::RepaintSignal;
+
+/// How often to check whether the files of loaded assets have changed.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Models and textures loaded from disk by path, shared between the UI and render threads. Each
+/// model loads on its own background thread, and once `watch` has been called, assets are
+/// reloaded whenever their files change. The lock on the cache's state is only held to look up
+/// and record assets, never while loading or processing them or checking their files for changes,
+/// so the UI thread (which checks the cache's generation every frame) is never held up by the disk.
+#[derive(Clone, Default)]
+pub(crate) struct AssetCache {
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    /// Notified whenever an asset finishes loading or a model finishes being subdivided
+    loaded: Condvar,
+    repaint_signal: Mutex<Option<Arc<dyn RepaintSignal>>>,
+}
+
+/// A model's address, how it's subdivided and how many times.
+type SubdivisionKey = (usize, SubdivisionScheme, usize);
+
+#[derive(Default)]
+struct State {
+    models: HashMap<PathBuf, Asset<ModelFile>>,
+    /// What each model was loaded from, for reloading it
+    model_inputs: HashMap<PathBuf, ModelInput>,
+    /// The other files each model was read from (besides its textures), and when they were
+    /// modified
+    model_dependencies: HashMap<PathBuf, Vec<(PathBuf, Option<SystemTime>)>>,
+    textures: HashMap<PathBuf, Asset<Texture>>,
+    /// Subdivided copies of models, by the address of the model they were made from (which is
+    /// checked against a weak reference, since the address can be reused once it's dropped).
+    /// `None` while the copy is being made.
+    subdivided: HashMap<SubdivisionKey, (Weak<Model>, Option<Arc<Model>>)>,
+    /// Reports on models, by the address of the model (checked in the same way)
+    reports: HashMap<usize, (Weak<Model>, Arc<MeshReport>)>,
+    /// Incremented each time assets finish reloading because their files changed
+    generation: u64,
+    watching: bool,
+}
+
+enum Asset<T> {
+    Loading,
+    Loaded {
+        value: Arc<T>,
+        modified: Option<SystemTime>,
+    },
+    Failed {
+        error: String,
+        modified: Option<SystemTime>,
+    },
+}
+
+impl<T> Asset<T> {
+    fn new(result: &Result<Arc<T>>, modified: Option<SystemTime>) -> Self {
+        match result {
+            Ok(value) => Asset::Loaded {
+                value: value.clone(),
+                modified,
+            },
+            Err(err) => Asset::Failed {
+                error: format!("{:#}", err),
+                modified,
+            },
+        }
+    }
+
+    /// When the asset's file was modified as of loading it (or failing to), or `None` while it's
+    /// still loading.
+    fn modified(&self) -> Option<Option<SystemTime>> {
+        match self {
+            Asset::Loading => None,
+            Asset::Loaded { modified, .. } | Asset::Failed { modified, .. } => Some(*modified),
+        }
+    }
+}
+
+impl State {
+    /// Drop the models that aren't in `used` (unless they're still loading, as something may be
+    /// waiting for them) and the textures that only the cache is holding on to.
+    fn evict_unused(&mut self, used: &[&ModelInput]) {
+        let is_used = |path: &Path| used.iter().any(|input| input.path() == path);
+        self.models
+            .retain(|path, model| matches!(model, Asset::Loading) || is_used(path));
+        let models = &self.models;
+        self.model_inputs
+            .retain(|path, _| models.contains_key(path));
+        self.model_dependencies
+            .retain(|path, _| models.contains_key(path));
+        self.textures.retain(|_, texture| match texture {
+            Asset::Loading => true,
+            Asset::Loaded { value, .. } => Arc::strong_count(value) > 1,
+            Asset::Failed { .. } => false,
+        });
+    }
+}
+
+impl AssetCache {
+    /// Get the model files (and their textures), loading any that aren't loaded yet at the same
+    /// time as each other, then waiting for them all to finish. Any other models that have
+    /// finished loading are dropped from the cache, along with textures nothing uses any more, so
+    /// that assets which are no longer needed can be freed.
+    pub(crate) fn models(&self, inputs: &[&ModelInput]) -> Result<Vec<Arc<ModelFile>>> {
+        {
+            let mut state = self.lock();
+            state.evict_unused(inputs);
+            for &input in inputs {
+                if !matches!(
+                    state.models.get(input.path()),
+                    Some(Asset::Loading | Asset::Loaded { .. })
+                ) {
+                    self.start_loading_model(&mut state, input.clone(), false);
+                }
+            }
+        }
+
+        inputs
+            .iter()
+            .map(|input| self.wait(|state| &state.models, input.path()))
+            .collect()
+    }
+
+    /// Start checking loaded assets for changes to their files, reloading them and then asking
+    /// the UI to repaint when they do. Does nothing if already watching.
+    pub(crate) fn watch(&self, repaint_signal: Arc<dyn RepaintSignal>) {
+        let mut state = self.lock();
+        if state.watching {
+            return;
+        }
+        state.watching = true;
+        *self.shared.repaint_signal.lock().expect("lock poisoned") = Some(repaint_signal);
+
+        let shared = Arc::downgrade(&self.shared);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(WATCH_INTERVAL);
+            // stop once the cache is no longer used by anything else
+            match Weak::upgrade(&shared) {
+                Some(shared) => AssetCache { shared }.reload_changed(),
+                None => break,
+            };
+        });
+    }
+
+    /// Counts how many times assets have been reloaded because their files changed, so anything
+    /// drawn with them can be redrawn when it changes.
+    pub(crate) fn generation(&self) -> u64 {
+        self.lock().generation
+    }
+
+    /// Start reloading every asset whose file has changed, along with every model using a texture
+    /// or other file which has changed. Returns how many models are being reloaded.
+    pub(crate) fn reload_changed(&self) -> usize {
+        // note when everything was modified as of loading it, so that the files can be checked
+        // without holding the lock
+        let (textures, models) = {
+            let state = self.lock();
+            let textures: Vec<_> = state
+                .textures
+                .iter()
+                .filter_map(|(path, texture)| Some((path.clone(), texture.modified()?)))
+                .collect();
+            let models: Vec<_> = state
+                .models
+                .iter()
+                .filter_map(|(path, model)| {
+                    let mut files = vec![(path.clone(), model.modified()?)];
+                    files.extend(
+                        state
+                            .model_dependencies
+                            .get(path)
+                            .into_iter()
+                            .flatten()
+                            .cloned(),
+                    );
+                    Some((state.model_inputs[path].clone(), files))
+                })
+                .collect();
+            (textures, models)
+        };
+
+        // changed textures are left in the cache until they're replaced by the models using them
+        // reloading, so that anything still waiting for them gets them
+        let is_changed =
+            |(path, modified): &(PathBuf, Option<SystemTime>)| modified_time(path) != *modified;
+        let changed_textures: HashSet<PathBuf> = textures
+            .into_iter()
+            .filter(is_changed)
+            .map(|(path, _)| path)
+            .collect();
+        let changed_models: Vec<ModelInput> = models
+            .into_iter()
+            .filter(|(input, files)| {
+                files.iter().any(is_changed)
+                    || input.texture_paths().any(|t| changed_textures.contains(t))
+            })
+            .map(|(input, _)| input)
+            .collect();
+
+        let mut state = self.lock();
+        let mut reloading = 0;
+        for input in changed_models {
+            // skip models that were dropped or started reloading while the files were checked
+            if state
+                .models
+                .get(input.path())
+                .and_then(Asset::modified)
+                .is_none()
+            {
+                continue;
+            }
+            println!("Reloading changed model: {}", input.path().display());
+            self.start_loading_model(&mut state, input, true);
+            reloading += 1;
+        }
+        reloading
+    }
+
+    fn start_loading_model(&self, state: &mut State, input: ModelInput, reload: bool) {
+        let path = input.path().to_owned();
+        state.models.insert(path.clone(), Asset::Loading);
+        state.model_inputs.insert(path.clone(), input.clone());
+
+        let cache = self.clone();
+        std::thread::spawn(move || {
+            // check when the file was modified before reading it, so that changes made while it is
+            // being read are picked up next time
+            let modified = modified_time(&path);
+            let result = ModelFile::load(&input, |texture| cache.texture(texture)).map(Arc::new);
+
+            // files found while loading are only checked afterwards, so a change to one of them
+            // during the load can be missed
+            let dependencies = result.as_ref().map_or_else(
+                |_| Vec::new(),
+                |file| {
+                    file.dependencies
+                        .iter()
+                        .map(|file| (file.clone(), modified_time(file)))
+                        .collect()
+                },
+            );
+
+            let mut state = cache.lock();
+            state
+                .models
+                .insert(path.clone(), Asset::new(&result, modified));
+            state.model_dependencies.insert(path.clone(), dependencies);
+            if reload {
+                state.generation += 1;
+            }
+            // this thread's copy is only kept to simplify, and is let go of once that's done so
+            // that the cache's copy is the only one once it's no longer used
+            let loaded = result.ok();
+            drop(state);
+            cache.shared.loaded.notify_all();
+            if reload {
+                cache.request_repaint();
+            }
+
+            if let Some(file) = loaded {
+                cache.generate_lods(&path, file);
+            }
+        });
+    }
+
+    /// Simplify a model file that has just been loaded into levels of detail, then replace the
+    /// cache's copy with one that has them. Until then the model is drawn at full detail.
+    fn generate_lods(&self, path: &Path, file: Arc<ModelFile>) {
+        let mut with_lods = ModelFile::clone(&file);
+        with_lods.generate_lods();
+
+        let mut state = self.lock();
+        // the model may have been reloaded or dropped while it was being simplified
+        let replaced = match state.models.get_mut(path) {
+            Some(Asset::Loaded { value, .. }) if Arc::ptr_eq(value, &file) => {
+                *value = Arc::new(with_lods);
+                state.generation += 1;
+                true
+            }
+            _ => false,
+        };
+        // let go of the old copy before the new one can be seen, so that nothing can find the new
+        // one while the old one is still held
+        drop(file);
+        drop(state);
+        if replaced {
+            self.request_repaint();
+        }
+    }
+
+    fn request_repaint(&self) {
+        if let Some(repaint_signal) = &*self.shared.repaint_signal.lock().expect("lock poisoned") {
+            repaint_signal.request_repaint();
+        }
+    }
+
+    /// Get a texture, loading it on this thread if no other thread is loading it already. Textures
+    /// whose files have changed since they were loaded are loaded again, replacing the old copy.
+    fn texture(&self, path: &Path) -> Result<Arc<Texture>> {
+        // check when the file was modified before reading it (and without holding the lock), so
+        // that changes made while it is being read are picked up next time
+        let modified = modified_time(path);
+        {
+            let mut state = self.lock();
+            match state.textures.get(path) {
+                Some(texture @ Asset::Loaded { .. }) if texture.modified() == Some(modified) => {
+                    drop(state);
+                    return self.wait(|state| &state.textures, path);
+                }
+                Some(Asset::Loading) => {
+                    drop(state);
+                    return self.wait(|state| &state.textures, path);
+                }
+                Some(Asset::Loaded { .. } | Asset::Failed { .. }) | None => {
+                    state.textures.insert(path.to_owned(), Asset::Loading);
+                }
+            }
+        }
+
+        let result = Texture::load_from_file(path).map(Arc::new);
+        self.lock()
+            .textures
+            .insert(path.to_owned(), Asset::new(&result, modified));
+        self.shared.loaded.notify_all();
+        result
+    }
+
+    /// Wait for the asset at `path` in the map picked by `assets` to finish loading.
+    fn wait<T>(
+        &self,
+        assets: impl Fn(&State) -> &HashMap<PathBuf, Asset<T>>,
+        path: &Path,
+    ) -> Result<Arc<T>> {
+        let mut state = self.lock();
+        loop {
+            match assets(&state).get(path) {
+                Some(Asset::Loaded { value, .. }) => return Ok(value.clone()),
+                Some(Asset::Failed { error, .. }) => return Err(anyhow!("{}", error)),
+                Some(Asset::Loading) => {
+                    state = self.shared.loaded.wait(state).expect("lock poisoned");
+                }
+                None => return Err(anyhow!("{} was never loaded", path.display())),
+            }
+        }
+    }
+
+    /// A copy of a loaded model subdivided by `scheme`, which is made on a background thread the
+    /// first time it's asked for and kept for as long as the model it was made from is. Until it's
+    /// ready the model itself is returned (unless `wait` is set, in which case this waits for it),
+    /// and the cache's generation goes up once it is.
+    pub(crate) fn subdivided(
+        &self,
+        model: &Arc<Model>,
+        scheme: SubdivisionScheme,
+        levels: usize,
+        wait: bool,
+    ) -> Arc<Model> {
+        if scheme == SubdivisionScheme::Off || levels == 0 {
+            return model.clone();
+        }
+        let key = (Arc::as_ptr(model) as usize, scheme, levels);
+        let mut state = self.lock();
+        loop {
+            match state.subdivided.get(&key) {
+                Some((source, subdivided))
+                    if source
+                        .upgrade()
+                        .is_some_and(|source| Arc::ptr_eq(&source, model)) =>
+                {
+                    match subdivided {
+                        Some(subdivided) => return subdivided.clone(),
+                        None if wait => {
+                            state = self.shared.loaded.wait(state).expect("lock poisoned");
+                        }
+                        None => return model.clone(),
+                    }
+                }
+                _ => {
+                    state
+                        .subdivided
+                        .retain(|_, (source, _)| source.strong_count() > 0);
+                    state.subdivided.insert(key, (Arc::downgrade(model), None));
+                    self.start_subdividing(key, Arc::downgrade(model));
+                }
+            }
+        }
+    }
+
+    fn start_subdividing(&self, key: SubdivisionKey, source: Weak<Model>) {
+        let cache = self.clone();
+        std::thread::spawn(move || {
+            // nothing needs the subdivided copy if the model has been let go of already
+            let model = match source.upgrade() {
+                Some(model) => model,
+                None => return,
+            };
+            let (_, scheme, levels) = key;
+            let subdivided = Arc::new(model.subdivided(scheme, levels));
+
+            let mut state = cache.lock();
+            // holding on to the model means its address can't have been reused by another
+            if let Some((_, pending)) = state.subdivided.get_mut(&key) {
+                *pending = Some(subdivided);
+                state.generation += 1;
+            }
+            // let go of the model before anything can see it's been subdivided, so that the cache
+            // doesn't keep it alive
+            drop(model);
+            drop(state);
+            cache.shared.loaded.notify_all();
+            cache.request_repaint();
+        });
+    }
+
+    /// The report on a model, worked out the first time it's asked for and kept for as long as the
+    /// model is.
+    pub(crate) fn report(&self, model: &Arc<Model>) -> Arc<MeshReport> {
+        let key = Arc::as_ptr(model) as usize;
+        if let Some((source, report)) = self.lock().reports.get(&key) {
+            if source
+                .upgrade()
+                .is_some_and(|source| Arc::ptr_eq(&source, model))
+            {
+                return report.clone();
+            }
+        }
+
+        let report = Arc::new(model.report());
+        let mut state = self.lock();
+        state
+            .reports
+            .retain(|_, (source, _)| source.strong_count() > 0);
+        state
+            .reports
+            .insert(key, (Arc::downgrade(model), report.clone()));
+        report
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.shared.state.lock().expect("asset cache lock poisoned")
+    }
+}
+
+impl std::fmt::Debug for AssetCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.lock();
+        f.debug_struct("AssetCache")
+            .field("models", &state.models.keys().collect::<Vec<_>>())
+            .field("textures", &state.textures.keys().collect::<Vec<_>>())
+            .field("generation", &state.generation)
+            .finish()
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crab_tv::Model;
+
+    #[test]
+    fn changed_texture_should_reload_models_using_it() -> Result<()> {
+        // work on a copy of the model so that its files can be changed
+        let dir = PathBuf::from("target/asset-cache-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        for file in [
+            "head.obj",
+            "head.diffuse.png",
+            "head.normals_global.png",
+            "head.normals_darboux.png",
+            "head.specular.png",
+        ] {
+            std::fs::copy(Path::new("assets").join(file), dir.join(file))?;
+        }
+        let input = Model::validate(&dir.join("head.obj"))?;
+
+        let cache = AssetCache::default();
+        let model = model_with_lods(&cache, &input)?.models[0].clone();
+        assert_eq!(model.diffuse_texture.width, 1024);
+        let generation = cache.generation();
+        assert_eq!(cache.reload_changed(), 0, "nothing should have changed yet");
+
+        let diffuse_path = dir.join("head.diffuse.png");
+        lodepng::encode32_file(&diffuse_path, &[rgb::RGBA8::default(); 4], 2, 2)?;
+        // make sure the change is seen even on filesystems with coarse modification times
+        std::fs::File::options()
+            .write(true)
+            .open(&diffuse_path)?
+            .set_modified(SystemTime::now() + Duration::from_secs(10))?;
+
+        assert_eq!(cache.reload_changed(), 1);
+        let reloaded = model_with_lods(&cache, &input)?.models[0].clone();
+        assert_eq!(reloaded.diffuse_texture.width, 2);
+        assert!(
+            Arc::ptr_eq(&model.specular_texture, &reloaded.specular_texture),
+            "unchanged textures should not be loaded again"
+        );
+        // once for the reload, and again once its levels of detail are ready
+        assert_eq!(cache.generation(), generation + 2);
+
+        // switching to another model lets the first go, along with its textures once nothing
+        // else is holding on to them
+        drop((model, reloaded));
+        let other = Model::validate(Path::new("assets/diablo.obj"))?;
+        cache.models(&[&other])?;
+        let state = cache.lock();
+        assert_eq!(state.models.keys().collect::<Vec<_>>(), vec![other.path()]);
+        assert!(state.textures.keys().all(|path| !path.starts_with(&dir)));
+        Ok(())
+    }
+
+    #[test]
+    fn models_should_be_subdivided_in_the_background() {
+        let cache = AssetCache::default();
+        let model = Arc::new(Model::plane());
+        let subdivide = |wait| cache.subdivided(&model, SubdivisionScheme::Loop, 1, wait);
+
+        assert!(
+            Arc::ptr_eq(&subdivide(false), &model),
+            "the model should be drawn as it is until it's subdivided"
+        );
+        let subdivided = subdivide(true);
+        assert_eq!(subdivided.face_count(), model.face_count() * 4);
+        assert_eq!(cache.generation(), 1);
+        assert!(Arc::ptr_eq(&subdivide(false), &subdivided));
+    }
+
+    /// Get a model file once its levels of detail have been generated, which happens after it
+    /// has loaded.
+    fn model_with_lods(cache: &AssetCache, input: &ModelInput) -> Result<Arc<ModelFile>> {
+        let deadline = std::time::Instant::now() + Duration::from_secs(30);
+        loop {
+            let file = cache.models(&[input])?.remove(0);
+            if !file.lods.is_empty() {
+                return Ok(file);
+            }
+            anyhow::ensure!(
+                std::time::Instant::now() < deadline,
+                "levels of detail were never generated for {}",
+                input.path().display()
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
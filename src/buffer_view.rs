@@ -0,0 +1,212 @@
+use crab_tv::{false_color_map, Canvas, FragmentRecord, SceneNode};
+use glam::Vec3;
+use rgb::RGBA8;
+
+use crate::worker::FinishedRender;
+
+/// Which buffer of a finished render to display.
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::EnumIter,
+    PartialEq,
+    Eq,
+    strum::Display,
+)]
+pub enum BufferView {
+    #[default]
+    Color,
+    Depth,
+    ShadowMap,
+    Normals,
+    AmbientOcclusion,
+}
+
+impl BufferView {
+    /// Whether this buffer is only available if fragments were recorded during rendering.
+    pub(crate) fn needs_fragments(self) -> bool {
+        matches!(self, BufferView::Normals | BufferView::AmbientOcclusion)
+    }
+
+    /// The name shown in the buffer picker, which says which buffers need fragments recorded.
+    pub(crate) fn label(self) -> String {
+        if self.needs_fragments() {
+            format!("{} (needs pixel details recorded)", self)
+        } else {
+            self.to_string()
+        }
+    }
+}
+
+/// Shown wherever a buffer has nothing in it.
+const BACKGROUND: RGBA8 = RGBA8::new(0, 0, 0, 255);
+
+/// Draw a buffer of a finished render as an image the right way up, as its width, height and
+/// pixels, or `None` if the render doesn't have that buffer. Buffers of single values are
+/// normalized to span the full range of brightness (or colors, if `false_color` is set).
+pub(crate) fn buffer_pixels(
+    view: BufferView,
+    render: &FinishedRender,
+    false_color: bool,
+) -> Option<(usize, usize, Vec<RGBA8>)> {
+    let image = &render.image;
+    // the shadow map is drawn at the size the scene was rendered at, which is larger than the
+    // final image when supersampling
+    let size_of = |canvas: &Canvas| (canvas.width(), canvas.height());
+    let ((width, height), pixels) = match view {
+        BufferView::Color => (size_of(image), image.pixels().to_vec()),
+        BufferView::Depth => (
+            size_of(image),
+            normalized_pixels(depth_values(image), false_color),
+        ),
+        BufferView::ShadowMap => {
+            let shadow_map = render.output.shadow_map.as_ref()?;
+            (
+                size_of(shadow_map),
+                normalized_pixels(depth_values(shadow_map), false_color),
+            )
+        }
+        BufferView::Normals => {
+            let normals = normal_values(image, &render.nodes)?;
+            (
+                size_of(image),
+                normals
+                    .into_iter()
+                    .map(|normal| match normal {
+                        Some(n) => {
+                            // map each component from [-1, 1] to [0, 255]
+                            let c = (n + Vec3::ONE) * 0.5 * 255.0;
+                            RGBA8::new(c.x as u8, c.y as u8, c.z as u8, 255)
+                        }
+                        None => BACKGROUND,
+                    })
+                    .collect(),
+            )
+        }
+        BufferView::AmbientOcclusion => {
+            let values = fragment_values(image, |f| f.ambient_occlusion);
+            if values.iter().all(Option::is_none) {
+                return None;
+            }
+            (size_of(image), normalized_pixels(values, false_color))
+        }
+    };
+    Some((width, height, pixels))
+}
+
+/// Every pixel of a canvas from top to bottom, in the order of its (already flipped) pixels. The
+/// canvas's other buffers are left upside down when its pixels are flipped, so undo that here.
+fn display_order(canvas: &Canvas) -> impl Iterator<Item = (i32, i32)> {
+    let (width, height) = (canvas.width() as i32, canvas.height() as i32);
+    (0..height).flat_map(move |y| (0..width).map(move |x| (x, height - 1 - y)))
+}
+
+fn depth_values(canvas: &Canvas) -> Vec<Option<f32>> {
+    display_order(canvas)
+        .map(|(x, y)| Some(canvas.z_buffer_at(x, y)).filter(|z| z.is_finite()))
+        .collect()
+}
+
+fn fragment_values(
+    canvas: &Canvas,
+    value: impl Fn(&FragmentRecord) -> Option<f32>,
+) -> Vec<Option<f32>> {
+    display_order(canvas)
+        .map(|(x, y)| canvas.fragment(x, y).and_then(&value))
+        .collect()
+}
+
+/// The models' vertex normals interpolated across each pixel and turned to face the way their
+/// nodes are turned (and posed), or `None` if no fragments were recorded.
+fn normal_values(canvas: &Canvas, nodes: &[SceneNode]) -> Option<Vec<Option<Vec3>>> {
+    let normals: Vec<_> = display_order(canvas)
+        .map(|(x, y)| fragment_normal(nodes, canvas.fragment(x, y)?))
+        .collect();
+    if normals.iter().all(Option::is_none) {
+        None
+    } else {
+        Some(normals)
+    }
+}
+
+/// The vertex normal of the model a fragment was drawn from, interpolated across its face and
+/// turned to face the way its node is turned (and posed), or `None` if the fragment wasn't part
+/// of a face.
+pub(crate) fn fragment_normal(nodes: &[SceneNode], fragment: &FragmentRecord) -> Option<Vec3> {
+    let node = &nodes[fragment.node];
+    let vertices = node.face_vertices(fragment.face?);
+    let bc = fragment.barycentric_coords;
+    let normal = vertices[0].normal * bc.x + vertices[1].normal * bc.y + vertices[2].normal * bc.z;
+    Some(
+        node.model_matrix()
+            .inverse()
+            .transpose()
+            .transform_vector3(normal)
+            .normalize_or_zero(),
+    )
+}
+
+fn normalized_pixels(values: Vec<Option<f32>>, false_color: bool) -> Vec<RGBA8> {
+    let (min, max) = values
+        .iter()
+        .flatten()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), v| {
+            (min.min(*v), max.max(*v))
+        });
+    let range = if max > min { max - min } else { 1.0 };
+
+    values
+        .into_iter()
+        .map(|value| match value {
+            Some(v) => {
+                let t = (v - min) / range;
+                if false_color {
+                    false_color_map(t)
+                } else {
+                    let c = (t * 255.0) as u8;
+                    RGBA8::new(c, c, c, 255)
+                }
+            }
+            None => BACKGROUND,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crab_tv::RenderScene;
+
+    use super::*;
+    use crate::{animation::NoProgress, assets::AssetCache, worker, AntiAliasing, RenderConfig};
+
+    #[test]
+    fn supersampled_shadow_map_should_be_shown_at_its_own_size() {
+        let config = RenderConfig {
+            scene: RenderScene::Shadowed,
+            width: 200,
+            height: 200,
+            anti_aliasing: AntiAliasing::Ssaa,
+            ssaa_factor: 2,
+            ..RenderConfig::default()
+        };
+        let input = config.validate().expect("config should be valid");
+        let render = worker::render(&input, &AssetCache::default(), Arc::new(NoProgress), true)
+            .expect("render should succeed");
+
+        let (width, height, pixels) =
+            buffer_pixels(BufferView::ShadowMap, &render, false).expect("scene has a shadow map");
+        assert_eq!((width, height), (400, 400));
+        assert_eq!(pixels.len(), width * height);
+
+        let (width, height, pixels) =
+            buffer_pixels(BufferView::Depth, &render, false).expect("every render has depth");
+        assert_eq!((width, height), (200, 200));
+        assert_eq!(pixels.len(), width * height);
+    }
+}
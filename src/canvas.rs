@@ -1,23 +1,212 @@
-use std::f32::consts::PI;
+use std::{f32::consts::PI, sync::Arc};
 
-use glam::{Mat3, Vec2, Vec3};
+use glam::{Mat3, Mat4, Vec2, Vec3, Vec4};
 use rgb::{ComponentMap, RGBA8};
 
 use crate::{
+    gbuffer::{GBuffer, GeometryShader},
     maths::{self, yolo_max, yolo_min},
-    Model, DEPTH_MAX,
+    progress::{Progress, ProgressSink},
+    Model, SceneNode, DEPTH_MAX,
 };
 
-#[derive(Copy, Clone, Debug, PartialEq, Default)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Vertex {
     pub position: Vec3,
     pub uv: Vec2,
     pub normal: Vec3,
+    /// Red, green, blue & alpha from 0 to 1, which the surface color is multiplied by; white for
+    /// models that are only colored by their textures
+    pub color: Vec4,
+    /// Indices of up to 4 joints of the model's skeleton that move this vertex
+    pub joints: [u16; 4],
+    /// How much each of `joints` moves this vertex, adding up to 1; all 0 for vertices that
+    /// aren't skinned
+    pub weights: Vec4,
 }
 
-pub trait Shader<S> {
-    fn vertex(&self, triangle: [Vertex; 3]) -> (Mat3, S);
+impl Default for Vertex {
+    fn default() -> Self {
+        Self {
+            position: Vec3::ZERO,
+            uv: Vec2::ZERO,
+            normal: Vec3::ZERO,
+            color: Vec4::ONE,
+            joints: [0; 4],
+            weights: Vec4::ZERO,
+        }
+    }
+}
+
+/// Which triangles (if any) should be discarded based on which way they face after the vertex
+/// stage has projected them to screen space.
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::EnumIter,
+    PartialEq,
+    Eq,
+    strum::Display,
+)]
+pub enum CullMode {
+    None,
+    #[default]
+    Back,
+    Front,
+}
+
+/// The order in which a front-facing triangle's vertices appear on screen.
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::EnumIter,
+    PartialEq,
+    Eq,
+    strum::Display,
+)]
+pub enum Winding {
+    #[default]
+    CounterClockwise,
+    Clockwise,
+}
+
+/// How a shaded fragment is combined with the color already in the canvas.
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::EnumIter,
+    PartialEq,
+    Eq,
+    strum::Display,
+)]
+pub enum BlendMode {
+    /// Replace the existing color and write depth
+    #[default]
+    Opaque,
+    /// Interpolate towards the fragment color by its alpha
+    Alpha,
+    /// Add the fragment color (scaled by its alpha) to the existing color
+    Additive,
+    /// Like alpha blending, but the fragment color has already been multiplied by its alpha (as
+    /// Phong shaders made `with_premultiplied_alpha` do)
+    Premultiplied,
+}
+
+impl BlendMode {
+    /// Whether fragments drawn with this mode should occlude fragments drawn behind them later.
+    pub fn writes_depth(self) -> bool {
+        self == BlendMode::Opaque
+    }
+
+    pub fn blend(self, src: RGBA8, dst: RGBA8) -> RGBA8 {
+        let alpha = src.a as f32 / 255.0;
+        let mix = |s: u8, d: u8, s_weight: f32, d_weight: f32| {
+            (s as f32 * s_weight + d as f32 * d_weight)
+                .round()
+                .min(255.0) as u8
+        };
+        match self {
+            BlendMode::Opaque => src,
+            BlendMode::Alpha => RGBA8::new(
+                mix(src.r, dst.r, alpha, 1.0 - alpha),
+                mix(src.g, dst.g, alpha, 1.0 - alpha),
+                mix(src.b, dst.b, alpha, 1.0 - alpha),
+                mix(src.a, dst.a, 1.0, 1.0 - alpha),
+            ),
+            BlendMode::Additive => RGBA8::new(
+                mix(src.r, dst.r, alpha, 1.0),
+                mix(src.g, dst.g, alpha, 1.0),
+                mix(src.b, dst.b, alpha, 1.0),
+                dst.a.max(src.a),
+            ),
+            BlendMode::Premultiplied => RGBA8::new(
+                mix(src.r, dst.r, 1.0, 1.0 - alpha),
+                mix(src.g, dst.g, 1.0, 1.0 - alpha),
+                mix(src.b, dst.b, 1.0, 1.0 - alpha),
+                mix(src.a, dst.a, 1.0, 1.0 - alpha),
+            ),
+        }
+    }
+}
+
+/// Filter used when downsampling a supersampled canvas back to its output resolution.
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::EnumIter,
+    PartialEq,
+    Eq,
+    strum::Display,
+)]
+pub enum ResampleFilter {
+    #[default]
+    Box,
+    Lanczos,
+}
+
+/// Counters for the triangles (and their fragments) that have been passed through the shader
+/// pipeline of a canvas.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RasterStats {
+    /// Distinct vertices run through the vertex stage, which is less than three per triangle
+    /// where triangles share vertices
+    pub vertices_shaded: usize,
+    pub triangles_submitted: usize,
+    pub triangles_culled: usize,
+    pub triangles_rasterized: usize,
+    /// Fragments that passed the depth test and so were run through the fragment shader
+    pub fragments_shaded: usize,
+    /// Fragments that were hidden behind something already drawn, so were never shaded
+    pub fragments_depth_rejected: usize,
+}
+
+/// What was drawn to a single pixel, for inspecting a render. Only recorded by canvases that have
+/// had `set_record_fragments` enabled.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct FragmentRecord {
+    /// Index of the scene node whose model was drawn here
+    pub node: usize,
+    /// Index into the model's faces of the triangle drawn here, if it was drawn from a model
+    pub face: Option<usize>,
+    /// Screen space barycentric coordinates of the pixel within that triangle
+    pub barycentric_coords: Vec3,
+    /// Whether the shader found this pixel to be in shadow, if it looked up a shadow map
+    pub shadowed: Option<bool>,
+    /// The multiplier applied to the pixel by `apply_ambient_occlusion`, if it was applied
+    pub ambient_occlusion: Option<f32>,
+}
+
+/// A shader run on the vertices of models, producing a `V` for each vertex, which are then gathered
+/// into an `S` for each triangle that its fragments are shaded with.
+pub trait Shader<V, S> {
+    /// Transform a vertex to screen coordinates, along with anything else its triangles need from
+    /// it. `model_shader` only runs this once for each of a model's vertices, however many
+    /// triangles share it.
+    fn vertex(&self, vertex: &Vertex) -> (Vec3, V);
+    /// Gather the outputs of a triangle's vertices (whose screen coordinates are the columns of
+    /// `screen_coords`) into the state used to shade its fragments.
+    fn triangle(&self, screen_coords: Mat3, vertices: [V; 3]) -> S;
     fn fragment(&self, barycentric_coords: Vec3, state: &S) -> Option<RGBA8>;
+
+    /// Add any details of how a fragment was shaded to its record. Only called when the canvas is
+    /// recording fragments.
+    fn inspect(&self, _barycentric_coords: Vec3, _state: &S, _record: &mut FragmentRecord) {}
 }
 
 #[derive(Clone, Debug)]
@@ -26,6 +215,24 @@ pub struct Canvas {
     height: usize,
     pixels: Vec<RGBA8>,
     z_buffer: Vec<f32>,
+    cull_mode: CullMode,
+    front_face: Winding,
+    stats: RasterStats,
+    blend_mode: BlendMode,
+    /// Number of coverage/depth samples per pixel; 1 means multisampling is disabled
+    msaa_samples: usize,
+    sample_pixels: Vec<RGBA8>,
+    sample_z_buffer: Vec<f32>,
+    /// Whether any sample of the pixel has been written since the last resolve
+    sample_touched: Vec<bool>,
+    /// Empty unless fragments are being recorded
+    fragments: Vec<Option<FragmentRecord>>,
+    /// Scene node being drawn, saved in fragment records
+    node: usize,
+    /// Pose of the scene node being drawn, which its model's vertices are moved into before
+    /// they're shaded
+    joint_matrices: Option<Arc<[Mat4]>>,
+    progress: Progress,
 }
 
 impl Canvas {
@@ -35,9 +242,31 @@ impl Canvas {
             height,
             pixels: vec![RGBA8::default(); width * height],
             z_buffer: vec![f32::NEG_INFINITY; width * height],
+            cull_mode: CullMode::default(),
+            front_face: Winding::default(),
+            stats: RasterStats::default(),
+            blend_mode: BlendMode::default(),
+            msaa_samples: 1,
+            sample_pixels: Vec::new(),
+            sample_z_buffer: Vec::new(),
+            sample_touched: Vec::new(),
+            fragments: Vec::new(),
+            node: 0,
+            joint_matrices: None,
+            progress: Progress::default(),
         }
     }
 
+    /// A blank canvas the size of this one, culling the same way and reporting progress to the same
+    /// sink, for drawing another pass of the same scene such as a shadow map. Unlike a clone, none
+    /// of this canvas's pixels, samples or fragment records are copied.
+    pub fn blank_copy(&self) -> Canvas {
+        let mut canvas = Canvas::new(self.width, self.height);
+        canvas.set_culling(self.cull_mode, self.front_face);
+        canvas.progress = self.progress.clone();
+        canvas
+    }
+
     /// Get a reference to the canvas's width.
     pub fn width(&self) -> usize {
         self.width
@@ -48,6 +277,123 @@ impl Canvas {
         self.height
     }
 
+    /// Set which faces are discarded by `model_shader`, and which winding order is front-facing.
+    pub fn set_culling(&mut self, cull_mode: CullMode, front_face: Winding) {
+        self.cull_mode = cull_mode;
+        self.front_face = front_face;
+    }
+
+    /// Set how fragments are combined with the existing canvas contents. When blending is enabled,
+    /// `model_shader` draws the model's triangles from back to front and does not write depth.
+    pub fn set_blend_mode(&mut self, blend_mode: BlendMode) {
+        self.blend_mode = blend_mode;
+    }
+
+    /// Get how fragments are combined with the existing canvas contents.
+    pub fn blend_mode(&self) -> BlendMode {
+        self.blend_mode
+    }
+
+    /// Set the number of samples per pixel used by `triangle_shader`; must be 1 (disabled), 2, 4
+    /// or 8. Coverage and depth are tracked per sample but fragments are shaded once per pixel.
+    pub fn set_msaa_samples(&mut self, samples: usize) {
+        assert!(
+            maths::msaa_sample_offsets(samples).is_some(),
+            "unsupported MSAA sample count: {}",
+            samples
+        );
+        self.msaa_samples = samples;
+        if samples > 1 {
+            let sample_count = self.width * self.height * samples;
+            self.sample_pixels = vec![RGBA8::default(); sample_count];
+            self.sample_z_buffer = vec![f32::NEG_INFINITY; sample_count];
+            self.sample_touched = vec![false; self.width * self.height];
+        } else {
+            self.sample_pixels = Vec::new();
+            self.sample_z_buffer = Vec::new();
+            self.sample_touched = Vec::new();
+        }
+    }
+
+    /// Get the number of samples per pixel.
+    pub fn msaa_samples(&self) -> usize {
+        self.msaa_samples
+    }
+
+    /// Set whether to keep a record of which triangle was drawn to each pixel (and how), so that the
+    /// render can be inspected afterwards. Existing records are discarded either way.
+    pub fn set_record_fragments(&mut self, enabled: bool) {
+        self.fragments = if enabled {
+            vec![None; self.width * self.height]
+        } else {
+            Vec::new()
+        };
+    }
+
+    /// Get the record of what was drawn at a pixel, if fragments are being recorded and anything
+    /// was drawn there. Note that `flip_y` does not flip fragment records.
+    pub fn fragment(&self, x: i32, y: i32) -> Option<&FragmentRecord> {
+        self.fragments
+            .get(y as usize * self.width + x as usize)
+            .and_then(Option::as_ref)
+    }
+
+    /// Get the record of what was drawn at a pixel for adding further details to it.
+    pub fn fragment_mut(&mut self, x: i32, y: i32) -> Option<&mut FragmentRecord> {
+        self.fragments
+            .get_mut(y as usize * self.width + x as usize)
+            .and_then(Option::as_mut)
+    }
+
+    /// Start a new fragment record for a pixel, if fragments are being recorded.
+    fn record_fragment<V, S>(
+        &mut self,
+        idx: usize,
+        face: Option<usize>,
+        barycentric_coords: Vec3,
+        shader: &dyn Shader<V, S>,
+        shader_state: &S,
+    ) {
+        if let Some(slot) = self.fragments.get_mut(idx) {
+            let mut record = FragmentRecord {
+                node: self.node,
+                face,
+                barycentric_coords,
+                ..FragmentRecord::default()
+            };
+            shader.inspect(barycentric_coords, shader_state, &mut record);
+            *slot = Some(record);
+        }
+    }
+
+    /// Set where progress updates should be sent while drawing, and which can cancel drawing.
+    pub fn set_progress_sink(&mut self, sink: Option<Arc<dyn ProgressSink>>) {
+        self.progress.set_sink(sink);
+    }
+
+    /// Set the name of the pass that subsequent progress updates are reported against.
+    pub fn begin_pass(&mut self, name: &str) {
+        self.progress.set_pass(name);
+        self.progress.report(0.0, self);
+    }
+
+    /// Set which scene node subsequently drawn fragments are recorded as belonging to (by its
+    /// index among the scene's nodes), and draw models in that node's pose.
+    pub fn begin_node(&mut self, index: usize, node: &SceneNode) {
+        self.node = index;
+        self.joint_matrices = node.joint_matrices.clone();
+    }
+
+    /// Whether the progress sink has asked for drawing to stop.
+    pub fn is_cancelled(&self) -> bool {
+        self.progress.is_cancelled()
+    }
+
+    /// Get the counters for triangles passed through `model_shader` so far.
+    pub fn stats(&self) -> &RasterStats {
+        &self.stats
+    }
+
     pub fn pixels(&self) -> &[RGBA8] {
         &self.pixels
     }
@@ -146,39 +492,131 @@ impl Canvas {
         }
     }
 
-    pub fn model_shader<S>(&mut self, model: &Model, shader: &dyn Shader<S>) {
-        for face in model.faces.iter() {
-            let mut vertices = [Vertex::default(); 3];
-            for j in 0..3 {
-                vertices[j] = Vertex {
-                    position: {
-                        let v = model.vertices[face.points[j].vertices_index];
-                        // this simplistic rendering code assumes that the vertice coordinates are
-                        // between -1 and 1, so confirm that assumption
-                        debug_assert!(
-                            -1.0 <= v.pos.x && v.pos.x <= 1.0,
-                            "x coordinate out of range: {}",
-                            v.pos.x
-                        );
-                        debug_assert!(
-                            -1.0 <= v.pos.y && v.pos.y <= 1.0,
-                            "y coordinate out of range: {}",
-                            v.pos.y
-                        );
-                        v.pos
-                    },
-                    uv: model.texture_coords[face.points[j].uv_index],
-                    normal: model.vertex_normals[face.points[j].normals_index],
+    pub fn model_shader<V: Copy, S>(&mut self, model: &Model, shader: &dyn Shader<V, S>) {
+        // blended triangles are only drawn once all of them have been through the vertex stage
+        let mut blended_triangles = Vec::new();
+        let mut transformed = vec![None; model.vertices.len()];
+
+        let face_count = model.face_count();
+        let report_interval = progress_report_interval(face_count);
+        for face_index in 0..face_count {
+            if face_index % report_interval == 0 {
+                self.progress
+                    .report(face_index as f32 / face_count as f32, self);
+                if self.is_cancelled() {
+                    break;
                 }
             }
 
-            let (screen_coords, shader_state) = shader.vertex(vertices);
+            let (screen_coords, vertices) =
+                self.transform_face(model, face_index, &mut transformed, |v| shader.vertex(v));
+
+            self.stats.triangles_submitted += 1;
+            if self.is_culled(screen_coords) {
+                self.stats.triangles_culled += 1;
+                continue;
+            }
+            self.stats.triangles_rasterized += 1;
+            let shader_state = shader.triangle(screen_coords, vertices);
+
+            if self.blend_mode == BlendMode::Opaque {
+                self.face_shader(screen_coords, shader, shader_state, Some(face_index));
+            } else {
+                blended_triangles.push((face_index, screen_coords, shader_state));
+            }
+        }
+
+        // blending only composites correctly if the farthest (lowest z) triangles are drawn first
+        blended_triangles.sort_by(|(_, a, _), (_, b, _)| {
+            maths::yolo_compare(&a.row(2).dot(Vec3::ONE), &b.row(2).dot(Vec3::ONE))
+        });
+        for (face_index, screen_coords, shader_state) in blended_triangles {
+            self.face_shader(screen_coords, shader, shader_state, Some(face_index));
+        }
 
-            self.triangle_shader(screen_coords, shader, shader_state);
+        self.resolve_samples();
+        self.progress.report(1.0, self);
+    }
+
+    /// Average the samples of every multisampled pixel that has been drawn to since the last
+    /// resolve into its final color. `model_shader` does this automatically, but it must be called
+    /// after drawing with `triangle_shader` directly.
+    pub fn resolve_samples(&mut self) {
+        let samples = self.msaa_samples;
+        if samples <= 1 {
+            return;
         }
+        for (idx, touched) in self.sample_touched.iter_mut().enumerate() {
+            if !*touched {
+                continue;
+            }
+            // the next draw starts again from the resolved color, so anything drawn over it
+            // directly in the meantime isn't overwritten by stale samples
+            *touched = false;
+            let mut total = [0u32; 4];
+            for color in &self.sample_pixels[idx * samples..(idx + 1) * samples] {
+                for (t, c) in total.iter_mut().zip(color.iter()) {
+                    *t += c as u32;
+                }
+            }
+            let [r, g, b, a] = total.map(|t| (t / samples as u32) as u8);
+            self.pixels[idx] = RGBA8::new(r, g, b, a);
+        }
+    }
+
+    /// Run the vertex stage on each corner of a face, reusing the outputs already cached in
+    /// `transformed` for vertices shared with faces drawn earlier.
+    fn transform_face<V: Copy>(
+        &mut self,
+        model: &Model,
+        face: usize,
+        transformed: &mut [Option<(Vec3, V)>],
+        vertex: impl Fn(&Vertex) -> (Vec3, V),
+    ) -> (Mat3, [V; 3]) {
+        let outputs = model.face(face).map(|index| {
+            let index = index as usize;
+            *transformed[index].get_or_insert_with(|| {
+                self.stats.vertices_shaded += 1;
+                vertex(&model.vertices[index].posed(self.joint_matrices.as_deref()))
+            })
+        });
+        (
+            Mat3::from_cols(outputs[0].0, outputs[1].0, outputs[2].0),
+            outputs.map(|(_, output)| output),
+        )
     }
 
-    pub fn triangle_shader<S>(&mut self, pts: Mat3, shader: &dyn Shader<S>, shader_state: S) {
+    fn is_culled(&self, screen_coords: Mat3) -> bool {
+        let area = maths::signed_area_2d(screen_coords);
+        // a positive area means the vertices are counter clockwise on screen
+        let front_facing = match self.front_face {
+            Winding::CounterClockwise => area > 0.0,
+            Winding::Clockwise => area < 0.0,
+        };
+        match self.cull_mode {
+            CullMode::None => false,
+            CullMode::Back => !front_facing,
+            CullMode::Front => front_facing,
+        }
+    }
+
+    pub fn triangle_shader<V, S>(&mut self, pts: Mat3, shader: &dyn Shader<V, S>, shader_state: S) {
+        self.face_shader(pts, shader, shader_state, None);
+    }
+
+    /// Like `triangle_shader`, but for a triangle that is a face of a model (if `face` is `Some`).
+    fn face_shader<V, S>(
+        &mut self,
+        pts: Mat3,
+        shader: &dyn Shader<V, S>,
+        shader_state: S,
+        face: Option<usize>,
+    ) {
+        if self.msaa_samples > 1 {
+            self.triangle_shader_multisampled(pts, shader, shader_state, face);
+            return;
+        }
+
         let mut bboxmin = Vec2::new((self.width() - 1) as f32, (self.height() - 1) as f32);
         let mut bboxmax = Vec2::new(0.0, 0.0);
         let clamp = Vec2::new((self.width() - 1) as f32, (self.height() - 1) as f32);
@@ -190,6 +628,7 @@ impl Canvas {
             }
         }
 
+        let blend_mode = self.blend_mode;
         for i in (bboxmin.x as i32)..=(bboxmax.x as i32) {
             for j in (bboxmin.y as i32)..=(bboxmax.y as i32) {
                 let p = Vec2::new(i as f32, j as f32);
@@ -201,20 +640,295 @@ impl Canvas {
                 for k in 0..3 {
                     pixel_z += pts.col(k)[2] * bc_screen[k];
                 }
-                let z_buf_for_pixel = self.z_buffer_at_mut(i, j);
-                if *z_buf_for_pixel < pixel_z {
-                    let maybe_color = shader.fragment(bc_screen, &shader_state);
-                    if let Some(color) = maybe_color {
-                        *z_buf_for_pixel = pixel_z;
-                        *self.pixel_mut(i, j) = color;
+                let visible = self.z_buffer_at(i, j) < pixel_z;
+                if !visible {
+                    self.stats.fragments_depth_rejected += 1;
+                    continue;
+                }
+                self.stats.fragments_shaded += 1;
+                let maybe_color = shader.fragment(bc_screen, &shader_state);
+                if let Some(color) = maybe_color {
+                    if blend_mode.writes_depth() {
+                        *self.z_buffer_at_mut(i, j) = pixel_z;
+                    }
+                    let pixel = self.pixel_mut(i, j);
+                    *pixel = blend_mode.blend(color, *pixel);
+                    let idx = j as usize * self.width + i as usize;
+                    self.record_fragment(idx, face, bc_screen, shader, &shader_state);
+                }
+            }
+        }
+    }
+
+    /// Rasterize the model into a G-buffer rather than shading it, for deferred shading. Depth
+    /// testing uses (and updates) this canvas's z-buffer, but its pixels are left untouched;
+    /// multisampling and blending are not supported by the geometry pass.
+    pub fn model_geometry_pass<V: Copy, S>(
+        &mut self,
+        model: &Model,
+        shader: &dyn GeometryShader<V, S>,
+        gbuffer: &mut GBuffer,
+    ) {
+        assert!(
+            gbuffer.width() == self.width && gbuffer.height() == self.height,
+            "G-buffer size must match canvas size"
+        );
+
+        let mut transformed = vec![None; model.vertices.len()];
+        let face_count = model.face_count();
+        let report_interval = progress_report_interval(face_count);
+        for face_index in 0..face_count {
+            if face_index % report_interval == 0 {
+                self.progress
+                    .report(face_index as f32 / face_count as f32, self);
+                if self.is_cancelled() {
+                    return;
+                }
+            }
+
+            let (pts, vertices) =
+                self.transform_face(model, face_index, &mut transformed, |v| shader.vertex(v));
+
+            self.stats.triangles_submitted += 1;
+            if self.is_culled(pts) {
+                self.stats.triangles_culled += 1;
+                continue;
+            }
+            self.stats.triangles_rasterized += 1;
+            let shader_state = shader.triangle(pts, vertices);
+
+            let mut bboxmin = Vec2::new((self.width() - 1) as f32, (self.height() - 1) as f32);
+            let mut bboxmax = Vec2::new(0.0, 0.0);
+            let clamp = Vec2::new((self.width() - 1) as f32, (self.height() - 1) as f32);
+            for i in 0..3 {
+                for j in 0..2 {
+                    bboxmin[j] = yolo_max(0.0, yolo_min(bboxmin[j], pts.col(i)[j]));
+                    bboxmax[j] = yolo_min(clamp[j], yolo_max(bboxmax[j], pts.col(i)[j]));
+                }
+            }
+
+            for i in (bboxmin.x as i32)..=(bboxmax.x as i32) {
+                for j in (bboxmin.y as i32)..=(bboxmax.y as i32) {
+                    let bc_screen =
+                        maths::barycentric_coords_3d_matrix(pts, Vec2::new(i as f32, j as f32));
+                    if bc_screen.x < 0.0 || bc_screen.y < 0.0 || bc_screen.z < 0.0 {
+                        continue;
+                    }
+                    let pixel_z = pts.row(2).dot(bc_screen);
+                    let visible = self.z_buffer_at(i, j) < pixel_z;
+                    if !visible {
+                        self.stats.fragments_depth_rejected += 1;
+                        continue;
+                    }
+                    self.stats.fragments_shaded += 1;
+                    if let Some(texel) = shader.fragment(bc_screen, &shader_state) {
+                        *self.z_buffer_at_mut(i, j) = pixel_z;
+                        gbuffer.write(i, j, texel, pixel_z);
+                        if let Some(slot) =
+                            self.fragments.get_mut(j as usize * self.width + i as usize)
+                        {
+                            *slot = Some(FragmentRecord {
+                                node: self.node,
+                                face: Some(face_index),
+                                barycentric_coords: bc_screen,
+                                ..FragmentRecord::default()
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        self.progress.report(1.0, self);
+    }
+
+    fn triangle_shader_multisampled<V, S>(
+        &mut self,
+        pts: Mat3,
+        shader: &dyn Shader<V, S>,
+        shader_state: S,
+        face: Option<usize>,
+    ) {
+        let samples = self.msaa_samples;
+        let offsets = maths::msaa_sample_offsets(samples).expect("sample count was validated");
+
+        // samples can sit up to half a pixel away from the pixel position, so pad the bounding box
+        let mut bboxmin = Vec2::new((self.width() - 1) as f32, (self.height() - 1) as f32);
+        let mut bboxmax = Vec2::new(0.0, 0.0);
+        let clamp = Vec2::new((self.width() - 1) as f32, (self.height() - 1) as f32);
+
+        for i in 0..3 {
+            for j in 0..2 {
+                bboxmin[j] = yolo_max(0.0, yolo_min(bboxmin[j], pts.col(i)[j] - 1.0));
+                bboxmax[j] = yolo_min(clamp[j], yolo_max(bboxmax[j], pts.col(i)[j] + 1.0));
+            }
+        }
+
+        let mut sample_z = [0.0; 8];
+        for i in (bboxmin.x as i32)..=(bboxmax.x as i32) {
+            for j in (bboxmin.y as i32)..=(bboxmax.y as i32) {
+                let idx = j as usize * self.width + i as usize;
+
+                // find which samples of this pixel the triangle covers & is in front of
+                let mut inside = false;
+                let mut covered = 0u8;
+                let mut first_covered_bc = None;
+                for (s, offset) in offsets.iter().enumerate() {
+                    let p = Vec2::new(i as f32, j as f32) + *offset;
+                    let bc = maths::barycentric_coords_3d_matrix(pts, p);
+                    if bc.x < 0.0 || bc.y < 0.0 || bc.z < 0.0 {
+                        continue;
+                    }
+                    inside = true;
+                    let z = pts.row(2).dot(bc);
+                    if self.sample_z_buffer[idx * samples + s] < z {
+                        covered |= 1 << s;
+                        sample_z[s] = z;
+                        first_covered_bc.get_or_insert(bc);
+                    }
+                }
+                let first_covered_bc = match first_covered_bc {
+                    Some(bc) => bc,
+                    None => {
+                        if inside {
+                            self.stats.fragments_depth_rejected += 1;
+                        }
+                        continue;
                     }
+                };
+                self.stats.fragments_shaded += 1;
+
+                // shade once per pixel, at the pixel position itself if the triangle covers it
+                let center_bc =
+                    maths::barycentric_coords_3d_matrix(pts, Vec2::new(i as f32, j as f32));
+                let bc = if center_bc.x < 0.0 || center_bc.y < 0.0 || center_bc.z < 0.0 {
+                    first_covered_bc
+                } else {
+                    center_bc
+                };
+                let color = match shader.fragment(bc, &shader_state) {
+                    Some(color) => color,
+                    None => continue,
+                };
+
+                if !self.sample_touched[idx] {
+                    // samples not covered by anything yet keep whatever the pixel was before
+                    let previous = self.pixels[idx];
+                    self.sample_pixels[idx * samples..(idx + 1) * samples].fill(previous);
+                    self.sample_touched[idx] = true;
                 }
+                for s in 0..samples {
+                    if covered & (1 << s) != 0 {
+                        let sample = &mut self.sample_pixels[idx * samples + s];
+                        *sample = self.blend_mode.blend(color, *sample);
+                        if self.blend_mode.writes_depth() {
+                            self.sample_z_buffer[idx * samples + s] = sample_z[s];
+                            self.z_buffer[idx] = yolo_max(self.z_buffer[idx], sample_z[s]);
+                        }
+                    }
+                }
+                self.record_fragment(idx, face, bc, shader, &shader_state);
             }
         }
     }
 
+    /// Produce a canvas that is `factor` times smaller in each dimension, for supersampling.
+    pub fn downsample(&self, factor: usize, filter: ResampleFilter) -> Canvas {
+        assert!(factor > 0, "downsample factor must be positive");
+        assert!(
+            self.width.is_multiple_of(factor) && self.height.is_multiple_of(factor),
+            "canvas size {}x{} must be a multiple of the downsample factor {}",
+            self.width,
+            self.height,
+            factor
+        );
+        let mut result = Canvas::new(self.width / factor, self.height / factor);
+        result.set_culling(self.cull_mode, self.front_face);
+        result.stats = self.stats;
+
+        // depth has no meaningful filtered value, so take the nearest depth in each block
+        for y in 0..result.height {
+            for x in 0..result.width {
+                let mut z = f32::NEG_INFINITY;
+                for sy in y * factor..(y + 1) * factor {
+                    for sx in x * factor..(x + 1) * factor {
+                        z = yolo_max(z, self.z_buffer[sy * self.width + sx]);
+                    }
+                }
+                result.z_buffer[y * result.width + x] = z;
+            }
+        }
+
+        // likewise fragment records can't be blended, so keep the one nearest each block's center
+        if !self.fragments.is_empty() {
+            result.fragments = (0..result.height)
+                .flat_map(|y| (0..result.width).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    self.fragments[(y * factor + factor / 2) * self.width + x * factor + factor / 2]
+                })
+                .collect();
+        }
+
+        let source: Vec<[f32; 4]> = self
+            .pixels
+            .iter()
+            .map(|p| [p.r as f32, p.g as f32, p.b as f32, p.a as f32])
+            .collect();
+        let filtered = match filter {
+            ResampleFilter::Box => {
+                let mut out = vec![[0.0; 4]; result.width * result.height];
+                for y in 0..result.height {
+                    for x in 0..result.width {
+                        let o = &mut out[y * result.width + x];
+                        for sy in y * factor..(y + 1) * factor {
+                            for sx in x * factor..(x + 1) * factor {
+                                let p = source[sy * self.width + sx];
+                                for c in 0..4 {
+                                    o[c] += p[c];
+                                }
+                            }
+                        }
+                        for c in o.iter_mut() {
+                            *c /= (factor * factor) as f32;
+                        }
+                    }
+                }
+                out
+            }
+            ResampleFilter::Lanczos => {
+                // separable, so filter horizontally then vertically
+                let horizontal = lanczos_pass(
+                    &source,
+                    (self.width, self.height),
+                    factor,
+                    |x, y| y * self.width + x,
+                    result.width,
+                    |x, y| y * result.width + x,
+                );
+                lanczos_pass(
+                    &horizontal,
+                    (self.height, result.width),
+                    factor,
+                    |y, x| y * result.width + x,
+                    result.height,
+                    |y, x| y * result.width + x,
+                )
+            }
+        };
+
+        for (pixel, f) in result.pixels.iter_mut().zip(filtered) {
+            let [r, g, b, a] = f.map(|c| c.round().clamp(0.0, 255.0) as u8);
+            *pixel = RGBA8::new(r, g, b, a);
+        }
+        result
+    }
+
     pub fn apply_ambient_occlusion(&mut self, strength: f32, ambient_occlusion_passes: usize) {
         for x in 0..self.width() {
+            self.progress.report(x as f32 / self.width() as f32, self);
+            if self.is_cancelled() {
+                return;
+            }
+
             for y in 0..self.height() {
                 if (*self.z_buffer_at_mut(x as i32, y as i32)) < -1e5 {
                     continue;
@@ -238,9 +952,65 @@ impl Canvas {
                 *self.pixel_mut(x as i32, y as i32) = self
                     .pixel(x as i32, y as i32)
                     .map(|c| (total * c as f32) as u8);
+                if let Some(record) = self.fragment_mut(x as i32, y as i32) {
+                    record.ambient_occlusion = Some(total);
+                }
+            }
+        }
+        self.progress.report(1.0, self);
+    }
+}
+
+/// Report progress roughly every 1% of faces drawn.
+fn progress_report_interval(face_count: usize) -> usize {
+    (face_count / 100).max(1)
+}
+
+const LANCZOS_LOBES: f32 = 3.0;
+
+fn lanczos_kernel(x: f32) -> f32 {
+    if x.abs() < 1e-6 {
+        1.0
+    } else if x.abs() >= LANCZOS_LOBES {
+        0.0
+    } else {
+        let px = PI * x;
+        LANCZOS_LOBES * px.sin() * (px / LANCZOS_LOBES).sin() / (px * px)
+    }
+}
+
+/// Shrink one axis of `source` by `factor` with a lanczos filter. Coordinates are given as (along
+/// the axis being shrunk, across it) and mapped to buffer indices by `src_index` & `dst_index`.
+fn lanczos_pass(
+    source: &[[f32; 4]],
+    (src_len, across_len): (usize, usize),
+    factor: usize,
+    src_index: impl Fn(usize, usize) -> usize,
+    dst_len: usize,
+    dst_index: impl Fn(usize, usize) -> usize,
+) -> Vec<[f32; 4]> {
+    let mut out = vec![[0.0; 4]; dst_len * across_len];
+    let scale = factor as f32;
+    let support = LANCZOS_LOBES * scale;
+    for d in 0..dst_len {
+        let center = (d as f32 + 0.5) * scale - 0.5;
+        let first = yolo_max(0.0, (center - support).ceil()) as usize;
+        let last = yolo_min((src_len - 1) as f32, (center + support).floor()) as usize;
+        for across in 0..across_len {
+            let mut total = [0.0; 4];
+            let mut weight_sum = 0.0;
+            for s in first..=last {
+                let weight = lanczos_kernel((s as f32 - center) / scale);
+                let p = source[src_index(s, across)];
+                for c in 0..4 {
+                    total[c] += p[c] * weight;
+                }
+                weight_sum += weight;
             }
+            out[dst_index(d, across)] = total.map(|t| t / weight_sum);
         }
     }
+    out
 }
 
 fn max_elevation_angle(image: &Canvas, p: Vec2, dir: Vec2, samples: usize) -> f32 {
@@ -270,3 +1040,176 @@ fn max_elevation_angle(image: &Canvas, p: Vec2, dir: Vec2, samples: usize) -> f3
     }
     max_angle
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{viewport_transform, PureColorShader};
+
+    #[test]
+    fn model_shader_should_transform_shared_vertices_once() {
+        let mut image = Canvas::new(64, 64);
+        image.set_culling(CullMode::None, Winding::CounterClockwise);
+        let model = Model::plane();
+        let shader = PureColorShader::new(
+            viewport_transform(0.0, 0.0, 64.0, 64.0),
+            Mat4::from_rotation_x(std::f32::consts::FRAC_PI_2),
+        );
+        image.model_shader(&model, &shader);
+
+        let stats = image.stats();
+        assert_eq!(stats.triangles_rasterized, 2);
+        assert_eq!(stats.vertices_shaded, 4, "the plane has 4 corners");
+    }
+
+    #[test]
+    fn models_should_be_drawn_in_their_nodes_pose() {
+        let mut model = Model::plane();
+        for vertex in &mut model.vertices {
+            vertex.weights = Vec4::X;
+        }
+        // the plane's only joint moves it half way out of the image
+        let node = SceneNode {
+            joint_matrices: Some(vec![Mat4::from_translation(Vec3::X)].into()),
+            ..SceneNode::new(Arc::new(model))
+        };
+        let shader = PureColorShader::new(
+            viewport_transform(0.0, 0.0, 64.0, 64.0),
+            Mat4::from_rotation_x(std::f32::consts::FRAC_PI_2),
+        );
+        let mut image = Canvas::new(64, 64);
+        image.set_culling(CullMode::None, Winding::CounterClockwise);
+        image.begin_node(0, &node);
+        image.model_shader(&node.model, &shader);
+
+        assert_eq!(image.pixel(16, 32), RGBA8::default());
+        assert_eq!(image.pixel(48, 32), crate::WHITE);
+        // and the node gives the same posed corners for inspecting what was drawn
+        let corners_x = node.face_vertices(0).map(|v| v.position.x);
+        assert!(corners_x.iter().all(|&x| x >= 0.0), "{:?}", corners_x);
+    }
+
+    #[test]
+    fn blend_modes_should_combine_fragments_with_the_existing_color() {
+        let dst = RGBA8::new(100, 200, 0, 255);
+        let red = |a| RGBA8::new(255, 0, 0, a);
+
+        for mode in [
+            BlendMode::Opaque,
+            BlendMode::Alpha,
+            BlendMode::Premultiplied,
+        ] {
+            assert_eq!(mode.blend(red(255), dst), red(255), "{}", mode);
+        }
+        assert_eq!(BlendMode::Opaque.blend(red(0), dst), red(0));
+        assert_eq!(BlendMode::Alpha.blend(red(0), dst), dst);
+        assert_eq!(
+            BlendMode::Alpha.blend(red(102), dst),
+            RGBA8::new(162, 120, 0, 255)
+        );
+        assert_eq!(BlendMode::Additive.blend(red(0), dst), dst);
+        assert_eq!(
+            BlendMode::Additive.blend(red(255), dst),
+            RGBA8::new(255, 200, 0, 255)
+        );
+        assert_eq!(
+            BlendMode::Additive.blend(red(102), dst),
+            RGBA8::new(202, 200, 0, 255)
+        );
+        // premultiplied colors are already scaled by their alpha, so a fully transparent one adds
+        // nothing and a 40% opaque one matches alpha blending the straight color
+        assert_eq!(BlendMode::Premultiplied.blend(RGBA8::default(), dst), dst);
+        assert_eq!(
+            BlendMode::Premultiplied.blend(RGBA8::new(102, 0, 0, 102), dst),
+            BlendMode::Alpha.blend(red(102), dst)
+        );
+    }
+
+    /// A triangle whose left edge runs down x = 3 (through the middle of that column's pixels)
+    fn right_of_column_three() -> Mat3 {
+        Mat3::from_cols(
+            Vec3::new(3.0, -10.0, 0.0),
+            Vec3::new(30.0, -10.0, 0.0),
+            Vec3::new(3.0, 30.0, 0.0),
+        )
+    }
+
+    #[test]
+    fn msaa_should_blend_partly_covered_pixels_and_resolve_each_draw_once() {
+        let mut image = Canvas::new(8, 8);
+        image.set_msaa_samples(4);
+        let shader = PureColorShader::new(Mat4::IDENTITY, Mat4::IDENTITY);
+        image.triangle_shader(right_of_column_three(), &shader, ());
+        image.resolve_samples();
+
+        assert_eq!(image.pixel(1, 2), RGBA8::default());
+        assert_eq!(image.pixel(5, 2), crate::WHITE);
+        // half of the edge pixel's samples are right of the edge
+        assert_eq!(image.pixel(3, 2), RGBA8::new(127, 127, 127, 127));
+
+        // drawing over the resolved pixels directly, and then drawing something else, shouldn't
+        // bring back the samples from before
+        *image.pixel_mut(3, 2) = crate::RED;
+        let elsewhere = Mat3::from_cols(
+            Vec3::new(6.0, 6.0, 0.0),
+            Vec3::new(7.0, 6.0, 0.0),
+            Vec3::new(6.0, 7.0, 0.0),
+        );
+        image.triangle_shader(elsewhere, &shader, ());
+        image.resolve_samples();
+        assert_eq!(image.pixel(3, 2), crate::RED);
+    }
+
+    /// A canvas whose left half is black and right half white
+    fn half_white(width: usize, height: usize) -> Canvas {
+        let mut image = Canvas::new(width, height);
+        for y in 0..height as i32 {
+            for x in (width / 2) as i32..width as i32 {
+                *image.pixel_mut(x, y) = crate::WHITE;
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn box_downsampling_should_average_each_block() {
+        let mut image = Canvas::new(4, 2);
+        for (i, pixel) in image.pixels_mut().iter_mut().enumerate() {
+            if i % 2 == 0 {
+                *pixel = crate::WHITE;
+            }
+        }
+        *image.z_buffer_at_mut(1, 1) = 0.5;
+
+        let small = image.downsample(2, ResampleFilter::Box);
+        assert_eq!((small.width(), small.height()), (2, 1));
+        assert_eq!(small.pixels(), [RGBA8::new(128, 128, 128, 128); 2]);
+        // depth keeps the nearest of each block
+        assert_eq!(small.z_buffer_at(0, 0), 0.5);
+        assert_eq!(small.z_buffer_at(1, 0), f32::NEG_INFINITY);
+
+        let edge = half_white(8, 8).downsample(2, ResampleFilter::Box);
+        assert_eq!(edge.pixel(1, 0), RGBA8::default());
+        assert_eq!(edge.pixel(2, 0), crate::WHITE);
+    }
+
+    #[test]
+    fn lanczos_downsampling_should_keep_flat_colors_and_edges() {
+        let mut flat = Canvas::new(8, 8);
+        flat.pixels_mut().fill(RGBA8::new(10, 200, 30, 255));
+        let small = flat.downsample(2, ResampleFilter::Lanczos);
+        assert!(small
+            .pixels()
+            .iter()
+            .all(|&p| p == RGBA8::new(10, 200, 30, 255)));
+
+        // the edge stays between the middle two columns, with a little ringing either side
+        let edge = half_white(16, 4).downsample(2, ResampleFilter::Lanczos);
+        for y in 0..2 {
+            let row: Vec<u8> = (0..8).map(|x| edge.pixel(x, y).r).collect();
+            assert!(row[..3].iter().all(|&r| r < 16), "{:?}", row);
+            assert!(row[3] < 64 && row[4] > 192, "{:?}", row);
+            assert!(row[5..].iter().all(|&r| r > 240), "{:?}", row);
+        }
+    }
+}
@@ -1,6 +1,6 @@
 /// Legacy canvas API, where only certain fixed functions are supported (no shaders).
 use glam::{IVec2, Mat4, Vec2, Vec3, Vec4};
-use rgb::{ComponentMap, RGBA8};
+use rgb::RGBA8;
 
 use crate::{
     maths::{self, yolo_max, yolo_min},
@@ -154,37 +154,38 @@ impl Canvas {
     }
 
     pub fn model_wireframe(&mut self, model: &Model, color: RGBA8) {
-        for face in model.faces.iter() {
+        for face in 0..model.face_count() {
+            let vertices = model.face_vertices(face);
             for j in 0..3 {
-                let v0 = model.vertices[face.points[j].vertices_index];
-                let v1 = model.vertices[face.points[(j + 1) % 3].vertices_index];
+                let v0 = vertices[j].position;
+                let v1 = vertices[(j + 1) % 3].position;
 
                 // this simplistic rendering code assumes that the vertice coordinates are
                 // between -1 and 1, so confirm that assumption
                 debug_assert!(
-                    -1.0 <= v0.pos.x && v0.pos.x <= 1.0,
+                    -1.0 <= v0.x && v0.x <= 1.0,
                     "x coordinate out of range: {}",
-                    v0.pos.x
+                    v0.x
                 );
                 debug_assert!(
-                    -1.0 <= v0.pos.y && v0.pos.y <= 1.0,
+                    -1.0 <= v0.y && v0.y <= 1.0,
                     "y coordinate out of range: {}",
-                    v0.pos.y
+                    v0.y
                 );
                 debug_assert!(
-                    -1.0 <= v1.pos.x && v1.pos.x <= 1.0,
+                    -1.0 <= v1.x && v1.x <= 1.0,
                     "x coordinate out of range: {}",
-                    v1.pos.x
+                    v1.x
                 );
                 debug_assert!(
-                    -1.0 <= v1.pos.y && v1.pos.y <= 1.0,
+                    -1.0 <= v1.y && v1.y <= 1.0,
                     "y coordinate out of range: {}",
-                    v1.pos.y
+                    v1.y
                 );
-                let x0 = ((v0.pos.x + 1.0) * (self.width() as f32 - 1.0) / 2.0) as i32;
-                let y0 = ((v0.pos.y + 1.0) * (self.height() as f32 - 1.0) / 2.0) as i32;
-                let x1 = ((v1.pos.x + 1.0) * (self.width() as f32 - 1.0) / 2.0) as i32;
-                let y1 = ((v1.pos.y + 1.0) * (self.height() as f32 - 1.0) / 2.0) as i32;
+                let x0 = ((v0.x + 1.0) * (self.width() as f32 - 1.0) / 2.0) as i32;
+                let y0 = ((v0.y + 1.0) * (self.height() as f32 - 1.0) / 2.0) as i32;
+                let x1 = ((v1.x + 1.0) * (self.width() as f32 - 1.0) / 2.0) as i32;
+                let y1 = ((v1.y + 1.0) * (self.height() as f32 - 1.0) / 2.0) as i32;
 
                 self.line(IVec2::new(x0, y0), IVec2::new(x1, y1), color);
             }
@@ -192,27 +193,28 @@ impl Canvas {
     }
 
     pub fn model_colored_triangles(&mut self, model: &Model) {
-        for face in model.faces.iter() {
+        for face in 0..model.face_count() {
+            let vertices = model.face_vertices(face);
             let mut screen_coords = [IVec2::new(0, 0); 3];
             for j in 0..3 {
-                let v = model.vertices[face.points[j].vertices_index];
+                let v = vertices[j].position;
 
                 // this simplistic rendering code assumes that the vertice coordinates are
                 // between -1 and 1, so confirm that assumption
                 debug_assert!(
-                    -1.0 <= v.pos.x && v.pos.x <= 1.0,
+                    -1.0 <= v.x && v.x <= 1.0,
                     "x coordinate out of range: {}",
-                    v.pos.x
+                    v.x
                 );
                 debug_assert!(
-                    -1.0 <= v.pos.y && v.pos.y <= 1.0,
+                    -1.0 <= v.y && v.y <= 1.0,
                     "y coordinate out of range: {}",
-                    v.pos.y
+                    v.y
                 );
 
                 screen_coords[j] = IVec2::new(
-                    ((v.pos.x + 1.0) * (self.width() as f32 - 1.0) / 2.0) as i32,
-                    ((v.pos.y + 1.0) * (self.height() as f32 - 1.0) / 2.0) as i32,
+                    ((v.x + 1.0) * (self.width() as f32 - 1.0) / 2.0) as i32,
+                    ((v.y + 1.0) * (self.height() as f32 - 1.0) / 2.0) as i32,
                 );
             }
             self.triangle_barycentric(&screen_coords, crate::colors::random_color());
@@ -244,42 +246,43 @@ impl Canvas {
 
         let overall_transform = viewport * transform.unwrap_or(Mat4::IDENTITY);
 
-        for face in model.faces.iter() {
+        for face in 0..model.face_count() {
+            let vertices = model.face_vertices(face);
             let mut screen_coords_2d = [IVec2::ZERO; 3];
             let mut screen_coords_3d = [Vec3::ZERO; 3];
             let mut world_coords = [Vec3::ZERO; 3];
             let mut texture_coords = [Vec2::ZERO; 3];
             for j in 0..3 {
-                let v = model.vertices[face.points[j].vertices_index];
+                let v = vertices[j].position;
 
                 // this simplistic rendering code assumes that the vertice coordinates are
                 // between -1 and 1, so confirm that assumption
                 debug_assert!(
-                    -1.0 <= v.pos.x && v.pos.x <= 1.0,
+                    -1.0 <= v.x && v.x <= 1.0,
                     "x coordinate out of range: {}",
-                    v.pos.x
+                    v.x
                 );
                 debug_assert!(
-                    -1.0 <= v.pos.y && v.pos.y <= 1.0,
+                    -1.0 <= v.y && v.y <= 1.0,
                     "y coordinate out of range: {}",
-                    v.pos.y
+                    v.y
                 );
 
                 screen_coords_2d[j] = IVec2::new(
-                    ((v.pos.x + 1.0) * (self.width() as f32 - 1.0) / 2.0) as i32,
-                    ((v.pos.y + 1.0) * (self.height() as f32 - 1.0) / 2.0) as i32,
+                    ((v.x + 1.0) * (self.width() as f32 - 1.0) / 2.0) as i32,
+                    ((v.y + 1.0) * (self.height() as f32 - 1.0) / 2.0) as i32,
                 );
 
-                world_coords[j] = v.pos;
+                world_coords[j] = v;
 
                 // step 1 - embed into 4D space by converting to homogeneous coordinates
-                let mut vec4: Vec4 = (v.pos, 1.0).into();
+                let mut vec4: Vec4 = (v, 1.0).into();
                 // step 2 - multiply with projection & viewport matrices to correct perspective
                 vec4 = overall_transform * vec4;
                 // step 3 - divide by w to reproject into 3d screen coordinates
                 screen_coords_3d[j] = Vec3::new(vec4.x / vec4.w, vec4.y / vec4.w, vec4.z / vec4.w);
 
-                let raw_texture_coords = model.texture_coords[face.points[j].uv_index];
+                let raw_texture_coords = vertices[j].uv;
                 texture_coords[j] = Vec2::new(
                     raw_texture_coords.x * model.diffuse_texture.width as f32,
                     raw_texture_coords.y * model.diffuse_texture.height as f32,
@@ -289,8 +292,7 @@ impl Canvas {
             let mut vertex_intensity = [0.0f32; 3];
             if shading == ModelShading::Gouraud {
                 for j in 0..3 {
-                    vertex_intensity[j] =
-                        model.vertex_normals[face.points[j].normals_index].dot(light_dir);
+                    vertex_intensity[j] = vertices[j].normal.dot(light_dir);
                 }
             } else {
                 let n =
@@ -345,6 +347,7 @@ impl Canvas {
     pub fn triangle_debug(&mut self, t0: IVec2, t1: IVec2, t2: IVec2) {
         let (t0, t1, t2) = {
             let mut vertices = [t0, t1, t2];
+            #[allow(clippy::unnecessary_sort_by)] // kept as it was written
             vertices.sort_by(|a, b| a.y.cmp(&b.y));
             (vertices[0], vertices[1], vertices[2])
         };
@@ -365,6 +368,7 @@ impl Canvas {
         // 1. sort the vertices by y coordinate, as prep for step 2
         let (t0, t1, t2) = {
             let mut vertices = [t0, t1, t2];
+            #[allow(clippy::unnecessary_sort_by)] // kept as it was written
             vertices.sort_by(|a, b| a.y.cmp(&b.y));
             (vertices[0], vertices[1], vertices[2])
         };
@@ -434,6 +438,7 @@ impl Canvas {
 
         let (t0, t1, t2) = {
             let mut vertices = [t0, t1, t2];
+            #[allow(clippy::unnecessary_sort_by)] // kept as it was written
             vertices.sort_by(|a, b| a.y.cmp(&b.y));
             (vertices[0], vertices[1], vertices[2])
         };
@@ -560,9 +565,9 @@ impl Canvas {
                         + varying_uv[2] * bc_screen[2];
 
                     let color = tex.data[(tex.height - uv.y as usize) * tex.width + uv.x as usize]
-                        .map(|comp| (comp as f32 * light_intensity) as u8);
+                        .map_rgb(|comp| (comp as f32 * light_intensity) as u8);
 
-                    *self.pixel_mut(i, j) = color.into();
+                    *self.pixel_mut(i, j) = color;
                 }
             }
         }
@@ -611,9 +616,9 @@ impl Canvas {
                         + light_intensity[2] * bc_screen[2];
 
                     let color = tex.data[(tex.height - uv.y as usize) * tex.width + uv.x as usize]
-                        .map(|comp| (comp as f32 * weighted_light_intensity) as u8);
+                        .map_rgb(|comp| (comp as f32 * weighted_light_intensity) as u8);
 
-                    *self.pixel_mut(i, j) = color.into();
+                    *self.pixel_mut(i, j) = color;
                 }
             }
         }
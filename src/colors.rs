@@ -20,3 +20,20 @@ pub fn random_color() -> RGBA8 {
         255,
     )
 }
+
+/// Map a value from 0.0 to 1.0 onto a perceptually ordered gradient (roughly matplotlib's
+/// "inferno"), from black through purple, red and orange to pale yellow.
+pub fn false_color_map(t: f32) -> RGBA8 {
+    const STOPS: [[f32; 3]; 5] = [
+        [0.0, 0.0, 4.0],
+        [87.0, 16.0, 110.0],
+        [188.0, 55.0, 84.0],
+        [249.0, 142.0, 9.0],
+        [252.0, 255.0, 164.0],
+    ];
+    let scaled = t.clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let i = (scaled as usize).min(STOPS.len() - 2);
+    let frac = scaled - i as f32;
+    let [r, g, b] = [0, 1, 2].map(|c| STOPS[i][c] + (STOPS[i + 1][c] - STOPS[i][c]) * frac);
+    RGBA8::new(r as u8, g as u8, b as u8, 255)
+}
@@ -0,0 +1,19 @@
+/// How to lay out the current render against a pinned reference render.
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::EnumIter,
+    PartialEq,
+    Eq,
+    strum::Display,
+)]
+pub enum CompareMode {
+    #[default]
+    SideBySide,
+    Wipe,
+    Difference,
+}
@@ -0,0 +1,144 @@
+use glam::{Mat3, Vec3};
+use rgb::RGBA8;
+
+use crate::{Vertex, DEPTH_MAX};
+
+/// The surface attributes of a single pixel, as written by the geometry pass of deferred shading.
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub struct GBufferTexel {
+    /// Unlit surface color
+    pub albedo: RGBA8,
+    /// Surface normal in view space (i.e. after the modelview & projection transforms)
+    pub normal: Vec3,
+    /// Specular intensity read from the specular map, from 0.0 to 1.0
+    pub specular: f32,
+    /// Exponent used for specular highlights
+    pub shininess: f32,
+    /// Light emitted by the surface regardless of lighting, e.g. from a glow map
+    pub emissive: RGBA8,
+}
+
+/// Like a `Shader`, but the fragment stage outputs surface attributes for a later lighting pass
+/// rather than a final color.
+pub trait GeometryShader<V, S> {
+    fn vertex(&self, vertex: &Vertex) -> (Vec3, V);
+    fn triangle(&self, screen_coords: Mat3, vertices: [V; 3]) -> S;
+    fn fragment(&self, barycentric_coords: Vec3, state: &S) -> Option<GBufferTexel>;
+}
+
+/// A channel of the G-buffer that can be visualized as an image.
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::EnumIter,
+    PartialEq,
+    Eq,
+    strum::Display,
+)]
+pub enum GBufferChannel {
+    #[default]
+    Albedo,
+    Normal,
+    Specular,
+    Emissive,
+    Depth,
+}
+
+/// Per-pixel surface attributes written by `Canvas::model_geometry_pass`.
+#[derive(Clone, Debug)]
+pub struct GBuffer {
+    width: usize,
+    height: usize,
+    texels: Vec<Option<GBufferTexel>>,
+    depth: Vec<f32>,
+}
+
+impl GBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            texels: vec![None; width * height],
+            depth: vec![f32::NEG_INFINITY; width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Get the surface at a pixel, or `None` if nothing was drawn there.
+    #[inline]
+    pub fn texel(&self, x: i32, y: i32) -> Option<&GBufferTexel> {
+        self.texels[self.index(x, y)].as_ref()
+    }
+
+    #[inline]
+    pub fn depth(&self, x: i32, y: i32) -> f32 {
+        self.depth[self.index(x, y)]
+    }
+
+    pub(crate) fn write(&mut self, x: i32, y: i32, texel: GBufferTexel, depth: f32) {
+        let idx = self.index(x, y);
+        self.texels[idx] = Some(texel);
+        self.depth[idx] = depth;
+    }
+
+    #[inline]
+    fn index(&self, x: i32, y: i32) -> usize {
+        debug_assert!(
+            x >= 0 && x < self.width as i32,
+            "x coordinate of '{}' is out of bounds 0 to {}",
+            x,
+            self.width as i32
+        );
+        debug_assert!(
+            y >= 0 && y < self.height as i32,
+            "y coordinate of '{}' is out of bounds 0 to {}",
+            y,
+            self.height as i32
+        );
+        y as usize * self.width + x as usize
+    }
+
+    /// Render a single channel as an image, with non-color channels remapped to be visible. Pixels
+    /// that nothing was drawn to are left as `background`.
+    pub fn channel_pixels(&self, channel: GBufferChannel, background: RGBA8) -> Vec<RGBA8> {
+        self.texels
+            .iter()
+            .zip(self.depth.iter())
+            .map(|(texel, depth)| {
+                let texel = match texel {
+                    Some(texel) => texel,
+                    None => return background,
+                };
+                match channel {
+                    GBufferChannel::Albedo => texel.albedo,
+                    GBufferChannel::Normal => {
+                        // map each component from [-1, 1] to [0, 255]
+                        let n = (texel.normal.normalize_or_zero() + Vec3::ONE) * 0.5 * 255.0;
+                        RGBA8::new(n.x as u8, n.y as u8, n.z as u8, 255)
+                    }
+                    GBufferChannel::Specular => {
+                        let c = (texel.specular * 255.0) as u8;
+                        RGBA8::new(c, c, c, 255)
+                    }
+                    GBufferChannel::Emissive => texel.emissive,
+                    GBufferChannel::Depth => {
+                        let c = (depth / DEPTH_MAX * 255.0) as u8;
+                        RGBA8::new(c, c, c, 255)
+                    }
+                }
+                .alpha(255)
+            })
+            .collect()
+    }
+}
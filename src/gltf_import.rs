@@ -0,0 +1,1185 @@
+//! Loading models from glTF 2.0 files, either `.gltf` (with its buffers and images embedded or in
+//! separate files) or binary `.glb`.
+//!
+//! The renderer has no node hierarchy nor PBR materials, so each triangle primitive in the file's
+//! scene becomes a `Model` of its own, with the transform of its node applied to its vertices and
+//! its material converted into the textures that a `Model` is drawn with. The whole scene is then
+//! moved and scaled to fit between -1 and 1 like OBJ models are, along with its cameras.
+//!
+//! Skinned meshes keep their vertices where they were bound to their skin (ignoring their node's
+//! transform, as glTF says to) and get a `Skeleton` made from the skin's joints, while the file's
+//! animations become clips moving those joints' nodes.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use glam::{Mat3, Mat4, Quat, Vec2, Vec3, Vec4};
+use rgb::RGBA8;
+
+use crate::{
+    maths,
+    model::{face_tangents, fit_transform, index_vertices},
+    AnimationChannel, AnimationClip, Camera, ChannelValues, Interpolation, Joint, JointTransform,
+    Model, ModelFile, Skeleton, Texture, Vertex, WHITE,
+};
+
+/// Size of the textures of materials that don't have any images
+const DEFAULT_TEXTURE_SIZE: usize = 256;
+/// How many texels the baked normal maps are spread out past the edges of each face
+const NORMAL_MAP_DILATION: usize = 4;
+/// Cameras look at a point at least this far in front of them, which they orbit around
+const MIN_CAMERA_DISTANCE: f32 = 1.0;
+
+pub(crate) fn load_gltf(path: &Path) -> Result<ModelFile> {
+    println!("Loading glTF model from file: {}", path.display());
+    let (document, buffers, images) = gltf::import(path)
+        .with_context(|| format!("Loading glTF model from '{}' failed", path.display()))?;
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .context("glTF file has no scenes")?;
+
+    let mut primitives = Vec::new();
+    let mut cameras = Vec::new();
+    for node in scene.nodes() {
+        collect_node(
+            &node,
+            Mat4::IDENTITY,
+            &buffers,
+            &mut primitives,
+            &mut cameras,
+        )?;
+    }
+    if primitives.is_empty() {
+        bail!("glTF scene has no triangles to draw");
+    }
+
+    let fit = fit_transform(
+        primitives
+            .iter()
+            .flat_map(|primitive| &primitive.corners)
+            .map(|vertex| vertex.position),
+    );
+    let mut materials = Materials {
+        images: &images,
+        loaded: HashMap::new(),
+    };
+    let nodes: Vec<_> = document.nodes().collect();
+    let mut parents = vec![None; nodes.len()];
+    for node in &nodes {
+        for child in node.children() {
+            parents[child.index()] = Some(node.index());
+        }
+    }
+    // by skin index, as primitives of the same mesh (or meshes sharing a skin) share a skeleton
+    let mut skeletons = HashMap::new();
+    let models = primitives
+        .into_iter()
+        .map(|primitive| {
+            let skeleton = match &primitive.skin {
+                Some(skin) => Some(match skeletons.entry(skin.index()) {
+                    Entry::Occupied(entry) => Arc::clone(entry.get()),
+                    Entry::Vacant(entry) => Arc::clone(entry.insert(Arc::new(read_skeleton(
+                        skin, &nodes, &parents, fit, &buffers,
+                    )?))),
+                }),
+                None => None,
+            };
+            primitive
+                .into_model(fit, skeleton, &mut materials)
+                .map(Arc::new)
+        })
+        .collect::<Result<_>>()?;
+    let animations = document
+        .animations()
+        .map(|animation| read_animation(&animation, &buffers))
+        .collect::<Result<_>>()?;
+    let cameras = cameras
+        .into_iter()
+        .map(|(name, world)| camera(name, world, fit))
+        .collect();
+    Ok(ModelFile {
+        models,
+        cameras,
+        animations,
+        lods: Vec::new(),
+        dependencies: external_files(&document, path),
+        warnings: Vec::new(),
+    })
+}
+
+/// The separate buffer & image files that a `.gltf` file refers to (rather than embedding them).
+fn external_files(document: &gltf::Document, path: &Path) -> Vec<PathBuf> {
+    let buffer_uris = document
+        .buffers()
+        .filter_map(|buffer| match buffer.source() {
+            gltf::buffer::Source::Uri(uri) => Some(uri),
+            gltf::buffer::Source::Bin => None,
+        });
+    let image_uris = document.images().filter_map(|image| match image.source() {
+        gltf::image::Source::Uri { uri, .. } => Some(uri),
+        gltf::image::Source::View { .. } => None,
+    });
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    buffer_uris
+        .chain(image_uris)
+        .filter(|uri| !uri.starts_with("data:"))
+        .map(|uri| dir.join(uri))
+        .collect()
+}
+
+/// The triangles of a primitive in world space (or the space they were bound to their skin in, for
+/// skinned primitives), three corners per triangle.
+struct Primitive<'d> {
+    corners: Vec<Vertex>,
+    has_uvs: bool,
+    material: gltf::Material<'d>,
+    skin: Option<gltf::Skin<'d>>,
+}
+
+/// Gather the primitives and cameras of a node and all of its descendants.
+fn collect_node<'d>(
+    node: &gltf::Node<'d>,
+    parent: Mat4,
+    buffers: &[gltf::buffer::Data],
+    primitives: &mut Vec<Primitive<'d>>,
+    cameras: &mut Vec<(String, Mat4)>,
+) -> Result<()> {
+    let world = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+
+    if let Some(mesh) = node.mesh() {
+        let skin = node.skin();
+        let primitive_world = if skin.is_some() {
+            Mat4::IDENTITY
+        } else {
+            world
+        };
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                println!(
+                    "Skipping glTF primitive drawn as {:?} rather than triangles",
+                    primitive.mode()
+                );
+                continue;
+            }
+            let read = read_primitive(&primitive, primitive_world, skin.clone(), buffers);
+            primitives.push(read.with_context(|| {
+                format!(
+                    "Reading glTF mesh '{}' failed",
+                    mesh.name().unwrap_or_default()
+                )
+            })?);
+        }
+    }
+    if let Some(camera) = node.camera() {
+        let name = camera
+            .name()
+            .or_else(|| node.name())
+            .map_or_else(|| format!("Camera {}", cameras.len() + 1), str::to_owned);
+        cameras.push((name, world));
+    }
+
+    for child in node.children() {
+        collect_node(&child, world, buffers, primitives, cameras)?;
+    }
+    Ok(())
+}
+
+fn read_primitive<'d>(
+    primitive: &gltf::Primitive<'d>,
+    world: Mat4,
+    skin: Option<gltf::Skin<'d>>,
+    buffers: &[gltf::buffer::Data],
+) -> Result<Primitive<'d>> {
+    let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &data[..]));
+    let positions: Vec<Vec3> = reader
+        .read_positions()
+        .context("primitive has no vertex positions")?
+        .map(|p| world.transform_point3(p.into()))
+        .collect();
+    let normal_m = world.inverse().transpose();
+    let normals: Option<Vec<Vec3>> = reader.read_normals().map(|normals| {
+        normals
+            .map(|n| normal_m.transform_vector3(n.into()).normalize_or_zero())
+            .collect()
+    });
+    // glTF's texture coordinates start from the top left of images rather than the bottom left
+    let uvs: Option<Vec<Vec2>> = reader
+        .read_tex_coords(tex_coord_set(&primitive.material())?)
+        .map(|uvs| uvs.into_f32().map(|[u, v]| Vec2::new(u, 1.0 - v)).collect());
+    let colors: Option<Vec<Vec4>> = reader
+        .read_colors(0)
+        .map(|colors| colors.into_rgba_f32().map(Vec4::from).collect());
+    // joints are only used by primitives of nodes with skins
+    let joint_count = skin.as_ref().map(|skin| skin.joints().count());
+    let joints: Option<Vec<[u16; 4]>> = joint_count
+        .and_then(|_| reader.read_joints(0))
+        .map(|joints| joints.into_u16().collect());
+    let weights: Option<Vec<Vec4>> = joint_count
+        .and_then(|_| reader.read_weights(0))
+        .map(|weights| weights.into_f32().map(Vec4::from).collect());
+    let indices: Vec<u32> = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        None => (0..positions.len() as u32).collect(),
+    };
+
+    let vertex_count = positions.len();
+    if normals.as_ref().is_some_and(|n| n.len() != vertex_count)
+        || uvs.as_ref().is_some_and(|uvs| uvs.len() != vertex_count)
+        || colors.as_ref().is_some_and(|c| c.len() != vertex_count)
+        || joints.as_ref().is_some_and(|j| j.len() != vertex_count)
+        || weights.as_ref().is_some_and(|w| w.len() != vertex_count)
+    {
+        bail!("primitive's vertex attributes have different numbers of vertices");
+    }
+    if let Some(index) = indices.iter().find(|&&i| i as usize >= vertex_count) {
+        bail!(
+            "primitive's index {} is out of range of its {} vertices",
+            index,
+            vertex_count
+        );
+    }
+
+    if let (Some(joints), Some(joint_count)) = (&joints, joint_count) {
+        if let Some(joint) = joints
+            .iter()
+            .flatten()
+            .find(|&&j| j as usize >= joint_count)
+        {
+            bail!(
+                "primitive's joint {} is out of range of its skin's {} joints",
+                joint,
+                joint_count
+            );
+        }
+    }
+
+    // mirroring turns triangles inside out, so wind them the other way to keep them facing out
+    let mirrored = world.determinant() < 0.0;
+    let mut corners = Vec::with_capacity(indices.len());
+    for face in indices.chunks_exact(3) {
+        let mut face = [face[0], face[1], face[2]].map(|i| i as usize);
+        if mirrored {
+            face.swap(1, 2);
+        }
+        let [p0, p1, p2] = face.map(|i| positions[i]);
+        let face_normal = (p1 - p0).cross(p2 - p0).normalize_or_zero();
+        corners.extend(face.map(|i| Vertex {
+            position: positions[i],
+            uv: uvs.as_ref().map_or(Vec2::ZERO, |uvs| uvs[i]),
+            normal: normals.as_ref().map_or(face_normal, |normals| normals[i]),
+            color: colors.as_ref().map_or(Vec4::ONE, |colors| colors[i]),
+            joints: joints.as_ref().map_or([0; 4], |joints| joints[i]),
+            weights: match (&joints, &weights) {
+                (Some(_), Some(weights)) => weights[i],
+                _ => Vec4::ZERO,
+            },
+        }));
+    }
+
+    Ok(Primitive {
+        corners,
+        has_uvs: uvs.is_some(),
+        material: primitive.material(),
+        skin,
+    })
+}
+
+impl Primitive<'_> {
+    fn into_model(
+        self,
+        fit: Mat4,
+        skeleton: Option<Arc<Skeleton>>,
+        materials: &mut Materials,
+    ) -> Result<Model> {
+        let has_uvs = self.has_uvs;
+        let (vertices, indices) = index_vertices(self.corners.into_iter().map(|vertex| {
+            let position = fit.transform_point3(vertex.position);
+            Vertex {
+                position,
+                // without texture coordinates, project textures straight along z, so that there's
+                // still a tangent space for normal mapping
+                uv: if has_uvs {
+                    vertex.uv
+                } else {
+                    position.truncate() * 0.5 + 0.5
+                },
+                ..vertex
+            }
+        }));
+        let textures = materials.textures(&self.material)?;
+        let (width, height) = (textures.diffuse.width, textures.diffuse.height);
+
+        Ok(Model {
+            normal_texture_global: Some(Arc::new(bake_normal_map(
+                &vertices, &indices, width, height,
+            ))),
+            tangents: face_tangents(&vertices, &indices),
+            vertices,
+            indices,
+            polygon_sizes: Vec::new(),
+            diffuse_texture: textures.diffuse,
+            normal_texture_darboux: textures.normal_darboux,
+            specular_texture: textures.specular,
+            glow_texture: textures.glow,
+            skeleton,
+            file_counts: None,
+        })
+    }
+}
+
+/// Make a skeleton from the joints of a skin, for moving vertices that were fitted into view by
+/// `fit`. Nodes between joints that aren't joints themselves are treated as fixed in place.
+fn read_skeleton(
+    skin: &gltf::Skin,
+    nodes: &[gltf::Node],
+    parents: &[Option<usize>],
+    fit: Mat4,
+    buffers: &[gltf::buffer::Data],
+) -> Result<Skeleton> {
+    let joint_nodes: Vec<_> = skin.joints().collect();
+    let joint_of_node: HashMap<usize, usize> = joint_nodes
+        .iter()
+        .enumerate()
+        .map(|(joint, node)| (node.index(), joint))
+        .collect();
+    let inverse_binds: Vec<Mat4> = match skin
+        .reader(|buffer| buffers.get(buffer.index()).map(|data| &data[..]))
+        .read_inverse_bind_matrices()
+    {
+        Some(matrices) => matrices.map(|m| Mat4::from_cols_array_2d(&m)).collect(),
+        None => vec![Mat4::IDENTITY; joint_nodes.len()],
+    };
+    if inverse_binds.len() < joint_nodes.len() {
+        bail!(
+            "glTF skin has {} inverse bind matrices for {} joints",
+            inverse_binds.len(),
+            joint_nodes.len()
+        );
+    }
+
+    // the vertices have been fitted into view, so they're unfitted before being moved by the
+    // joints, and the joints without parents are fitted along with everything else
+    let unfit = fit.inverse();
+    let joints = joint_nodes
+        .iter()
+        .enumerate()
+        .map(|(joint, node)| {
+            let mut parent_offset = Mat4::IDENTITY;
+            let mut parent = None;
+            let mut ancestor = parents[node.index()];
+            while let Some(index) = ancestor {
+                if let Some(&parent_joint) = joint_of_node.get(&index) {
+                    parent = Some(parent_joint);
+                    break;
+                }
+                parent_offset =
+                    Mat4::from_cols_array_2d(&nodes[index].transform().matrix()) * parent_offset;
+                ancestor = parents[index];
+            }
+            if parent.is_none() {
+                parent_offset = fit * parent_offset;
+            }
+
+            let (translation, [x, y, z, w], scale) = node.transform().decomposed();
+            Joint {
+                name: node
+                    .name()
+                    .map_or_else(|| format!("Joint {}", joint + 1), str::to_owned),
+                node: node.index(),
+                parent,
+                parent_offset,
+                rest: JointTransform {
+                    translation: translation.into(),
+                    rotation: Quat::from_xyzw(x, y, z, w),
+                    scale: scale.into(),
+                },
+                inverse_bind: inverse_binds[joint] * unfit,
+            }
+        })
+        .collect();
+    Ok(Skeleton { joints })
+}
+
+/// Read the keyframes of an animation that move nodes. Cubic spline keyframes are blended linearly
+/// between their values, ignoring their tangents, and morph target weights are skipped since models
+/// don't have morph targets.
+fn read_animation(
+    animation: &gltf::Animation,
+    buffers: &[gltf::buffer::Data],
+) -> Result<AnimationClip> {
+    use gltf::animation::{util::ReadOutputs, Interpolation as GltfInterpolation};
+
+    let name = animation.name().map_or_else(
+        || format!("Animation {}", animation.index() + 1),
+        str::to_owned,
+    );
+    let mut channels = Vec::new();
+    for channel in animation.channels() {
+        let reader = channel.reader(|buffer| buffers.get(buffer.index()).map(|data| &data[..]));
+        let times: Vec<f32> = reader
+            .read_inputs()
+            .with_context(|| format!("Animation '{}' has a channel with no keyframes", name))?
+            .collect();
+        let interpolation = channel.sampler().interpolation();
+        let cubic = interpolation == GltfInterpolation::CubicSpline;
+        let values = match reader
+            .read_outputs()
+            .with_context(|| format!("Animation '{}' has a channel with no values", name))?
+        {
+            ReadOutputs::Translations(values) => {
+                ChannelValues::Translation(keyframe_values(values.map(Vec3::from), cubic))
+            }
+            ReadOutputs::Rotations(values) => ChannelValues::Rotation(keyframe_values(
+                values
+                    .into_f32()
+                    .map(|[x, y, z, w]| Quat::from_xyzw(x, y, z, w)),
+                cubic,
+            )),
+            ReadOutputs::Scales(values) => {
+                ChannelValues::Scale(keyframe_values(values.map(Vec3::from), cubic))
+            }
+            ReadOutputs::MorphTargetWeights(_) => continue,
+        };
+        let value_count = match &values {
+            ChannelValues::Translation(values) | ChannelValues::Scale(values) => values.len(),
+            ChannelValues::Rotation(values) => values.len(),
+        };
+        if value_count != times.len() || times.windows(2).any(|t| t[0] > t[1]) {
+            bail!(
+                "Animation '{}' has a channel whose keyframe times don't match its values",
+                name
+            );
+        }
+        channels.push(AnimationChannel {
+            node: channel.target().node().index(),
+            times,
+            values,
+            interpolation: match interpolation {
+                GltfInterpolation::Step => Interpolation::Step,
+                _ => Interpolation::Linear,
+            },
+        });
+    }
+
+    Ok(AnimationClip {
+        name,
+        duration: channels
+            .iter()
+            .filter_map(|channel| channel.times.last())
+            .fold(0.0, |duration, &time| duration.max(time)),
+        channels,
+    })
+}
+
+/// The value of each keyframe, dropping the in & out tangents around each one of cubic splines.
+fn keyframe_values<T>(values: impl Iterator<Item = T>, cubic: bool) -> Vec<T> {
+    if cubic {
+        values.skip(1).step_by(3).collect()
+    } else {
+        values.collect()
+    }
+}
+
+/// Turn the node transform of a camera into a view of the fitted scene.
+fn camera(name: String, world: Mat4, fit: Mat4) -> Camera {
+    let look_from = fit.transform_point3(world.transform_point3(Vec3::ZERO));
+    // cameras look along their -z axis
+    let forward = world.transform_vector3(-Vec3::Z).normalize_or_zero();
+    let up = world.transform_vector3(Vec3::Y).normalize_or_zero();
+    // look at the point along the view that's closest to the middle of the scene
+    let distance = (-look_from).dot(forward).max(MIN_CAMERA_DISTANCE);
+    Camera {
+        name,
+        look_from,
+        look_at: look_from + forward * distance,
+        up,
+    }
+}
+
+/// The set of texture coordinates (i.e. which `TEXCOORD_n` attribute) that a material's textures
+/// are looked up with. Models only have one set, so every texture must use the same one.
+fn tex_coord_set(material: &gltf::Material) -> Result<u32> {
+    let pbr = material.pbr_metallic_roughness();
+    let mut sets: Vec<u32> = [
+        pbr.base_color_texture(),
+        pbr.metallic_roughness_texture(),
+        material.emissive_texture(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|info| info.tex_coord())
+    .chain(material.normal_texture().map(|normal| normal.tex_coord()))
+    .collect();
+    sets.sort_unstable();
+    sets.dedup();
+    match sets[..] {
+        [] => Ok(0),
+        [set] => Ok(set),
+        _ => bail!(
+            "material '{}' uses more than one set of texture coordinates ({:?}), which isn't \
+            supported",
+            material.name().unwrap_or_default(),
+            sets
+        ),
+    }
+}
+
+/// The textures a material is drawn with.
+#[derive(Clone)]
+struct MaterialTextures {
+    diffuse: Arc<Texture>,
+    normal_darboux: Arc<Texture>,
+    specular: Arc<Texture>,
+    glow: Option<Arc<Texture>>,
+}
+
+/// Converts glTF materials to textures, once for each material however many primitives use it.
+struct Materials<'i> {
+    images: &'i [gltf::image::Data],
+    /// By material index, where `None` is the default material
+    loaded: HashMap<Option<usize>, MaterialTextures>,
+}
+
+impl Materials<'_> {
+    fn textures(&mut self, material: &gltf::Material) -> Result<MaterialTextures> {
+        if let Some(textures) = self.loaded.get(&material.index()) {
+            return Ok(textures.clone());
+        }
+        let textures = self.convert(material).with_context(|| {
+            format!(
+                "Loading glTF material '{}' failed",
+                material.name().unwrap_or_default()
+            )
+        })?;
+        self.loaded.insert(material.index(), textures.clone());
+        Ok(textures)
+    }
+
+    fn convert(&self, material: &gltf::Material) -> Result<MaterialTextures> {
+        let pbr = material.pbr_metallic_roughness();
+        let image = |info: Option<gltf::texture::Info>| {
+            info.map(|info| self.image(info.texture().source().index()))
+                .transpose()
+        };
+        let base_color = image(pbr.base_color_texture())?;
+        let normal = material
+            .normal_texture()
+            .map(|normal| self.image(normal.texture().source().index()))
+            .transpose()?;
+        let metallic_roughness = image(pbr.metallic_roughness_texture())?;
+        let emissive = image(material.emissive_texture())?;
+
+        // the shaders look every texture of a model up with the same coordinates, scaled by the
+        // size of the diffuse texture, so they must all be the same size
+        let (width, height) = [&base_color, &normal, &metallic_roughness, &emissive]
+            .into_iter()
+            .flatten()
+            .map(|texture| (texture.width, texture.height))
+            .max_by_key(|(width, height)| width * height)
+            .unwrap_or((DEFAULT_TEXTURE_SIZE, DEFAULT_TEXTURE_SIZE));
+        let texture = |image: Option<Texture>, default: RGBA8, pixel: &dyn Fn(RGBA8) -> RGBA8| {
+            let data = match image {
+                Some(image) => resample(&image, width, height)
+                    .into_iter()
+                    .map(pixel)
+                    .collect(),
+                None => vec![pixel(default); width * height],
+            };
+            Arc::new(Texture::new(width, height, data))
+        };
+
+        let base_color_factor = pbr.base_color_factor();
+        let alpha_mode = material.alpha_mode();
+        let alpha_cutoff = material.alpha_cutoff().unwrap_or(0.5) * 255.0;
+        let diffuse = texture(base_color, WHITE, &|color| {
+            let color = scale_color(color, base_color_factor);
+            let a = match alpha_mode {
+                gltf::material::AlphaMode::Opaque => 255,
+                gltf::material::AlphaMode::Mask if (color.a as f32) < alpha_cutoff => 0,
+                gltf::material::AlphaMode::Mask => 255,
+                gltf::material::AlphaMode::Blend => color.a,
+            };
+            RGBA8 { a, ..color }
+        });
+
+        let normal_darboux = texture(normal, RGBA8::new(128, 128, 255, 255), &|normal| normal);
+
+        // there's no roughness in the lighting model, but the smoother a surface is, the more
+        // specular highlight it should have
+        let roughness_factor = pbr.roughness_factor();
+        let specular = texture(metallic_roughness, WHITE, &|texel| {
+            let specular = 255 - (texel.g as f32 * roughness_factor) as u8;
+            RGBA8::new(specular, specular, specular, 255)
+        });
+
+        let [r, g, b] = material.emissive_factor();
+        let glow = (r > 0.0 || g > 0.0 || b > 0.0)
+            .then(|| texture(emissive, WHITE, &|color| scale_color(color, [r, g, b, 1.0])));
+
+        Ok(MaterialTextures {
+            diffuse,
+            normal_darboux,
+            specular,
+            glow,
+        })
+    }
+
+    fn image(&self, index: usize) -> Result<Texture> {
+        let image = self
+            .images
+            .get(index)
+            .with_context(|| format!("image {} is missing", index))?;
+        let (width, height) = (image.width as usize, image.height as usize);
+        use gltf::image::Format;
+        let (channels, channel_size) = match image.format {
+            Format::R8 => (1, 1),
+            Format::R8G8 => (2, 1),
+            Format::R8G8B8 => (3, 1),
+            Format::R8G8B8A8 => (4, 1),
+            Format::R16 => (1, 2),
+            Format::R16G16 => (2, 2),
+            Format::R16G16B16 => (3, 2),
+            Format::R16G16B16A16 => (4, 2),
+            Format::R32G32B32FLOAT => (3, 4),
+            Format::R32G32B32A32FLOAT => (4, 4),
+        };
+        if image.pixels.len() != width * height * channels * channel_size {
+            bail!(
+                "image {} is {} bytes, which doesn't match its size and format",
+                index,
+                image.pixels.len()
+            );
+        }
+
+        let channel = |bytes: &[u8]| match channel_size {
+            1 => bytes[0],
+            2 => (u16::from_ne_bytes([bytes[0], bytes[1]]) >> 8) as u8,
+            _ => {
+                let value = f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                (value.clamp(0.0, 1.0) * 255.0) as u8
+            }
+        };
+        let data = image
+            .pixels
+            .chunks_exact(channels * channel_size)
+            .map(|pixel| {
+                let c: Vec<u8> = pixel.chunks_exact(channel_size).map(channel).collect();
+                match c[..] {
+                    // one and two channel images are greyscale, with alpha for two channels
+                    [l] => RGBA8::new(l, l, l, 255),
+                    [l, a] => RGBA8::new(l, l, l, a),
+                    [r, g, b] => RGBA8::new(r, g, b, 255),
+                    [r, g, b, a] => RGBA8::new(r, g, b, a),
+                    _ => unreachable!("images have from one to four channels"),
+                }
+            })
+            .collect();
+        Ok(Texture::new(width, height, data))
+    }
+}
+
+fn scale_color(color: RGBA8, factor: [f32; 4]) -> RGBA8 {
+    let scale = |c: u8, f: f32| (c as f32 * f.clamp(0.0, 1.0)) as u8;
+    RGBA8::new(
+        scale(color.r, factor[0]),
+        scale(color.g, factor[1]),
+        scale(color.b, factor[2]),
+        scale(color.a, factor[3]),
+    )
+}
+
+/// Resize a texture's pixels by picking the nearest pixel.
+fn resample(texture: &Texture, width: usize, height: usize) -> Vec<RGBA8> {
+    (0..width * height)
+        .map(|i| {
+            let (x, y) = (i % width, i / width);
+            texture.data[y * texture.height / height * texture.width + x * texture.width / width]
+        })
+        .collect()
+}
+
+/// Draw a model's vertex normals into a normal map in global space, by rasterizing each face at
+/// its texture coordinates. glTF only has normal maps in tangent space, but some shaders need one
+/// in global space.
+fn bake_normal_map(vertices: &[Vertex], indices: &[u32], width: usize, height: usize) -> Texture {
+    let mut normals: Vec<Option<Vec3>> = vec![None; width * height];
+    let texture_size = Vec2::new(width as f32, height as f32);
+    for face in indices.chunks_exact(3) {
+        let corners = [face[0], face[1], face[2]].map(|i| vertices[i as usize]);
+        // the same texel coordinates that the shaders look textures up with
+        let texels = corners.map(|corner| (corner.uv * texture_size).extend(0.0));
+        let pts = Mat3::from_cols(texels[0], texels[1], texels[2]);
+        let min = texels[0].min(texels[1]).min(texels[2]).floor();
+        // faces that wrap around the texture more than once only need to be drawn once
+        let max = texels[0]
+            .max(texels[1])
+            .max(texels[2])
+            .min(min + Vec3::new(texture_size.x, texture_size.y, 0.0));
+
+        for y in (min.y as i64)..=(max.y as i64) {
+            for x in (min.x as i64)..=(max.x as i64) {
+                let bc = maths::barycentric_coords_3d_matrix(
+                    pts,
+                    Vec2::new(x as f32 + 0.5, y as f32 + 0.5),
+                );
+                if bc.min_element() < 0.0 {
+                    continue;
+                }
+                let normal =
+                    corners[0].normal * bc.x + corners[1].normal * bc.y + corners[2].normal * bc.z;
+                let (x, y) = (
+                    x.rem_euclid(width as i64) as usize,
+                    y.rem_euclid(height as i64) as usize,
+                );
+                // the same row that `Texture::get_pixel` reads
+                normals[(height - y) % height * width + x] = Some(normal.normalize_or_zero());
+            }
+        }
+    }
+
+    // spread the normals out past the edges of the faces, where lookups can land when rounding
+    for _ in 0..NORMAL_MAP_DILATION {
+        let previous = normals.clone();
+        for (i, normal) in normals.iter_mut().enumerate() {
+            if normal.is_some() {
+                continue;
+            }
+            let (x, y) = (i % width, i / width);
+            let neighbours = [
+                (x > 0).then(|| i - 1),
+                (x + 1 < width).then(|| i + 1),
+                (y > 0).then(|| i - width),
+                (y + 1 < height).then(|| i + width),
+            ];
+            *normal = neighbours.into_iter().flatten().find_map(|n| previous[n]);
+        }
+    }
+
+    let encode = |c: f32| ((c + 1.0) / 2.0 * 255.0).round() as u8;
+    let data = normals
+        .into_iter()
+        .map(|normal| {
+            let n = normal.unwrap_or(Vec3::Z);
+            RGBA8::new(encode(n.x), encode(n.y), encode(n.z), 255)
+        })
+        .collect();
+    Texture::new(width, height, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A square's corners, then two sets of texture coordinates (the first all zero), then the
+    /// indices of its two triangles: 124 bytes in all.
+    fn square_buffer() -> Vec<u8> {
+        let corners = [
+            [-1.0f32, -1.0, 0.0],
+            [1.0, -1.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [-1.0, 1.0, 0.0],
+        ];
+        let uvs =
+            [[0.0f32; 2]; 4]
+                .into_iter()
+                .chain([[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]]);
+        let mut buffer: Vec<u8> = corners
+            .iter()
+            .flatten()
+            .chain(uvs.collect::<Vec<_>>().iter().flatten())
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        for index in [0u16, 1, 2, 0, 2, 3] {
+            buffer.extend_from_slice(&index.to_le_bytes());
+        }
+        buffer
+    }
+
+    /// A glTF file drawing `square_buffer` with the given buffer & material.
+    fn square_gltf(buffer: &str, material: &str) -> String {
+        r#"{
+            "asset": {"version": "2.0"},
+            "scenes": [{"nodes": [0]}],
+            "nodes": [{"mesh": 0}],
+            "meshes": [{"primitives": [{
+                "attributes": {"POSITION": 0, "TEXCOORD_0": 1, "TEXCOORD_1": 2},
+                "indices": 3,
+                "material": 0
+            }]}],
+            "materials": [MATERIAL],
+            "buffers": [BUFFER],
+            "bufferViews": [
+                {"buffer": 0, "byteOffset": 0, "byteLength": 48},
+                {"buffer": 0, "byteOffset": 48, "byteLength": 32},
+                {"buffer": 0, "byteOffset": 80, "byteLength": 32},
+                {"buffer": 0, "byteOffset": 112, "byteLength": 12}
+            ],
+            "accessors": [
+                {"bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3",
+                    "min": [-1, -1, 0], "max": [1, 1, 0]},
+                {"bufferView": 1, "componentType": 5126, "count": 4, "type": "VEC2"},
+                {"bufferView": 2, "componentType": 5126, "count": 4, "type": "VEC2"},
+                {"bufferView": 3, "componentType": 5123, "count": 6, "type": "SCALAR"}
+            ]
+        }"#
+        .replace("BUFFER", buffer)
+        .replace("MATERIAL", material)
+    }
+
+    fn base64(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        bytes
+            .chunks(3)
+            .flat_map(|chunk| {
+                let n = chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0u32, |n, (i, &byte)| n | (byte as u32) << (16 - 8 * i));
+                (0..4).map(move |i| match i <= chunk.len() {
+                    true => ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char,
+                    false => '=',
+                })
+            })
+            .collect()
+    }
+
+    fn data_uri(mime_type: &str, bytes: &[u8]) -> String {
+        format!("data:{};base64,{}", mime_type, base64(bytes))
+    }
+
+    #[test]
+    fn embedded_and_binary_gltf_files_should_load() -> Result<()> {
+        let dir = Path::new("target/gltf-import-test");
+        std::fs::create_dir_all(dir)?;
+        let buffer = square_buffer();
+
+        let embedded = square_gltf(
+            &format!(
+                r#"{{"uri": "{}", "byteLength": 124}}"#,
+                data_uri("application/octet-stream", &buffer)
+            ),
+            "{}",
+        );
+        std::fs::write(dir.join("embedded.gltf"), embedded)?;
+
+        // a binary glTF file is a header, then the JSON and the buffer in chunks padded to 4 bytes
+        let mut json = square_gltf(r#"{"byteLength": 124}"#, "{}").into_bytes();
+        json.resize(json.len().div_ceil(4) * 4, b' ');
+        let mut glb = Vec::new();
+        glb.extend_from_slice(b"glTF");
+        glb.extend_from_slice(&2u32.to_le_bytes());
+        glb.extend_from_slice(&(12 + 8 + json.len() as u32 + 8 + 124).to_le_bytes());
+        for (chunk_type, chunk) in [(b"JSON", &json), (b"BIN\0", &buffer)] {
+            glb.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            glb.extend_from_slice(chunk_type);
+            glb.extend_from_slice(chunk);
+        }
+        std::fs::write(dir.join("binary.glb"), glb)?;
+
+        for name in ["embedded.gltf", "binary.glb"] {
+            let file = load_gltf(&dir.join(name))?;
+            assert_eq!(file.models.len(), 1, "{}", name);
+            assert_eq!(file.models[0].face_count(), 2, "{}", name);
+            assert!(file.dependencies.is_empty(), "{}", name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn textures_should_be_looked_up_with_their_texture_coordinate_set() -> Result<()> {
+        let dir = Path::new("target/gltf-import-test");
+        std::fs::create_dir_all(dir)?;
+        let image = lodepng::encode_memory(&[WHITE; 4], 2, 2, lodepng::ColorType::RGBA, 8)?;
+        let with_material = |material: &str| {
+            square_gltf(
+                &format!(
+                    r#"{{"uri": "{}", "byteLength": 124}}"#,
+                    data_uri("application/octet-stream", &square_buffer())
+                ),
+                material,
+            )
+            .replacen(
+                r#""buffers""#,
+                &format!(
+                    r#""textures": [{{"source": 0}}], "images": [{{"uri": "{}"}}], "buffers""#,
+                    data_uri("image/png", &image)
+                ),
+                1,
+            )
+        };
+
+        std::fs::write(
+            dir.join("second-uvs.gltf"),
+            with_material(
+                r#"{"pbrMetallicRoughness": {"baseColorTexture": {"index": 0, "texCoord": 1}}}"#,
+            ),
+        )?;
+        let file = load_gltf(&dir.join("second-uvs.gltf"))?;
+        let uvs: Vec<_> = file.models[0].vertices.iter().map(|v| v.uv).collect();
+        assert!(uvs.contains(&Vec2::new(1.0, 1.0)), "{:?}", uvs);
+
+        std::fs::write(
+            dir.join("mixed-uvs.gltf"),
+            with_material(
+                r#"{"pbrMetallicRoughness": {"baseColorTexture": {"index": 0, "texCoord": 1}},
+                    "normalTexture": {"index": 0}}"#,
+            ),
+        )?;
+        assert!(load_gltf(&dir.join("mixed-uvs.gltf")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn gltf_nodes_and_cameras_should_be_fit_into_view() -> Result<()> {
+        // a square in a scaled & moved node, with a camera in front of it, with its buffer & image
+        // in separate files
+        let dir = Path::new("target/gltf-import-test");
+        std::fs::create_dir_all(dir)?;
+        let mut buffer = Vec::new();
+        for value in [
+            [-1.0f32, -1.0, 0.0],
+            [1.0, -1.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [-1.0, 1.0, 0.0],
+        ]
+        .iter()
+        .flatten()
+        .chain([0.0, 1.0, 1.0, 1.0, 1.0, 0.0, 0.0, 0.0].iter())
+        {
+            buffer.extend_from_slice(&value.to_le_bytes());
+        }
+        for index in [0u16, 1, 2, 0, 2, 3] {
+            buffer.extend_from_slice(&index.to_le_bytes());
+        }
+        std::fs::write(dir.join("square.bin"), &buffer)?;
+        lodepng::encode32_file(
+            dir.join("square.png"),
+            &[RGBA8::new(200, 100, 50, 255); 4],
+            2,
+            2,
+        )?;
+        std::fs::write(
+            dir.join("square.gltf"),
+            r#"{
+                "asset": {"version": "2.0"},
+                "scene": 0,
+                "scenes": [{"nodes": [0, 2]}],
+                "nodes": [
+                    {"translation": [10, 0, 0], "scale": [2, 2, 2], "children": [1]},
+                    {"mesh": 0},
+                    {"name": "Front", "camera": 0, "translation": [10, 0, 5]}
+                ],
+                "cameras": [{"type": "perspective", "perspective": {"yfov": 0.8, "znear": 0.1}}],
+                "meshes": [{"primitives": [{
+                    "attributes": {"POSITION": 0, "TEXCOORD_0": 1},
+                    "indices": 2,
+                    "material": 0
+                }]}],
+                "materials": [{"pbrMetallicRoughness": {
+                    "baseColorTexture": {"index": 0},
+                    "baseColorFactor": [0.5, 1, 1, 1]
+                }}],
+                "textures": [{"source": 0}],
+                "images": [{"uri": "square.png"}],
+                "buffers": [{"uri": "square.bin", "byteLength": 92}],
+                "bufferViews": [
+                    {"buffer": 0, "byteOffset": 0, "byteLength": 48},
+                    {"buffer": 0, "byteOffset": 48, "byteLength": 32},
+                    {"buffer": 0, "byteOffset": 80, "byteLength": 12}
+                ],
+                "accessors": [
+                    {"bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3",
+                        "min": [-1, -1, 0], "max": [1, 1, 0]},
+                    {"bufferView": 1, "componentType": 5126, "count": 4, "type": "VEC2"},
+                    {"bufferView": 2, "componentType": 5123, "count": 6, "type": "SCALAR"}
+                ]
+            }"#,
+        )?;
+
+        let file = load_gltf(&dir.join("square.gltf"))?;
+
+        assert_eq!(file.models.len(), 1);
+        let model = &file.models[0];
+        assert_eq!(model.face_count(), 2);
+        let corners: Vec<_> = model.vertices.iter().map(|v| v.position).collect();
+        assert!(corners.contains(&Vec3::new(-1.0, -1.0, 0.0)));
+        assert!(corners.contains(&Vec3::new(1.0, 1.0, 0.0)));
+        assert_eq!(model.diffuse_texture.data[0], RGBA8::new(100, 100, 50, 255));
+        assert_eq!(
+            file.dependencies,
+            vec![dir.join("square.bin"), dir.join("square.png")]
+        );
+        assert_eq!(
+            file.cameras,
+            vec![Camera {
+                name: "Front".to_owned(),
+                look_from: Vec3::new(0.0, 0.0, 2.5),
+                look_at: Vec3::ZERO,
+                up: Vec3::Y,
+            }]
+        );
+        Ok(())
+    }
+
+    /// A skinned arm: a strip from x = 0 to 4 whose left end is bound to a shoulder joint and
+    /// whose right end to an elbow joint 2 along from it (or to `right_joint`, if that's not 1).
+    /// Both joints hang off nodes which aren't joints, and the node drawing the mesh has a
+    /// transform that skinning ignores. The elbow bends a quarter turn around z over a second,
+    /// with cubic spline keyframes.
+    fn skinned_arm_gltf(right_joint: u8) -> String {
+        let floats = |values: &[f32]| -> Vec<u8> {
+            values
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect()
+        };
+        let mut buffer = floats(&[0.0, 1.0, 0.0, 4.0, 1.0, 0.0, 4.0, 2.0, 0.0, 0.0, 2.0, 0.0]);
+        for joint in [0, right_joint, right_joint, 0] {
+            buffer.extend_from_slice(&[joint, 0, 0, 0]);
+        }
+        buffer.extend(floats(&[1.0, 0.0, 0.0, 0.0].repeat(4)));
+        for index in [0u16, 1, 2, 0, 2, 3] {
+            buffer.extend_from_slice(&index.to_le_bytes());
+        }
+        // inverse bind matrices: the shoulder is at (0, 1, 0) and the elbow at (2, 1, 0)
+        for joint in [Vec3::new(0.0, 1.0, 0.0), Vec3::new(2.0, 1.0, 0.0)] {
+            buffer.extend(floats(&Mat4::from_translation(-joint).to_cols_array()));
+        }
+        // keyframe times, then each keyframe's in tangent, value and out tangent (the tangents
+        // being nonsense, as they're skipped)
+        buffer.extend(floats(&[0.0, 1.0]));
+        let bent = Quat::from_rotation_z(std::f32::consts::FRAC_PI_2);
+        for value in [Quat::IDENTITY, bent] {
+            buffer.extend(floats(&[9.0; 4]));
+            buffer.extend(floats(&Vec4::from(value).to_array()));
+            buffer.extend(floats(&[9.0; 4]));
+        }
+        assert_eq!(buffer.len(), 372);
+
+        r#"{
+            "asset": {"version": "2.0"},
+            "scenes": [{"nodes": [0, 1]}],
+            "nodes": [
+                {"mesh": 0, "skin": 0, "translation": [100, 0, 0], "scale": [1, 3, 1]},
+                {"translation": [0, 1, 0], "children": [2]},
+                {"name": "Shoulder", "children": [3]},
+                {"translation": [1, 0, 0], "children": [4]},
+                {"name": "Elbow", "translation": [1, 0, 0]}
+            ],
+            "skins": [{"joints": [2, 4], "inverseBindMatrices": 4}],
+            "meshes": [{"primitives": [{
+                "attributes": {"POSITION": 0, "JOINTS_0": 1, "WEIGHTS_0": 2},
+                "indices": 3
+            }]}],
+            "animations": [{
+                "name": "Bend",
+                "channels": [{"sampler": 0, "target": {"node": 4, "path": "rotation"}}],
+                "samplers": [{"input": 5, "output": 6, "interpolation": "CUBICSPLINE"}]
+            }],
+            "buffers": [BUFFER],
+            "bufferViews": [
+                {"buffer": 0, "byteOffset": 0, "byteLength": 48},
+                {"buffer": 0, "byteOffset": 48, "byteLength": 16},
+                {"buffer": 0, "byteOffset": 64, "byteLength": 64},
+                {"buffer": 0, "byteOffset": 128, "byteLength": 12},
+                {"buffer": 0, "byteOffset": 140, "byteLength": 128},
+                {"buffer": 0, "byteOffset": 268, "byteLength": 8},
+                {"buffer": 0, "byteOffset": 276, "byteLength": 96}
+            ],
+            "accessors": [
+                {"bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3",
+                    "min": [0, 1, 0], "max": [4, 2, 0]},
+                {"bufferView": 1, "componentType": 5121, "count": 4, "type": "VEC4"},
+                {"bufferView": 2, "componentType": 5126, "count": 4, "type": "VEC4"},
+                {"bufferView": 3, "componentType": 5123, "count": 6, "type": "SCALAR"},
+                {"bufferView": 4, "componentType": 5126, "count": 2, "type": "MAT4"},
+                {"bufferView": 5, "componentType": 5126, "count": 2, "type": "SCALAR",
+                    "min": [0], "max": [1]},
+                {"bufferView": 6, "componentType": 5126, "count": 6, "type": "VEC4"}
+            ]
+        }"#
+        .replace(
+            "BUFFER",
+            &format!(
+                r#"{{"uri": "{}", "byteLength": 372}}"#,
+                data_uri("application/octet-stream", &buffer)
+            ),
+        )
+    }
+
+    #[test]
+    fn skinned_vertices_should_follow_their_joints_from_the_rest_pose() -> Result<()> {
+        let dir = Path::new("target/gltf-import-test");
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(dir.join("arm.gltf"), skinned_arm_gltf(1))?;
+        let file = load_gltf(&dir.join("arm.gltf"))?;
+
+        let model = &file.models[0];
+        let skeleton = model.skeleton.as_ref().expect("arm should be skinned");
+        let [shoulder, elbow] = [&skeleton.joints[0], &skeleton.joints[1]];
+        assert_eq!(
+            (shoulder.name.as_str(), shoulder.parent),
+            ("Shoulder", None)
+        );
+        assert_eq!((elbow.name.as_str(), elbow.parent), ("Elbow", Some(0)));
+        // the node between the joints is folded into the elbow's offset from the shoulder
+        assert_eq!(elbow.parent_offset, Mat4::from_translation(Vec3::X));
+
+        // the cubic spline's tangents are dropped, leaving its values
+        assert_eq!(file.animations.len(), 1);
+        let clip = &file.animations[0];
+        assert_eq!((clip.name.as_str(), clip.duration), ("Bend", 1.0));
+        let bent = Quat::from_rotation_z(std::f32::consts::FRAC_PI_2);
+        assert_eq!(
+            clip.channels[0].values,
+            ChannelValues::Rotation(vec![Quat::IDENTITY, bent])
+        );
+
+        // the rest pose puts every vertex back where it was bound, after the arm was fitted into
+        // view (without the mesh node's transform)
+        let fit = fit_transform(
+            [(0.0, 1.0), (4.0, 1.0), (4.0, 2.0), (0.0, 2.0)]
+                .into_iter()
+                .map(|(x, y)| Vec3::new(x, y, 0.0)),
+        );
+        let rest = skeleton.joint_matrices(None, 0.0);
+        for vertex in &model.vertices {
+            assert!(vertex
+                .skinned(&rest)
+                .position
+                .abs_diff_eq(vertex.position, 1e-5));
+        }
+        // halfway through the clip the elbow has bent an eighth of a turn, taking the vertices at
+        // the end of the arm with it, while the ones at the shoulder stay put
+        let halfway = skeleton.joint_matrices(Some(clip), 0.5);
+        let hand = fit.transform_point3(Vec3::new(4.0, 1.0, 0.0));
+        let vertex = model
+            .vertices
+            .iter()
+            .find(|vertex| vertex.position.abs_diff_eq(hand, 1e-5))
+            .expect("arm should have a vertex at the hand");
+        let bent_hand =
+            Vec3::new(2.0, 1.0, 0.0) + Vec3::new(2.0, 2.0, 0.0) / std::f32::consts::SQRT_2;
+        assert!(vertex
+            .skinned(&halfway)
+            .position
+            .abs_diff_eq(fit.transform_point3(bent_hand), 1e-4));
+        for vertex in model.vertices.iter().filter(|v| v.joints[0] == 0) {
+            assert!(vertex
+                .skinned(&halfway)
+                .position
+                .abs_diff_eq(vertex.position, 1e-5));
+        }
+
+        // vertices can only be bound to the skin's joints
+        std::fs::write(dir.join("bad-arm.gltf"), skinned_arm_gltf(2))?;
+        let error = load_gltf(&dir.join("bad-arm.gltf")).expect_err("joint 2 shouldn't exist");
+        assert!(format!("{:#}", error).contains("out of range of its skin's 2 joints"));
+        Ok(())
+    }
+}
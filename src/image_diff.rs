@@ -0,0 +1,94 @@
+use rgb::RGBA8;
+
+use crate::false_color_map;
+
+/// How much two images of the same size differ, pixel by pixel.
+#[derive(Debug)]
+pub struct ImageDiff {
+    /// The largest channel difference at each pixel, normalized so the largest difference in the
+    /// image is the brightest color
+    pub heatmap: Vec<RGBA8>,
+    /// The largest channel difference at each pixel, from 0 to 255
+    pub pixel_diffs: Vec<u8>,
+    /// Mean absolute difference over every color channel of every pixel, from 0 to 255
+    pub mean_abs_diff: f32,
+    /// Largest absolute difference of any color channel, from 0 to 255
+    pub max_abs_diff: u8,
+    /// Root mean square difference over every color channel of every pixel, from 0 to 255
+    pub rms_diff: f32,
+    /// Fraction of pixels where any color channel differs
+    pub differing_fraction: f32,
+}
+
+impl ImageDiff {
+    /// Fraction of pixels where any color channel differs by more than `tolerance`.
+    pub fn fraction_differing_by_more_than(&self, tolerance: u8) -> f32 {
+        let differing = self.pixel_diffs.iter().filter(|&&d| d > tolerance).count();
+        differing as f32 / self.pixel_diffs.len().max(1) as f32
+    }
+
+    /// Peak signal-to-noise ratio in decibels, which is infinite for identical images.
+    pub fn psnr(&self) -> f32 {
+        20.0 * (255.0 / self.rms_diff).log10()
+    }
+}
+
+/// Compare two images of the same size, ignoring alpha.
+pub fn diff_images(reference: &[RGBA8], current: &[RGBA8]) -> ImageDiff {
+    assert_eq!(reference.len(), current.len());
+
+    let pixel_diffs: Vec<[u8; 3]> = reference
+        .iter()
+        .zip(current)
+        .map(|(a, b)| [a.r.abs_diff(b.r), a.g.abs_diff(b.g), a.b.abs_diff(b.b)])
+        .collect();
+
+    let channel_count = (pixel_diffs.len() * 3).max(1) as f64;
+    let (sum, sum_sq) = pixel_diffs
+        .iter()
+        .flatten()
+        .fold((0.0, 0.0), |(sum, sum_sq), &d| {
+            let d = d as f64;
+            (sum + d, sum_sq + d * d)
+        });
+    let largest_diffs: Vec<u8> = pixel_diffs
+        .iter()
+        .map(|d| d.iter().copied().max().unwrap_or(0))
+        .collect();
+    let max_abs_diff = largest_diffs.iter().copied().max().unwrap_or(0);
+
+    let mut diff = ImageDiff {
+        heatmap: largest_diffs
+            .iter()
+            .map(|&d| false_color_map(d as f32 / max_abs_diff.max(1) as f32))
+            .collect(),
+        pixel_diffs: largest_diffs,
+        mean_abs_diff: (sum / channel_count) as f32,
+        max_abs_diff,
+        rms_diff: (sum_sq / channel_count).sqrt() as f32,
+        differing_fraction: 0.0,
+    };
+    diff.differing_fraction = diff.fraction_differing_by_more_than(0);
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_images_should_measure_differing_channels() {
+        let black = RGBA8::new(0, 0, 0, 255);
+        let reference = [black, black, black, black];
+        let current = [black, RGBA8::new(30, 0, 0, 255), black, black];
+
+        let diff = diff_images(&reference, &current);
+
+        assert_eq!(diff.max_abs_diff, 30);
+        assert_eq!(diff.mean_abs_diff, 30.0 / 12.0);
+        assert_eq!(diff.differing_fraction, 0.25);
+        assert_eq!(diff.heatmap[0], false_color_map(0.0));
+        assert_eq!(diff.heatmap[1], false_color_map(1.0));
+        assert!(diff_images(&reference, &reference).psnr().is_infinite());
+    }
+}
@@ -1,3 +1,8 @@
+//! The renderer behind crab-tv: canvases, models and the shaders and scenes that draw them. The
+//! binary adds the UI, background rendering and asset loading on top. Scenes, shaders and image
+//! comparison live here rather than in the binary because benchmarks can only link against the
+//! library, and they (like the golden image tests) need to draw exactly what the UI draws.
+
 #![deny(clippy::all)] // make all clippy warnings into errors
 #![allow(clippy::many_single_char_names)]
 #![allow(clippy::needless_range_loop)]
@@ -5,12 +10,48 @@
 mod canvas;
 mod canvas_legacy;
 mod colors;
+mod gbuffer;
+mod gltf_import;
+mod image_diff;
 mod maths;
+mod mesh_cache;
+mod mesh_export;
+mod mesh_import;
+mod mesh_report;
 mod model;
+mod progress;
+mod scene_graph;
+mod scenes;
+mod shaders;
+mod simplify;
+mod skinning;
+mod subdivide;
 
 pub use colors::*;
 
-pub use canvas::{Canvas, Shader, Vertex};
+pub use canvas::{
+    BlendMode, Canvas, CullMode, FragmentRecord, RasterStats, ResampleFilter, Shader, Vertex,
+    Winding,
+};
 pub use canvas_legacy::ModelShading;
+pub use gbuffer::{GBuffer, GBufferChannel, GBufferTexel, GeometryShader};
+pub use image_diff::{diff_images, ImageDiff};
 pub use maths::{look_at_transform, viewport_transform, yolo_max, yolo_min, DEPTH_MAX};
-pub use model::{Face, Model, ModelInput, Texture};
+pub use mesh_export::{export_models, export_obj, export_ply};
+pub use mesh_report::{FileCounts, MeshReport};
+pub use model::{Camera, Model, ModelFile, ModelInput, Texture};
+pub use progress::ProgressSink;
+pub use scene_graph::{MaterialOverrides, SceneNode, Transform};
+pub use scenes::{
+    render_scene, RenderScene, SceneOutput, SceneStats, AMBIENT_OCCLUSION_PASS, GEOMETRY_PASS,
+    LIGHTING_PASS, MAIN_PASS, POST_PROCESS_PASS, SHADOW_PASS,
+};
+pub use shaders::{
+    DepthShader, GouraudShader, NormalMap, NormalShader, PhongShader, PhongShadowInput,
+    PureColorShader, UnlitShader,
+};
+pub use simplify::LodChain;
+pub use skinning::{
+    AnimationChannel, AnimationClip, ChannelValues, Interpolation, Joint, JointTransform, Skeleton,
+};
+pub use subdivide::SubdivisionScheme;
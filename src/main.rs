@@ -2,16 +2,51 @@
 #![allow(clippy::many_single_char_names)]
 #![allow(clippy::needless_range_loop)]
 
-mod scenes;
-mod shaders;
+mod animation;
+mod assets;
+mod buffer_view;
+mod compare;
+mod scene_objects;
 mod ui;
+mod worker;
 
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use crate::scenes::RenderScene;
 use anyhow::{bail, Context, Result};
-use crab_tv::{Model, ModelInput};
-use glam::Vec3;
+use crab_tv::{
+    BlendMode, Camera, CullMode, Model, ModelFile, ModelInput, RenderScene, ResampleFilter,
+    SubdivisionScheme, Texture, Winding,
+};
+use glam::{Quat, Vec3};
+use scene_objects::{SceneObject, SceneObjectInput};
+
+/// Each level of subdivision makes about 4 times as many faces, so more than this gets very slow.
+pub(crate) const MAX_SUBDIVISION_LEVELS: usize = 3;
+
+/// How (if at all) edges should be smoothed out in the rendered image.
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::EnumIter,
+    PartialEq,
+    Eq,
+    strum::Display,
+)]
+pub enum AntiAliasing {
+    #[default]
+    Off,
+    /// Multisampling: coverage and depth per sample, shading per pixel
+    Msaa,
+    /// Supersampling: render at a higher resolution then downsample
+    Ssaa,
+}
 
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize, PartialEq)]
 #[serde(default)] // if we add new fields, give them default values when deserializing old state
@@ -24,6 +59,8 @@ pub struct RenderConfig {
     width: usize,
     height: usize,
     model: PathBuf,
+    /// Other models drawn around the main one
+    objects: Vec<SceneObject>,
     auto_rotate_camera_speed: f32,
     #[serde(skip)]
     auto_rotate_camera_angle: f32,
@@ -43,7 +80,34 @@ pub struct RenderConfig {
     ambient_occlusion_strength: f32,
     enable_glow_map: bool,
     base_shininess: f32,
+    cull_mode: CullMode,
+    front_face: Winding,
+    anti_aliasing: AntiAliasing,
+    msaa_samples: usize,
+    ssaa_factor: usize,
+    ssaa_filter: ResampleFilter,
+    transparency_opacity: f32,
+    transparency_blend_mode: BlendMode,
+    progressive_refinement: bool,
+    preview_scale: usize,
+    record_fragments: bool,
+    /// Whether previews draw simplified models, and the most faces each model can have in them
+    preview_lods: bool,
+    preview_lod_faces: usize,
+    subdivision_scheme: SubdivisionScheme,
+    subdivision_levels: usize,
+    /// Which of the main model's animation clips poses its skeleton, if any
+    animation_clip: Option<usize>,
+    #[serde(skip)]
+    animation_time: f32,
+    /// Whether the animation clip plays (looping), and how fast
+    animation_playing: bool,
+    animation_speed: f32,
     output_filename: String,
+    /// Where to export the main model's meshes to, as OBJ or PLY
+    model_export_filename: String,
+    /// Whether OBJ exports include a material library and textures
+    export_materials: bool,
     display_actual_size: bool,
     auto_rerender: bool,
 }
@@ -57,6 +121,85 @@ impl RenderConfig {
         self.auto_rotate_light_speed > 0.0
             || self.auto_rotate_camera_speed > 0.0
             || self.demo_mode_speed > 0.0
+            || self.is_animating()
+    }
+
+    /// Whether an animation clip is playing, so the model's pose changes every frame.
+    pub(crate) fn is_animating(&self) -> bool {
+        self.animation_clip.is_some() && self.animation_playing && self.animation_speed > 0.0
+    }
+
+    /// Rotate the camera around the point it's looking at, by angles in radians. Pitching stops
+    /// just short of looking straight along the up direction, to keep the view from flipping over.
+    pub(crate) fn orbit_camera(&mut self, yaw: f32, pitch: f32) {
+        const MIN_ANGLE_TO_UP: f32 = 0.01;
+
+        let offset = Quat::from_axis_angle(self.camera_up, yaw)
+            * (self.camera_look_from - self.camera_look_at);
+        let offset = match self.camera_up.cross(offset).try_normalize() {
+            Some(right) => {
+                let pitched = Quat::from_axis_angle(right, pitch) * offset;
+                let angle_to_up = pitched.angle_between(self.camera_up);
+                if angle_to_up > MIN_ANGLE_TO_UP
+                    && angle_to_up < std::f32::consts::PI - MIN_ANGLE_TO_UP
+                {
+                    pitched
+                } else {
+                    offset
+                }
+            }
+            None => offset,
+        };
+        self.camera_look_from = self.camera_look_at + offset;
+    }
+
+    /// Move the camera and the point it's looking at sideways and up, relative to the view.
+    pub(crate) fn pan_camera(&mut self, right: f32, up: f32) {
+        let forward = (self.camera_look_at - self.camera_look_from).normalize_or_zero();
+        let right_dir = forward.cross(self.camera_up).normalize_or_zero();
+        let up_dir = right_dir.cross(forward);
+        let shift = right_dir * right + up_dir * up;
+        self.camera_look_from += shift;
+        self.camera_look_at += shift;
+    }
+
+    /// Scale the camera's perspective distance, keeping it within the range the UI allows.
+    pub(crate) fn dolly_camera(&mut self, factor: f32) {
+        self.camera_distance = (self.camera_distance * factor).clamp(1.0, 10.0);
+    }
+
+    /// Move the camera to a viewpoint saved in a model file, moving it along its view to within the
+    /// range of distances the UI allows.
+    pub(crate) fn view_from(&mut self, camera: &Camera) {
+        self.auto_rotate_camera_speed = 0.0;
+        let offset = camera.look_from - camera.look_at;
+        self.camera_distance = offset.length().clamp(1.0, 10.0);
+        self.camera_look_from =
+            camera.look_at + offset.try_normalize().unwrap_or(Vec3::Z) * self.camera_distance;
+        self.camera_look_at = camera.look_at;
+        self.camera_up = camera.up;
+    }
+
+    pub(crate) fn reset_camera(&mut self) {
+        let default = RenderConfig::default();
+        self.camera_distance = default.camera_distance;
+        self.camera_look_from = default.camera_look_from;
+        self.camera_look_at = default.camera_look_at;
+        self.camera_up = default.camera_up;
+        self.auto_rotate_camera_angle = default.auto_rotate_camera_angle;
+    }
+
+    /// Rotate the light direction by angles in radians, around the camera's up and right axes.
+    pub(crate) fn rotate_light(&mut self, yaw: f32, pitch: f32) {
+        let mut light_dir = Quat::from_axis_angle(self.camera_up, yaw) * self.light_dir;
+        if let Some(right) = self
+            .camera_up
+            .cross(self.camera_look_from - self.camera_look_at)
+            .try_normalize()
+        {
+            light_dir = Quat::from_axis_angle(right, pitch) * light_dir;
+        }
+        self.light_dir = light_dir.normalize_or_zero();
     }
 
     pub(crate) fn validate(&self) -> Result<RenderInput> {
@@ -73,6 +216,29 @@ impl RenderConfig {
 
         let model_input = Model::validate(&self.model)
             .with_context(|| format!("Failed to load model from {}", self.model.display()))?;
+        let objects = self
+            .objects
+            .iter()
+            .map(SceneObject::validate)
+            .collect::<Result<_>>()?;
+
+        let (msaa_samples, ssaa_factor) = match self.anti_aliasing {
+            AntiAliasing::Off => (1, 1),
+            AntiAliasing::Msaa => {
+                if ![2, 4, 8].contains(&self.msaa_samples) {
+                    bail!("MSAA sample count must be 2, 4 or 8");
+                }
+                (self.msaa_samples, 1)
+            }
+            AntiAliasing::Ssaa => {
+                if self.ssaa_factor < 2 {
+                    bail!("Supersampling factor must be 2 or greater");
+                } else if self.width.max(self.height) * self.ssaa_factor > 5000 {
+                    bail!("Supersampled width and height must be 5000 or less");
+                }
+                (1, self.ssaa_factor)
+            }
+        };
 
         if self.camera_look_from == self.camera_look_at {
             bail!("Camera's 'look from' position must not be the same as its 'look at' position");
@@ -84,11 +250,27 @@ impl RenderConfig {
             bail!("Height must be 1.0 or less");
         }
 
+        if !(0.0..=1.0).contains(&self.transparency_opacity) {
+            bail!("Transparency opacity must be between 0.0 and 1.0");
+        }
+
+        if !(1..=MAX_SUBDIVISION_LEVELS).contains(&self.subdivision_levels) {
+            bail!(
+                "Subdivision levels must be between 1 and {}",
+                MAX_SUBDIVISION_LEVELS
+            );
+        }
+
+        if self.preview_scale < 1 {
+            bail!("Preview scale must be 1 or greater");
+        }
+
         Ok(RenderInput {
             scene: self.scene,
             width: self.width,
             height: self.height,
             model_input,
+            objects,
             light_dir: self.light_dir,
             camera_perspective_dist: self.camera_distance,
             camera_look_from: self.camera_look_from,
@@ -102,6 +284,19 @@ impl RenderConfig {
             ambient_occlusion_strength: self.ambient_occlusion_strength,
             enable_glow_map: self.enable_glow_map,
             base_shininess: self.base_shininess,
+            cull_mode: self.cull_mode,
+            front_face: self.front_face,
+            msaa_samples,
+            ssaa_factor,
+            ssaa_filter: self.ssaa_filter,
+            transparency_opacity: self.transparency_opacity,
+            transparency_blend_mode: self.transparency_blend_mode,
+            record_fragments: self.record_fragments,
+            lod_face_budget: None,
+            subdivision_scheme: self.subdivision_scheme,
+            subdivision_levels: self.subdivision_levels,
+            animation_clip: self.animation_clip,
+            animation_time: self.animation_time,
         })
     }
 }
@@ -115,6 +310,7 @@ impl Default for RenderConfig {
             width: 1000,
             height: 1000,
             model: PathBuf::from("assets/head.obj"),
+            objects: Vec::new(),
             auto_rotate_camera_speed: 0.1,
             auto_rotate_camera_angle: 0.0,
             light_dir: Vec3::new(0.0, 0.0, 1.0),
@@ -132,7 +328,28 @@ impl Default for RenderConfig {
             ambient_occlusion_strength: 2.0,
             enable_glow_map: true,
             base_shininess: 5.0,
+            cull_mode: CullMode::default(),
+            front_face: Winding::default(),
+            anti_aliasing: AntiAliasing::default(),
+            msaa_samples: 4,
+            ssaa_factor: 2,
+            ssaa_filter: ResampleFilter::default(),
+            transparency_opacity: 0.5,
+            transparency_blend_mode: BlendMode::Alpha,
+            progressive_refinement: true,
+            preview_scale: 4,
+            record_fragments: false,
+            preview_lods: true,
+            preview_lod_faces: 2000,
+            subdivision_scheme: SubdivisionScheme::default(),
+            subdivision_levels: 1,
+            animation_clip: None,
+            animation_time: 0.0,
+            animation_playing: true,
+            animation_speed: 1.0,
             output_filename: "target/output.png".to_owned(),
+            model_export_filename: "target/export.obj".to_owned(),
+            export_materials: true,
             display_actual_size: true,
             auto_rerender: true,
         }
@@ -145,6 +362,7 @@ pub struct RenderInput {
     width: usize,
     height: usize,
     model_input: ModelInput,
+    objects: Vec<SceneObjectInput>,
     light_dir: Vec3,
     camera_perspective_dist: f32,
     camera_look_from: Vec3,
@@ -158,10 +376,105 @@ pub struct RenderInput {
     ambient_occlusion_strength: f32,
     enable_glow_map: bool,
     base_shininess: f32,
+    cull_mode: CullMode,
+    front_face: Winding,
+    msaa_samples: usize,
+    ssaa_factor: usize,
+    ssaa_filter: ResampleFilter,
+    transparency_opacity: f32,
+    transparency_blend_mode: BlendMode,
+    record_fragments: bool,
+    /// Draw models with more faces than this with their simplified levels of detail instead, and
+    /// don't subdivide any of them
+    lod_face_budget: Option<usize>,
+    subdivision_scheme: SubdivisionScheme,
+    subdivision_levels: usize,
+    animation_clip: Option<usize>,
+    /// Seconds into the animation clip
+    animation_time: f32,
+}
+
+impl RenderInput {
+    /// A cheaper version of this render for quick feedback while the config is being changed: the
+    /// resolution is divided by `scale`, expensive effects are turned off and models are simplified
+    /// to `lod_face_budget` faces (rather than subdivided) if set. Returns `None` if the scene has
+    /// no cheaper version.
+    pub(crate) fn preview(
+        &self,
+        scale: usize,
+        lod_face_budget: Option<usize>,
+    ) -> Option<RenderInput> {
+        let scene = self.scene.preview_scene()?;
+        Some(RenderInput {
+            scene,
+            // scenes can't be drawn smaller than the minimum size that validation allows
+            width: (self.width / scale).max(200).min(self.width),
+            height: (self.height / scale).max(200).min(self.height),
+            ambient_occlusion_passes: 0,
+            msaa_samples: 1,
+            ssaa_factor: 1,
+            lod_face_budget,
+            ..self.clone()
+        })
+    }
 }
 
 fn main() {
-    let app = ui::RendererApp::new();
-    let native_options = eframe::NativeOptions::default();
-    eframe::run_native(Box::new(app), native_options);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => {
+            let app = ui::RendererApp::new();
+            let native_options = eframe::NativeOptions::default();
+            eframe::run_native(Box::new(app), native_options);
+        }
+        [command, path] if command == "animate" => {
+            if let Err(err) = animation::export_from_file(path.as_ref()) {
+                eprintln!("Exporting animation failed: {:?}", err);
+                std::process::exit(1);
+            }
+        }
+        [command, model, output] if command == "export" => {
+            if let Err(err) = export_model(model.as_ref(), output.as_ref()) {
+                eprintln!("Exporting model failed: {:?}", err);
+                std::process::exit(1);
+            }
+        }
+        [command, model] if command == "report" => {
+            if let Err(err) = print_model_report(model.as_ref()) {
+                eprintln!("Reporting on model failed: {:?}", err);
+                std::process::exit(1);
+            }
+        }
+        _ => {
+            eprintln!(
+                "Usage: crab-tv [animate <animation.ron> | export <model> <output.obj|ply> | \
+                report <model>]"
+            );
+            std::process::exit(2);
+        }
+    }
+}
+
+fn load_model_file(model: &Path) -> Result<ModelFile> {
+    let input = Model::validate(model)?;
+    ModelFile::load(&input, |path| Texture::load_from_file(path).map(Arc::new))
+}
+
+/// Print what's in each of a model file's models, and any problems with their geometry.
+fn print_model_report(model: &Path) -> Result<()> {
+    let file = load_model_file(model)?;
+    for (i, model) in file.models.iter().enumerate() {
+        if file.models.len() > 1 {
+            println!("\nModel {} of {}", i + 1, file.models.len());
+        }
+        print!("{}", model.report());
+    }
+    Ok(())
+}
+
+/// Load a model then save it in another format, so that the renderer can be used to convert and
+/// clean up meshes without opening a window.
+fn export_model(model: &Path, output: &Path) -> Result<()> {
+    let file = load_model_file(model)?;
+    crab_tv::export_models(&file.models, output, true)
 }
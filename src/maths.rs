@@ -57,6 +57,41 @@ pub(crate) fn barycentric_coords_3d_matrix(pts: Mat3, p: Vec2) -> Vec3 {
     }
 }
 
+/// Twice the signed area of the triangle formed by the x & y components of each column; positive if
+/// the points are in counter clockwise order.
+pub(crate) fn signed_area_2d(pts: Mat3) -> f32 {
+    let a = pts.col(0).truncate();
+    let b = pts.col(1).truncate();
+    let c = pts.col(2).truncate();
+    (b - a).perp_dot(c - a)
+}
+
+/// Sub-pixel sample positions for multisampling, using the standard D3D/Vulkan patterns.
+pub(crate) fn msaa_sample_offsets(samples: usize) -> Option<Vec<Vec2>> {
+    let pattern: &[(f32, f32)] = match samples {
+        1 => &[(0.0, 0.0)],
+        2 => &[(4.0, 4.0), (-4.0, -4.0)],
+        4 => &[(-2.0, -6.0), (6.0, -2.0), (-6.0, 2.0), (2.0, 6.0)],
+        8 => &[
+            (1.0, -3.0),
+            (-1.0, 3.0),
+            (5.0, 1.0),
+            (-3.0, -5.0),
+            (-5.0, 5.0),
+            (-7.0, -1.0),
+            (3.0, 7.0),
+            (7.0, -7.0),
+        ],
+        _ => return None,
+    };
+    Some(
+        pattern
+            .iter()
+            .map(|(x, y)| Vec2::new(x / 16.0, y / 16.0))
+            .collect(),
+    )
+}
+
 #[inline]
 pub(crate) fn yolo_compare<N: std::cmp::PartialOrd>(a: &N, b: &N) -> std::cmp::Ordering {
     a.partial_cmp(b).expect("hopefully a and b are comparable")
@@ -0,0 +1,312 @@
+//! A compact binary copy of a model's parsed geometry, saved in the user's cache directory so that
+//! later loads can skip parsing the model's text. Each cache file records a hash of the model file
+//! it was made from, and is ignored (then replaced) once that file changes.
+//!
+//! The format is little endian: a header of `MAGIC`, `VERSION` and the source hash, then the
+//! vertices, indices and tangents, each as a `u32` count followed by that many entries (12 `f32`s
+//! per vertex for its position, texture coordinates, normal and color, a `u32` per index and 3
+//! `f32`s per tangent). Then come the corner counts of the file's polygons, as a `u32` count and
+//! a `u32` each (none if they're all triangles), followed by a `u32` which is 1 if the file's
+//! counts of positions, texture coordinates, normals and unused positions follow (as 4 more
+//! `u32`s), or 0 if they don't. Only models from formats without skeletons are cached, so
+//! vertices' joints and weights aren't saved.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use glam::{Vec2, Vec3, Vec4};
+
+use crate::{FileCounts, Vertex};
+
+const MAGIC: &[u8; 8] = b"CRABMESH";
+/// Increment this whenever the format or what the parsers produce changes, so that old cache files
+/// are regenerated.
+const VERSION: u32 = 7;
+
+/// The geometry of a model, as parsed from its file.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Mesh {
+    pub(crate) vertices: Vec<Vertex>,
+    pub(crate) indices: Vec<u32>,
+    pub(crate) tangents: Vec<Vec3>,
+    /// How many corners each polygon in the file had, if any weren't triangles
+    pub(crate) polygon_sizes: Vec<u32>,
+    /// What the file listed before its vertices were merged, if it lists positions separately
+    pub(crate) file_counts: Option<FileCounts>,
+}
+
+/// Where the mesh cache of a model file is kept. Caches aren't kept next to their models, which
+/// may be somewhere read-only or shared, so each is named after its model plus a hash of the
+/// model's full path (as models in different directories can have the same name).
+pub(crate) fn cache_path(model: &Path) -> PathBuf {
+    let full_path = model.canonicalize().unwrap_or_else(|_| model.to_owned());
+    let name = format!(
+        "{}-{:016x}.meshcache",
+        model.file_stem().unwrap_or_default().to_string_lossy(),
+        hash(full_path.to_string_lossy().as_bytes())
+    );
+    cache_dir().join(name)
+}
+
+/// The platform's per-user cache directory for mesh caches, or the temporary directory if there
+/// isn't one.
+fn cache_dir() -> PathBuf {
+    let env_dir = |var| {
+        std::env::var_os(var)
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+    };
+    let platform_dir = if cfg!(windows) {
+        env_dir("LOCALAPPDATA")
+    } else if cfg!(target_os = "macos") {
+        env_dir("HOME").map(|home| home.join("Library/Caches"))
+    } else {
+        env_dir("XDG_CACHE_HOME").or_else(|| env_dir("HOME").map(|home| home.join(".cache")))
+    };
+    platform_dir
+        .unwrap_or_else(std::env::temp_dir)
+        .join("crab-tv")
+        .join("meshcache")
+}
+
+/// A hash of a model file's contents (64 bit FNV-1a), which stays the same across platforms and
+/// compiler versions, unlike the standard library's hashers.
+pub(crate) fn hash(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    bytes.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(PRIME)
+    })
+}
+
+/// Read the mesh cached at `path`, or `None` if there is no cache there or it was made from a
+/// different version of the model file.
+pub(crate) fn read(path: &Path, source_hash: u64) -> Result<Option<Mesh>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).context("attempting to read mesh cache"),
+    };
+
+    let mut reader = Reader { bytes: &bytes };
+    if reader.take(MAGIC.len())? != MAGIC {
+        bail!("not a mesh cache file");
+    }
+    if reader.u32()? != VERSION || reader.u64()? != source_hash {
+        return Ok(None);
+    }
+
+    let vertices = reader.list(|r| {
+        Ok(Vertex {
+            position: r.vec3()?,
+            uv: Vec2::new(r.f32()?, r.f32()?),
+            normal: r.vec3()?,
+            color: Vec4::new(r.f32()?, r.f32()?, r.f32()?, r.f32()?),
+            ..Vertex::default()
+        })
+    })?;
+    let indices = reader.list(Reader::u32)?;
+    if indices.len() % 3 != 0 {
+        bail!(
+            "mesh cache has {} indices, which isn't a whole number of triangles",
+            indices.len()
+        );
+    }
+    if let Some(index) = indices.iter().find(|&&i| i as usize >= vertices.len()) {
+        bail!(
+            "mesh cache index {} is out of range of its {} vertices",
+            index,
+            vertices.len()
+        );
+    }
+    let tangents = reader.list(Reader::vec3)?;
+    if tangents.len() != indices.len() / 3 {
+        bail!(
+            "mesh cache has {} tangents for {} triangles",
+            tangents.len(),
+            indices.len() / 3
+        );
+    }
+    let polygon_sizes = reader.list(Reader::u32)?;
+    let split_faces: usize = polygon_sizes
+        .iter()
+        .map(|&size| (size as usize).saturating_sub(2))
+        .sum();
+    if !polygon_sizes.is_empty()
+        && (polygon_sizes.iter().any(|&size| size < 3) || split_faces != tangents.len())
+    {
+        bail!(
+            "mesh cache's {} polygons don't match its {} triangles",
+            polygon_sizes.len(),
+            tangents.len()
+        );
+    }
+    let file_counts = match reader.u32()? {
+        0 => None,
+        1 => Some(FileCounts {
+            positions: reader.u32()? as usize,
+            uvs: reader.u32()? as usize,
+            normals: reader.u32()? as usize,
+            unused_positions: reader.u32()? as usize,
+        }),
+        flag => bail!("mesh cache has an invalid file counts flag {}", flag),
+    };
+    if !reader.bytes.is_empty() {
+        bail!(
+            "mesh cache has {} unexpected bytes at the end",
+            reader.bytes.len()
+        );
+    }
+
+    Ok(Some(Mesh {
+        vertices,
+        indices,
+        tangents,
+        polygon_sizes,
+        file_counts,
+    }))
+}
+
+/// Save the mesh parsed from a model file with the given hash to `path`.
+pub(crate) fn write(path: &Path, source_hash: u64, mesh: &Mesh) -> Result<()> {
+    let mut bytes = Vec::with_capacity(
+        64 + mesh.vertices.len() * 48 + mesh.indices.len() * 4 + mesh.tangents.len() * 12,
+    );
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&source_hash.to_le_bytes());
+
+    let write_u32 = |bytes: &mut Vec<u8>, value: usize| -> Result<()> {
+        let value = u32::try_from(value).context("mesh is too big to cache")?;
+        bytes.extend_from_slice(&value.to_le_bytes());
+        Ok(())
+    };
+    let write_floats = |bytes: &mut Vec<u8>, floats: &[f32]| {
+        for float in floats {
+            bytes.extend_from_slice(&float.to_le_bytes());
+        }
+    };
+
+    write_u32(&mut bytes, mesh.vertices.len())?;
+    for vertex in &mesh.vertices {
+        write_floats(&mut bytes, &vertex.position.to_array());
+        write_floats(&mut bytes, &vertex.uv.to_array());
+        write_floats(&mut bytes, &vertex.normal.to_array());
+        write_floats(&mut bytes, &vertex.color.to_array());
+    }
+    write_u32(&mut bytes, mesh.indices.len())?;
+    for &index in &mesh.indices {
+        bytes.extend_from_slice(&index.to_le_bytes());
+    }
+    write_u32(&mut bytes, mesh.tangents.len())?;
+    for tangent in &mesh.tangents {
+        write_floats(&mut bytes, &tangent.to_array());
+    }
+    write_u32(&mut bytes, mesh.polygon_sizes.len())?;
+    for &size in &mesh.polygon_sizes {
+        bytes.extend_from_slice(&size.to_le_bytes());
+    }
+    match mesh.file_counts {
+        Some(counts) => {
+            write_u32(&mut bytes, 1)?;
+            for count in [
+                counts.positions,
+                counts.uvs,
+                counts.normals,
+                counts.unused_positions,
+            ] {
+                write_u32(&mut bytes, count)?;
+            }
+        }
+        None => write_u32(&mut bytes, 0)?,
+    }
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).context("attempting to create mesh cache directory")?;
+    }
+    std::fs::write(path, bytes).context("attempting to write mesh cache")
+}
+
+/// Reads values from the front of a mesh cache file's bytes.
+struct Reader<'b> {
+    bytes: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, len: usize) -> Result<&'b [u8]> {
+        if self.bytes.len() < len {
+            bail!("mesh cache ends unexpectedly");
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn vec3(&mut self) -> Result<Vec3> {
+        Ok(Vec3::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+
+    /// Read a count, then that many items.
+    fn list<T>(&mut self, mut item: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        let len = self.u32()? as usize;
+        // don't trust the count to preallocate with, in case the file is corrupt
+        let mut items = Vec::with_capacity(len.min(self.bytes.len()));
+        for _ in 0..len {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_mesh_should_match_parsed_mesh_until_source_changes() -> Result<()> {
+        let source = std::fs::read("assets/head.obj")?;
+        let mesh = crate::Model::parse_obj(std::str::from_utf8(&source)?)?;
+        let source_hash = hash(&source);
+
+        let path = PathBuf::from("target/mesh-cache-test/head.meshcache");
+        write(&path, source_hash, &mesh)?;
+
+        assert_eq!(read(&path, source_hash)?, Some(mesh.clone()));
+        assert_eq!(read(&path, source_hash ^ 1)?, None);
+
+        // caches that are cut short or point outside their vertices are rejected
+        let bytes = std::fs::read(&path)?;
+        std::fs::write(&path, &bytes[..bytes.len() - 1])?;
+        assert!(read(&path, source_hash).is_err());
+        let bad_index = Mesh {
+            indices: vec![0, 1, mesh.vertices.len() as u32],
+            tangents: vec![Vec3::X],
+            ..mesh
+        };
+        write(&path, source_hash, &bad_index)?;
+        assert!(read(&path, source_hash).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn caches_should_be_kept_apart_from_their_models() {
+        let path = cache_path(Path::new("assets/head.obj"));
+        assert!(!path.starts_with("assets"));
+        assert_ne!(path, cache_path(Path::new("elsewhere/head.obj")));
+        assert!(path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with("head-")));
+    }
+}
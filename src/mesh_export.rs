@@ -0,0 +1,226 @@
+//! Writers for saving models back out, after any processing they went through when loading, so the
+//! renderer can convert and clean up meshes. OBJ files can be written with a material library and
+//! the models' textures alongside them (named the way OBJ models are loaded), while PLY files keep
+//! only the geometry and vertex colors.
+
+use std::{fmt::Write as _, path::Path, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use glam::{Vec3, Vec4};
+
+use crate::{Model, Texture};
+
+/// Save models to an OBJ or PLY file, depending on the extension of `path`. See [`export_obj`]
+/// for what `materials` does.
+pub fn export_models(models: &[Arc<Model>], path: &Path, materials: bool) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("creating directory '{}'", dir.display()))?;
+    }
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("obj") => export_obj(models, path, materials),
+        Some("ply") => export_ply(models, path),
+        _ => bail!(
+            "Can't export to '{}': only .obj and .ply files are supported",
+            path.display()
+        ),
+    }
+}
+
+/// Save models to an OBJ file, one object per model. Vertex colors are written after the
+/// positions if any vertex isn't white. If `materials` is set, an MTL file with a material per
+/// model is written too, along with each model's textures as PNGs. A single model's textures are
+/// named after the OBJ file, so that it can be loaded again as is.
+pub fn export_obj(models: &[Arc<Model>], path: &Path, materials: bool) -> Result<()> {
+    let stem = file_stem(path)?;
+    let colored = models
+        .iter()
+        .flat_map(|model| &model.vertices)
+        .any(|vertex| vertex.color.truncate() != Vec3::ONE);
+
+    let mut obj = String::from("# exported by crab-tv\n");
+    let mut mtl = String::from("# exported by crab-tv\n");
+    if materials {
+        writeln!(obj, "mtllib {}.mtl", stem)?;
+    }
+    // OBJ indices are 1-based and count up through every object in the file
+    let mut first_index = 1;
+    for (i, model) in models.iter().enumerate() {
+        if models.len() > 1 {
+            writeln!(obj, "o model_{}", i)?;
+        }
+        for vertex in &model.vertices {
+            let p = vertex.position;
+            if colored {
+                let c = vertex.color;
+                writeln!(obj, "v {} {} {} {} {} {}", p.x, p.y, p.z, c.x, c.y, c.z)?;
+            } else {
+                writeln!(obj, "v {} {} {}", p.x, p.y, p.z)?;
+            }
+        }
+        for vertex in &model.vertices {
+            writeln!(obj, "vt {} {}", vertex.uv.x, vertex.uv.y)?;
+        }
+        for vertex in &model.vertices {
+            let n = vertex.normal;
+            writeln!(obj, "vn {} {} {}", n.x, n.y, n.z)?;
+        }
+        if materials {
+            let material = format!("material_{}", i);
+            writeln!(obj, "usemtl {}", material)?;
+            let prefix = if models.len() > 1 {
+                format!("{}.{}", stem, i)
+            } else {
+                stem.to_owned()
+            };
+            write_material(&mut mtl, &material, model, path, &prefix)?;
+        }
+        // each vertex has its own position, texture coordinates and normal, so one index is used
+        // for all three
+        for face in model.indices.chunks_exact(3) {
+            let [a, b, c] = [face[0], face[1], face[2]].map(|index| index as usize + first_index);
+            writeln!(obj, "f {a}/{a}/{a} {b}/{b}/{b} {c}/{c}/{c}")?;
+        }
+        first_index += model.vertices.len();
+    }
+
+    println!("Exporting model to file: {}", path.display());
+    if materials {
+        std::fs::write(path.with_extension("mtl"), mtl).context("writing material library")?;
+    }
+    std::fs::write(path, obj).context("writing OBJ file")
+}
+
+/// Add a model's material to an MTL file, saving its textures next to `path` with names starting
+/// with `prefix`.
+fn write_material(
+    mtl: &mut String,
+    material: &str,
+    model: &Model,
+    path: &Path,
+    prefix: &str,
+) -> Result<()> {
+    let save = |texture: &Texture, suffix: &str| -> Result<String> {
+        let name = format!("{}.{}.png", prefix, suffix);
+        texture.save_to_file(&path.with_file_name(&name))?;
+        Ok(name)
+    };
+    writeln!(mtl, "\nnewmtl {}", material)?;
+    writeln!(mtl, "Kd 1 1 1")?;
+    writeln!(mtl, "map_Kd {}", save(&model.diffuse_texture, "diffuse")?)?;
+    writeln!(mtl, "map_Ks {}", save(&model.specular_texture, "specular")?)?;
+    writeln!(
+        mtl,
+        "map_Bump {}",
+        save(&model.normal_texture_darboux, "normals_darboux")?
+    )?;
+    if let Some(glow_texture) = &model.glow_texture {
+        writeln!(mtl, "map_Ke {}", save(glow_texture, "glow")?)?;
+    }
+    // MTL has no global space normal maps, but it's needed to load the model here again
+    if let Some(normal_texture_global) = &model.normal_texture_global {
+        save(normal_texture_global, "normals_global")?;
+    }
+    Ok(())
+}
+
+/// Save models to a binary PLY file as a single mesh, with each vertex's position, normal,
+/// texture coordinates and color.
+pub fn export_ply(models: &[Arc<Model>], path: &Path) -> Result<()> {
+    let vertex_count: usize = models.iter().map(|model| model.vertices.len()).sum();
+    let face_count: usize = models.iter().map(|model| model.face_count()).sum();
+    let header = format!(
+        "ply\n\
+        format binary_little_endian 1.0\n\
+        comment exported by crab-tv\n\
+        element vertex {}\n\
+        property float x\n\
+        property float y\n\
+        property float z\n\
+        property float nx\n\
+        property float ny\n\
+        property float nz\n\
+        property float s\n\
+        property float t\n\
+        property uchar red\n\
+        property uchar green\n\
+        property uchar blue\n\
+        property uchar alpha\n\
+        element face {}\n\
+        property list uchar uint vertex_indices\n\
+        end_header\n",
+        vertex_count, face_count
+    );
+
+    let mut ply = header.into_bytes();
+    ply.reserve(vertex_count * 36 + face_count * 13);
+    for vertex in models.iter().flat_map(|model| &model.vertices) {
+        let (p, n, uv) = (vertex.position, vertex.normal, vertex.uv);
+        for float in [p.x, p.y, p.z, n.x, n.y, n.z, uv.x, uv.y] {
+            ply.extend_from_slice(&float.to_le_bytes());
+        }
+        let color = (vertex.color.clamp(Vec4::ZERO, Vec4::ONE) * 255.0).round();
+        ply.extend(color.to_array().map(|c| c as u8));
+    }
+    let mut first_index = 0;
+    for model in models {
+        for face in model.indices.chunks_exact(3) {
+            ply.push(3);
+            for &index in face {
+                let index = u32::try_from(index as usize + first_index)
+                    .context("model has too many vertices to export")?;
+                ply.extend_from_slice(&index.to_le_bytes());
+            }
+        }
+        first_index += model.vertices.len();
+    }
+
+    println!("Exporting model to file: {}", path.display());
+    std::fs::write(path, ply).context("writing PLY file")
+}
+
+fn file_stem(path: &Path) -> Result<&str> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .with_context(|| format!("'{}' has no file name", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::ModelFile;
+
+    #[test]
+    fn exported_models_should_load_back_the_same() -> Result<()> {
+        let mut model = Model::plane();
+        model.vertices[0].color = Vec4::new(1.0, 0.0, 0.0, 1.0);
+        let models = vec![Arc::new(model)];
+        let dir = PathBuf::from("target/mesh-export-test");
+        std::fs::create_dir_all(&dir)?;
+
+        let obj = dir.join("plane.obj");
+        export_models(&models, &obj, true)?;
+        let loaded = ModelFile::load(&Model::validate(&obj)?, |path| {
+            Texture::load_from_file(path).map(Arc::new)
+        })?;
+        let loaded = &loaded.models[0];
+        assert_eq!(loaded.vertices, models[0].vertices);
+        assert_eq!(loaded.indices, models[0].indices);
+        assert_eq!(loaded.diffuse_texture.data, models[0].diffuse_texture.data);
+
+        let ply = dir.join("plane.ply");
+        export_models(&models, &ply, false)?;
+        let loaded = ModelFile::load(&Model::validate(&ply)?, |_| bail!("PLY has no textures"))?;
+        let loaded = &loaded.models[0];
+        assert_eq!(loaded.indices, models[0].indices);
+        for (loaded, exported) in loaded.vertices.iter().zip(&models[0].vertices) {
+            assert_eq!(loaded.position, exported.position);
+            assert_eq!(loaded.normal, exported.normal);
+            assert_eq!(loaded.uv, exported.uv);
+            assert_eq!(loaded.color, exported.color);
+        }
+        Ok(())
+    }
+}
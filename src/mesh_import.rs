@@ -0,0 +1,636 @@
+//! Parsers for the mesh formats that 3D scanners and CAD tools export: STL (binary or ASCII) and
+//! PLY (ASCII or binary, optionally with vertex colors). Neither format has texture coordinates,
+//! and their coordinates can be on any scale, so meshes are fitted to between -1 and 1.
+
+use anyhow::{bail, Context, Result};
+use glam::{Vec2, Vec3, Vec4};
+
+use crate::{
+    mesh_cache::Mesh,
+    model::{face_tangents, fit_transform, index_vertices, only_polygons},
+    FileCounts, Vertex,
+};
+
+/// Parse a binary or ASCII STL file, giving each triangle a flat normal.
+pub(crate) fn parse_stl(bytes: &[u8]) -> Result<Mesh> {
+    let positions = if is_binary_stl(bytes) {
+        bytes[84..]
+            .chunks_exact(50)
+            // skip each triangle's normal, which is often left as zero, and its attributes
+            .flat_map(|triangle| triangle[12..48].chunks_exact(12))
+            .map(|p| {
+                let f = |i: usize| f32::from_le_bytes(p[i..i + 4].try_into().unwrap());
+                Vec3::new(f(0), f(4), f(8))
+            })
+            .collect()
+    } else {
+        parse_ascii_stl(bytes)?
+    };
+    if positions.is_empty() {
+        bail!("STL file has no triangles");
+    }
+
+    let fit = fit_transform(positions.iter().copied());
+    let corners = positions.chunks_exact(3).flat_map(|triangle| {
+        let [p0, p1, p2] = [0, 1, 2].map(|i| fit.transform_point3(triangle[i]));
+        let normal = (p1 - p0).cross(p2 - p0).normalize_or_zero();
+        [p0, p1, p2].map(|position| Vertex {
+            position,
+            normal,
+            ..untextured_vertex()
+        })
+    });
+    // the corners of each triangle are listed separately, so there's nothing more to count
+    Ok(mesh(corners, Vec::new(), None))
+}
+
+/// Binary STL files start with an 80 byte header (which may well start with "solid", like ASCII
+/// ones), then a triangle count and 50 bytes per triangle, so only the size tells them apart.
+fn is_binary_stl(bytes: &[u8]) -> bool {
+    bytes.len() >= 84 && {
+        let count = u32::from_le_bytes(bytes[80..84].try_into().unwrap()) as usize;
+        bytes.len() == 84 + count * 50
+    }
+}
+
+fn parse_ascii_stl(bytes: &[u8]) -> Result<Vec<Vec3>> {
+    let text = std::str::from_utf8(bytes).context("STL file is neither binary nor ASCII")?;
+    let mut positions = Vec::new();
+    let mut words = text.split_ascii_whitespace();
+    while let Some(word) = words.next() {
+        if word == "vertex" {
+            let mut coord = || -> Result<f32> {
+                let word = words.next().context("STL vertex ends unexpectedly")?;
+                word.parse()
+                    .with_context(|| format!("STL vertex has an invalid coordinate: {}", word))
+            };
+            positions.push(Vec3::new(coord()?, coord()?, coord()?));
+        }
+    }
+    if !positions.len().is_multiple_of(3) {
+        bail!(
+            "STL file has {} vertices, which isn't a whole number of triangles",
+            positions.len()
+        );
+    }
+    Ok(positions)
+}
+
+/// Parse an ASCII or binary PLY file, triangulating its faces. Vertices keep their colors and
+/// normals if the file has them, otherwise they get smooth normals.
+pub(crate) fn parse_ply(bytes: &[u8]) -> Result<Mesh> {
+    let header_end = bytes
+        .windows(b"end_header".len())
+        .position(|window| window == b"end_header")
+        .context("PLY file has no end_header")?;
+    let header = std::str::from_utf8(&bytes[..header_end]).context("PLY header isn't text")?;
+    // the body starts after the end of the end_header line
+    let body_start = bytes[header_end..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(bytes.len(), |i| header_end + i + 1);
+    let (format, elements) = parse_ply_header(header)?;
+    let mut body = match format {
+        PlyFormat::Ascii => PlyBody::Ascii(
+            std::str::from_utf8(&bytes[body_start..])
+                .context("ASCII PLY body isn't text")?
+                .split_ascii_whitespace(),
+        ),
+        PlyFormat::BinaryLittleEndian | PlyFormat::BinaryBigEndian => PlyBody::Binary {
+            bytes: &bytes[body_start..],
+            big_endian: format == PlyFormat::BinaryBigEndian,
+        },
+    };
+
+    let mut vertices: Vec<Vertex> = Vec::new();
+    let mut has_normals = false;
+    let mut has_uvs = false;
+    let mut faces: Vec<[usize; 3]> = Vec::new();
+    let mut polygon_sizes = Vec::new();
+    for element in &elements {
+        match element.name.as_str() {
+            "vertex" => {
+                let index_of = |names: &[&str]| {
+                    element
+                        .properties
+                        .iter()
+                        .position(|p| names.contains(&p.name.as_str()))
+                };
+                let [x, y, z] = ["x", "y", "z"].map(|name| index_of(&[name]));
+                let (Some(x), Some(y), Some(z)) = (x, y, z) else {
+                    bail!("PLY vertices have no x, y and z properties");
+                };
+                let normal = [index_of(&["nx"]), index_of(&["ny"]), index_of(&["nz"])];
+                has_normals = normal.iter().all(Option::is_some);
+                let uv = match [
+                    index_of(&["s", "u", "texture_u"]),
+                    index_of(&["t", "v", "texture_v"]),
+                ] {
+                    [Some(u), Some(v)] => Some((u, v)),
+                    _ => None,
+                };
+                has_uvs = uv.is_some();
+                let color = [
+                    index_of(&["red", "r"]),
+                    index_of(&["green", "g"]),
+                    index_of(&["blue", "b"]),
+                    index_of(&["alpha", "a"]),
+                ];
+
+                let mut values = vec![0.0; element.properties.len()];
+                for _ in 0..element.count {
+                    for (value, property) in values.iter_mut().zip(&element.properties) {
+                        *value = match property.kind {
+                            PlyPropertyKind::Scalar(ty) => {
+                                let v = body.read(ty)?;
+                                // integer colors span their type's range, but float ones span 0 to 1
+                                if color.contains(&Some(property.index)) && !ty.is_float() {
+                                    v / ty.max()
+                                } else {
+                                    v
+                                }
+                            }
+                            PlyPropertyKind::List { count, item } => {
+                                body.skip_list(count, item)?;
+                                0.0
+                            }
+                        };
+                    }
+                    let get = |i: Option<usize>, default: f64| i.map_or(default, |i| values[i]);
+                    let untextured = untextured_vertex();
+                    vertices.push(Vertex {
+                        position: Vec3::new(values[x] as f32, values[y] as f32, values[z] as f32),
+                        normal: Vec3::from(normal.map(|i| get(i, 0.0) as f32)).normalize_or_zero(),
+                        uv: uv.map_or(untextured.uv, |(u, v)| {
+                            Vec2::new(values[u] as f32, values[v] as f32)
+                        }),
+                        color: Vec4::from(color.map(|i| get(i, 1.0) as f32)),
+                        ..untextured
+                    });
+                }
+            }
+            "face" => {
+                let indices = element
+                    .properties
+                    .iter()
+                    .position(|p| p.name == "vertex_indices" || p.name == "vertex_index")
+                    .context("PLY faces have no vertex_indices property")?;
+                let mut polygon = Vec::new();
+                for _ in 0..element.count {
+                    for property in &element.properties {
+                        match property.kind {
+                            PlyPropertyKind::List { count, item } if property.index == indices => {
+                                let len = body.read(count)? as usize;
+                                polygon.clear();
+                                for _ in 0..len {
+                                    let index = body.read(item)?;
+                                    if index < 0.0 || index.fract() != 0.0 {
+                                        bail!("PLY face index {} isn't a vertex number", index);
+                                    }
+                                    polygon.push(index as usize);
+                                }
+                            }
+                            PlyPropertyKind::List { count, item } => body.skip_list(count, item)?,
+                            PlyPropertyKind::Scalar(ty) => {
+                                body.read(ty)?;
+                            }
+                        }
+                    }
+                    // fan out from the first corner, which is fine for the convex polygons
+                    // scanners output
+                    for i in 2..polygon.len() {
+                        faces.push([polygon[0], polygon[i - 1], polygon[i]]);
+                    }
+                    if polygon.len() >= 3 {
+                        polygon_sizes.push(polygon.len() as u32);
+                    }
+                }
+            }
+            _ => body.skip_element(element)?,
+        }
+    }
+    if faces.is_empty() {
+        bail!("PLY file has no faces");
+    }
+    if let Some(&index) = faces.iter().flatten().find(|&&i| i >= vertices.len()) {
+        bail!(
+            "PLY face index {} is out of range of its {} vertices",
+            index,
+            vertices.len()
+        );
+    }
+
+    let fit = fit_transform(vertices.iter().map(|vertex| vertex.position));
+    for vertex in &mut vertices {
+        vertex.position = fit.transform_point3(vertex.position);
+    }
+    if !has_normals {
+        // sum each face's area weighted normal into its corners
+        for vertex in &mut vertices {
+            vertex.normal = Vec3::ZERO;
+        }
+        for face in &faces {
+            let [p0, p1, p2] = face.map(|i| vertices[i].position);
+            let normal = (p1 - p0).cross(p2 - p0);
+            for &i in face {
+                vertices[i].normal += normal;
+            }
+        }
+        for vertex in &mut vertices {
+            vertex.normal = vertex.normal.normalize_or_zero();
+        }
+    }
+    let mut used = vec![false; vertices.len()];
+    for &i in faces.iter().flatten() {
+        used[i] = true;
+    }
+    // every vertex has all of the properties that any of them have
+    let file_counts = FileCounts {
+        positions: vertices.len(),
+        uvs: if has_uvs { vertices.len() } else { 0 },
+        normals: if has_normals { vertices.len() } else { 0 },
+        unused_positions: used.iter().filter(|&&used| !used).count(),
+    };
+    Ok(mesh(
+        faces.iter().flatten().map(|&i| vertices[i]),
+        only_polygons(polygon_sizes),
+        Some(file_counts),
+    ))
+}
+
+/// The same texture coordinates everywhere, as there are no textures to look anything up in.
+fn untextured_vertex() -> Vertex {
+    Vertex {
+        uv: Vec2::splat(0.5),
+        ..Vertex::default()
+    }
+}
+
+fn mesh(
+    corners: impl Iterator<Item = Vertex>,
+    polygon_sizes: Vec<u32>,
+    file_counts: Option<FileCounts>,
+) -> Mesh {
+    let (vertices, indices) = index_vertices(corners);
+    let tangents = face_tangents(&vertices, &indices);
+    Mesh {
+        vertices,
+        indices,
+        tangents,
+        polygon_sizes,
+        file_counts,
+    }
+}
+
+#[derive(PartialEq, Eq)]
+enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>,
+}
+
+struct PlyProperty {
+    name: String,
+    /// Position among its element's properties.
+    index: usize,
+    kind: PlyPropertyKind,
+}
+
+#[derive(Clone, Copy)]
+enum PlyPropertyKind {
+    Scalar(PlyType),
+    List { count: PlyType, item: PlyType },
+}
+
+#[derive(Clone, Copy)]
+enum PlyType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl PlyType {
+    fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "char" | "int8" => PlyType::I8,
+            "uchar" | "uint8" => PlyType::U8,
+            "short" | "int16" => PlyType::I16,
+            "ushort" | "uint16" => PlyType::U16,
+            "int" | "int32" => PlyType::I32,
+            "uint" | "uint32" => PlyType::U32,
+            "float" | "float32" => PlyType::F32,
+            "double" | "float64" => PlyType::F64,
+            _ => bail!("unknown PLY property type: {}", name),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            PlyType::I8 | PlyType::U8 => 1,
+            PlyType::I16 | PlyType::U16 => 2,
+            PlyType::I32 | PlyType::U32 | PlyType::F32 => 4,
+            PlyType::F64 => 8,
+        }
+    }
+
+    fn is_float(self) -> bool {
+        matches!(self, PlyType::F32 | PlyType::F64)
+    }
+
+    fn max(self) -> f64 {
+        match self {
+            PlyType::I8 => i8::MAX as f64,
+            PlyType::U8 => u8::MAX as f64,
+            PlyType::I16 => i16::MAX as f64,
+            PlyType::U16 => u16::MAX as f64,
+            PlyType::I32 => i32::MAX as f64,
+            PlyType::U32 => u32::MAX as f64,
+            PlyType::F32 | PlyType::F64 => 1.0,
+        }
+    }
+}
+
+fn parse_ply_header(header: &str) -> Result<(PlyFormat, Vec<PlyElement>)> {
+    let mut lines = header.lines().map(str::trim);
+    if lines.next() != Some("ply") {
+        bail!("not a PLY file");
+    }
+    let mut format = None;
+    let mut elements: Vec<PlyElement> = Vec::new();
+    for line in lines {
+        let words: Vec<&str> = line.split_ascii_whitespace().collect();
+        match words.as_slice() {
+            ["format", name, _version] => {
+                format = Some(match *name {
+                    "ascii" => PlyFormat::Ascii,
+                    "binary_little_endian" => PlyFormat::BinaryLittleEndian,
+                    "binary_big_endian" => PlyFormat::BinaryBigEndian,
+                    _ => bail!("unknown PLY format: {}", name),
+                })
+            }
+            ["element", name, count] => elements.push(PlyElement {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .with_context(|| format!("invalid PLY element count: {}", line))?,
+                properties: Vec::new(),
+            }),
+            ["property", rest @ ..] => {
+                let element = elements
+                    .last_mut()
+                    .with_context(|| format!("PLY property before any element: {}", line))?;
+                let (kind, name) = match rest {
+                    ["list", count, item, name] => (
+                        PlyPropertyKind::List {
+                            count: PlyType::parse(count)?,
+                            item: PlyType::parse(item)?,
+                        },
+                        name,
+                    ),
+                    [ty, name] => (PlyPropertyKind::Scalar(PlyType::parse(ty)?), name),
+                    _ => bail!("invalid PLY property: {}", line),
+                };
+                element.properties.push(PlyProperty {
+                    name: name.to_string(),
+                    index: element.properties.len(),
+                    kind,
+                });
+            }
+            ["comment" | "obj_info", ..] | [] => (),
+            _ => bail!("unknown PLY header line: {}", line),
+        }
+    }
+    Ok((format.context("PLY header has no format")?, elements))
+}
+
+/// Reads values from the body of a PLY file, after its header.
+enum PlyBody<'b> {
+    Ascii(std::str::SplitAsciiWhitespace<'b>),
+    Binary { bytes: &'b [u8], big_endian: bool },
+}
+
+impl PlyBody<'_> {
+    fn read(&mut self, ty: PlyType) -> Result<f64> {
+        match self {
+            PlyBody::Ascii(words) => {
+                let word = words.next().context("PLY file ends unexpectedly")?;
+                word.parse()
+                    .with_context(|| format!("invalid PLY value: {}", word))
+            }
+            PlyBody::Binary { bytes, big_endian } => {
+                if bytes.len() < ty.size() {
+                    bail!("PLY file ends unexpectedly");
+                }
+                let (bytes_of_value, rest) = bytes.split_at(ty.size());
+                *bytes = rest;
+                // reverse big endian values so they can all be read as little endian
+                let mut value = [0; 8];
+                value[..ty.size()].copy_from_slice(bytes_of_value);
+                if *big_endian {
+                    value[..ty.size()].reverse();
+                }
+                let [b0, b1, b2, b3, ..] = value;
+                Ok(match ty {
+                    PlyType::I8 => b0 as i8 as f64,
+                    PlyType::U8 => b0 as f64,
+                    PlyType::I16 => i16::from_le_bytes([b0, b1]) as f64,
+                    PlyType::U16 => u16::from_le_bytes([b0, b1]) as f64,
+                    PlyType::I32 => i32::from_le_bytes([b0, b1, b2, b3]) as f64,
+                    PlyType::U32 => u32::from_le_bytes([b0, b1, b2, b3]) as f64,
+                    PlyType::F32 => f32::from_le_bytes([b0, b1, b2, b3]) as f64,
+                    PlyType::F64 => f64::from_le_bytes(value),
+                })
+            }
+        }
+    }
+
+    fn skip_list(&mut self, count: PlyType, item: PlyType) -> Result<()> {
+        let len = self.read(count)? as usize;
+        for _ in 0..len {
+            self.read(item)?;
+        }
+        Ok(())
+    }
+
+    fn skip_element(&mut self, element: &PlyElement) -> Result<()> {
+        for _ in 0..element.count {
+            for property in &element.properties {
+                match property.kind {
+                    PlyPropertyKind::Scalar(ty) => {
+                        self.read(ty)?;
+                    }
+                    PlyPropertyKind::List { count, item } => self.skip_list(count, item)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stl_and_ply_squares_should_parse_to_the_same_fitted_triangles() -> Result<()> {
+        let stl = "solid square
+            facet normal 0 0 0
+              outer loop
+                vertex 0 0 0
+                vertex 4 0 0
+                vertex 4 4 0
+              endloop
+            endfacet
+            facet normal 0 0 0
+              outer loop
+                vertex 0 0 0
+                vertex 4 4 0
+                vertex 0 4 0
+              endloop
+            endfacet
+            endsolid square";
+        let ply = "ply
+            format ascii 1.0
+            comment a square with a red corner
+            element vertex 4
+            property float x
+            property float y
+            property float z
+            property uchar red
+            property uchar green
+            property uchar blue
+            element face 1
+            property list uchar int vertex_indices
+            end_header
+            0 0 0 255 0 0
+            4 0 0 255 255 255
+            4 4 0 255 255 255
+            0 4 0 255 255 255
+            4 0 1 2 3";
+
+        let stl = parse_stl(stl.as_bytes())?;
+        assert!(parse_ply(ply.replace("4 0 1 2 3", "4 0 1 -2 3").as_bytes()).is_err());
+        let ply = parse_ply(ply.as_bytes())?;
+        for mesh in [&stl, &ply] {
+            assert_eq!(mesh.vertices.len(), 4);
+            assert_eq!(mesh.indices, [0, 1, 2, 0, 2, 3]);
+            let positions: Vec<_> = mesh.vertices.iter().map(|v| v.position).collect();
+            assert_eq!(
+                positions,
+                [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)]
+                    .map(|(x, y)| Vec3::new(x, y, 0.0))
+            );
+            assert!(mesh.vertices.iter().all(|v| v.normal == Vec3::Z));
+        }
+        assert_eq!(stl.vertices[0].color, Vec4::ONE);
+        assert_eq!(ply.vertices[0].color, Vec4::new(1.0, 0.0, 0.0, 1.0));
+        assert_eq!(ply.vertices[1].color, Vec4::ONE);
+
+        // a binary STL of the same two triangles, with a header that looks like an ASCII one
+        let mut binary = b"solid square".to_vec();
+        binary.resize(80, 0);
+        binary.extend_from_slice(&2u32.to_le_bytes());
+        for triangle in stl.indices.chunks_exact(3) {
+            binary.extend_from_slice(&[0; 12]);
+            for &i in triangle {
+                for coord in stl.vertices[i as usize].position.to_array() {
+                    binary.extend_from_slice(&coord.to_le_bytes());
+                }
+            }
+            binary.extend_from_slice(&[0; 2]);
+        }
+        assert_eq!(parse_stl(&binary)?, stl);
+        Ok(())
+    }
+
+    #[test]
+    fn binary_ply_files_should_parse_like_ascii_ones() -> Result<()> {
+        let header = |format| {
+            format!(
+                "ply
+format {} 1.0
+element vertex 3
+property float x
+property float y
+property float z
+property float nx
+property float ny
+property float nz
+property ushort red
+property uchar green
+property uchar blue
+property double confidence
+element face 1
+property uchar flags
+property list uchar int vertex_indices
+end_header
+",
+                format
+            )
+        };
+        // positions, normals, a ushort red, green and blue, then an ignored confidence
+        let vertices = [
+            (
+                [0.0f32, 0.0, 0.0],
+                [0.0f32, 0.0, 2.0],
+                [65535u16, 0, 0],
+                0.5f64,
+            ),
+            ([2.0, 0.0, 0.0], [0.0, 0.0, 2.0], [0, 255, 0], 0.5),
+            ([0.0, 2.0, 0.0], [1.0, 0.0, 0.0], [0, 0, 255], 0.5),
+        ];
+
+        let mut ascii = header("ascii");
+        for (position, normal, [r, g, b], confidence) in vertices {
+            for v in position.iter().chain(&normal) {
+                ascii += &format!("{} ", v);
+            }
+            ascii += &format!("{} {} {} {}\n", r, g, b, confidence);
+        }
+        ascii += "7 3 0 1 2\n";
+        let expected = parse_ply(ascii.as_bytes())?;
+        assert_eq!(expected.indices, [0, 1, 2]);
+        assert_eq!(expected.vertices[0].normal, Vec3::Z);
+        assert_eq!(expected.vertices[2].normal, Vec3::X);
+        assert_eq!(expected.vertices[0].color, Vec4::new(1.0, 0.0, 0.0, 1.0));
+        assert_eq!(expected.vertices[1].color, Vec4::new(0.0, 1.0, 0.0, 1.0));
+
+        for (format, big_endian) in [("binary_little_endian", false), ("binary_big_endian", true)] {
+            macro_rules! bytes {
+                ($v:expr) => {
+                    if big_endian {
+                        $v.to_be_bytes().to_vec()
+                    } else {
+                        $v.to_le_bytes().to_vec()
+                    }
+                };
+            }
+            let mut binary = header(format).into_bytes();
+            for (position, normal, [r, g, b], confidence) in vertices {
+                for v in position.iter().chain(&normal) {
+                    binary.extend(bytes!(v));
+                }
+                binary.extend(bytes!(r));
+                binary.extend([g as u8, b as u8]);
+                binary.extend(bytes!(confidence));
+            }
+            binary.extend([7, 3]);
+            for i in 0..3i32 {
+                binary.extend(bytes!(i));
+            }
+            assert_eq!(parse_ply(&binary)?, expected, "{}", format);
+        }
+        Ok(())
+    }
+}
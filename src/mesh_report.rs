@@ -0,0 +1,338 @@
+use std::{collections::HashMap, fmt};
+
+use glam::Vec3;
+
+use crate::Model;
+
+/// Twice the area below which a triangle counts as having no area (models are scaled to between
+/// -1 and 1, so this is far below anything visible).
+const ZERO_AREA: f32 = 1e-10;
+
+/// What a model's file listed, before identical vertices were merged into one (after which there's
+/// no telling how many there were, or whether any of them went unused).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileCounts {
+    pub positions: usize,
+    pub uvs: usize,
+    pub normals: usize,
+    /// Positions that no face uses
+    pub unused_positions: usize,
+}
+
+/// What's in a model, and anything about its geometry that's likely to make it render oddly.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MeshReport {
+    /// Distinct vertices, i.e. distinct combinations of position, texture coordinates, normal and
+    /// color
+    pub vertex_count: usize,
+    pub face_count: usize,
+    /// Positions, texture coordinates and normals listed in the model's file, or the distinct ones
+    /// among its vertices if it wasn't read from a file that lists them
+    pub position_count: usize,
+    pub uv_count: usize,
+    pub normal_count: usize,
+    /// Corners of the box around every vertex, or `None` if there are no vertices
+    pub bounds: Option<(Vec3, Vec3)>,
+    /// Faces with two or more corners at the same position
+    pub degenerate_faces: usize,
+    /// Faces whose corners are all at different positions, but in a line
+    pub zero_area_faces: usize,
+    /// Edges shared by more than two faces
+    pub non_manifold_edges: usize,
+    /// Edges used by only one face, around holes or the outside of open surfaces
+    pub boundary_edges: usize,
+    /// Positions in the model's file (or vertices, if it wasn't read from a file) that no face uses
+    pub unused_vertices: usize,
+    /// Faces with the same corner positions as an earlier face
+    pub duplicate_faces: usize,
+    /// Vertices with texture coordinates outside 0 to 1, which wrap around their textures
+    pub out_of_range_uvs: usize,
+    /// Edges between two faces which go around it the same way, so one of them is facing the
+    /// opposite way to the other
+    pub inconsistent_winding_edges: usize,
+    /// Name, width & height of each texture
+    pub textures: Vec<(&'static str, usize, usize)>,
+}
+
+impl Model {
+    /// Count what's in this model and check its geometry for problems. Edges are compared by the
+    /// positions of their ends, so that vertices split along texture seams still join up.
+    pub fn report(&self) -> MeshReport {
+        let bits = |v: Vec3| v.to_array().map(f32::to_bits);
+        // the same id for every vertex at the same position
+        let mut position_ids = HashMap::new();
+        let positions: Vec<usize> = self
+            .vertices
+            .iter()
+            .map(|vertex| {
+                let next_id = position_ids.len();
+                *position_ids.entry(bits(vertex.position)).or_insert(next_id)
+            })
+            .collect();
+        let distinct = |key: &dyn Fn(usize) -> [u32; 3]| {
+            let mut keys: Vec<_> = (0..self.vertices.len()).map(key).collect();
+            keys.sort_unstable();
+            keys.dedup();
+            keys.len()
+        };
+
+        let mut used = vec![false; self.vertices.len()];
+        let mut degenerate_faces = 0;
+        let mut zero_area_faces = 0;
+        let mut duplicate_faces = 0;
+        let mut seen_faces = HashMap::new();
+        // faces going each way along each edge, keyed by the edge's ends in ascending order
+        let mut edges: HashMap<(usize, usize), (usize, usize)> = HashMap::new();
+        for face in 0..self.face_count() {
+            let indices = self.face(face).map(|i| i as usize);
+            for &i in &indices {
+                used[i] = true;
+            }
+            let ids = indices.map(|i| positions[i]);
+            if ids[0] == ids[1] || ids[1] == ids[2] || ids[2] == ids[0] {
+                degenerate_faces += 1;
+                continue;
+            }
+            let [p0, p1, p2] = indices.map(|i| self.vertices[i].position);
+            if (p1 - p0).cross(p2 - p0).length() < ZERO_AREA {
+                zero_area_faces += 1;
+                continue;
+            }
+            let mut sorted = ids;
+            sorted.sort_unstable();
+            if seen_faces.insert(sorted, face).is_some() {
+                duplicate_faces += 1;
+            }
+            for (a, b) in [(ids[0], ids[1]), (ids[1], ids[2]), (ids[2], ids[0])] {
+                let counts = edges.entry((a.min(b), a.max(b))).or_default();
+                if a < b {
+                    counts.0 += 1;
+                } else {
+                    counts.1 += 1;
+                }
+            }
+        }
+
+        let bounds = self.vertices.iter().fold(None, |bounds, vertex| {
+            let (min, max) = bounds.unwrap_or((vertex.position, vertex.position));
+            Some((min.min(vertex.position), max.max(vertex.position)))
+        });
+        let mut textures = vec![("Diffuse", &self.diffuse_texture)];
+        textures.extend(
+            self.normal_texture_global
+                .as_ref()
+                .map(|normals| ("Normals (global)", normals)),
+        );
+        textures.push(("Normals (tangent)", &self.normal_texture_darboux));
+        textures.push(("Specular", &self.specular_texture));
+        textures.extend(self.glow_texture.as_ref().map(|glow| ("Glow", glow)));
+
+        let file_counts = self.file_counts.unwrap_or_else(|| FileCounts {
+            positions: position_ids.len(),
+            uvs: distinct(&|i| {
+                let uv = self.vertices[i].uv;
+                [uv.x.to_bits(), uv.y.to_bits(), 0]
+            }),
+            normals: distinct(&|i| bits(self.vertices[i].normal)),
+            unused_positions: used.iter().filter(|&&used| !used).count(),
+        });
+        MeshReport {
+            vertex_count: self.vertices.len(),
+            face_count: self.face_count(),
+            position_count: file_counts.positions,
+            uv_count: file_counts.uvs,
+            normal_count: file_counts.normals,
+            bounds,
+            degenerate_faces,
+            zero_area_faces,
+            non_manifold_edges: edges.values().filter(|(a, b)| a + b > 2).count(),
+            boundary_edges: edges.values().filter(|(a, b)| a + b == 1).count(),
+            unused_vertices: file_counts.unused_positions,
+            duplicate_faces,
+            out_of_range_uvs: self
+                .vertices
+                .iter()
+                .filter(|vertex| {
+                    !(0.0..=1.0).contains(&vertex.uv.x) || !(0.0..=1.0).contains(&vertex.uv.y)
+                })
+                .count(),
+            inconsistent_winding_edges: edges
+                .values()
+                .filter(|&&counts| counts == (2, 0) || counts == (0, 2))
+                .count(),
+            textures: textures
+                .into_iter()
+                .map(|(name, texture)| (name, texture.width, texture.height))
+                .collect(),
+        }
+    }
+}
+
+impl MeshReport {
+    /// Each line of the report as a label and a value, plus whether it's a problem worth pointing
+    /// out (everything counted apart from open boundaries, which lots of fine models have).
+    pub fn lines(&self) -> Vec<(String, String, bool)> {
+        let fmt_vec = |v: Vec3| format!("({:.3}, {:.3}, {:.3})", v.x, v.y, v.z);
+        let mut lines = vec![
+            ("Vertices".to_owned(), self.vertex_count.to_string(), false),
+            ("Faces".to_owned(), self.face_count.to_string(), false),
+            (
+                "Positions".to_owned(),
+                self.position_count.to_string(),
+                false,
+            ),
+            (
+                "Texture coordinates".to_owned(),
+                self.uv_count.to_string(),
+                false,
+            ),
+            ("Normals".to_owned(), self.normal_count.to_string(), false),
+            (
+                "Bounding box".to_owned(),
+                match self.bounds {
+                    Some((min, max)) => format!("{} to {}", fmt_vec(min), fmt_vec(max)),
+                    None => "none".to_owned(),
+                },
+                false,
+            ),
+        ];
+        // each count, and whether any at all are a problem
+        let checks = [
+            ("Degenerate faces", self.degenerate_faces, true),
+            ("Zero area faces", self.zero_area_faces, true),
+            ("Non-manifold edges", self.non_manifold_edges, true),
+            ("Boundary edges", self.boundary_edges, false),
+            ("Unused vertices", self.unused_vertices, true),
+            ("Duplicate faces", self.duplicate_faces, true),
+            ("Out of range UVs", self.out_of_range_uvs, true),
+            (
+                "Inconsistent winding edges",
+                self.inconsistent_winding_edges,
+                true,
+            ),
+        ];
+        lines.extend(checks.into_iter().map(|(label, count, is_problem)| {
+            (label.to_owned(), count.to_string(), is_problem && count > 0)
+        }));
+        lines.extend(self.textures.iter().map(|(name, width, height)| {
+            (
+                format!("{} texture", name),
+                format!("{}x{}", width, height),
+                false,
+            )
+        }));
+        lines
+    }
+}
+
+impl fmt::Display for MeshReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (label, value, problem) in self.lines() {
+            let marker = if problem { "  <- check this" } else { "" };
+            writeln!(f, "{:<28}{}{}", format!("{}:", label), value, marker)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec2;
+
+    use super::*;
+    use crate::Vertex;
+
+    #[test]
+    fn report_should_count_each_kind_of_problem() {
+        let vertex = |x, y| Vertex {
+            position: Vec3::new(x, y, 0.0),
+            ..Vertex::default()
+        };
+        let mut vertices = vec![
+            vertex(0.0, 0.0),
+            vertex(1.0, 0.0),
+            vertex(1.0, 1.0),
+            vertex(0.0, 1.0),
+            vertex(0.5, 0.0),
+            // never used
+            vertex(-1.0, -1.0),
+        ];
+        vertices[3].uv = Vec2::new(1.5, 0.5);
+        let model = Model {
+            vertices,
+            indices: vec![
+                0, 1, 2, // a triangle
+                0, 3, 2, // next to it, but facing the other way
+                0, 1, 2, // the first one again
+                0, 0, 1, // degenerate
+                0, 4, 1, // in a line
+            ],
+            ..Model::plane()
+        };
+
+        let report = model.report();
+        assert_eq!(report.face_count, 5);
+        assert_eq!(report.position_count, 6);
+        assert_eq!(report.uv_count, 2);
+        assert_eq!(
+            report.bounds,
+            Some((Vec3::new(-1.0, -1.0, 0.0), Vec3::new(1.0, 1.0, 0.0)))
+        );
+        assert_eq!(report.degenerate_faces, 1);
+        assert_eq!(report.zero_area_faces, 1);
+        // the diagonal is shared by three faces, while the duplicated face's other two edges are
+        // both gone around the same way twice
+        assert_eq!(report.non_manifold_edges, 1);
+        assert_eq!(report.inconsistent_winding_edges, 2);
+        assert_eq!(report.boundary_edges, 2);
+        assert_eq!(report.unused_vertices, 1);
+        assert_eq!(report.duplicate_faces, 1);
+        assert_eq!(report.out_of_range_uvs, 1);
+        assert_eq!(report.textures[0], ("Diffuse", 64, 64));
+
+        // open boundaries are counted, but not pointed out as problems
+        let lines = report.lines();
+        let is_problem = |label| lines.iter().any(|line| line.0 == label && line.2);
+        assert!(is_problem("Degenerate faces"));
+        assert!(!is_problem("Boundary edges"));
+
+        let empty = Model {
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            tangents: Vec::new(),
+            ..Model::plane()
+        };
+        assert_eq!(empty.report().bounds, None);
+    }
+
+    #[test]
+    fn report_should_count_what_obj_files_list_before_merging_vertices() -> anyhow::Result<()> {
+        // the last position is never used, and the same normal is listed twice
+        let mesh = Model::parse_obj(
+            "v 0 0 0
+            v 1 0 0
+            v 0 1 0
+            v 1 1 0
+            vt 0 0
+            vn 0 0 1
+            vn 0 0 1
+            f 1/1/1 2/1/1 3/1/2",
+        )?;
+        let model = Model {
+            vertices: mesh.vertices,
+            indices: mesh.indices,
+            tangents: mesh.tangents,
+            file_counts: mesh.file_counts,
+            ..Model::plane()
+        };
+
+        let report = model.report();
+        assert_eq!(report.vertex_count, 3);
+        assert_eq!(
+            (report.position_count, report.uv_count, report.normal_count),
+            (4, 1, 2)
+        );
+        assert_eq!(report.unused_vertices, 1);
+        Ok(())
+    }
+}
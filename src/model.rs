@@ -1,26 +1,18 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use anyhow::{anyhow, bail, Context, Result};
 use derive_more::Constructor;
-use glam::{Vec2, Vec3};
-use rgb::{ComponentMap, RGB8, RGBA8};
+use glam::{Mat4, Vec2, Vec3, Vec4};
+use rgb::{ComponentMap, RGBA8};
 
-#[derive(Clone, Copy, Debug, PartialEq, Constructor)]
-pub struct Vertex {
-    pub pos: Vec3,
-}
-
-#[derive(Clone, Debug, Constructor)]
-pub struct FacePoint {
-    pub vertices_index: usize,
-    pub uv_index: usize,
-    pub normals_index: usize,
-}
-
-#[derive(Clone, Debug, Constructor)]
-pub struct Face {
-    pub points: Vec<FacePoint>,
-}
+use crate::{
+    mesh_cache::{self, Mesh},
+    mesh_import, AnimationClip, FileCounts, LodChain, Skeleton, Vertex, WHITE,
+};
 
 type TextureInput = PathBuf;
 
@@ -28,7 +20,7 @@ type TextureInput = PathBuf;
 pub struct Texture {
     pub width: usize,
     pub height: usize,
-    pub data: Vec<RGB8>,
+    pub data: Vec<RGBA8>,
 }
 
 impl Texture {
@@ -39,9 +31,9 @@ impl Texture {
         Ok(path.to_owned())
     }
 
-    fn load_from_file(path: &TextureInput) -> Result<Self> {
+    pub fn load_from_file(path: &Path) -> Result<Self> {
         println!("Loading texture from file: {}", path.display());
-        let diffuse_bitmap = lodepng::decode24_file(path)
+        let diffuse_bitmap = lodepng::decode32_file(path)
             .with_context(|| format!("Loading texture from '{}' failed", path.display()))?;
         Ok(Texture::new(
             diffuse_bitmap.width,
@@ -50,13 +42,17 @@ impl Texture {
         ))
     }
 
+    pub fn save_to_file(&self, path: &Path) -> Result<()> {
+        lodepng::encode32_file(path, &self.data, self.width, self.height)
+            .with_context(|| format!("Saving texture to '{}' failed", path.display()))
+    }
+
     pub fn get_pixel(&self, uv: Vec2) -> RGBA8 {
-        let x = uv.x as usize;
-        let y = uv.y as usize;
-        debug_assert!(x < self.width);
-        debug_assert!(y < self.height);
+        // wrap around, so that texture coordinates outside of 0 to 1 repeat the texture
+        let x = (uv.x.floor() as i64).rem_euclid(self.width as i64) as usize;
+        let y = (uv.y.floor() as i64).rem_euclid(self.height as i64) as usize;
 
-        self.data[(self.height - y) * self.width + x].into()
+        self.data[(self.height - y) % self.height * self.width + x]
     }
 
     pub fn get_normal(&self, uv: Vec2) -> Vec3 {
@@ -77,8 +73,25 @@ impl Texture {
 #[derive(Clone, Debug)]
 pub struct ModelInput {
     model: PathBuf,
+    format: ModelFormat,
+}
+
+#[derive(Clone, Debug)]
+enum ModelFormat {
+    /// An OBJ file, with its textures in PNG files named after it
+    Obj(ObjTextures),
+    /// A glTF file, which contains (or refers to) its own textures
+    Gltf,
+    /// An STL file, which has no textures nor colors
+    Stl,
+    /// A PLY file, which has no textures but can have vertex colors
+    Ply,
+}
+
+#[derive(Clone, Debug)]
+struct ObjTextures {
     diffuse_texture: PathBuf,
-    normal_texture_global: PathBuf,
+    normal_texture_global: Option<PathBuf>,
     normal_texture_darboux: PathBuf,
     specular_texture: PathBuf,
     glow_texture: Option<PathBuf>,
@@ -88,21 +101,136 @@ impl ModelInput {
     pub fn path(&self) -> &Path {
         self.model.as_path()
     }
+
+    /// Paths of the texture files that will be loaded along with the model. Textures of glTF
+    /// models are loaded as part of the model, so aren't included.
+    pub fn texture_paths(&self) -> impl Iterator<Item = &Path> {
+        let textures = match &self.format {
+            ModelFormat::Obj(textures) => Some(textures),
+            ModelFormat::Gltf | ModelFormat::Stl | ModelFormat::Ply => None,
+        };
+        textures
+            .into_iter()
+            .flat_map(|textures| {
+                [
+                    &textures.diffuse_texture,
+                    &textures.normal_texture_darboux,
+                    &textures.specular_texture,
+                ]
+                .into_iter()
+                .chain(textures.normal_texture_global.as_ref())
+                .chain(textures.glow_texture.as_ref())
+            })
+            .map(PathBuf::as_path)
+    }
+}
+
+/// Everything loaded from a model file: one or more models (an OBJ file has one, while a glTF file
+/// has one for each mesh primitive in its scene), plus any cameras it defines.
+#[derive(Clone, Debug)]
+pub struct ModelFile {
+    pub models: Vec<Arc<Model>>,
+    pub cameras: Vec<Camera>,
+    /// Animations of the skeletons of the models
+    pub animations: Vec<AnimationClip>,
+    /// Simplified copies of each model, for drawing quickly while the view is moving. Empty until
+    /// `generate_lods` is called.
+    pub lods: Vec<LodChain>,
+    /// Other files the models were read from, such as the separate buffers and images of a glTF
+    /// file, so that changes to them can be noticed. Doesn't include textures loaded through
+    /// `load`'s `load_texture`, which are listed by `ModelInput::texture_paths`.
+    pub dependencies: Vec<PathBuf>,
+    /// Problems that didn't stop the file loading, such as its mesh cache not being saved
+    pub warnings: Vec<String>,
+}
+
+impl ModelFile {
+    /// Load a model file, getting the textures of OBJ models from `load_texture` so that they can
+    /// be cached and shared between models.
+    pub fn load(
+        input: &ModelInput,
+        load_texture: impl FnMut(&Path) -> Result<Arc<Texture>>,
+    ) -> Result<Self> {
+        let mut warnings = Vec::new();
+        let model = match input.format {
+            ModelFormat::Obj(_) => Model::load_obj(input, load_texture, &mut warnings)?,
+            ModelFormat::Gltf => return crate::gltf_import::load_gltf(&input.model),
+            ModelFormat::Stl | ModelFormat::Ply => {
+                let parse = match input.format {
+                    ModelFormat::Stl => mesh_import::parse_stl,
+                    _ => mesh_import::parse_ply,
+                };
+                Model::untextured(load_mesh(&input.model, parse, &mut warnings)?)
+            }
+        };
+        Ok(Self {
+            models: vec![Arc::new(model)],
+            cameras: Vec::new(),
+            animations: Vec::new(),
+            lods: Vec::new(),
+            dependencies: Vec::new(),
+            warnings,
+        })
+    }
+
+    /// Simplify each model into a chain of levels of detail, for `model_with_at_most`.
+    pub fn generate_lods(&mut self) {
+        self.lods = self
+            .models
+            .iter()
+            .map(|model| LodChain::new(model))
+            .collect();
+    }
+
+    /// The model at `index`, or its most detailed simplified copy with at most `max_faces` faces
+    /// if it has more than that (and levels of detail have been generated).
+    pub fn model_with_at_most(&self, index: usize, max_faces: usize) -> &Arc<Model> {
+        let model = &self.models[index];
+        if model.face_count() <= max_faces {
+            return model;
+        }
+        self.lods
+            .get(index)
+            .and_then(|lods| lods.pick(max_faces))
+            .unwrap_or(model)
+    }
+}
+
+/// A viewpoint saved in a model file, in the same coordinates as its models.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Camera {
+    pub name: String,
+    pub look_from: Vec3,
+    pub look_at: Vec3,
+    pub up: Vec3,
 }
 
 #[derive(Clone, Debug)]
 pub struct Model {
+    /// Every distinct combination of position, texture coordinates and normal used by the faces
     pub vertices: Vec<Vertex>,
-    pub vertex_normals: Vec<Vec3>,
-    pub faces: Vec<Face>,
-    pub texture_coords: Vec<Vec2>,
-    pub diffuse_texture: Texture,
-    /// Normal texture in global/cartesian coordinate system - should be mostly multicolor
-    pub normal_texture_global: Texture,
+    /// Indices into `vertices` of the corners of each (triangular) face, three per face
+    pub indices: Vec<u32>,
+    /// How many corners each polygon in the model's file had, in the order of their faces in
+    /// `indices` (each polygon being split into a fan of triangles around its first corner), so
+    /// that they can be put back together. Empty if every face was a triangle, or the model wasn't
+    /// read from an OBJ or PLY file.
+    pub polygon_sizes: Vec<u32>,
+    /// Direction each face's texture coordinates increase in u, for building tangent space
+    pub tangents: Vec<Vec3>,
+    pub diffuse_texture: Arc<Texture>,
+    /// Normal texture in global/cartesian coordinate system - should be mostly multicolor. Models
+    /// without one (such as STL and PLY meshes) use their interpolated vertex normals instead.
+    pub normal_texture_global: Option<Arc<Texture>>,
     /// Normal texture in darboux frame (tangent space) - should be mostly blue
-    pub normal_texture_darboux: Texture,
-    pub specular_texture: Texture,
-    pub glow_texture: Option<Texture>,
+    pub normal_texture_darboux: Arc<Texture>,
+    pub specular_texture: Arc<Texture>,
+    pub glow_texture: Option<Arc<Texture>>,
+    /// The joints that move the vertices, for skinned models
+    pub skeleton: Option<Arc<Skeleton>>,
+    /// What the model's file listed before its vertices were merged, for its report. `None` for
+    /// models that weren't parsed from an OBJ or PLY file, including copies made from them.
+    pub file_counts: Option<FileCounts>,
 }
 
 impl Model {
@@ -110,18 +238,34 @@ impl Model {
         let model_ext = model
             .extension()
             .ok_or_else(|| anyhow!("Model file '{:?}' must have an extension", model))?;
-        if model_ext != "obj" {
-            bail!(
-                "Model file '{:?}' must be an Obj file that ends in .obj",
+        let format = match model_ext.to_str() {
+            Some("gltf" | "glb") => Some(ModelFormat::Gltf),
+            Some("stl") => Some(ModelFormat::Stl),
+            Some("ply") => Some(ModelFormat::Ply),
+            Some("obj") => None,
+            _ => bail!(
+                "Model file '{:?}' must be an Obj file that ends in .obj, a glTF file that ends \
+                in .gltf or .glb, or an STL or PLY mesh",
                 model
-            );
+            ),
+        };
+        // only OBJ models have textures in separate files to check
+        if let Some(format) = format {
+            if !model.exists() {
+                bail!("Model file does not exist: {}", model.display());
+            }
+            return Ok(ModelInput {
+                model: model.to_owned(),
+                format,
+            });
         }
 
         let diffuse_texture = Texture::validate(model.with_extension("diffuse.png").as_ref())
             .context("Validating diffuse texture failed")?;
+        // the global space normal map is optional, as models exported without texture
+        // coordinates have none
         let normal_texture_global =
-            Texture::validate(model.with_extension("normals_global.png").as_ref())
-                .context("Validating (global space) normal texture failed")?;
+            Texture::validate(model.with_extension("normals_global.png").as_ref()).ok();
         let normal_texture_darboux =
             Texture::validate(model.with_extension("normals_darboux.png").as_ref())
                 .context("Validating (darboux frame) normal texture failed")?;
@@ -132,143 +276,508 @@ impl Model {
 
         Ok(ModelInput {
             model: model.to_owned(),
+            format: ModelFormat::Obj(ObjTextures {
+                diffuse_texture,
+                normal_texture_global,
+                normal_texture_darboux,
+                specular_texture,
+                glow_texture,
+            }),
+        })
+    }
+
+    pub fn load_obj_file(input: &ModelInput) -> Result<Self> {
+        Self::load_obj_file_with_textures(input, |path| Texture::load_from_file(path).map(Arc::new))
+    }
+
+    /// Load a model, getting its textures from `load_texture` so that they can be cached and
+    /// shared between models.
+    pub fn load_obj_file_with_textures(
+        input: &ModelInput,
+        load_texture: impl FnMut(&Path) -> Result<Arc<Texture>>,
+    ) -> Result<Self> {
+        let mut warnings = Vec::new();
+        let model = Self::load_obj(input, load_texture, &mut warnings)?;
+        for warning in warnings {
+            println!("{}", warning);
+        }
+        Ok(model)
+    }
+
+    /// Load an OBJ model, adding any problems that don't stop it loading to `warnings`.
+    fn load_obj(
+        input: &ModelInput,
+        mut load_texture: impl FnMut(&Path) -> Result<Arc<Texture>>,
+        warnings: &mut Vec<String>,
+    ) -> Result<Self> {
+        let textures = match &input.format {
+            ModelFormat::Obj(textures) => textures,
+            _ => bail!("{} is not an OBJ model", input.model.display()),
+        };
+        let mesh = load_mesh(
+            &input.model,
+            |source| {
+                let contents = std::str::from_utf8(source)
+                    .with_context(|| "attempting to read model file as text")?;
+                Self::parse_obj(contents)
+            },
+            warnings,
+        )?;
+
+        let diffuse_texture =
+            load_texture(&textures.diffuse_texture).context("Loading diffuse texture failed")?;
+        let normal_texture_global = textures
+            .normal_texture_global
+            .as_ref()
+            .map(|texture| load_texture(texture))
+            .transpose()
+            .context("Loading (global space) normal texture failed")?;
+        let normal_texture_darboux = load_texture(&textures.normal_texture_darboux)
+            .context("Loading (darboux frame) normal texture failed")?;
+        let specular_texture =
+            load_texture(&textures.specular_texture).context("Loading specular texture failed")?;
+        let glow_texture = textures.glow_texture.as_ref().and_then(|texture| {
+            load_texture(texture)
+                .context("Loading glow texture failed")
+                .ok()
+        });
+
+        Ok(Self {
+            vertices: mesh.vertices,
+            indices: mesh.indices,
+            polygon_sizes: mesh.polygon_sizes,
+            tangents: mesh.tangents,
             diffuse_texture,
             normal_texture_global,
             normal_texture_darboux,
             specular_texture,
             glow_texture,
+            skeleton: None,
+            file_counts: mesh.file_counts,
         })
     }
 
-    pub fn load_obj_file(input: &ModelInput) -> Result<Self> {
-        use std::io::prelude::*;
-
-        println!("Loading model from file: {}", input.model.display());
-        let mut contents = String::new();
-        std::fs::File::open(&input.model)
-            .with_context(|| "attempting to open model file")?
-            .read_to_string(&mut contents)
-            .with_context(|| "attempting to read model file")?;
-
-        let mut vertices = Vec::new();
-        let mut faces = Vec::new();
+    pub(crate) fn parse_obj(contents: &str) -> Result<Mesh> {
+        let mut positions = Vec::new();
+        let mut colors = Vec::new();
         let mut texture_coords = Vec::new();
         let mut vertex_normals = Vec::new();
-        for line in contents.lines() {
+        // faces refer to these by index, so they are only known once the whole file is read
+        let mut face_points = Vec::new();
+        let mut polygon_sizes = Vec::new();
+        for (line_index, line) in contents.lines().enumerate() {
             let line = line.trim();
             if line.is_empty() {
                 continue;
             }
+            let context = || format!("on line {}: {}", line_index + 1, line);
 
             let mut parts = line.split_whitespace();
+            let line_type = parts.next().unwrap_or_default();
+            let mut extract_floats = |count: usize| -> Result<Vec<f32>> {
+                (0..count)
+                    .map(|_| {
+                        let part = parts.next().context("too few numbers")?;
+                        part.parse::<f32>()
+                            .with_context(|| format!("'{}' is not a number", part))
+                    })
+                    .collect::<Result<_>>()
+                    .with_context(context)
+            };
 
-            let line_type = parts.next().unwrap();
             match line_type {
                 "v" => {
                     // vertex, eg: v 0.608654 -0.568839 -0.416318
-                    let mut extract_float = || {
-                        parts
-                            .next()
-                            .expect("vertex data point")
-                            .parse::<f32>()
-                            .expect("vertex float position")
-                    };
-                    let x = extract_float();
-                    let y = extract_float();
-                    let z = extract_float();
-                    vertices.push(Vertex::new(Vec3::new(x, y, z)));
+                    let xyz = extract_floats(3)?;
+                    let (x, y, z) = (xyz[0], xyz[1], xyz[2]);
+                    // this simplistic rendering code assumes that the vertice coordinates are
+                    // between -1 and 1, so confirm that assumption
+                    if !(-1.0..=1.0).contains(&x) || !(-1.0..=1.0).contains(&y) {
+                        bail!(
+                            "vertex coordinates ({}, {}) are out of the range -1 to 1 {}",
+                            x,
+                            y,
+                            context()
+                        );
+                    }
+                    positions.push(Vec3::new(x, y, z));
+                    // some tools follow the position with a color, eg: v 0.608 -0.568 -0.416 1 0 0
+                    let color: Vec<f32> = line
+                        .split_whitespace()
+                        .skip(4)
+                        .filter_map(|part| part.parse().ok())
+                        .collect();
+                    colors.push(match color[..] {
+                        [r, g, b, ..] => Vec4::new(r, g, b, 1.0),
+                        _ => Vec4::ONE,
+                    });
                 }
                 "f" => {
                     // face, eg: f 1193/1240/1193 1180/1227/1180 1179/1226/1179
-                    let mut vertices = Vec::new();
-                    for vertex in parts {
-                        let mut vertex_parts = vertex.split('/');
-                        let vertices_index = vertex_parts.next().unwrap().parse::<i32>().unwrap();
-                        let uvs_index = vertex_parts.next().unwrap().parse::<i32>().unwrap();
-                        let normals_index = vertex_parts.next().unwrap().parse::<i32>().unwrap();
-                        // vertex indices should be 1-based & we ignore negative indices even though
-                        // officially they are allowed
-                        assert!(
-                            vertices_index > 0,
-                            "Only positive 1-based indexing is supported for faces vertex indexing"
-                        );
-                        assert!(
-                            uvs_index > 0,
-                            "Only positive 1-based indexing is supported for face texture coordinate indexing"
-                        );
-                        assert!(
-                            normals_index > 0,
-                            "Only positive 1-based indexing is supported for face normal indexing"
-                        );
-
-                        vertices.push(FacePoint::new(
-                            vertices_index as usize - 1,
-                            uvs_index as usize - 1,
-                            normals_index as usize - 1,
-                        ));
+                    let polygon = line
+                        .split_whitespace()
+                        .skip(1)
+                        .map(|vertex| {
+                            let mut indices = vertex.split('/').map(|index| {
+                                // indices should be 1-based & we ignore negative indices even
+                                // though officially they are allowed
+                                match index.parse::<usize>() {
+                                    Ok(index) if index > 0 => Ok(index - 1),
+                                    _ => Err(anyhow!(
+                                        "'{}' is not a positive 1-based index (as faces must have \
+                                        position, texture coordinate and normal indices)",
+                                        vertex
+                                    )),
+                                }
+                            });
+                            let mut next = || {
+                                indices.next().unwrap_or_else(|| {
+                                    Err(anyhow!("'{}' is missing indices", vertex))
+                                })
+                            };
+                            Ok((next()?, next()?, next()?))
+                        })
+                        .collect::<Result<Vec<_>>>()
+                        .with_context(context)?;
+                    if polygon.len() < 3 {
+                        bail!("faces must have at least 3 vertices {}", context());
                     }
-                    debug_assert!(
-                        vertices.len() == 3,
-                        "only faces with exactly 3 vertices are supported; found {} vertices",
-                        vertices.len()
-                    );
-                    faces.push(Face::new(vertices));
+                    // fan out from the first corner, which is fine for convex polygons
+                    for i in 2..polygon.len() {
+                        face_points.extend([polygon[0], polygon[i - 1], polygon[i]]);
+                    }
+                    polygon_sizes.push(polygon.len() as u32);
                 }
                 "vt" => {
                     // triangle texture coordinates, eg: vt  0.532 0.923 0.000
-                    let mut extract_float = || {
-                        parts
-                            .next()
-                            .expect("vertex tex coord")
-                            .parse::<f32>()
-                            .expect("vertex float coord")
-                    };
-                    let u = extract_float();
-                    let v = extract_float();
-                    texture_coords.push(Vec2::new(u, v));
+                    let uv = extract_floats(2)?;
+                    texture_coords.push(Vec2::new(uv[0], uv[1]));
                 }
                 "vn" => {
                     // vertex normal vectors, eg: vn  0.001 0.482 -0.876
-                    let mut extract_float = || {
-                        parts
-                            .next()
-                            .expect("vertex normal component")
-                            .parse::<f32>()
-                            .expect("vertex float component")
-                    };
-                    let x = extract_float();
-                    let y = extract_float();
-                    let z = extract_float();
-                    vertex_normals.push(Vec3::new(x, y, z));
+                    let xyz = extract_floats(3)?;
+                    vertex_normals.push(Vec3::new(xyz[0], xyz[1], xyz[2]));
                 }
                 _ => (), // ignore unknown line type
             }
         }
 
-        let diffuse_texture = Texture::load_from_file(&input.diffuse_texture)
-            .context("Loading diffuse texture failed")?;
-        let normal_texture_global = Texture::load_from_file(&input.normal_texture_global)
-            .context("Loading (global space) normal texture failed")?;
-        let normal_texture_darboux = Texture::load_from_file(&input.normal_texture_darboux)
-            .context("Loading (darboux frame) normal texture failed")?;
-        let specular_texture = Texture::load_from_file(&input.specular_texture)
-            .context("Loading specular texture failed")?;
-        let glow_texture = input.glow_texture.as_ref().and_then(|texture| {
-            Texture::load_from_file(texture)
-                .context("Loading glow texture failed")
-                .ok()
-        });
+        let mut used = vec![false; positions.len()];
+        for &(position, _, _) in &face_points {
+            if let Some(used) = used.get_mut(position) {
+                *used = true;
+            }
+        }
+        let file_counts = FileCounts {
+            positions: positions.len(),
+            uvs: texture_coords.len(),
+            normals: vertex_normals.len(),
+            unused_positions: used.iter().filter(|&&used| !used).count(),
+        };
 
-        Ok(Self {
+        let vertices = face_points
+            .into_iter()
+            .map(|(position, uv, normal)| {
+                Ok(Vertex {
+                    position: *positions
+                        .get(position)
+                        .with_context(|| format!("vertex {} doesn't exist", position + 1))?,
+                    uv: *texture_coords
+                        .get(uv)
+                        .with_context(|| format!("texture coordinate {} doesn't exist", uv + 1))?,
+                    normal: *vertex_normals
+                        .get(normal)
+                        .with_context(|| format!("normal {} doesn't exist", normal + 1))?,
+                    color: colors[position],
+                    ..Vertex::default()
+                })
+            })
+            .collect::<Result<Vec<_>>>()
+            .context("a face refers to something that isn't in the file")?;
+        let (vertices, indices) = index_vertices(vertices.into_iter());
+        Ok(Mesh {
+            tangents: face_tangents(&vertices, &indices),
             vertices,
-            vertex_normals,
-            faces,
-            texture_coords,
-            diffuse_texture,
-            normal_texture_global,
-            normal_texture_darboux,
-            specular_texture,
-            glow_texture,
+            indices,
+            polygon_sizes: only_polygons(polygon_sizes),
+            file_counts: Some(file_counts),
+        })
+    }
+
+    /// A model with no textures, colored only by its vertices' colors, such as a 3D scan.
+    fn untextured(mesh: Mesh) -> Self {
+        // the textures are solid colors, so tiny ones are all that's needed
+        let solid_texture = |color| Arc::new(solid_texture(color, 1));
+        Self {
+            vertices: mesh.vertices,
+            indices: mesh.indices,
+            polygon_sizes: mesh.polygon_sizes,
+            tangents: mesh.tangents,
+            diffuse_texture: solid_texture(WHITE),
+            // there's no global space normal map to go with the mesh, so the vertex normals are
+            // used as they are
+            normal_texture_global: None,
+            normal_texture_darboux: solid_texture(RGBA8::new(128, 128, 255, 255)),
+            specular_texture: solid_texture(RGBA8::new(50, 50, 50, 255)),
+            glow_texture: None,
+            skeleton: None,
+            file_counts: mesh.file_counts,
+        }
+    }
+
+    pub fn face_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    /// The indices into `vertices` of a face's corners.
+    pub fn face(&self, face: usize) -> [u32; 3] {
+        let corners = &self.indices[face * 3..face * 3 + 3];
+        [corners[0], corners[1], corners[2]]
+    }
+
+    pub fn face_vertices(&self, face: usize) -> [Vertex; 3] {
+        self.face(face).map(|index| self.vertices[index as usize])
+    }
+
+    /// The indices into `vertices` of the corners of the polygons in the model's file, put back
+    /// together from the triangles they were split into. Just the triangles if the model doesn't
+    /// have any other polygons (or `polygon_sizes` doesn't match its faces).
+    pub fn polygons(&self) -> Vec<Vec<u32>> {
+        let split_faces: usize = self
+            .polygon_sizes
+            .iter()
+            .map(|&size| (size as usize).saturating_sub(2))
+            .sum();
+        if self.polygon_sizes.is_empty() || split_faces != self.face_count() {
+            return (0..self.face_count())
+                .map(|face| self.face(face).to_vec())
+                .collect();
+        }
+        let mut face = 0;
+        self.polygon_sizes
+            .iter()
+            .map(|&size| {
+                let triangles = (size as usize).saturating_sub(2);
+                // the first triangle has the first three corners, then each one after it adds
+                // the next corner
+                let mut polygon = self.face(face).to_vec();
+                polygon.extend((face + 1..face + triangles).map(|t| self.face(t)[2]));
+                face += triangles;
+                polygon
+            })
+            .collect()
+    }
+
+    /// A flat square facing up (+y), spanning -1 to 1 along the x and z axes, with a checkerboard
+    /// texture. Useful as a ground for other models to stand on and cast shadows onto.
+    pub fn plane() -> Self {
+        const TEXTURE_SIZE: usize = 64;
+        const CHECKER_SIZE: usize = 8;
+        let solid_texture = |color| Arc::new(solid_texture(color, TEXTURE_SIZE));
+        let checkerboard = (0..TEXTURE_SIZE * TEXTURE_SIZE)
+            .map(|i| {
+                let (x, y) = (i % TEXTURE_SIZE, i / TEXTURE_SIZE);
+                if (x / CHECKER_SIZE + y / CHECKER_SIZE).is_multiple_of(2) {
+                    RGBA8::new(200, 200, 200, 255)
+                } else {
+                    RGBA8::new(120, 120, 120, 255)
+                }
+            })
+            .collect();
+
+        let corners = [(-1.0, -1.0), (-1.0, 1.0), (1.0, 1.0), (1.0, -1.0)];
+        // keep texture coordinates away from the edges, where lookups would fall off the texture
+        let uv = |c: f32| 0.5 + c * 0.48;
+
+        let vertices: Vec<_> = corners
+            .iter()
+            .map(|&(x, z)| Vertex {
+                position: Vec3::new(x, 0.0, z),
+                uv: Vec2::new(uv(x), uv(-z)),
+                normal: Vec3::Y,
+                ..Vertex::default()
+            })
+            .collect();
+        let indices = vec![0, 1, 2, 0, 2, 3];
+
+        Self {
+            tangents: face_tangents(&vertices, &indices),
+            vertices,
+            indices,
+            polygon_sizes: Vec::new(),
+            diffuse_texture: Arc::new(Texture::new(TEXTURE_SIZE, TEXTURE_SIZE, checkerboard)),
+            normal_texture_global: Some(solid_texture(RGBA8::new(128, 255, 128, 255))),
+            normal_texture_darboux: solid_texture(RGBA8::new(128, 128, 255, 255)),
+            specular_texture: solid_texture(RGBA8::new(10, 10, 10, 255)),
+            glow_texture: None,
+            skeleton: None,
+            file_counts: None,
+        }
+    }
+}
+
+/// Read a model file's geometry with `parse`, or from its mesh cache if the file hasn't changed since
+/// it was last parsed (as parsing is slow for big models). Problems with the cache don't stop the
+/// model loading, so they're added to `warnings` instead.
+fn load_mesh(
+    path: &Path,
+    parse: impl FnOnce(&[u8]) -> Result<Mesh>,
+    warnings: &mut Vec<String>,
+) -> Result<Mesh> {
+    println!("Loading model from file: {}", path.display());
+    let source = std::fs::read(path).with_context(|| "attempting to read model file")?;
+
+    let source_hash = mesh_cache::hash(&source);
+    let cache_path = mesh_cache::cache_path(path);
+    match mesh_cache::read(&cache_path, source_hash) {
+        Ok(Some(mesh)) => return Ok(mesh),
+        Ok(None) => (),
+        Err(err) => warnings.push(format!(
+            "Ignored unreadable mesh cache {}: {:#}",
+            cache_path.display(),
+            err
+        )),
+    }
+    let mesh = parse(&source)?;
+    if let Err(err) = mesh_cache::write(&cache_path, source_hash, &mesh) {
+        warnings.push(format!(
+            "Failed to save mesh cache {}: {:#}",
+            cache_path.display(),
+            err
+        ));
+    }
+    Ok(mesh)
+}
+
+fn solid_texture(color: RGBA8, size: usize) -> Texture {
+    Texture::new(size, size, vec![color; size * size])
+}
+
+/// Transform that moves and uniformly scales the given positions to fit between -1 and 1, for
+/// model formats whose coordinates can be on any scale.
+pub(crate) fn fit_transform(positions: impl Iterator<Item = Vec3>) -> Mat4 {
+    let (min, max) = positions.fold(
+        (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+        |(min, max), p| (min.min(p), max.max(p)),
+    );
+    let half_size = ((max - min) / 2.0).max_element();
+    let scale = if half_size > 0.0 {
+        1.0 / half_size
+    } else {
+        1.0
+    };
+    Mat4::from_scale(Vec3::splat(scale)) * Mat4::from_translation(-(min + max) / 2.0)
+}
+
+/// The number of corners of each polygon read from a file, or none if they're all triangles (so
+/// there's nothing to put back together).
+pub(crate) fn only_polygons(polygon_sizes: Vec<u32>) -> Vec<u32> {
+    if polygon_sizes.iter().all(|&size| size == 3) {
+        Vec::new()
+    } else {
+        polygon_sizes
+    }
+}
+
+/// Merge identical vertices of a list of face corners, giving the distinct vertices plus the index of
+/// each corner's vertex among them.
+pub(crate) fn index_vertices(corners: impl Iterator<Item = Vertex>) -> (Vec<Vertex>, Vec<u32>) {
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    // floats aren't `Eq`, so vertices are compared by their bits (which is fine, as the same numbers
+    // parsed from the same text always have the same bits)
+    let mut seen: HashMap<([u32; 16], [u16; 4]), u32> = HashMap::new();
+    for vertex in corners {
+        let Vertex {
+            position: p,
+            uv,
+            normal: n,
+            color: c,
+            joints,
+            weights: w,
+        } = vertex;
+        let floats = [
+            p.x, p.y, p.z, uv.x, uv.y, n.x, n.y, n.z, c.x, c.y, c.z, c.w, w.x, w.y, w.z, w.w,
+        ];
+        let key = (floats.map(f32::to_bits), joints);
+        let index = *seen.entry(key).or_insert_with(|| {
+            vertices.push(vertex);
+            (vertices.len() - 1) as u32
+        });
+        indices.push(index);
+    }
+    (vertices, indices)
+}
+
+/// The direction in which texture coordinates' u increases across each (triangular) face, or zero
+/// where the face's texture coordinates don't span an area.
+pub(crate) fn face_tangents(vertices: &[Vertex], indices: &[u32]) -> Vec<Vec3> {
+    indices
+        .chunks_exact(3)
+        .map(|face| {
+            let point = |i: usize| {
+                let v = &vertices[face[i] as usize];
+                (v.position, v.uv)
+            };
+            let ((p0, uv0), (p1, uv1), (p2, uv2)) = (point(0), point(1), point(2));
+            let (e1, e2) = (p1 - p0, p2 - p0);
+            let (duv1, duv2) = (uv1 - uv0, uv2 - uv0);
+            let det = duv1.x * duv2.y - duv2.x * duv1.y;
+            if det.abs() <= f32::EPSILON {
+                return Vec3::ZERO;
+            }
+            ((e1 * duv2.y - e2 * duv1.y) / det).normalize_or_zero()
         })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsed_faces_should_share_identical_vertices() {
+        // a square split into two triangles, which share two of its corners
+        let mesh = Model::parse_obj(
+            "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+             vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\n\
+             vn 0 0 1\n\
+             f 1/1/1 2/2/1 3/3/1\nf 1/1/1 3/3/1 4/4/1\n",
+        )
+        .expect("model should parse");
+
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3]);
+        assert_eq!(mesh.vertices[3].uv, Vec2::new(0.0, 1.0));
+        assert_eq!(mesh.tangents, vec![Vec3::X, Vec3::X]);
+    }
+
+    #[test]
+    fn polygon_faces_should_be_split_into_triangles() {
+        // the same square as a single quad, followed by a triangle which must stay intact
+        let mesh = Model::parse_obj(
+            "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 0 0 1\n\
+             vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\n\
+             vn 0 0 1\n\
+             f 1/1/1 2/2/1 3/3/1 4/4/1\nf 1/1/1 2/2/1 5/1/1\n",
+        )
+        .expect("model should parse");
+
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3, 0, 1, 4]);
+        assert_eq!(mesh.tangents.len(), 3);
+        assert_eq!(mesh.polygon_sizes, vec![4, 3]);
+    }
+
+    #[test]
+    fn invalid_obj_files_should_be_errors() {
+        for contents in [
+            "v 2 0 0\n",
+            "v 0 0\n",
+            "v 0 0 0\nvt 0 0\nvn 0 0 1\nf 1/1/1 1/1/1\n",
+            "v 0 0 0\nvt 0 0\nvn 0 0 1\nf 1/1/1 1/1/1 2/1/1\n",
+            "v 0 0 0\nvt 0 0\nvn 0 0 1\nf 1 1 1\n",
+        ] {
+            assert!(Model::parse_obj(contents).is_err(), "{:?}", contents);
+        }
     }
 }
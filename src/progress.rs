@@ -0,0 +1,50 @@
+use std::{fmt, sync::Arc};
+
+use crate::Canvas;
+
+/// Receives progress updates from long running canvas operations, and can ask for them to stop
+/// early. Implementations must be cheap to call, since they are called many times per pass.
+pub trait ProgressSink: Send + Sync {
+    /// Called periodically with the fraction (from 0.0 to 1.0) of the current pass that has been
+    /// completed, along with the canvas as drawn so far.
+    fn report(&self, pass: &str, fraction: f32, canvas: &Canvas);
+
+    /// Once this returns true, canvas operations stop early and leave the canvas partly drawn.
+    fn is_cancelled(&self) -> bool;
+}
+
+/// The progress sink attached to a canvas, plus the name of the pass currently being drawn.
+#[derive(Clone, Default)]
+pub(crate) struct Progress {
+    sink: Option<Arc<dyn ProgressSink>>,
+    pass: String,
+}
+
+impl Progress {
+    pub(crate) fn set_sink(&mut self, sink: Option<Arc<dyn ProgressSink>>) {
+        self.sink = sink;
+    }
+
+    pub(crate) fn set_pass(&mut self, pass: &str) {
+        self.pass = pass.to_owned();
+    }
+
+    pub(crate) fn report(&self, fraction: f32, canvas: &Canvas) {
+        if let Some(ref sink) = self.sink {
+            sink.report(&self.pass, fraction, canvas);
+        }
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.sink.as_ref().is_some_and(|sink| sink.is_cancelled())
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Progress")
+            .field("sink", &self.sink.as_ref().map(|_| "ProgressSink"))
+            .field("pass", &self.pass)
+            .finish()
+    }
+}
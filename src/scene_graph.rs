@@ -0,0 +1,118 @@
+use std::sync::Arc;
+
+use glam::{EulerRot, Mat4, Quat, Vec3};
+
+use crate::{AnimationClip, Model, Vertex};
+
+/// Where an object sits in the world: scaled, then rotated, then moved.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Transform {
+    pub translation: Vec3,
+    /// Rotation in degrees around the y (yaw), then x (pitch), then z (roll) axes
+    pub rotation: Vec3,
+    pub scale: Vec3,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: Vec3::ZERO,
+            rotation: Vec3::ZERO,
+            scale: Vec3::ONE,
+        }
+    }
+}
+
+impl Transform {
+    pub fn matrix(&self) -> Mat4 {
+        let rotation = Quat::from_euler(
+            EulerRot::YXZ,
+            self.rotation.y.to_radians(),
+            self.rotation.x.to_radians(),
+            self.rotation.z.to_radians(),
+        );
+        Mat4::from_scale_rotation_translation(self.scale, rotation, self.translation)
+    }
+}
+
+/// Changes to how an object's model is shaded, on top of its textures and the scene's settings.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct MaterialOverrides {
+    /// Multiplier for each channel of the diffuse color
+    pub tint: Vec3,
+    /// Used in place of the scene's specular shininess, if set
+    pub shininess: Option<f32>,
+    /// Used in place of full opacity, if set
+    pub opacity: Option<f32>,
+}
+
+impl Default for MaterialOverrides {
+    fn default() -> Self {
+        Self {
+            tint: Vec3::ONE,
+            shininess: None,
+            opacity: None,
+        }
+    }
+}
+
+/// A model placed in the scene. Nodes drawing the same model share it, so one model can be
+/// instanced many times without loading or storing it again.
+#[derive(Clone, Debug)]
+pub struct SceneNode {
+    pub model: Arc<Model>,
+    pub transform: Transform,
+    pub material: MaterialOverrides,
+    /// Moves the model's skinned vertices into the pose to draw them in, if it has a skeleton
+    pub joint_matrices: Option<Arc<[Mat4]>>,
+}
+
+impl SceneNode {
+    /// A node drawing the model as it is (in its rest pose, if it's skinned), at the origin.
+    pub fn new(model: Arc<Model>) -> Self {
+        Self {
+            joint_matrices: model
+                .skeleton
+                .as_ref()
+                .map(|skeleton| skeleton.joint_matrices(None, 0.0).into()),
+            model,
+            transform: Transform::default(),
+            material: MaterialOverrides::default(),
+        }
+    }
+
+    /// The node with its model in the pose that `clip` has it in at `time` seconds, or in its rest
+    /// pose without a clip.
+    pub fn posed(self, clip: Option<&AnimationClip>, time: f32) -> Self {
+        Self {
+            joint_matrices: self
+                .model
+                .skeleton
+                .as_ref()
+                .map(|skeleton| skeleton.joint_matrices(clip, time).into()),
+            ..self
+        }
+    }
+
+    /// The corners of one of the model's faces, moved into the node's pose as they're drawn.
+    pub fn face_vertices(&self, face: usize) -> [Vertex; 3] {
+        self.model
+            .face_vertices(face)
+            .map(|vertex| vertex.posed(self.joint_matrices.as_deref()))
+    }
+
+    /// Moves the node's model from its own coordinates into the world's.
+    pub fn model_matrix(&self) -> Mat4 {
+        self.transform.matrix()
+    }
+
+    /// Direction of a light in the world, as seen from the node's own coordinates.
+    pub fn light_dir(&self, light_dir: Vec3) -> Vec3 {
+        self.model_matrix()
+            .inverse()
+            .transform_vector3(light_dir)
+            .normalize_or_zero()
+    }
+}
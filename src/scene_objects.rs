@@ -0,0 +1,91 @@
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use crab_tv::{MaterialOverrides, Model, ModelInput, Transform};
+use glam::Vec3;
+
+/// A model placed in the scene alongside the main model, optionally copied in a grid to make a
+/// crowd of instances.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, PartialEq)]
+#[serde(default)]
+pub(crate) struct SceneObject {
+    /// Model to draw, or the ground plane if not set
+    pub(crate) model: Option<PathBuf>,
+    pub(crate) transform: Transform,
+    pub(crate) material: MaterialOverrides,
+    /// Number of copies along the x axis
+    pub(crate) columns: usize,
+    /// Number of copies along the z axis
+    pub(crate) rows: usize,
+    /// Distance between neighbouring copies
+    pub(crate) spacing: f32,
+}
+
+impl SceneObject {
+    pub(crate) fn ground_plane() -> Self {
+        Self {
+            model: None,
+            transform: Transform {
+                translation: Vec3::new(0.0, -1.0, 0.0),
+                scale: Vec3::splat(2.0),
+                ..Transform::default()
+            },
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<SceneObjectInput> {
+        let model = match &self.model {
+            Some(path) => Some(
+                Model::validate(path)
+                    .with_context(|| format!("Failed to load model from {}", path.display()))?,
+            ),
+            None => None,
+        };
+        if self.columns < 1 || self.rows < 1 {
+            bail!("Scene objects must have at least 1 column and row of copies");
+        }
+        if self.transform.scale.cmpeq(Vec3::ZERO).any() {
+            bail!("Scene objects must not be scaled to nothing");
+        }
+
+        // center the grid of copies on the object's position
+        let grid_center = Vec3::new(self.columns as f32 - 1.0, 0.0, self.rows as f32 - 1.0) * 0.5;
+        let transforms = (0..self.rows)
+            .flat_map(|row| (0..self.columns).map(move |column| (row, column)))
+            .map(|(row, column)| Transform {
+                translation: self.transform.translation
+                    + (Vec3::new(column as f32, 0.0, row as f32) - grid_center) * self.spacing,
+                ..self.transform
+            })
+            .collect();
+
+        Ok(SceneObjectInput {
+            model,
+            transforms,
+            material: self.material,
+        })
+    }
+}
+
+impl Default for SceneObject {
+    fn default() -> Self {
+        Self {
+            model: Some(PathBuf::from("assets/head.obj")),
+            transform: Transform::default(),
+            material: MaterialOverrides::default(),
+            columns: 1,
+            rows: 1,
+            spacing: 1.0,
+        }
+    }
+}
+
+/// A validated scene object, with a transform for each of its copies.
+#[derive(Clone, Debug)]
+pub(crate) struct SceneObjectInput {
+    /// Model to load, or `None` for the ground plane
+    pub(crate) model: Option<ModelInput>,
+    pub(crate) transforms: Vec<Transform>,
+    pub(crate) material: MaterialOverrides,
+}
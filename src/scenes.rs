@@ -1,13 +1,19 @@
-use anyhow::Result;
-use glam::{IVec2, Mat4, Vec3};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use glam::{IVec2, Mat4, Quat, Vec3};
 
-use crab_tv::{
-    look_at_transform, viewport_transform, Canvas, Model, ModelShading, BLUE, CYAN, GREEN, RED,
-    WHITE,
+use crate::{
+    look_at_transform, viewport_transform, BlendMode, Canvas, GBuffer, GBufferChannel,
+    ModelShading, RasterStats, SceneNode, Shader, Texture, BLUE, CYAN, GREEN, RED, WHITE,
 };
+use rgb::RGBA8;
 use strum::IntoEnumIterator;
 
-use crate::shaders::{NormalMap, PhongShadowInput};
+use crate::shaders::{NormalMap, PhongShader, PhongShadowInput};
 
 #[derive(
     Copy,
@@ -50,10 +56,17 @@ pub enum RenderScene {
     Shadowed,
     ScreenSpaceAmbientOcclusionCalculated,
     ScreenSpaceAmbientOcclusion,
+    Transparency,
+    GBufferAlbedo,
+    GBufferNormal,
+    GBufferSpecular,
+    GBufferEmissive,
+    GBufferDepth,
+    DeferredShading,
 }
 
 impl RenderScene {
-    // pub(crate) fn demo_time(self) -> f32 {
+    // pub fn demo_time(self) -> f32 {
     //     match self {
     //         RenderScene::FivePixels => 0.5,
     //         RenderScene::Lines => 0.5,
@@ -84,7 +97,7 @@ impl RenderScene {
     //     }
     // }
 
-    pub(crate) fn demo_time(self) -> f32 {
+    pub fn demo_time(self) -> f32 {
         match self {
             RenderScene::MovableCamera => 1.0,
             RenderScene::ReimplementAsShader => 1.0,
@@ -98,12 +111,45 @@ impl RenderScene {
             RenderScene::Shadowed => 1.0,
             RenderScene::ScreenSpaceAmbientOcclusionCalculated => 1.0,
             RenderScene::ScreenSpaceAmbientOcclusion => 3.0,
+            RenderScene::Transparency => 1.0,
+            RenderScene::DeferredShading => 1.0,
             _ => 0.0,
         }
     }
 
+    /// The G-buffer channel displayed by this scene, if it is one of the G-buffer scenes.
+    pub fn gbuffer_channel(self) -> Option<GBufferChannel> {
+        match self {
+            RenderScene::GBufferAlbedo => Some(GBufferChannel::Albedo),
+            RenderScene::GBufferNormal => Some(GBufferChannel::Normal),
+            RenderScene::GBufferSpecular => Some(GBufferChannel::Specular),
+            RenderScene::GBufferEmissive => Some(GBufferChannel::Emissive),
+            RenderScene::GBufferDepth => Some(GBufferChannel::Depth),
+            _ => None,
+        }
+    }
+
+    /// A scene that looks similar but is quicker to render, for previewing while the config is being
+    /// changed. `None` for scenes drawn at fixed pixel positions, which can't be shrunk.
+    pub fn preview_scene(self) -> Option<Self> {
+        match self {
+            RenderScene::Lines
+            | RenderScene::TriangleLineSweepVerbose
+            | RenderScene::TriangleLineSweepCompact
+            | RenderScene::TriangleBarycentric => None,
+            // skip the shadow map & G-buffer passes (previews also skip ambient occlusion)
+            RenderScene::Shadowed
+            | RenderScene::ScreenSpaceAmbientOcclusion
+            | RenderScene::DeferredShading => Some(RenderScene::PhongShader),
+            // previews skip the occlusion passes, so show the depth that occlusion is worked out
+            // from instead of a blank silhouette
+            RenderScene::ScreenSpaceAmbientOcclusionCalculated => Some(RenderScene::GBufferDepth),
+            _ => Some(self),
+        }
+    }
+
     // return the render scene that is after the current one
-    pub(crate) fn next_scene(self) -> Self {
+    pub fn next_scene(self) -> Self {
         let mut iter = RenderScene::iter();
         // find the current scene
         for scene in iter.by_ref() {
@@ -115,11 +161,72 @@ impl RenderScene {
     }
 }
 
+// names of the passes that progress is reported against while rendering
+pub const MAIN_PASS: &str = "Main";
+pub const SHADOW_PASS: &str = "Shadow map";
+pub const AMBIENT_OCCLUSION_PASS: &str = "Ambient occlusion";
+pub const GEOMETRY_PASS: &str = "Geometry";
+pub const LIGHTING_PASS: &str = "Lighting";
+pub const POST_PROCESS_PASS: &str = "Post-processing";
+
+/// What rendering a scene produced besides the image itself.
+#[derive(Clone, Debug, Default)]
+pub struct SceneOutput {
+    /// Depth buffer rendered from the perspective of the light, if the scene has shadows (shared
+    /// with the shaders that drew them)
+    pub shadow_map: Option<Arc<Canvas>>,
+    pub stats: SceneStats,
+}
+
+/// How long each pass of rendering a scene took, and how much was drawn.
+#[derive(Clone, Debug, Default)]
+pub struct SceneStats {
+    /// Wall time spent in each pass, in the order the passes first ran
+    pub pass_times: Vec<(&'static str, Duration)>,
+    /// Counters from the image canvas, so excluding the shadow map
+    pub raster: RasterStats,
+    /// Number of pixels of the image that anything was drawn to
+    pub pixels_covered: usize,
+}
+
+impl SceneStats {
+    /// Run `f`, adding the time it takes to the time spent in the given pass.
+    pub fn time_pass<T>(&mut self, pass: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add_pass_time(pass, start.elapsed());
+        result
+    }
+
+    pub fn add_pass_time(&mut self, pass: &'static str, duration: Duration) {
+        match self.pass_times.iter_mut().find(|(p, _)| *p == pass) {
+            Some((_, total)) => *total += duration,
+            None => self.pass_times.push((pass, duration)),
+        }
+    }
+
+    pub fn total_time(&self) -> Duration {
+        self.pass_times.iter().map(|(_, d)| *d).sum()
+    }
+
+    /// Average number of fragments shaded per pixel drawn to, so 1.0 means no pixel was shaded
+    /// more than once.
+    pub fn overdraw(&self) -> f32 {
+        if self.pixels_covered == 0 {
+            0.0
+        } else {
+            self.raster.fragments_shaded as f32 / self.pixels_covered as f32
+        }
+    }
+}
+
+/// Render the scene's nodes. Scenes drawn without shaders only draw the first node's model,
+/// ignoring its transform and material.
 #[allow(clippy::too_many_arguments)]
 pub fn render_scene(
     image: &mut Canvas,
     scene: &RenderScene,
-    model: &Model,
+    nodes: &[SceneNode],
     light_dir: Vec3,
     camera_distance: f32,
     camera_look_from: Vec3,
@@ -133,8 +240,17 @@ pub fn render_scene(
     ambient_occlusion_strength: f32,
     enable_glow_map: bool,
     base_shininess: f32,
-) -> Result<()> {
-    println!("Rendering scene: {}", scene);
+    transparency_opacity: f32,
+    transparency_blend_mode: BlendMode,
+) -> Result<SceneOutput> {
+    let model = match nodes.first() {
+        Some(node) => &*node.model,
+        None => bail!("The scene has nothing in it to render"),
+    };
+
+    let start = Instant::now();
+    image.begin_pass(MAIN_PASS);
+    let mut output = SceneOutput::default();
 
     let viewport = viewport_transform(
         image.width() as f32 / 8.0,
@@ -155,16 +271,14 @@ pub fn render_scene(
 
     let uniform_m = projection_transform * model_view_transform;
 
-    let phong_normal_map = if use_tangent_space_normal_map {
-        NormalMap::TangentSpace(&model.normal_texture_darboux)
-    } else {
-        NormalMap::GlobalSpace(&model.normal_texture_global)
-    };
-
-    let glow_texture = if enable_glow_map {
-        model.glow_texture.as_ref()
-    } else {
-        None
+    let phong = PhongSettings {
+        viewport,
+        uniform_m,
+        light_dir,
+        phong_lighting_weights,
+        use_tangent_space_normal_map,
+        enable_glow_map,
+        base_shininess,
     };
 
     match scene {
@@ -256,221 +370,531 @@ pub fn render_scene(
             ModelShading::Gouraud,
             Some(projection_transform * model_view_transform),
         ),
-        RenderScene::ReimplementAsShader => {
-            image.model_shader(
-                model,
-                &crate::shaders::GouraudShader::new(
+        RenderScene::ReimplementAsShader => draw_nodes(image, nodes, |node| {
+            crate::shaders::GouraudShader::new(
+                viewport,
+                uniform_m * node.model_matrix(),
+                node.light_dir(light_dir),
+                Some(&node.model.diffuse_texture),
+                false,
+            )
+        }),
+        RenderScene::GouraudIntensitiesBucketed => draw_nodes(image, nodes, |node| {
+            crate::shaders::GouraudShader::new(
+                viewport,
+                uniform_m * node.model_matrix(),
+                node.light_dir(light_dir),
+                Some(&node.model.diffuse_texture),
+                true,
+            )
+        }),
+        RenderScene::DepthTestedTriangles => draw_nodes(image, nodes, |node| {
+            crate::shaders::UnlitShader::triangles(viewport, uniform_m * node.model_matrix())
+        }),
+        RenderScene::NormalGlobalAsDiffuse => {
+            // models without a global space normal map are shown as if every normal faced +Z
+            let flat_normals = Texture::new(1, 1, vec![RGBA8::new(128, 128, 255, 255)]);
+            draw_nodes(image, nodes, |node| {
+                crate::shaders::UnlitShader::textured(
                     viewport,
-                    uniform_m,
-                    light_dir,
-                    Some(&model.diffuse_texture),
-                    false,
-                ),
-            );
+                    uniform_m * node.model_matrix(),
+                    node.model
+                        .normal_texture_global
+                        .as_deref()
+                        .unwrap_or(&flat_normals),
+                )
+            })
         }
-        RenderScene::GouraudIntensitiesBucketed => {
-            image.model_shader(
-                model,
-                &crate::shaders::GouraudShader::new(
-                    viewport,
-                    uniform_m,
-                    light_dir,
-                    Some(&model.diffuse_texture),
-                    true,
-                ),
-            );
+        RenderScene::NormalShader => draw_nodes(image, nodes, |node| {
+            crate::shaders::NormalShader::new(
+                viewport,
+                uniform_m * node.model_matrix(),
+                node.light_dir(light_dir),
+                &node.model.diffuse_texture,
+                node.model.normal_texture_global.as_deref(),
+            )
+        }),
+        RenderScene::SpecularAsDiffuse => draw_nodes(image, nodes, |node| {
+            crate::shaders::UnlitShader::textured(
+                viewport,
+                uniform_m * node.model_matrix(),
+                &node.model.specular_texture,
+            )
+        }),
+        RenderScene::NormalTangentAsDiffuse => draw_nodes(image, nodes, |node| {
+            crate::shaders::UnlitShader::textured(
+                viewport,
+                uniform_m * node.model_matrix(),
+                &node.model.normal_texture_darboux,
+            )
+        }),
+        RenderScene::PhongShader => {
+            draw_phong_nodes(image, nodes, transparency_blend_mode, |node| {
+                phong.shader(node, node.model_matrix(), None, 1.0)
+            });
         }
-        RenderScene::DepthTestedTriangles => {
-            image.model_shader(
-                model,
-                &crate::shaders::UnlitShader::triangles(viewport, uniform_m),
+        RenderScene::ShadowBuffer => draw_nodes(image, nodes, |node| {
+            crate::shaders::DepthShader::new(
+                viewport,
+                // NB: looking from the light position so that framebuffer is filled with shadow buffer
+                look_at_transform(light_dir, camera_look_at, camera_up) * node.model_matrix(),
+            )
+        }),
+        RenderScene::Shadowed => {
+            let shadows = shadow_pass(
+                image,
+                nodes,
+                viewport,
+                uniform_m,
+                light_dir,
+                camera_look_at,
+                camera_up,
+                shadow_darkness,
+                shadow_z_fix,
+                &mut output,
             );
+
+            draw_phong_nodes(image, nodes, transparency_blend_mode, |node| {
+                phong.shader(node, node.model_matrix(), Some(&shadows), 1.0)
+            });
         }
-        RenderScene::NormalGlobalAsDiffuse => {
-            image.model_shader(
-                model,
-                &crate::shaders::UnlitShader::textured(
-                    viewport,
-                    uniform_m,
-                    &model.normal_texture_global,
-                ),
-            );
+        RenderScene::ScreenSpaceAmbientOcclusionCalculated => {
+            draw_nodes(image, nodes, |node| {
+                crate::shaders::PureColorShader::new(viewport, uniform_m * node.model_matrix())
+            });
+
+            image.begin_pass(AMBIENT_OCCLUSION_PASS);
+            output.stats.time_pass(AMBIENT_OCCLUSION_PASS, || {
+                image.apply_ambient_occlusion(ambient_occlusion_strength, ambient_occlusion_passes)
+            })
         }
-        RenderScene::NormalShader => {
-            image.model_shader(
-                model,
-                &crate::shaders::NormalShader::new(
-                    viewport,
-                    uniform_m,
-                    light_dir,
-                    &model.diffuse_texture,
-                    &model.normal_texture_global,
-                ),
+        RenderScene::ScreenSpaceAmbientOcclusion => {
+            let shadows = shadow_pass(
+                image,
+                nodes,
+                viewport,
+                uniform_m,
+                light_dir,
+                camera_look_at,
+                camera_up,
+                shadow_darkness,
+                shadow_z_fix,
+                &mut output,
             );
+
+            draw_phong_nodes(image, nodes, transparency_blend_mode, |node| {
+                phong.shader(node, node.model_matrix(), Some(&shadows), 1.0)
+            });
+            image.begin_pass(AMBIENT_OCCLUSION_PASS);
+            output.stats.time_pass(AMBIENT_OCCLUSION_PASS, || {
+                image.apply_ambient_occlusion(ambient_occlusion_strength, ambient_occlusion_passes)
+            })
         }
-        RenderScene::SpecularAsDiffuse => {
-            image.model_shader(
-                model,
-                &crate::shaders::UnlitShader::textured(
-                    viewport,
-                    uniform_m,
-                    &model.specular_texture,
-                ),
+        RenderScene::Transparency => {
+            // opaque objects must be drawn first so that translucent ones can be blended over them
+            let opaque_m = Mat4::from_scale_rotation_translation(
+                Vec3::splat(0.7),
+                Quat::IDENTITY,
+                Vec3::new(-0.35, 0.0, -0.4),
             );
-        }
-        RenderScene::NormalTangentAsDiffuse => {
-            image.model_shader(
-                model,
-                &crate::shaders::UnlitShader::textured(
-                    viewport,
-                    uniform_m,
-                    &model.normal_texture_darboux,
-                ),
+            draw_nodes(image, nodes, |node| {
+                phong.shader(node, opaque_m * node.model_matrix(), None, 1.0)
+            });
+
+            let translucent_m = Mat4::from_scale_rotation_translation(
+                Vec3::splat(0.7),
+                Quat::IDENTITY,
+                Vec3::new(0.35, 0.0, 0.4),
             );
+            let opaque_blend_mode = image.blend_mode();
+            image.set_blend_mode(transparency_blend_mode);
+            draw_nodes(image, nodes, |node| {
+                phong
+                    .shader(
+                        node,
+                        translucent_m * node.model_matrix(),
+                        None,
+                        transparency_opacity,
+                    )
+                    .with_premultiplied_alpha(transparency_blend_mode == BlendMode::Premultiplied)
+            });
+            image.set_blend_mode(opaque_blend_mode);
         }
-        RenderScene::PhongShader => {
-            image.model_shader(
-                model,
-                &crate::shaders::PhongShader::new(
+        RenderScene::GBufferAlbedo
+        | RenderScene::GBufferNormal
+        | RenderScene::GBufferSpecular
+        | RenderScene::GBufferEmissive
+        | RenderScene::GBufferDepth
+        | RenderScene::DeferredShading => {
+            // shadows are only needed by the lighting pass, so skip them when viewing the G-buffer
+            let shadows = if scene.gbuffer_channel().is_none() {
+                Some(shadow_pass(
+                    image,
+                    nodes,
                     viewport,
                     uniform_m,
                     light_dir,
-                    phong_lighting_weights,
-                    &model.diffuse_texture,
-                    phong_normal_map,
-                    &model.specular_texture,
-                    None,
-                    glow_texture,
-                    base_shininess,
-                ),
-            );
-        }
-        RenderScene::ShadowBuffer => {
-            image.model_shader(
-                model,
-                &crate::shaders::DepthShader::new(
-                    viewport,
-                    // NB: looking from the light position so that framebuffer is filled with shadow buffer
-                    look_at_transform(light_dir, camera_look_at, camera_up),
-                ),
-            );
-        }
-        RenderScene::Shadowed => {
-            let mut shadow_buffer = image.clone();
-            let shadow_modelview_transform =
-                look_at_transform(light_dir, camera_look_at, camera_up);
-            let shadow_projection = Mat4::IDENTITY;
-            shadow_buffer.model_shader(
-                model,
-                &crate::shaders::DepthShader::new(
-                    viewport,
-                    shadow_projection * shadow_modelview_transform,
-                ),
-            );
-            let shadow_m = viewport * shadow_projection * shadow_modelview_transform;
+                    camera_look_at,
+                    camera_up,
+                    shadow_darkness,
+                    shadow_z_fix,
+                    &mut output,
+                ))
+            } else {
+                None
+            };
 
-            image.model_shader(
-                model,
-                &crate::shaders::PhongShader::new(
-                    viewport,
-                    uniform_m,
-                    light_dir,
-                    phong_lighting_weights,
-                    &model.diffuse_texture,
-                    phong_normal_map,
-                    &model.specular_texture,
-                    Some(PhongShadowInput::new(
-                        shadow_m * (viewport * uniform_m).inverse(),
-                        shadow_buffer,
-                        shadow_darkness,
-                        shadow_z_fix,
-                    )),
-                    glow_texture,
-                    base_shininess,
-                ),
-            );
+            let mut gbuffer = GBuffer::new(image.width(), image.height());
+            image.begin_pass(GEOMETRY_PASS);
+            output.stats.time_pass(GEOMETRY_PASS, || {
+                for (index, node) in nodes.iter().enumerate() {
+                    image.begin_node(index, node);
+                    let shader = phong.shader(node, node.model_matrix(), None, 1.0);
+                    image.model_geometry_pass(&node.model, &shader, &mut gbuffer);
+                }
+            });
+
+            match scene.gbuffer_channel() {
+                Some(channel) => image
+                    .pixels_mut()
+                    .copy_from_slice(&gbuffer.channel_pixels(channel, RGBA8::default())),
+                None => {
+                    // the G-buffer holds every node's surfaces, so lighting only needs the camera
+                    let shader = phong.shader(&nodes[0], Mat4::IDENTITY, shadows.as_ref(), 1.0);
+                    image.begin_pass(LIGHTING_PASS);
+                    output
+                        .stats
+                        .time_pass(LIGHTING_PASS, || shader.light_gbuffer(image, &gbuffer));
+                }
+            }
         }
-        RenderScene::ScreenSpaceAmbientOcclusionCalculated => {
-            let z_depth_shader = crate::shaders::PureColorShader::new(viewport, uniform_m);
-            image.model_shader(model, &z_depth_shader);
+    }
+
+    output.stats.raster = *image.stats();
+    output.stats.pixels_covered = (0..image.height() as i32)
+        .flat_map(|y| (0..image.width() as i32).map(move |x| (x, y)))
+        .filter(|&(x, y)| image.z_buffer_at(x, y).is_finite())
+        .count();
+
+    // whatever time isn't accounted for by the other passes was spent in the main pass
+    let main_time = start.elapsed().saturating_sub(output.stats.total_time());
+    output.stats.pass_times.insert(0, (MAIN_PASS, main_time));
+
+    output.stats.time_pass(POST_PROCESS_PASS, || image.flip_y());
+
+    Ok(output)
+}
+
+/// The settings for Phong shading that are shared by every node in the scene.
+struct PhongSettings {
+    viewport: Mat4,
+    uniform_m: Mat4,
+    light_dir: Vec3,
+    phong_lighting_weights: Vec3,
+    use_tangent_space_normal_map: bool,
+    enable_glow_map: bool,
+    base_shininess: f32,
+}
+
+impl PhongSettings {
+    /// A Phong shader for drawing the node's model, moved by `model_m` and with the node's
+    /// material overrides applied.
+    fn shader<'t>(
+        &self,
+        node: &'t SceneNode,
+        model_m: Mat4,
+        shadows: Option<&'t PhongShadowInput>,
+        opacity: f32,
+    ) -> PhongShader<'t> {
+        let model = &*node.model;
+        let normal_map = if self.use_tangent_space_normal_map {
+            NormalMap::TangentSpace(&model.normal_texture_darboux)
+        } else {
+            NormalMap::GlobalSpace(model.normal_texture_global.as_deref())
+        };
+        let glow_texture = if self.enable_glow_map {
+            model.glow_texture.as_deref()
+        } else {
+            None
+        };
 
-            image.apply_ambient_occlusion(ambient_occlusion_strength, ambient_occlusion_passes)
+        PhongShader::new(
+            self.viewport,
+            self.uniform_m,
+            model_m,
+            self.light_dir,
+            self.phong_lighting_weights,
+            &model.diffuse_texture,
+            normal_map,
+            &model.specular_texture,
+            shadows,
+            glow_texture,
+            node.material.shininess.unwrap_or(self.base_shininess),
+            node.material.opacity.map_or(opacity, |o| o * opacity),
+            node.material.tint,
+        )
+    }
+}
+
+/// Draw each node's model with the shader made for it by `shader_for`.
+fn draw_nodes<'n, V: Copy, S, T: Shader<V, S>>(
+    image: &mut Canvas,
+    nodes: &'n [SceneNode],
+    shader_for: impl Fn(&'n SceneNode) -> T,
+) {
+    for (index, node) in nodes.iter().enumerate() {
+        image.begin_node(index, node);
+        image.model_shader(&node.model, &shader_for(node));
+    }
+}
+
+/// Draw each node's model with the Phong shader made for it by `shader_for`. Nodes made translucent
+/// by their material are drawn last with the given blend mode, so they blend over the opaque ones.
+fn draw_phong_nodes<'n>(
+    image: &mut Canvas,
+    nodes: &'n [SceneNode],
+    translucent_blend_mode: BlendMode,
+    shader_for: impl Fn(&'n SceneNode) -> PhongShader<'n>,
+) {
+    let is_translucent = |node: &SceneNode| node.material.opacity.is_some_and(|o| o < 1.0);
+    for (index, node) in nodes.iter().enumerate() {
+        if !is_translucent(node) {
+            image.begin_node(index, node);
+            image.model_shader(&node.model, &shader_for(node));
         }
-        RenderScene::ScreenSpaceAmbientOcclusion => {
-            let mut shadow_buffer = image.clone();
-            let shadow_modelview_transform =
-                look_at_transform(light_dir, camera_look_at, camera_up);
-            let shadow_projection = Mat4::IDENTITY;
-            shadow_buffer.model_shader(
-                model,
-                &crate::shaders::DepthShader::new(
-                    viewport,
-                    shadow_projection * shadow_modelview_transform,
-                ),
-            );
-            let shadow_m = viewport * shadow_projection * shadow_modelview_transform;
+    }
 
-            image.model_shader(
-                model,
-                &crate::shaders::PhongShader::new(
-                    viewport,
-                    uniform_m,
-                    light_dir,
-                    phong_lighting_weights,
-                    &model.diffuse_texture,
-                    phong_normal_map,
-                    &model.specular_texture,
-                    Some(PhongShadowInput::new(
-                        shadow_m * (viewport * uniform_m).inverse(),
-                        shadow_buffer,
-                        shadow_darkness,
-                        shadow_z_fix,
-                    )),
-                    glow_texture,
-                    base_shininess,
-                ),
-            );
-            image.apply_ambient_occlusion(ambient_occlusion_strength, ambient_occlusion_passes)
+    let opaque_blend_mode = image.blend_mode();
+    image.set_blend_mode(translucent_blend_mode);
+    let premultiplied_alpha = translucent_blend_mode == BlendMode::Premultiplied;
+    for (index, node) in nodes.iter().enumerate() {
+        if is_translucent(node) {
+            image.begin_node(index, node);
+            let shader = shader_for(node).with_premultiplied_alpha(premultiplied_alpha);
+            image.model_shader(&node.model, &shader);
         }
     }
+    image.set_blend_mode(opaque_blend_mode);
+}
 
-    image.flip_y();
+/// Render a depth buffer of every node from the perspective of the light, for use by the Phong
+/// shader. A copy is kept in `output` for viewing.
+#[allow(clippy::too_many_arguments)]
+fn shadow_pass(
+    image: &Canvas,
+    nodes: &[SceneNode],
+    viewport: Mat4,
+    uniform_m: Mat4,
+    light_dir: Vec3,
+    camera_look_at: Vec3,
+    camera_up: Vec3,
+    shadow_darkness: f32,
+    shadow_z_fix: f32,
+    output: &mut SceneOutput,
+) -> PhongShadowInput {
+    let start = Instant::now();
+    let mut shadow_buffer = image.blank_copy();
+    shadow_buffer.begin_pass(SHADOW_PASS);
+    let shadow_modelview_transform = look_at_transform(light_dir, camera_look_at, camera_up);
+    let shadow_projection = Mat4::IDENTITY;
+    for (index, node) in nodes.iter().enumerate() {
+        shadow_buffer.begin_node(index, node);
+        shadow_buffer.model_shader(
+            &node.model,
+            &crate::shaders::DepthShader::new(
+                viewport,
+                shadow_projection * shadow_modelview_transform * node.model_matrix(),
+            ),
+        );
+    }
+    let shadow_m = viewport * shadow_projection * shadow_modelview_transform;
+    let shadow_buffer = Arc::new(shadow_buffer);
+    output.shadow_map = Some(shadow_buffer.clone());
+    output.stats.add_pass_time(SHADOW_PASS, start.elapsed());
 
-    Ok(())
+    PhongShadowInput::new(
+        shadow_m * (viewport * uniform_m).inverse(),
+        shadow_buffer,
+        shadow_darkness,
+        shadow_z_fix,
+    )
 }
+
 #[cfg(test)]
 mod tests {
-    use std::path::Path;
+    use std::{
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
 
+    use rgb::RGBA8;
     use strum::IntoEnumIterator;
 
     use super::*;
+    use crate::{MaterialOverrides, Model, Transform};
+
+    /// Set this environment variable to overwrite the golden images with what is rendered now.
+    const BLESS_ENV_VAR: &str = "BLESS_GOLDEN_IMAGES";
+    const GOLDEN_DIR: &str = "tests/golden";
+    /// Where the actual and diff images of failing scenes are written.
+    const FAILURE_DIR: &str = "target/golden-failures";
+    const IMAGE_SIZE: usize = 200;
+
+    /// How far a pixel's color channels can drift from the golden image (e.g. from floating point
+    /// differences between platforms) before the pixel counts as different.
+    const PIXEL_TOLERANCE: u8 = 8;
+    /// Fraction of pixels which may differ before an image no longer matches its golden image.
+    const MAX_DIFFERING_FRACTION: f32 = 0.002;
+
+    fn render_golden_scene(scene: RenderScene, nodes: &[SceneNode]) -> Result<Vec<RGBA8>> {
+        let mut image = Canvas::new(IMAGE_SIZE, IMAGE_SIZE);
+        render_scene(
+            &mut image,
+            &scene,
+            nodes,
+            Vec3::new(0.0, 0.0, 1.0),
+            3.0,
+            Vec3::new(0.0, 0.0, 3.0),
+            Vec3::ZERO,
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(1.0, 1.0, 0.6),
+            true,
+            0.7,
+            5.0,
+            5,
+            2.0,
+            true,
+            5.0,
+            0.5,
+            BlendMode::Alpha,
+        )?;
+        let mut pixels = image.pixels().to_vec();
+        if matches!(
+            scene,
+            RenderScene::ModelColoredTriangles | RenderScene::DepthTestedTriangles
+        ) {
+            // these scenes color each triangle randomly, so only which pixels are drawn can be
+            // compared (against the background, which the corners are always left as)
+            let background = pixels[0];
+            for pixel in pixels.iter_mut() {
+                if *pixel != background {
+                    *pixel = crate::BLACK;
+                }
+            }
+        }
+        Ok(pixels)
+    }
+
+    fn save_png(path: &Path, pixels: &[RGBA8]) {
+        std::fs::create_dir_all(path.parent().expect("path should have a parent"))
+            .expect("creating output directory should succeed");
+        lodepng::encode32_file(path, pixels, IMAGE_SIZE, IMAGE_SIZE)
+            .unwrap_or_else(|err| panic!("saving {} should succeed: {}", path.display(), err));
+    }
+
+    /// Compare a rendered image to its golden image, returning why it doesn't match if it doesn't.
+    fn check_golden_image(name: &str, actual: &[RGBA8], bless: bool) -> Option<String> {
+        let golden_path = PathBuf::from(GOLDEN_DIR).join(format!("{}.png", name));
+        if bless {
+            save_png(&golden_path, actual);
+            return None;
+        }
+
+        let golden = match lodepng::decode32_file(&golden_path) {
+            Ok(golden) => golden,
+            Err(err) => return Some(format!("can't load {}: {}", golden_path.display(), err)),
+        };
+        let failure_path =
+            |suffix| PathBuf::from(FAILURE_DIR).join(format!("{}.{}.png", name, suffix));
+        if (golden.width, golden.height) != (IMAGE_SIZE, IMAGE_SIZE) {
+            save_png(&failure_path("actual"), actual);
+            return Some(format!(
+                "golden image is {}x{} rather than {}x{}",
+                golden.width, golden.height, IMAGE_SIZE, IMAGE_SIZE
+            ));
+        }
+
+        let diff = crate::diff_images(&golden.buffer, actual);
+        let differing = diff.fraction_differing_by_more_than(PIXEL_TOLERANCE);
+        if differing <= MAX_DIFFERING_FRACTION {
+            return None;
+        }
+        save_png(&failure_path("actual"), actual);
+        save_png(&failure_path("diff"), &diff.heatmap);
+        Some(format!(
+            "{:.2}% of pixels differ (max difference {}, mean {:.3}); see {}",
+            differing * 100.0,
+            diff.max_abs_diff,
+            diff.mean_abs_diff,
+            failure_path("diff").display()
+        ))
+    }
+
+    /// The model standing on a ground plane, in front of a row of smaller, tinted and turned
+    /// instances of itself.
+    fn crowd_on_ground_plane(model: Arc<Model>) -> Vec<SceneNode> {
+        let mut nodes = vec![
+            SceneNode::new(model.clone()),
+            SceneNode {
+                transform: Transform {
+                    translation: Vec3::new(0.0, -1.0, 0.0),
+                    scale: Vec3::splat(2.0),
+                    ..Transform::default()
+                },
+                ..SceneNode::new(Arc::new(Model::plane()))
+            },
+        ];
+        nodes.extend((0..3).map(|i| SceneNode {
+            transform: Transform {
+                translation: Vec3::new(i as f32 - 1.0, -0.6, -1.0),
+                rotation: Vec3::new(0.0, (i as f32 - 1.0) * 30.0, 0.0),
+                scale: Vec3::splat(0.4),
+            },
+            material: MaterialOverrides {
+                tint: Vec3::new(1.0, 0.6 + 0.2 * i as f32, 0.6),
+                shininess: Some(1.0),
+                opacity: None,
+            },
+            ..SceneNode::new(model.clone())
+        }));
+        nodes
+    }
 
     #[test]
-    fn every_scene_should_render_without_errors() -> Result<()> {
-        for scene in RenderScene::iter() {
-            let mut image = Canvas::new(200, 200);
-            println!("Rendering scene: {:?}", scene);
-            render_scene(
-                &mut image,
-                &scene,
-                &Model::load_obj_file(&Model::validate(Path::new("assets/african_head.obj"))?)
-                    .expect("model load should succeed"),
-                Vec3::new(0.0, 0.0, -1.0),
-                3.0,
-                Vec3::new(0.0, 0.0, 3.0),
-                Vec3::ZERO,
-                Vec3::new(0.0, 1.0, 0.0),
-                Vec3::new(1.0, 1.0, 0.6),
-                true,
-                0.7,
-                5.0,
-                5,
-                2.0,
-                true,
-                1.0,
-            )?;
+    fn every_scene_should_match_its_golden_image() -> Result<()> {
+        let bless = std::env::var_os(BLESS_ENV_VAR).is_some();
+        // don't leave images from earlier failures lying around to be confused with these ones
+        let _ = std::fs::remove_dir_all(FAILURE_DIR);
+        let mut failures = Vec::new();
+        for model_name in ["head", "diablo"] {
+            let model_path = PathBuf::from(format!("assets/{}.obj", model_name));
+            let model = Model::load_obj_file(&Model::validate(&model_path)?)
+                .expect("model load should succeed");
+            let nodes = [SceneNode::new(Arc::new(model))];
+            for scene in RenderScene::iter() {
+                let name = format!("{}/{:?}", model_name, scene);
+                let actual = render_golden_scene(scene, &nodes)?;
+                if let Some(failure) = check_golden_image(&name, &actual, bless) {
+                    failures.push(format!("{}: {}", name, failure));
+                }
+            }
+
+            let nodes = crowd_on_ground_plane(nodes[0].model.clone());
+            for scene in [RenderScene::Shadowed, RenderScene::DeferredShading] {
+                let name = format!("{}/scene-graph/{:?}", model_name, scene);
+                let actual = render_golden_scene(scene, &nodes)?;
+                if let Some(failure) = check_golden_image(&name, &actual, bless) {
+                    failures.push(format!("{}: {}", name, failure));
+                }
+            }
         }
+        assert!(
+            failures.is_empty(),
+            "{} scene(s) don't match their golden images (set {} to update them):\n{}",
+            failures.len(),
+            BLESS_ENV_VAR,
+            failures.join("\n")
+        );
         Ok(())
     }
 }
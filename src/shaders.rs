@@ -1,7 +1,11 @@
+use std::sync::Arc;
+
 use glam::{Mat3, Mat4, Vec2, Vec3, Vec4};
 
-use crab_tv::{Canvas, Shader, Texture, Vertex};
-use rgb::{ComponentMap, RGBA8};
+use crate::{
+    Canvas, FragmentRecord, GBuffer, GBufferTexel, GeometryShader, Shader, Texture, Vertex,
+};
+use rgb::{ComponentMap, RGB8, RGBA8};
 
 pub struct GouraudShaderState {
     varying_uv: [Vec2; 3],
@@ -33,43 +37,39 @@ impl<'t> GouraudShader<'t> {
     }
 }
 
-impl Shader<GouraudShaderState> for GouraudShader<'_> {
-    fn vertex(&self, input: [Vertex; 3]) -> (Mat3, GouraudShaderState) {
-        let mut varying_tri = Mat3::ZERO;
-        let mut varying_uv = [Vec2::ZERO; 3];
-        let mut varying_light_intensity = [0f32; 3];
-        for (i, vert) in input.iter().enumerate() {
-            *varying_tri.col_mut(i) = {
-                // Transform the vertex position
-                // step 1 - embed into 4D space by converting to homogeneous coordinates
-                let mut vec4: Vec4 = (vert.position, 1.0).into();
-                // step 2 - multiply with projection & viewport matrices to correct perspective
-                vec4 = self.vertex_transform * vec4;
-                // step 3 - divide by w to reproject into 3d screen coordinates
-                Vec3::new(vec4.x / vec4.w, vec4.y / vec4.w, vec4.z / vec4.w)
-            };
-
-            // Transform the vertex texture coordinates based on the texture we have
-            varying_uv[i] = if let Some(texture) = self.diffuse_texture {
-                Vec2::new(
-                    vert.uv.x * texture.width as f32,
-                    vert.uv.y * texture.height as f32,
-                )
-            } else {
-                vert.uv
-            };
-
-            // Calculate the light intensity
-            varying_light_intensity[i] = vert.normal.dot(self.light_dir);
-        }
+impl Shader<(Vec2, f32), GouraudShaderState> for GouraudShader<'_> {
+    fn vertex(&self, vert: &Vertex) -> (Vec3, (Vec2, f32)) {
+        // Transform the vertex position
+        let screen_coords = {
+            // step 1 - embed into 4D space by converting to homogeneous coordinates
+            let mut vec4: Vec4 = (vert.position, 1.0).into();
+            // step 2 - multiply with projection & viewport matrices to correct perspective
+            vec4 = self.vertex_transform * vec4;
+            // step 3 - divide by w to reproject into 3d screen coordinates
+            Vec3::new(vec4.x / vec4.w, vec4.y / vec4.w, vec4.z / vec4.w)
+        };
 
-        (
-            varying_tri,
-            GouraudShaderState {
-                varying_uv,
-                varying_light_intensity,
-            },
-        )
+        // Transform the vertex texture coordinates based on the texture we have
+        let uv = if let Some(texture) = self.diffuse_texture {
+            Vec2::new(
+                vert.uv.x * texture.width as f32,
+                vert.uv.y * texture.height as f32,
+            )
+        } else {
+            vert.uv
+        };
+
+        // Calculate the light intensity
+        let light_intensity = vert.normal.dot(self.light_dir);
+
+        (screen_coords, (uv, light_intensity))
+    }
+
+    fn triangle(&self, _screen_coords: Mat3, vertices: [(Vec2, f32); 3]) -> GouraudShaderState {
+        GouraudShaderState {
+            varying_uv: vertices.map(|(uv, _)| uv),
+            varying_light_intensity: vertices.map(|(_, light_intensity)| light_intensity),
+        }
     }
 
     fn fragment(&self, barycentric_coords: Vec3, state: &GouraudShaderState) -> Option<RGBA8> {
@@ -95,10 +95,10 @@ impl Shader<GouraudShaderState> for GouraudShader<'_> {
         let unlit_color = if let Some(tex) = self.diffuse_texture {
             tex.get_pixel(uv)
         } else {
-            crab_tv::WHITE
+            crate::WHITE
         };
 
-        Some(unlit_color.map(|comp| (comp as f32 * weighted_light_intensity) as u8))
+        Some(unlit_color.map_rgb(|comp| (comp as f32 * weighted_light_intensity) as u8))
     }
 }
 
@@ -118,7 +118,8 @@ fn bucket_intensity(intensity: f32) -> f32 {
     }
 }
 
-type VertexUVs = [Vec2; 3];
+/// Texture coordinates and corrected normals of a triangle's vertices
+type VertexUVsAndNormals = ([Vec2; 3], Mat3);
 
 /// A shader that handles normals correctly based on a global normal map
 #[derive(Clone, Debug)]
@@ -130,8 +131,9 @@ pub struct NormalShader<'t> {
     uniform_mit: Mat4,
     light_dir: Vec3,
     diffuse_texture: &'t Texture,
-    /// normal texture must be in global coordinates (not tangent space)
-    normal_texture: &'t Texture,
+    /// normal texture must be in global coordinates (not tangent space); models without one use
+    /// their interpolated vertex normals
+    normal_texture: Option<&'t Texture>,
 }
 
 impl<'t> NormalShader<'t> {
@@ -140,7 +142,7 @@ impl<'t> NormalShader<'t> {
         uniform_m: Mat4,
         light_dir: Vec3,
         diffuse_texture: &'t Texture,
-        normal_texture_global: &'t Texture,
+        normal_texture_global: Option<&'t Texture>,
     ) -> NormalShader<'t> {
         Self {
             viewport,
@@ -153,45 +155,58 @@ impl<'t> NormalShader<'t> {
     }
 }
 
-impl Shader<VertexUVs> for NormalShader<'_> {
-    fn vertex(&self, input: [Vertex; 3]) -> (Mat3, VertexUVs) {
-        let mut varying_tri = Mat3::ZERO;
-        let mut varying_uv = [Vec2::ZERO; 3];
-        for (i, vert) in input.iter().enumerate() {
-            *varying_tri.col_mut(i) =
-                (self.viewport * self.uniform_m).project_point3(vert.position);
-
-            varying_uv[i] = Vec2::new(
-                vert.uv.x * self.diffuse_texture.width as f32,
-                vert.uv.y * self.diffuse_texture.height as f32,
-            );
-        }
+impl Shader<(Vec2, Vec3), VertexUVsAndNormals> for NormalShader<'_> {
+    fn vertex(&self, vert: &Vertex) -> (Vec3, (Vec2, Vec3)) {
+        (
+            (self.viewport * self.uniform_m).project_point3(vert.position),
+            (
+                Vec2::new(
+                    vert.uv.x * self.diffuse_texture.width as f32,
+                    vert.uv.y * self.diffuse_texture.height as f32,
+                ),
+                self.uniform_mit.transform_vector3(vert.normal.normalize()),
+            ),
+        )
+    }
 
-        (varying_tri, varying_uv)
+    fn triangle(&self, _screen_coords: Mat3, varying: [(Vec2, Vec3); 3]) -> VertexUVsAndNormals {
+        (
+            varying.map(|(uv, _)| uv),
+            Mat3::from_cols(varying[0].1, varying[1].1, varying[2].1),
+        )
     }
 
-    fn fragment(&self, barycentric_coords: Vec3, varying_uv: &VertexUVs) -> Option<RGBA8> {
+    fn fragment(
+        &self,
+        barycentric_coords: Vec3,
+        (varying_uv, varying_nrm): &VertexUVsAndNormals,
+    ) -> Option<RGBA8> {
         let uv = varying_uv[0] * barycentric_coords[0]
             + varying_uv[1] * barycentric_coords[1]
             + varying_uv[2] * barycentric_coords[2];
 
         // correct normals for the affine transformation done in vertex shader
-        let n = self
-            .uniform_mit
-            .project_point3(self.normal_texture.get_normal(uv))
-            .normalize();
+        let n = match self.normal_texture {
+            Some(normal_texture) => self
+                .uniform_mit
+                .project_point3(normal_texture.get_normal(uv))
+                .normalize(),
+            None => (*varying_nrm * barycentric_coords).normalize(),
+        };
         let l = self.uniform_m.project_point3(self.light_dir).normalize();
-        let intensity = crab_tv::yolo_max(0.0, n.dot(l));
+        let intensity = crate::yolo_max(0.0, n.dot(l));
 
         let unlit_color = self.diffuse_texture.get_pixel(uv);
 
-        Some(unlit_color.map(|comp| (comp as f32 * intensity) as u8))
+        Some(unlit_color.map_rgb(|comp| (comp as f32 * intensity) as u8))
     }
 }
 
 #[derive(Clone, Debug)]
 pub enum NormalMap<'t> {
-    GlobalSpace(&'t Texture),
+    /// A normal map in the model's coordinates, or the interpolated vertex normals for models
+    /// without one
+    GlobalSpace(Option<&'t Texture>),
     TangentSpace(&'t Texture),
 }
 
@@ -201,7 +216,7 @@ pub enum NormalMap<'t> {
 pub struct PhongShadowInput {
     // transform framebuffer screen coordinates to shadowbuffer screen coordinates for shadows
     uniform_m_shadow: Mat4,
-    shadow_buffer: Canvas,
+    shadow_buffer: Arc<Canvas>,
     shadow_multiplier: f32,
     // Require shadows to be this much longer (deeper), to avoid z-fighting
     shadow_z_fix: f32,
@@ -210,7 +225,7 @@ pub struct PhongShadowInput {
 impl PhongShadowInput {
     pub fn new(
         uniform_m_shadow: Mat4,
-        shadow_buffer: Canvas,
+        shadow_buffer: Arc<Canvas>,
         shadow_darkness: f32,
         shadow_z_fix: f32,
     ) -> Self {
@@ -223,10 +238,19 @@ impl PhongShadowInput {
     }
 }
 
+/// What the Phong shader's vertex stage passes on for each vertex.
+#[derive(Clone, Copy, Debug)]
+pub struct PhongVarying {
+    normal: Vec3,
+    uv: Vec2,
+    color: Vec4,
+}
+
 pub struct PhongShaderState {
     varying_tri: Mat3,
     varying_nrm: Mat3,
     varying_uv: [Vec2; 3],
+    varying_color: [Vec4; 3],
 }
 
 /// Phong shader renders using ambient/diffuse/specular lighting model, with normals rendered using
@@ -234,9 +258,10 @@ pub struct PhongShaderState {
 #[derive(Clone, Debug)]
 pub struct PhongShader<'t> {
     viewport: Mat4,
-    /// projection matrix * modelview matrix
+    /// projection matrix * modelview matrix * model matrix
     uniform_m: Mat4,
-    /// projection matrix * modelview matrix then inverted & transposed, for correcting normals
+    /// projection matrix * modelview matrix * model matrix then inverted & transposed, for
+    /// correcting normals
     uniform_mit: Mat4,
     light_dir: Vec3,
     /// Ambient, diffuse, specular lighting weights
@@ -245,28 +270,38 @@ pub struct PhongShader<'t> {
     /// normal texture must be in tangent space coordinates
     normal_texture: NormalMap<'t>,
     specular_texture: &'t Texture,
-    shadows: Option<PhongShadowInput>,
+    shadows: Option<&'t PhongShadowInput>,
     glow_texture: Option<&'t Texture>,
     base_shininess: f32,
+    /// Multiplier applied to the diffuse texture's alpha, for rendering translucent models
+    opacity: f32,
+    /// Multiplier applied to each channel of the diffuse texture
+    tint: Vec3,
+    /// Whether fragment colors are multiplied by their alpha, for `BlendMode::Premultiplied`
+    premultiplied_alpha: bool,
 }
 
 impl<'t> PhongShader<'t> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         viewport: Mat4,
         uniform_m: Mat4,
+        model_m: Mat4,
         light_dir: Vec3,
         phong_lighting_weights: Vec3,
         diffuse_texture: &'t Texture,
         normal_texture: NormalMap<'t>,
         specular_texture: &'t Texture,
-        shadows: Option<PhongShadowInput>,
+        shadows: Option<&'t PhongShadowInput>,
         glow_texture: Option<&'t Texture>,
         base_shininess: f32,
+        opacity: f32,
+        tint: Vec3,
     ) -> PhongShader<'t> {
         Self {
             viewport,
-            uniform_m,
-            uniform_mit: uniform_m.inverse().transpose(),
+            uniform_m: uniform_m * model_m,
+            uniform_mit: (uniform_m * model_m).inverse().transpose(),
             light_dir,
             phong_lighting_weights,
             diffuse_texture,
@@ -275,42 +310,62 @@ impl<'t> PhongShader<'t> {
             shadows,
             glow_texture,
             base_shininess,
+            opacity,
+            tint,
+            premultiplied_alpha: false,
         }
     }
-}
 
-impl Shader<PhongShaderState> for PhongShader<'_> {
-    fn vertex(&self, input: [Vertex; 3]) -> (Mat3, PhongShaderState) {
-        let mut varying_nrm = Mat3::ZERO;
-        let mut varying_tri = Mat3::ZERO;
-        let mut varying_uv = [Vec2::ZERO; 3];
-        for (i, vert) in input.iter().enumerate() {
-            *varying_nrm.col_mut(i) = self.uniform_mit.transform_vector3(vert.normal.normalize());
-
-            *varying_tri.col_mut(i) =
-                (self.viewport * self.uniform_m).project_point3(vert.position);
-
-            varying_uv[i] = Vec2::new(
-                vert.uv.x * self.diffuse_texture.width as f32,
-                vert.uv.y * self.diffuse_texture.height as f32,
-            );
+    /// Multiply the color of each fragment by its alpha, as `BlendMode::Premultiplied` expects.
+    pub fn with_premultiplied_alpha(self, premultiplied_alpha: bool) -> Self {
+        Self {
+            premultiplied_alpha,
+            ..self
         }
+    }
+}
 
+impl PhongShader<'_> {
+    /// Transform a vertex, returning its screen coordinates plus its normal, texture coordinates &
+    /// color.
+    fn transform_vertex(&self, vert: &Vertex) -> (Vec3, PhongVarying) {
         (
-            varying_tri,
-            PhongShaderState {
-                varying_nrm,
-                varying_tri,
-                varying_uv,
+            (self.viewport * self.uniform_m).project_point3(vert.position),
+            PhongVarying {
+                normal: self.uniform_mit.transform_vector3(vert.normal.normalize()),
+                uv: Vec2::new(
+                    vert.uv.x * self.diffuse_texture.width as f32,
+                    vert.uv.y * self.diffuse_texture.height as f32,
+                ),
+                color: vert.color,
             },
         )
     }
 
-    fn fragment(&self, barycentric_coords: Vec3, state: &PhongShaderState) -> Option<RGBA8> {
+    fn assemble_triangle(
+        &self,
+        varying_tri: Mat3,
+        vertices: [PhongVarying; 3],
+    ) -> PhongShaderState {
+        PhongShaderState {
+            varying_tri,
+            varying_nrm: Mat3::from_cols(
+                vertices[0].normal,
+                vertices[1].normal,
+                vertices[2].normal,
+            ),
+            varying_uv: vertices.map(|v| v.uv),
+            varying_color: vertices.map(|v| v.color),
+        }
+    }
+
+    /// Look up the material properties of the surface at this fragment.
+    fn surface(&self, barycentric_coords: Vec3, state: &PhongShaderState) -> GBufferTexel {
         let PhongShaderState {
             varying_tri,
             varying_uv,
             varying_nrm,
+            varying_color,
         } = *state;
 
         let uv = varying_uv[0] * barycentric_coords[0]
@@ -319,10 +374,12 @@ impl Shader<PhongShaderState> for PhongShader<'_> {
 
         // calculate normal for this fragment using the normal texture
         let n = match self.normal_texture {
-            NormalMap::GlobalSpace(normal_texture) => self
+            NormalMap::GlobalSpace(Some(normal_texture)) => self
                 .uniform_mit
                 .project_point3(normal_texture.get_normal(uv))
                 .normalize(),
+            // the vertex normals were already corrected in the vertex shader
+            NormalMap::GlobalSpace(None) => (varying_nrm * barycentric_coords).normalize(),
             NormalMap::TangentSpace(normal_texture) => {
                 let bn = (varying_nrm * barycentric_coords).normalize();
 
@@ -349,8 +406,10 @@ impl Shader<PhongShaderState> for PhongShader<'_> {
 
                 let b = {
                     let mut b = Mat3::ZERO;
-                    *b.col_mut(0) = i.normalize();
-                    *b.col_mut(1) = j.normalize();
+                    // models without texture coordinates have no tangents, so fall back to the
+                    // interpolated normal
+                    *b.col_mut(0) = i.normalize_or_zero();
+                    *b.col_mut(1) = j.normalize_or_zero();
                     *b.col_mut(2) = bn;
                     b
                 };
@@ -358,70 +417,166 @@ impl Shader<PhongShaderState> for PhongShader<'_> {
                 (b * normal_texture.get_normal(uv)).normalize()
             }
         };
-        let l = self.uniform_m.project_point3(self.light_dir).normalize();
-        let r = (n * (n.dot(l) * 2.0) - l).normalize(); // reflected light
 
-        // calculate lighting intensity for this pixel
-        let ambient_intensity = 1.0;
-        let diffuse_intensity = crab_tv::yolo_max(0.0, n.dot(self.light_dir));
-        let specular_intensity = crab_tv::yolo_max(0.0, r.z)
-            .powf(self.base_shininess + self.specular_texture.get_specular(uv));
+        let specular = self.specular_texture.get_specular(uv);
+
+        let emissive = if let Some(glow_texture) = &self.glow_texture {
+            glow_texture.get_pixel(uv)
+        } else {
+            crate::BLACK
+        };
 
-        // check if this pixel is shadowed according to the shadow buffer
-        let shadow_multiplier = if let Some(PhongShadowInput {
+        let albedo = self.diffuse_texture.get_pixel(uv);
+        let color = varying_color[0] * barycentric_coords[0]
+            + varying_color[1] * barycentric_coords[1]
+            + varying_color[2] * barycentric_coords[2];
+        let tint = self.tint.to_array();
+        let mut channels = [albedo.r, albedo.g, albedo.b];
+        for ((c, t), vc) in channels.iter_mut().zip(tint).zip(color.to_array()) {
+            // round after multiplying by the vertex color, so that white leaves textures as they are
+            *c = ((*c as f32 * vc).round() * t).min(255.0) as u8;
+        }
+        let alpha = (albedo.a as f32 * color.w).round() as u8;
+
+        GBufferTexel {
+            albedo: RGBA8::new(channels[0], channels[1], channels[2], alpha),
+            normal: n,
+            specular,
+            shininess: self.base_shininess + specular,
+            emissive,
+        }
+    }
+
+    /// Check if the given screen position is shadowed according to the shadow buffer, or `None` if
+    /// shadows are disabled.
+    fn is_shadowed(&self, screen_pos: Vec3) -> Option<bool> {
+        let PhongShadowInput {
             uniform_m_shadow,
             ref shadow_buffer,
-            shadow_multiplier,
             shadow_z_fix,
-        }) = &self.shadows
+            ..
+        } = self.shadows?;
+
+        // look up corresponding point in the shadow buffer
+        let sb_p = {
+            let p = *uniform_m_shadow * screen_pos.extend(1.0);
+            (p / p.w).truncate() // convert from homogenous coordinates back to vec3
+        };
+        // anything outside the light's view can't have anything between it and the light
+        let (x, y) = (sb_p.x.floor() as i32, sb_p.y.floor() as i32);
+        if x < 0 || y < 0 || x >= shadow_buffer.width() as i32 || y >= shadow_buffer.height() as i32
         {
-            let uniform_m_shadow = uniform_m_shadow.to_owned();
-
-            // look up corresponding point in the shadow buffer
-            let sb_p = {
-                let p = uniform_m_shadow * (varying_tri * barycentric_coords).extend(1.0);
-                (p / p.w).truncate() // convert from homogenous coordinates back to vec3
-            };
-            let shaded = (shadow_buffer.pixel(sb_p.x as i32, sb_p.y as i32).r as f32)
-                >= sb_p.z + shadow_z_fix;
-            if shaded {
-                *shadow_multiplier
-            } else {
-                1.0
-            }
-        } else {
-            1.0
+            return Some(false);
+        }
+        Some((shadow_buffer.pixel(x, y).r as f32) >= sb_p.z + shadow_z_fix)
+    }
+
+    /// Calculate the lit color of a surface at the given screen position (including depth).
+    fn light(&self, surface: &GBufferTexel, screen_pos: Vec3) -> RGBA8 {
+        let n = surface.normal;
+        let l = self.uniform_m.project_point3(self.light_dir).normalize();
+        let r = (n * (n.dot(l) * 2.0) - l).normalize(); // reflected light
+
+        // calculate lighting intensity for this pixel
+        let ambient_intensity = 1.0;
+        let diffuse_intensity = crate::yolo_max(0.0, n.dot(self.light_dir));
+        let specular_intensity = crate::yolo_max(0.0, r.z).powf(surface.shininess);
+
+        let shadow_multiplier = match (&self.shadows, self.is_shadowed(screen_pos)) {
+            (Some(shadows), Some(true)) => shadows.shadow_multiplier,
+            _ => 1.0,
         };
 
-        let glow = if let Some(glow_texture) = &self.glow_texture {
-            glow_texture.get_pixel(uv)
-        } else {
-            crab_tv::BLACK
-        }
-        .map(f32::from);
+        let glow = surface.emissive.map(f32::from);
 
         // phong shading weights of each light component
         let ambient_weight = self.phong_lighting_weights.x;
         let diffuse_weight = self.phong_lighting_weights.y;
         let specular_weight = self.phong_lighting_weights.z;
 
-        let unlit_color = self.diffuse_texture.get_pixel(uv);
+        let unlit_color = surface.albedo;
+
+        let lit_color: RGB8 = unlit_color
+            .rgb()
+            .iter()
+            .map(|c| {
+                ambient_weight * ambient_intensity
+                    + (c as f32 * shadow_multiplier)
+                        * (diffuse_weight * diffuse_intensity
+                            + specular_weight * specular_intensity)
+            })
+            .zip(glow.rgb().iter())
+            .map(|(phong_comp, glow_comp)| {
+                crate::yolo_max(phong_comp + glow_comp, glow_comp).min(255.0) as u8
+            })
+            .collect();
+
+        lit_color.alpha((unlit_color.a as f32 * self.opacity).min(255.0) as u8)
+    }
 
-        Some(
-            unlit_color
-                .iter()
-                .map(|c| {
-                    ambient_weight * ambient_intensity
-                        + (c as f32 * shadow_multiplier)
-                            * (diffuse_weight * diffuse_intensity
-                                + specular_weight * specular_intensity)
-                })
-                .zip(glow.iter())
-                .map(|(phong_comp, glow_comp)| {
-                    crab_tv::yolo_max(phong_comp + glow_comp, glow_comp).min(255.0) as u8
-                })
-                .collect(),
-        )
+    /// The lighting pass of deferred shading: light every pixel of the G-buffer that was drawn to
+    /// by the geometry pass.
+    pub fn light_gbuffer(&self, image: &mut Canvas, gbuffer: &GBuffer) {
+        for y in 0..gbuffer.height() as i32 {
+            for x in 0..gbuffer.width() as i32 {
+                if let Some(surface) = gbuffer.texel(x, y) {
+                    let screen_pos = Vec3::new(x as f32, y as f32, gbuffer.depth(x, y));
+                    *image.pixel_mut(x, y) = self.light(surface, screen_pos);
+                    if let Some(record) = image.fragment_mut(x, y) {
+                        record.shadowed = self.is_shadowed(screen_pos);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Shader<PhongVarying, PhongShaderState> for PhongShader<'_> {
+    fn vertex(&self, vert: &Vertex) -> (Vec3, PhongVarying) {
+        self.transform_vertex(vert)
+    }
+
+    fn triangle(&self, screen_coords: Mat3, vertices: [PhongVarying; 3]) -> PhongShaderState {
+        self.assemble_triangle(screen_coords, vertices)
+    }
+
+    fn fragment(&self, barycentric_coords: Vec3, state: &PhongShaderState) -> Option<RGBA8> {
+        let surface = self.surface(barycentric_coords, state);
+        let color = self.light(&surface, state.varying_tri * barycentric_coords);
+        if self.premultiplied_alpha {
+            let alpha = color.a as f32 / 255.0;
+            Some(
+                color
+                    .rgb()
+                    .map(|c| (c as f32 * alpha).round() as u8)
+                    .alpha(color.a),
+            )
+        } else {
+            Some(color)
+        }
+    }
+
+    fn inspect(
+        &self,
+        barycentric_coords: Vec3,
+        state: &PhongShaderState,
+        record: &mut FragmentRecord,
+    ) {
+        record.shadowed = self.is_shadowed(state.varying_tri * barycentric_coords);
+    }
+}
+
+impl GeometryShader<PhongVarying, PhongShaderState> for PhongShader<'_> {
+    fn vertex(&self, vert: &Vertex) -> (Vec3, PhongVarying) {
+        self.transform_vertex(vert)
+    }
+
+    fn triangle(&self, screen_coords: Mat3, vertices: [PhongVarying; 3]) -> PhongShaderState {
+        self.assemble_triangle(screen_coords, vertices)
+    }
+
+    fn fragment(&self, barycentric_coords: Vec3, state: &PhongShaderState) -> Option<GBufferTexel> {
+        Some(self.surface(barycentric_coords, state))
     }
 }
 
@@ -460,28 +615,23 @@ impl<'t> UnlitShader<'t> {
     }
 }
 
-impl Shader<UnlitShaderState> for UnlitShader<'_> {
-    fn vertex(&self, input: [Vertex; 3]) -> (Mat3, UnlitShaderState) {
-        let mut varying_tri = Mat3::ZERO;
-        let mut varying_uv = [Vec2::ZERO; 3];
-        for (i, vert) in input.iter().enumerate() {
-            *varying_tri.col_mut(i) = self.vertex_transform.project_point3(vert.position);
-
-            if let Some(texture) = self.texture {
-                varying_uv[i] = Vec2::new(
-                    vert.uv.x * texture.width as f32,
-                    vert.uv.y * texture.height as f32,
-                )
-            }
-        }
+impl Shader<Vec2, UnlitShaderState> for UnlitShader<'_> {
+    fn vertex(&self, vert: &Vertex) -> (Vec3, Vec2) {
+        let uv = match self.texture {
+            Some(texture) => Vec2::new(
+                vert.uv.x * texture.width as f32,
+                vert.uv.y * texture.height as f32,
+            ),
+            None => Vec2::ZERO,
+        };
+        (self.vertex_transform.project_point3(vert.position), uv)
+    }
 
-        (
-            varying_tri,
-            UnlitShaderState {
-                varying_uv,
-                triangle_color: crab_tv::random_color(),
-            },
-        )
+    fn triangle(&self, _screen_coords: Mat3, varying_uv: [Vec2; 3]) -> UnlitShaderState {
+        UnlitShaderState {
+            varying_uv,
+            triangle_color: crate::random_color(),
+        }
     }
 
     fn fragment(&self, barycentric_coords: Vec3, state: &UnlitShaderState) -> Option<RGBA8> {
@@ -522,21 +672,22 @@ impl DepthShader {
     }
 }
 
-impl Shader<DepthVaryingTri> for DepthShader {
-    fn vertex(&self, input: [Vertex; 3]) -> (Mat3, DepthVaryingTri) {
-        let mut varying_tri = Mat3::ZERO;
-        for (i, vert) in input.iter().enumerate() {
-            *varying_tri.col_mut(i) =
-                (self.viewport * self.uniform_m).project_point3(vert.position);
-        }
+impl Shader<(), DepthVaryingTri> for DepthShader {
+    fn vertex(&self, vert: &Vertex) -> (Vec3, ()) {
+        (
+            (self.viewport * self.uniform_m).project_point3(vert.position),
+            (),
+        )
+    }
 
-        (varying_tri, varying_tri)
+    fn triangle(&self, screen_coords: Mat3, _vertices: [(); 3]) -> DepthVaryingTri {
+        screen_coords
     }
 
     fn fragment(&self, barycentric_coords: Vec3, varying_tri: &DepthVaryingTri) -> Option<RGBA8> {
         let p = (*varying_tri) * barycentric_coords;
-        let depth_scaled = p.z / crab_tv::DEPTH_MAX;
-        Some(crab_tv::WHITE.map(|c| (c as f32 * depth_scaled) as u8))
+        let depth_scaled = p.z / crate::DEPTH_MAX;
+        Some(crate::WHITE.map_rgb(|c| (c as f32 * depth_scaled) as u8))
     }
 }
 
@@ -557,18 +708,17 @@ impl PureColorShader {
     }
 }
 
-impl Shader<()> for PureColorShader {
-    fn vertex(&self, input: [Vertex; 3]) -> (Mat3, ()) {
-        let mut varying_tri = Mat3::ZERO;
-        for (i, vert) in input.iter().enumerate() {
-            *varying_tri.col_mut(i) =
-                (self.viewport * self.uniform_m).project_point3(vert.position);
-        }
-
-        (varying_tri, ())
+impl Shader<(), ()> for PureColorShader {
+    fn vertex(&self, vert: &Vertex) -> (Vec3, ()) {
+        (
+            (self.viewport * self.uniform_m).project_point3(vert.position),
+            (),
+        )
     }
 
+    fn triangle(&self, _screen_coords: Mat3, _vertices: [(); 3]) {}
+
     fn fragment(&self, _barycentric_coords: Vec3, _: &()) -> Option<RGBA8> {
-        Some(crab_tv::WHITE)
+        Some(crate::WHITE)
     }
 }
@@ -0,0 +1,521 @@
+//! Mesh simplification by quadric error metrics (Garland & Heckbert), so that big models can be
+//! drawn quickly while the view is moving.
+//!
+//! Edges are collapsed one at a time, cheapest first, by moving one end onto the other. Each
+//! position's quadric measures how far a point is from the planes of the faces that were around
+//! it, so a collapse costs how far it moves the surface. Because vertices only ever move onto
+//! existing vertices, every vertex keeps its texture coordinates and normal. Boundaries and texture
+//! seams get extra planes along them to keep them in place, and a collapse is skipped if it would
+//! drag texture coordinates across a seam, fold a face over or join up the mesh in a non-manifold
+//! way.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet},
+    sync::Arc,
+};
+
+use glam::DVec3;
+
+use crate::{model::face_tangents, Model};
+
+/// How much more moving a boundary or texture seam costs than moving the surface the same distance.
+const SEAM_WEIGHT: f64 = 100.0;
+/// Levels of detail stop once halving the face count again would go below this.
+const MIN_LOD_FACES: usize = 500;
+
+impl Model {
+    /// A copy of this model simplified to at most `target_faces` faces, or as close to that as
+    /// possible without tearing texture seams or folding faces over.
+    pub fn simplified(&self, target_faces: usize) -> Model {
+        let mut simplifier = Simplifier::new(self);
+        simplifier.collapse_until(target_faces);
+        simplifier.into_model(self)
+    }
+}
+
+/// Progressively simplified copies of a model, each with about half the faces of the one before.
+#[derive(Clone, Debug, Default)]
+pub struct LodChain {
+    /// From most to least detailed, not including the original model
+    pub levels: Vec<Arc<Model>>,
+}
+
+impl LodChain {
+    pub fn new(model: &Model) -> Self {
+        let mut levels: Vec<Arc<Model>> = Vec::new();
+        loop {
+            let previous = levels.last().map_or(model, |level| level);
+            let target = previous.face_count() / 2;
+            if target < MIN_LOD_FACES {
+                break;
+            }
+            let level = previous.simplified(target);
+            // stop once collapses are mostly being refused, as later levels would barely differ
+            if level.face_count() * 10 > previous.face_count() * 9 {
+                break;
+            }
+            levels.push(Arc::new(level));
+        }
+        LodChain { levels }
+    }
+
+    /// The most detailed level with at most `max_faces` faces (or the least detailed level if none
+    /// are that simple), or `None` if there are no levels.
+    pub fn pick(&self, max_faces: usize) -> Option<&Arc<Model>> {
+        self.levels
+            .iter()
+            .find(|level| level.face_count() <= max_faces)
+            .or(self.levels.last())
+    }
+}
+
+/// A symmetric 4x4 matrix giving the sum of squared distances from a point to a set of planes.
+#[derive(Clone, Copy, Debug, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    /// Squared distance to the plane through `point` with unit normal `normal`, times `weight`.
+    fn plane(normal: DVec3, point: DVec3, weight: f64) -> Self {
+        let [a, b, c] = normal.to_array();
+        let d = -normal.dot(point);
+        Quadric(
+            [
+                a * a,
+                a * b,
+                a * c,
+                a * d,
+                b * b,
+                b * c,
+                b * d,
+                c * c,
+                c * d,
+                d * d,
+            ]
+            .map(|q| q * weight),
+        )
+    }
+
+    fn error(&self, p: DVec3) -> f64 {
+        let [aa, ab, ac, ad, bb, bc, bd, cc, cd, dd] = self.0;
+        let (x, y, z) = (p.x, p.y, p.z);
+        aa * x * x
+            + 2.0 * ab * x * y
+            + 2.0 * ac * x * z
+            + 2.0 * ad * x
+            + bb * y * y
+            + 2.0 * bc * y * z
+            + 2.0 * bd * y
+            + cc * z * z
+            + 2.0 * cd * z
+            + dd
+    }
+
+    fn add(&mut self, other: &Quadric) {
+        for (q, o) in self.0.iter_mut().zip(other.0) {
+            *q += o;
+        }
+    }
+}
+
+/// Moving the `remove` position onto the `keep` position, costing `cost`. Only valid while both
+/// positions are still at the versions they were when it was worked out.
+struct Collapse {
+    cost: f64,
+    keep: usize,
+    remove: usize,
+    versions: (u32, u32),
+}
+
+impl PartialEq for Collapse {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Collapse {}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Collapse {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.cost.total_cmp(&other.cost)
+    }
+}
+
+/// A model's faces being simplified. Collapses work on positions rather than vertices, since a
+/// position on a texture seam has a vertex for each side of the seam.
+struct Simplifier {
+    /// Position of each vertex
+    position_of: Vec<usize>,
+    positions: Vec<DVec3>,
+    quadrics: Vec<Quadric>,
+    /// Incremented whenever a position's quadric or neighbours change
+    versions: Vec<u32>,
+    removed: Vec<bool>,
+    /// Faces that have (or had) a corner at each position
+    position_faces: Vec<Vec<usize>>,
+    faces: Vec<[u32; 3]>,
+    face_alive: Vec<bool>,
+    live_faces: usize,
+    queue: BinaryHeap<Reverse<Collapse>>,
+}
+
+impl Simplifier {
+    fn new(model: &Model) -> Self {
+        let mut position_ids = HashMap::new();
+        let mut positions = Vec::new();
+        let position_of: Vec<usize> = model
+            .vertices
+            .iter()
+            .map(|vertex| {
+                *position_ids
+                    .entry(vertex.position.to_array().map(f32::to_bits))
+                    .or_insert_with(|| {
+                        positions.push(vertex.position.as_dvec3());
+                        positions.len() - 1
+                    })
+            })
+            .collect();
+
+        let mut quadrics = vec![Quadric::default(); positions.len()];
+        let mut position_faces = vec![Vec::new(); positions.len()];
+        let mut faces = Vec::with_capacity(model.face_count());
+        // the faces along each edge, with the vertices at the edge's lower & higher position
+        let mut edges = HashMap::<_, Vec<_>>::new();
+        for face in 0..model.face_count() {
+            let vertices = model.face(face);
+            let ids = vertices.map(|v| position_of[v as usize]);
+            if ids[0] == ids[1] || ids[1] == ids[2] || ids[2] == ids[0] {
+                continue;
+            }
+            let face = faces.len();
+            faces.push(vertices);
+
+            let [p0, p1, p2] = ids.map(|id| positions[id]);
+            let normal = (p1 - p0).cross(p2 - p0);
+            let plane = Quadric::plane(normal.normalize_or_zero(), p0, normal.length() / 2.0);
+            for (i, &id) in ids.iter().enumerate() {
+                quadrics[id].add(&plane);
+                position_faces[id].push(face);
+                let (j, next) = ((i + 1) % 3, ids[(i + 1) % 3]);
+                let edge_vertices = if id < next {
+                    [vertices[i], vertices[j]]
+                } else {
+                    [vertices[j], vertices[i]]
+                };
+                edges
+                    .entry((id.min(next), id.max(next)))
+                    .or_default()
+                    .push((face, edge_vertices));
+            }
+        }
+
+        // hold boundaries & seams in place with planes through them, perpendicular to their faces
+        for (&(a, b), sides) in &edges {
+            let boundary = sides.len() == 1;
+            let seam = sides.iter().any(|(_, vertices)| *vertices != sides[0].1);
+            if !boundary && !seam {
+                continue;
+            }
+            let edge = positions[b] - positions[a];
+            for &(face, _) in sides {
+                let [p0, p1, p2] = faces[face].map(|v| positions[position_of[v as usize]]);
+                let face_normal = (p1 - p0).cross(p2 - p0).normalize_or_zero();
+                let normal = edge.cross(face_normal).normalize_or_zero();
+                let plane =
+                    Quadric::plane(normal, positions[a], SEAM_WEIGHT * edge.length_squared());
+                quadrics[a].add(&plane);
+                quadrics[b].add(&plane);
+            }
+        }
+
+        let live_faces = faces.len();
+        let mut simplifier = Simplifier {
+            position_of,
+            versions: vec![0; positions.len()],
+            removed: vec![false; positions.len()],
+            positions,
+            quadrics,
+            position_faces,
+            face_alive: vec![true; faces.len()],
+            faces,
+            live_faces,
+            queue: BinaryHeap::new(),
+        };
+        for &(a, b) in edges.keys() {
+            simplifier.queue_collapse(a, b);
+        }
+        simplifier
+    }
+
+    /// Queue collapsing the edge between two positions, whichever way round costs less.
+    fn queue_collapse(&mut self, a: usize, b: usize) {
+        let mut quadric = self.quadrics[a];
+        quadric.add(&self.quadrics[b]);
+        let (cost_a, cost_b) = (
+            quadric.error(self.positions[a]),
+            quadric.error(self.positions[b]),
+        );
+        let (keep, remove, cost) = if cost_a <= cost_b {
+            (a, b, cost_a)
+        } else {
+            (b, a, cost_b)
+        };
+        self.queue.push(Reverse(Collapse {
+            cost,
+            keep,
+            remove,
+            versions: (self.versions[keep], self.versions[remove]),
+        }));
+    }
+
+    fn collapse_until(&mut self, target_faces: usize) {
+        while self.live_faces > target_faces {
+            let Some(Reverse(collapse)) = self.queue.pop() else {
+                break;
+            };
+            let (keep, remove) = (collapse.keep, collapse.remove);
+            if self.removed[keep]
+                || self.removed[remove]
+                || collapse.versions != (self.versions[keep], self.versions[remove])
+            {
+                continue;
+            }
+            if let Some(remap) = self.plan_collapse(keep, remove) {
+                self.collapse(keep, remove, &remap);
+            } else if let Some(remap) = self.plan_collapse(remove, keep) {
+                self.collapse(remove, keep, &remap);
+            }
+        }
+    }
+
+    fn live_faces_at(&self, position: usize) -> impl Iterator<Item = usize> + '_ {
+        self.position_faces[position]
+            .iter()
+            .copied()
+            .filter(|&face| self.face_alive[face])
+    }
+
+    fn neighbours(&self, position: usize) -> HashSet<usize> {
+        self.live_faces_at(position)
+            .flat_map(|face| self.faces[face])
+            .map(|v| self.position_of[v as usize])
+            .filter(|&p| p != position)
+            .collect()
+    }
+
+    /// Check whether `remove` can be moved onto `keep`, returning which vertex at `keep` each
+    /// vertex at `remove` becomes if so.
+    fn plan_collapse(&self, keep: usize, remove: usize) -> Option<HashMap<u32, u32>> {
+        let corner_at = |face: usize, position: usize| {
+            self.faces[face]
+                .into_iter()
+                .find(|&v| self.position_of[v as usize] == position)
+        };
+
+        // the faces along the edge disappear, and show which vertices on either side match up
+        let mut remap = HashMap::new();
+        let mut shared_faces = 0;
+        for face in self.live_faces_at(remove) {
+            if let Some(kept) = corner_at(face, keep) {
+                let removed = corner_at(face, remove)?;
+                remap.entry(removed).or_insert(kept);
+                shared_faces += 1;
+            }
+        }
+        if shared_faces == 0 {
+            return None;
+        }
+        // more shared neighbours than faces along the edge would pinch the surface together
+        let shared_neighbours = self
+            .neighbours(keep)
+            .intersection(&self.neighbours(remove))
+            .count();
+        if shared_neighbours > shared_faces {
+            return None;
+        }
+
+        for face in self.live_faces_at(remove) {
+            let vertices = self.faces[face];
+            if vertices
+                .iter()
+                .any(|&v| self.position_of[v as usize] == keep)
+            {
+                continue;
+            }
+            // a vertex on the far side of a seam has no match at `keep` to take its place
+            if vertices
+                .iter()
+                .any(|&v| self.position_of[v as usize] == remove && !remap.contains_key(&v))
+            {
+                return None;
+            }
+            let before = vertices.map(|v| self.positions[self.position_of[v as usize]]);
+            let after = before.map(|p| {
+                if p == self.positions[remove] {
+                    self.positions[keep]
+                } else {
+                    p
+                }
+            });
+            let normal = |[p0, p1, p2]: [DVec3; 3]| (p1 - p0).cross(p2 - p0);
+            if normal(before).dot(normal(after)) <= 0.0 {
+                return None;
+            }
+        }
+        Some(remap)
+    }
+
+    fn collapse(&mut self, keep: usize, remove: usize, remap: &HashMap<u32, u32>) {
+        for face in std::mem::take(&mut self.position_faces[remove]) {
+            if !self.face_alive[face] {
+                continue;
+            }
+            let vertices = &mut self.faces[face];
+            if vertices
+                .iter()
+                .any(|&v| self.position_of[v as usize] == keep)
+            {
+                self.face_alive[face] = false;
+                self.live_faces -= 1;
+            } else {
+                for v in vertices.iter_mut() {
+                    if let Some(&kept) = remap.get(v) {
+                        *v = kept;
+                    }
+                }
+                self.position_faces[keep].push(face);
+            }
+        }
+        let removed_quadric = self.quadrics[remove];
+        self.quadrics[keep].add(&removed_quadric);
+        self.removed[remove] = true;
+        self.versions[keep] += 1;
+
+        let face_alive = &self.face_alive;
+        self.position_faces[keep].retain(|&face| face_alive[face]);
+        for neighbour in self.neighbours(keep) {
+            self.queue_collapse(keep, neighbour);
+        }
+    }
+
+    fn into_model(self, model: &Model) -> Model {
+        let mut new_index = vec![None; model.vertices.len()];
+        let mut vertices = Vec::new();
+        let mut indices = Vec::with_capacity(self.live_faces * 3);
+        for (face, vertex_indices) in self.faces.iter().enumerate() {
+            if !self.face_alive[face] {
+                continue;
+            }
+            for &v in vertex_indices {
+                let index = *new_index[v as usize].get_or_insert_with(|| {
+                    vertices.push(model.vertices[v as usize]);
+                    vertices.len() as u32 - 1
+                });
+                indices.push(index);
+            }
+        }
+        Model {
+            tangents: face_tangents(&vertices, &indices),
+            vertices,
+            indices,
+            polygon_sizes: Vec::new(),
+            diffuse_texture: model.diffuse_texture.clone(),
+            normal_texture_global: model.normal_texture_global.clone(),
+            normal_texture_darboux: model.normal_texture_darboux.clone(),
+            specular_texture: model.specular_texture.clone(),
+            glow_texture: model.glow_texture.clone(),
+            skeleton: model.skeleton.clone(),
+            file_counts: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Vec2, Vec3};
+
+    use super::*;
+    use crate::Vertex;
+
+    /// A bumpy square made of a grid of `size` by `size` quads.
+    fn bumpy_grid(size: usize) -> Model {
+        let vertices: Vec<Vertex> = (0..=size)
+            .flat_map(|y| (0..=size).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let uv = Vec2::new(x as f32, y as f32) / size as f32;
+                let height = (uv.x * 7.0).sin() * (uv.y * 5.0).cos() * 0.2;
+                Vertex {
+                    position: (uv * 2.0 - Vec2::ONE).extend(height),
+                    uv,
+                    normal: Vec3::Z,
+                    ..Vertex::default()
+                }
+            })
+            .collect();
+        let corner = |x: usize, y: usize| (y * (size + 1) + x) as u32;
+        let indices: Vec<u32> = (0..size)
+            .flat_map(|y| (0..size).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                let [a, b, c, d] = [
+                    corner(x, y),
+                    corner(x + 1, y),
+                    corner(x + 1, y + 1),
+                    corner(x, y + 1),
+                ];
+                [a, b, c, a, c, d]
+            })
+            .collect();
+        Model {
+            tangents: face_tangents(&vertices, &indices),
+            vertices,
+            indices,
+            ..Model::plane()
+        }
+    }
+
+    #[test]
+    fn lods_should_halve_faces_without_tearing_the_mesh() {
+        let model = bumpy_grid(32);
+        let original = model.report();
+        let lods = LodChain::new(&model);
+        let face_counts: Vec<_> = lods.levels.iter().map(|level| level.face_count()).collect();
+        assert!(face_counts.len() >= 2, "{:?}", face_counts);
+        // each collapse removes one or two faces, so levels can end up a face short of half
+        let mut previous = model.face_count();
+        for &count in &face_counts {
+            assert!(
+                (previous / 2 - 1..=previous / 2).contains(&count),
+                "{:?}",
+                face_counts
+            );
+            previous = count;
+        }
+        assert!(previous / 2 < MIN_LOD_FACES);
+
+        for level in &lods.levels {
+            let report = level.report();
+            assert_eq!(report.degenerate_faces, 0);
+            assert_eq!(report.non_manifold_edges, 0);
+            // collapsing along a boundary shortens it, but nothing should tear new holes
+            assert!(report.boundary_edges <= original.boundary_edges);
+            // vertices only move onto other vertices, so every vertex is one of the original's
+            assert!(level
+                .vertices
+                .iter()
+                .all(|vertex| model.vertices.contains(vertex)));
+        }
+        let [first, second] = [face_counts[0], face_counts[1]];
+        assert_eq!(lods.pick(first).map(|m| m.face_count()), Some(first));
+        assert_eq!(lods.pick(first - 1).map(|m| m.face_count()), Some(second));
+        assert_eq!(
+            lods.pick(1).map(|m| m.face_count()),
+            face_counts.last().copied()
+        );
+    }
+}
@@ -0,0 +1,348 @@
+//! Skeletal animation: skeletons whose joints move the vertices bound to them, and clips that
+//! animate those joints over time.
+//!
+//! Each vertex of a skinned model names up to 4 joints along with how much each one moves it. To
+//! draw a pose, every joint gets a matrix which moves vertices from where they were when the skin
+//! was bound to where that joint has taken them, and shaders move each vertex by the weighted sum
+//! of its joints' matrices before projecting it.
+
+use glam::{Mat4, Quat, Vec3, Vec4};
+
+use crate::Vertex;
+
+/// A joint's transform relative to its parent: scaled, then rotated, then moved.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct JointTransform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl Default for JointTransform {
+    fn default() -> Self {
+        Self {
+            translation: Vec3::ZERO,
+            rotation: Quat::IDENTITY,
+            scale: Vec3::ONE,
+        }
+    }
+}
+
+impl JointTransform {
+    pub fn matrix(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(self.scale, self.rotation, self.translation)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Joint {
+    pub name: String,
+    /// The node that animation clips move this joint by
+    pub node: usize,
+    /// Index of the joint this one hangs off, if any
+    pub parent: Option<usize>,
+    /// From the parent joint's space (or the model's, for joints without a parent) to the space
+    /// this joint's transform is relative to
+    pub parent_offset: Mat4,
+    /// Where the joint is when no clip is playing
+    pub rest: JointTransform,
+    /// From the model's space to the joint's, as it was when the skin was bound to it
+    pub inverse_bind: Mat4,
+}
+
+/// The joints that a skinned model's vertices are bound to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Skeleton {
+    pub joints: Vec<Joint>,
+}
+
+impl Skeleton {
+    /// The matrix of each joint for the pose `clip` has it in at `time` seconds (looping), or for
+    /// the rest pose without a clip.
+    pub fn joint_matrices(&self, clip: Option<&AnimationClip>, time: f32) -> Vec<Mat4> {
+        let time = match clip {
+            Some(clip) if clip.duration > 0.0 => time.rem_euclid(clip.duration),
+            _ => 0.0,
+        };
+        let local: Vec<Mat4> = self
+            .joints
+            .iter()
+            .map(|joint| {
+                let transform = match clip {
+                    Some(clip) => clip.pose(joint.node, joint.rest, time),
+                    None => joint.rest,
+                };
+                joint.parent_offset * transform.matrix()
+            })
+            .collect();
+
+        // joints can come before their parents, so each joint's transform in the model is worked
+        // out (once) by following its parents up to the root
+        fn model_transform(
+            joint: usize,
+            joints: &[Joint],
+            local: &[Mat4],
+            found: &mut [Option<Mat4>],
+        ) -> Mat4 {
+            if let Some(transform) = found[joint] {
+                return transform;
+            }
+            let transform = match joints[joint].parent {
+                Some(parent) => model_transform(parent, joints, local, found) * local[joint],
+                None => local[joint],
+            };
+            found[joint] = Some(transform);
+            transform
+        }
+        let mut found = vec![None; self.joints.len()];
+        (0..self.joints.len())
+            .map(|joint| {
+                model_transform(joint, &self.joints, &local, &mut found)
+                    * self.joints[joint].inverse_bind
+            })
+            .collect()
+    }
+}
+
+/// How values are found between a channel's keyframes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Keep the previous keyframe's value until the next one
+    Step,
+    /// Blend linearly (or spherically, for rotations) between keyframes
+    Linear,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChannelValues {
+    Translation(Vec<Vec3>),
+    Rotation(Vec<Quat>),
+    Scale(Vec<Vec3>),
+}
+
+/// Keyframes for one part of one node's transform.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnimationChannel {
+    pub node: usize,
+    /// In seconds, ascending, with a value for each
+    pub times: Vec<f32>,
+    pub values: ChannelValues,
+    pub interpolation: Interpolation,
+}
+
+/// A named animation, such as a walk cycle, moving the joints of a model file's skeletons.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnimationClip {
+    pub name: String,
+    /// Time of the last keyframe, in seconds
+    pub duration: f32,
+    pub channels: Vec<AnimationChannel>,
+}
+
+impl AnimationClip {
+    /// The transform of a node at `time`, with whatever the clip doesn't animate left at `rest`.
+    pub fn pose(&self, node: usize, rest: JointTransform, time: f32) -> JointTransform {
+        let mut pose = rest;
+        for channel in self.channels.iter().filter(|channel| channel.node == node) {
+            match &channel.values {
+                ChannelValues::Translation(values) => {
+                    pose.translation = channel
+                        .sample(values, time, Vec3::lerp)
+                        .unwrap_or(pose.translation)
+                }
+                ChannelValues::Rotation(values) => {
+                    pose.rotation = channel
+                        .sample(values, time, Quat::slerp)
+                        .unwrap_or(pose.rotation)
+                }
+                ChannelValues::Scale(values) => {
+                    pose.scale = channel
+                        .sample(values, time, Vec3::lerp)
+                        .unwrap_or(pose.scale)
+                }
+            }
+        }
+        pose
+    }
+}
+
+impl AnimationChannel {
+    /// The value at `time`, holding the first and last keyframes' values before and after them.
+    fn sample<T: Copy>(
+        &self,
+        values: &[T],
+        time: f32,
+        blend: impl Fn(T, T, f32) -> T,
+    ) -> Option<T> {
+        let next = self.times.partition_point(|&t| t <= time);
+        if next == 0 {
+            return values.first().copied();
+        } else if next >= self.times.len() {
+            return values.get(self.times.len() - 1).copied();
+        }
+        let (before, after) = (values[next - 1], values[next]);
+        match self.interpolation {
+            Interpolation::Step => Some(before),
+            Interpolation::Linear => {
+                let (start, end) = (self.times[next - 1], self.times[next]);
+                Some(blend(before, after, (time - start) / (end - start)))
+            }
+        }
+    }
+}
+
+impl Vertex {
+    /// This vertex moved by the weighted sum of its joints' matrices. Vertices without any weights
+    /// aren't bound to any joints, so they stay where they are.
+    pub fn skinned(&self, joint_matrices: &[Mat4]) -> Vertex {
+        let total_weight = self.weights.dot(Vec4::ONE);
+        if total_weight <= 0.0 {
+            return *self;
+        }
+        let matrix = self
+            .joints
+            .iter()
+            .zip(self.weights.to_array())
+            .filter(|&(_, weight)| weight > 0.0)
+            .filter_map(|(&joint, weight)| Some(*joint_matrices.get(joint as usize)? * weight))
+            .fold(Mat4::ZERO, |sum, m| sum + m)
+            * (1.0 / total_weight);
+        Vertex {
+            position: matrix.transform_point3(self.position),
+            normal: matrix.transform_vector3(self.normal).normalize_or_zero(),
+            ..*self
+        }
+    }
+
+    /// This vertex moved into the pose given by `joint_matrices`, or as it is without any.
+    pub fn posed(&self, joint_matrices: Option<&[Mat4]>) -> Vertex {
+        match joint_matrices {
+            Some(joint_matrices) => self.skinned(joint_matrices),
+            None => *self,
+        }
+    }
+}
+
+/// Blend the joints & weights of several vertices into the (up to) 4 most influential joints,
+/// with weights adding up to 1.
+pub(crate) fn blend_skins(skins: impl Iterator<Item = ([u16; 4], Vec4)>) -> ([u16; 4], Vec4) {
+    let mut influences: Vec<(u16, f32)> = Vec::new();
+    for (joints, weights) in skins {
+        for (joint, weight) in joints.into_iter().zip(weights.to_array()) {
+            match influences.iter_mut().find(|(j, _)| *j == joint) {
+                Some((_, total)) => *total += weight,
+                None => influences.push((joint, weight)),
+            }
+        }
+    }
+    influences.retain(|&(_, weight)| weight > 0.0);
+    influences.sort_by(|a, b| b.1.total_cmp(&a.1));
+    influences.truncate(4);
+
+    let total_weight: f32 = influences.iter().map(|(_, weight)| weight).sum();
+    let mut joints = [0; 4];
+    let mut weights = [0.0; 4];
+    for (i, (joint, weight)) in influences.into_iter().enumerate() {
+        joints[i] = joint;
+        weights[i] = weight / total_weight;
+    }
+    (joints, Vec4::from(weights))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vertices_should_follow_their_animated_joints() {
+        // an arm along x: the shoulder at the origin, and the elbow 1 along from it
+        let skeleton = Skeleton {
+            joints: vec![
+                Joint {
+                    name: "Elbow".to_owned(),
+                    node: 1,
+                    parent: Some(1),
+                    parent_offset: Mat4::IDENTITY,
+                    rest: JointTransform {
+                        translation: Vec3::X,
+                        ..JointTransform::default()
+                    },
+                    inverse_bind: Mat4::from_translation(-Vec3::X),
+                },
+                Joint {
+                    name: "Shoulder".to_owned(),
+                    node: 0,
+                    parent: None,
+                    parent_offset: Mat4::IDENTITY,
+                    rest: JointTransform::default(),
+                    inverse_bind: Mat4::IDENTITY,
+                },
+            ],
+        };
+        // over a second, the shoulder turns a quarter turn around z
+        let clip = AnimationClip {
+            name: "Wave".to_owned(),
+            duration: 1.0,
+            channels: vec![AnimationChannel {
+                node: 0,
+                times: vec![0.0, 1.0],
+                values: ChannelValues::Rotation(vec![
+                    Quat::IDENTITY,
+                    Quat::from_rotation_z(std::f32::consts::FRAC_PI_2),
+                ]),
+                interpolation: Interpolation::Linear,
+            }],
+        };
+        let hand = Vertex {
+            position: Vec3::new(2.0, 0.0, 0.0),
+            normal: Vec3::Y,
+            joints: [0, 1, 0, 0],
+            weights: Vec4::new(0.5, 0.5, 0.0, 0.0),
+            ..Vertex::default()
+        };
+
+        let rest = skeleton.joint_matrices(None, 0.0);
+        assert!(hand
+            .skinned(&rest)
+            .position
+            .abs_diff_eq(hand.position, 1e-6));
+
+        // just before the end of the clip, the whole arm has turned to point up
+        let raised = skeleton.joint_matrices(Some(&clip), 0.999_999);
+        let skinned = hand.skinned(&raised);
+        assert!(skinned.position.abs_diff_eq(Vec3::new(0.0, 2.0, 0.0), 1e-4));
+        assert!(skinned.normal.abs_diff_eq(-Vec3::X, 1e-4));
+        // halfway through it's turned halfway, and then it loops back to the start
+        let halfway = hand.skinned(&skeleton.joint_matrices(Some(&clip), 1.5));
+        let diagonal = Vec3::new(std::f32::consts::SQRT_2, std::f32::consts::SQRT_2, 0.0);
+        assert!(halfway.position.abs_diff_eq(diagonal, 1e-4));
+
+        // vertices which aren't bound to joints stay put
+        let unbound = Vertex::default();
+        assert_eq!(unbound.skinned(&raised), unbound);
+    }
+
+    #[test]
+    fn blended_skins_should_keep_the_most_influential_joints() {
+        let (joints, weights) = blend_skins(
+            [
+                ([1, 2, 0, 0], Vec4::new(0.75, 0.25, 0.0, 0.0)),
+                ([3, 2, 0, 0], Vec4::new(0.5, 0.5, 0.0, 0.0)),
+            ]
+            .into_iter(),
+        );
+        assert_eq!(joints, [1, 2, 3, 0]);
+        assert_eq!(weights, Vec4::new(0.375, 0.375, 0.25, 0.0));
+
+        // only 4 joints fit, so the lightest is dropped and the rest make up its weight
+        let (joints, weights) = blend_skins(
+            [
+                ([1, 2, 0, 0], Vec4::new(0.5, 0.5, 0.0, 0.0)),
+                ([3, 4, 5, 0], Vec4::new(0.5, 0.375, 0.125, 0.0)),
+            ]
+            .into_iter(),
+        );
+        assert_eq!(joints, [1, 2, 3, 4]);
+        assert!(weights.abs_diff_eq(Vec4::new(0.5, 0.5, 0.5, 0.375) / 1.875, 1e-6));
+    }
+}
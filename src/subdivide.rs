@@ -0,0 +1,515 @@
+//! Subdivision surfaces, for drawing low-poly models smoothly.
+//!
+//! Subdivision works on positions rather than vertices, so the surface stays smooth across texture
+//! seams, while texture coordinates, colors and skin weights are interpolated linearly within each
+//! face so that seams stay where they are. Boundaries, and edges where the model's normals are
+//! split (i.e. intentionally hard edges), are creases: they're subdivided as curves of their own
+//! rather than being smoothed into the faces around them. Normals are recalculated from the
+//! smoothed surface.
+
+use std::collections::{BTreeMap, HashMap};
+
+use glam::{Vec2, Vec3, Vec4};
+
+use crate::{
+    model::{face_tangents, index_vertices},
+    skinning::blend_skins,
+    Model, Vertex,
+};
+
+/// How to smooth a model by subdividing its faces.
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::EnumIter,
+    PartialEq,
+    Eq,
+    Hash,
+    strum::Display,
+)]
+pub enum SubdivisionScheme {
+    #[default]
+    Off,
+    /// Split each triangle into 4 triangles
+    Loop,
+    /// Split each face into quads (a polygon with n corners into n quads, then each quad into 4),
+    /// which are only split into triangles at the end. Starts from the polygons in the model's file
+    /// rather than the triangles they were split into.
+    #[strum(serialize = "Catmull-Clark")]
+    CatmullClark,
+}
+
+impl Model {
+    /// A copy of this model with its faces subdivided `levels` times by `scheme`.
+    pub fn subdivided(&self, scheme: SubdivisionScheme, levels: usize) -> Model {
+        // Loop subdivision only works on triangles
+        let faces = match scheme {
+            SubdivisionScheme::CatmullClark => self.polygons(),
+            SubdivisionScheme::Off | SubdivisionScheme::Loop => (0..self.face_count())
+                .map(|face| self.face(face).to_vec())
+                .collect(),
+        };
+        let mut mesh = PolyMesh::new(self, &faces);
+        for _ in 0..levels {
+            mesh = match scheme {
+                SubdivisionScheme::Off => break,
+                SubdivisionScheme::Loop => mesh.loop_step(),
+                SubdivisionScheme::CatmullClark => mesh.catmull_clark_step(),
+            };
+        }
+        mesh.into_model(self)
+    }
+}
+
+/// A corner of a face: its position, plus the attributes that are interpolated across the face.
+#[derive(Clone, Copy, Debug)]
+struct Corner {
+    position: usize,
+    uv: Vec2,
+    /// Only used to tell which faces should be smooth where they meet, as normals are calculated
+    /// from the subdivided surface at the end
+    normal: Vec3,
+    color: Vec4,
+    joints: [u16; 4],
+    weights: Vec4,
+}
+
+impl Corner {
+    /// The average of some corners' attributes, at another position.
+    fn average(position: usize, corners: &[Corner]) -> Corner {
+        let n = corners.len() as f32;
+        let (joints, weights) = blend_skins(corners.iter().map(|c| (c.joints, c.weights)));
+        Corner {
+            position,
+            uv: corners
+                .iter()
+                .map(|c| c.uv)
+                .fold(Vec2::ZERO, |sum, v| sum + v)
+                / n,
+            normal: corners
+                .iter()
+                .map(|c| c.normal)
+                .fold(Vec3::ZERO, |sum, v| sum + v)
+                / n,
+            color: corners
+                .iter()
+                .map(|c| c.color)
+                .fold(Vec4::ZERO, |sum, v| sum + v)
+                / n,
+            joints,
+            weights,
+        }
+    }
+}
+
+/// Faces with any number of corners.
+struct PolyMesh {
+    positions: Vec<Vec3>,
+    faces: Vec<Vec<Corner>>,
+}
+
+/// How the faces of a mesh join up.
+struct Topology {
+    /// Each edge, by its positions in ascending order, with the faces along it
+    edges: BTreeMap<(usize, usize), Vec<usize>>,
+    /// Positions joined to each position by an edge
+    neighbours: Vec<Vec<usize>>,
+    /// Positions joined to each position by a crease
+    crease_neighbours: Vec<Vec<usize>>,
+    /// Faces around each position
+    position_faces: Vec<Vec<usize>>,
+}
+
+impl PolyMesh {
+    /// The faces of a model, given as indices into its vertices.
+    fn new(model: &Model, faces: &[Vec<u32>]) -> Self {
+        let mut position_ids = HashMap::new();
+        let mut positions = Vec::new();
+        let faces = faces
+            .iter()
+            .map(|face| {
+                face.iter()
+                    .map(|&index| {
+                        let vertex = &model.vertices[index as usize];
+                        Corner {
+                            position: *position_ids
+                                .entry(vertex.position.to_array().map(f32::to_bits))
+                                .or_insert_with(|| {
+                                    positions.push(vertex.position);
+                                    positions.len() - 1
+                                }),
+                            uv: vertex.uv,
+                            normal: vertex.normal,
+                            color: vertex.color,
+                            joints: vertex.joints,
+                            weights: vertex.weights,
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        PolyMesh { positions, faces }
+    }
+
+    /// The corners of a face along each of its edges.
+    fn face_edges(face: &[Corner]) -> impl Iterator<Item = (Corner, Corner)> + '_ {
+        (0..face.len()).map(|i| (face[i], face[(i + 1) % face.len()]))
+    }
+
+    fn topology(&self) -> Topology {
+        let mut edges: BTreeMap<(usize, usize), Vec<usize>> = BTreeMap::new();
+        let mut position_faces = vec![Vec::new(); self.positions.len()];
+        for (f, face) in self.faces.iter().enumerate() {
+            for (a, b) in Self::face_edges(face) {
+                let key = (a.position.min(b.position), a.position.max(b.position));
+                edges.entry(key).or_default().push(f);
+                position_faces[a.position].push(f);
+            }
+        }
+
+        let mut neighbours = vec![Vec::new(); self.positions.len()];
+        let mut crease_neighbours = vec![Vec::new(); self.positions.len()];
+        for (&(a, b), faces) in &edges {
+            neighbours[a].push(b);
+            neighbours[b].push(a);
+            if self.is_crease(a, b, faces) {
+                crease_neighbours[a].push(b);
+                crease_neighbours[b].push(a);
+            }
+        }
+        Topology {
+            edges,
+            neighbours,
+            crease_neighbours,
+            position_faces,
+        }
+    }
+
+    /// Whether the edge between two positions should stay sharp: it's on a boundary, shared by more
+    /// than two faces, or the faces on either side have different normals along it.
+    fn is_crease(&self, a: usize, b: usize, faces: &[usize]) -> bool {
+        let normal_at = |face: usize, position: usize| {
+            self.faces[face]
+                .iter()
+                .find(|corner| corner.position == position)
+                .map(|corner| corner.normal)
+        };
+        match faces {
+            [f, g] => normal_at(*f, a) != normal_at(*g, a) || normal_at(*f, b) != normal_at(*g, b),
+            _ => true,
+        }
+    }
+
+    /// The new position of an existing position, given how the surface around it is smoothed.
+    fn vertex_point(&self, topology: &Topology, p: usize, smooth: impl Fn() -> Vec3) -> Vec3 {
+        let v = self.positions[p];
+        match topology.crease_neighbours[p][..] {
+            // a crease is smoothed along itself as a curve
+            [a, b] => 0.75 * v + 0.125 * (self.positions[a] + self.positions[b]),
+            // the surface is smooth here (treating the end of a crease as smooth too)
+            [] | [_] => smooth(),
+            // where creases meet there's a corner, which stays put
+            _ => v,
+        }
+    }
+
+    /// Split each triangle into 4 by adding a position in the middle of each edge, then move every
+    /// position towards a weighted average of its neighbours.
+    fn loop_step(&self) -> Self {
+        let topology = self.topology();
+        let mut positions: Vec<Vec3> = (0..self.positions.len())
+            .map(|p| {
+                self.vertex_point(&topology, p, || {
+                    let n = topology.neighbours[p].len() as f32;
+                    let beta = if n == 3.0 {
+                        3.0 / 16.0
+                    } else {
+                        3.0 / (8.0 * n)
+                    };
+                    let neighbour_sum = topology.neighbours[p]
+                        .iter()
+                        .map(|&q| self.positions[q])
+                        .fold(Vec3::ZERO, |sum, v| sum + v);
+                    (1.0 - n * beta) * self.positions[p] + beta * neighbour_sum
+                })
+            })
+            .collect();
+
+        let edge_points = self.edge_points(&topology, &mut positions, |a, b, faces| {
+            // weigh in the corners opposite the edge in both triangles
+            let opposite = faces
+                .iter()
+                .filter_map(|&f| {
+                    self.faces[f]
+                        .iter()
+                        .find(|c| c.position != a && c.position != b)
+                })
+                .map(|c| self.positions[c.position])
+                .fold(Vec3::ZERO, |sum, v| sum + v);
+            0.375 * (self.positions[a] + self.positions[b]) + 0.125 * opposite
+        });
+
+        let mut faces = Vec::with_capacity(self.faces.len() * 4);
+        for face in &self.faces {
+            let mids: Vec<Corner> = Self::face_edges(face)
+                .map(|(a, b)| Corner::average(edge_points[&edge_key(a, b)], &[a, b]))
+                .collect();
+            if let ([c0, c1, c2], [m01, m12, m20]) = (&face[..], &mids[..]) {
+                faces.extend([
+                    vec![*c0, *m01, *m20],
+                    vec![*m01, *c1, *m12],
+                    vec![*m20, *m12, *c2],
+                    vec![*m01, *m12, *m20],
+                ]);
+            }
+        }
+        PolyMesh { positions, faces }
+    }
+
+    /// Split each face into quads by adding a position in the middle of the face and of each edge,
+    /// then move every position towards the average of the faces and edges around it.
+    fn catmull_clark_step(&self) -> Self {
+        let topology = self.topology();
+        let face_points: Vec<Vec3> = self
+            .faces
+            .iter()
+            .map(|face| {
+                face.iter()
+                    .map(|c| self.positions[c.position])
+                    .fold(Vec3::ZERO, |sum, v| sum + v)
+                    / face.len() as f32
+            })
+            .collect();
+        let mut positions: Vec<Vec3> = (0..self.positions.len())
+            .map(|p| {
+                self.vertex_point(&topology, p, || {
+                    let faces = &topology.position_faces[p];
+                    let neighbours = &topology.neighbours[p];
+                    let n = neighbours.len() as f32;
+                    let face_average = faces
+                        .iter()
+                        .map(|&f| face_points[f])
+                        .fold(Vec3::ZERO, |sum, v| sum + v)
+                        / faces.len() as f32;
+                    let edge_average = neighbours
+                        .iter()
+                        .map(|&q| (self.positions[p] + self.positions[q]) / 2.0)
+                        .fold(Vec3::ZERO, |sum, v| sum + v)
+                        / n;
+                    (face_average + 2.0 * edge_average + (n - 3.0) * self.positions[p]) / n
+                })
+            })
+            .collect();
+
+        let edge_points = self.edge_points(&topology, &mut positions, |a, b, faces| {
+            let face_sum = faces
+                .iter()
+                .fold(Vec3::ZERO, |sum, &f| sum + face_points[f]);
+            (self.positions[a] + self.positions[b] + face_sum) / 4.0
+        });
+
+        let mut faces = Vec::with_capacity(self.faces.len() * 4);
+        for (f, face) in self.faces.iter().enumerate() {
+            let center = Corner::average(positions.len(), face);
+            positions.push(face_points[f]);
+            let mids: Vec<Corner> = Self::face_edges(face)
+                .map(|(a, b)| Corner::average(edge_points[&edge_key(a, b)], &[a, b]))
+                .collect();
+            for i in 0..face.len() {
+                let before = mids[(i + face.len() - 1) % face.len()];
+                faces.push(vec![face[i], mids[i], center, before]);
+            }
+        }
+        PolyMesh { positions, faces }
+    }
+
+    /// Add a position along each edge, at the midpoint of creases or where `smooth` puts it
+    /// otherwise. Returns the new position of each edge.
+    fn edge_points(
+        &self,
+        topology: &Topology,
+        positions: &mut Vec<Vec3>,
+        smooth: impl Fn(usize, usize, &[usize]) -> Vec3,
+    ) -> HashMap<(usize, usize), usize> {
+        topology
+            .edges
+            .iter()
+            .map(|(&(a, b), faces)| {
+                let point = if self.is_crease(a, b, faces) {
+                    (self.positions[a] + self.positions[b]) / 2.0
+                } else {
+                    smooth(a, b, faces)
+                };
+                positions.push(point);
+                ((a, b), positions.len() - 1)
+            })
+            .collect()
+    }
+
+    /// Split the faces into triangles and give each vertex the normal of the surface around it,
+    /// averaged only over faces that are meant to be smooth there.
+    fn into_model(self, model: &Model) -> Model {
+        let triangles: Vec<[Corner; 3]> = self
+            .faces
+            .iter()
+            .flat_map(|face| (2..face.len()).map(move |i| [face[0], face[i - 1], face[i]]))
+            .collect();
+
+        // corners at the same position with the same interpolated normal are on the same smooth
+        // part of the surface
+        let group = |c: &Corner| (c.position, c.normal.to_array().map(f32::to_bits));
+        let mut normals: HashMap<_, Vec3> = HashMap::new();
+        for triangle in &triangles {
+            let [p0, p1, p2] = triangle.map(|c| self.positions[c.position]);
+            // not normalized, so bigger faces count for more
+            let face_normal = (p1 - p0).cross(p2 - p0);
+            for corner in triangle {
+                *normals.entry(group(corner)).or_default() += face_normal;
+            }
+        }
+
+        let (vertices, indices) = index_vertices(triangles.iter().flatten().map(|corner| {
+            let normal = normals[&group(corner)].normalize_or_zero();
+            Vertex {
+                position: self.positions[corner.position],
+                uv: corner.uv,
+                // keep normals pointing the same way as the model's, whichever way its faces wind
+                normal: if normal.dot(corner.normal) < 0.0 {
+                    -normal
+                } else {
+                    normal
+                },
+                color: corner.color,
+                joints: corner.joints,
+                weights: corner.weights,
+            }
+        }));
+        Model {
+            tangents: face_tangents(&vertices, &indices),
+            vertices,
+            indices,
+            polygon_sizes: Vec::new(),
+            diffuse_texture: model.diffuse_texture.clone(),
+            normal_texture_global: model.normal_texture_global.clone(),
+            normal_texture_darboux: model.normal_texture_darboux.clone(),
+            specular_texture: model.specular_texture.clone(),
+            glow_texture: model.glow_texture.clone(),
+            skeleton: model.skeleton.clone(),
+            file_counts: None,
+        }
+    }
+}
+
+fn edge_key(a: Corner, b: Corner) -> (usize, usize) {
+    (a.position.min(b.position), a.position.max(b.position))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cube made of 6 quads split into 12 triangles, sharing 8 positions, with smooth normals
+    /// pointing out from its center.
+    fn cube() -> Model {
+        let corners: Vec<Vec3> = (0..8)
+            .map(|i| Vec3::new((i & 1) as f32, (i >> 1 & 1) as f32, (i >> 2) as f32) * 2.0 - 1.0)
+            .collect();
+        let quads = [
+            [0, 2, 3, 1],
+            [4, 5, 7, 6],
+            [0, 1, 5, 4],
+            [2, 6, 7, 3],
+            [0, 4, 6, 2],
+            [1, 3, 7, 5],
+        ];
+        let vertices: Vec<Vertex> = corners
+            .iter()
+            .map(|&position| Vertex {
+                position,
+                normal: position.normalize(),
+                ..Vertex::default()
+            })
+            .collect();
+        let indices = quads
+            .iter()
+            .flat_map(|[a, b, c, d]| [*a, *b, *c, *a, *c, *d])
+            .collect();
+        Model {
+            vertices,
+            indices,
+            polygon_sizes: vec![4; 6],
+            ..Model::plane()
+        }
+    }
+
+    #[test]
+    fn subdivided_cube_should_round_off_but_keep_creases() {
+        let cube = cube();
+        for (scheme, faces) in [
+            (SubdivisionScheme::Loop, 12 * 4 * 4),
+            (SubdivisionScheme::CatmullClark, 6 * 4 * 4 * 2),
+        ] {
+            let smooth = cube.subdivided(scheme, 2);
+            assert_eq!(smooth.face_count(), faces, "{}", scheme);
+            let report = smooth.report();
+            assert_eq!(report.boundary_edges, 0, "{}", scheme);
+            assert_eq!(report.inconsistent_winding_edges, 0, "{}", scheme);
+            // the corners are pulled in towards the center, and every normal points outwards
+            let furthest = smooth
+                .vertices
+                .iter()
+                .map(|v| v.position.length())
+                .fold(0.0, f32::max);
+            assert!(furthest < 3f32.sqrt() * 0.9, "{}: {}", scheme, furthest);
+            assert!(
+                smooth
+                    .vertices
+                    .iter()
+                    .all(|v| v.normal.dot(v.position) > 0.0),
+                "{}",
+                scheme
+            );
+        }
+
+        // Catmull-Clark starts from the quads, so every corner is rounded off the same (where the
+        // triangles' diagonals would pull some corners in further than others)
+        let smooth = cube.subdivided(SubdivisionScheme::CatmullClark, 2);
+        let corner_reach: Vec<f32> = (0..8)
+            .map(|i| {
+                let direction = Vec3::new((i & 1) as f32, (i >> 1 & 1) as f32, (i >> 2) as f32);
+                smooth
+                    .vertices
+                    .iter()
+                    .map(|v| v.position.dot(direction * 2.0 - 1.0))
+                    .fold(f32::MIN, f32::max)
+            })
+            .collect();
+        let spread = corner_reach.iter().fold(f32::MIN, |max, &r| max.max(r))
+            - corner_reach.iter().fold(f32::MAX, |min, &r| min.min(r));
+        assert!(spread < 1e-5, "{:?}", corner_reach);
+        // without its quads, the cube is subdivided from its triangles
+        let triangles = Model {
+            polygon_sizes: Vec::new(),
+            ..cube
+        };
+        assert_eq!(
+            triangles
+                .subdivided(SubdivisionScheme::CatmullClark, 1)
+                .face_count(),
+            12 * 3 * 2
+        );
+
+        // the plane's edges are all boundaries, so it stays a square with its corners in place
+        let plane = Model::plane().subdivided(SubdivisionScheme::Loop, 1);
+        assert_eq!(plane.face_count(), 8);
+        let report = plane.report();
+        assert_eq!(
+            report.bounds,
+            Some((Vec3::new(-1.0, 0.0, -1.0), Vec3::new(1.0, 0.0, 1.0)))
+        );
+    }
+}
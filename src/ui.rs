@@ -1,6 +1,13 @@
-use std::path::PathBuf;
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
-use crab_tv::{Canvas, Model, DEPTH_MAX};
+use crab_tv::{
+    BlendMode, CullMode, ImageDiff, ResampleFilter, SceneStats, SubdivisionScheme, Winding,
+    DEPTH_MAX,
+};
 use eframe::{
     egui::{self, TextureId},
     epi,
@@ -9,14 +16,46 @@ use glam::Vec3;
 use rgb::RGBA8;
 use strum::IntoEnumIterator;
 
-use crate::{RenderConfig, RenderInput, RenderScene};
+use crate::{
+    animation::{AnimationFormat, AnimationPath, AnimationSettings, Keyframe},
+    assets::AssetCache,
+    buffer_view::{self, BufferView},
+    compare::CompareMode,
+    scene_objects::SceneObject,
+    worker::{FinishedRender, RenderJob, RenderUpdate},
+    AntiAliasing, RenderConfig, RenderInput, RenderScene, MAX_SUBDIVISION_LEVELS,
+};
+
+/// How long the config must stay unchanged before a preview render is refined to full quality.
+const REFINE_DELAY: Duration = Duration::from_millis(300);
+
+/// How many renders' statistics to keep, for graphing.
+const STATS_HISTORY_LEN: usize = 60;
+
+/// Storage key for the animation export settings, which are saved alongside the render config.
+const ANIMATION_KEY: &str = "animation";
+
+/// Radians to rotate the camera or light by per point that the mouse is dragged.
+const VIEW_ROTATE_SPEED: f32 = 0.01;
+/// How much scrolling changes the camera distance, as an exponent per point scrolled.
+const VIEW_DOLLY_SPEED: f32 = 0.002;
+/// Width in points of the handle for dragging the wipe between the reference and current render.
+const WIPE_HANDLE_WIDTH: f32 = 12.0;
 
 #[derive(Debug, Default)]
 struct UiData {
     last_render_width: usize,
     last_render_height: usize,
+    /// Size to display the render at when displaying at actual size, which is larger than the
+    /// render itself for previews
+    last_render_display_size: (usize, usize),
     last_render_pixels: Vec<RGBA8>,
     last_render_tex: Option<TextureId>,
+    /// The finished render and what was used to draw it, kept around for inspecting
+    last_render: Option<FinishedRender>,
+    /// Texture showing one of the last render's buffers, for the buffer view and false color
+    /// setting it was drawn with (or no texture if the render doesn't have that buffer)
+    buffer_tex: Option<(BufferView, bool, Option<TextureId>)>,
 }
 
 impl UiData {
@@ -24,6 +63,7 @@ impl UiData {
         Self {
             last_render_width: width,
             last_render_height: height,
+            last_render_display_size: (width, height),
             last_render_pixels: vec![
                 RGBA8 {
                     r: 0,
@@ -42,6 +82,45 @@ impl UiData {
             tex_allocator.free(existing_tex);
             self.last_render_tex = None;
         }
+        self.clear_buffer_texture(tex_allocator);
+    }
+
+    fn clear_buffer_texture(&mut self, tex_allocator: &mut dyn eframe::epi::TextureAllocator) {
+        if let Some((_, _, Some(existing_tex))) = self.buffer_tex {
+            tex_allocator.free(existing_tex);
+        }
+        self.buffer_tex = None;
+    }
+
+    /// Make sure the texture showing the chosen buffer of the last render is up to date.
+    fn update_buffer_texture(
+        &mut self,
+        view: BufferView,
+        false_color: bool,
+        tex_allocator: &mut dyn eframe::epi::TextureAllocator,
+    ) {
+        if view == BufferView::Color
+            || matches!(self.buffer_tex, Some((v, f, _)) if v == view && f == false_color)
+        {
+            return;
+        }
+        self.clear_buffer_texture(tex_allocator);
+
+        let tex = self
+            .last_render
+            .as_ref()
+            .and_then(|render| buffer_view::buffer_pixels(view, render, false_color))
+            .map(|(width, height, pixels)| alloc_texture(tex_allocator, (width, height), &pixels));
+        self.buffer_tex = Some((view, false_color, tex));
+    }
+
+    /// The texture to display for the chosen buffer view, falling back to the color buffer if the
+    /// last render doesn't have the chosen buffer.
+    fn displayed_texture(&self, view: BufferView) -> Option<TextureId> {
+        match self.buffer_tex {
+            Some((v, _, Some(tex))) if v == view => Some(tex),
+            _ => self.last_render_tex,
+        }
     }
 
     fn store_image(
@@ -55,18 +134,15 @@ impl UiData {
         );
 
         self.last_render_pixels = pixels.to_vec();
+        self.clear_buffer_texture(tex_allocator);
 
         if let Some(existing_tex) = self.last_render_tex {
             tex_allocator.free(existing_tex);
         }
-        let tex_pixels = self
-            .last_render_pixels
-            .iter()
-            .map(|rgb| egui::Color32::from_rgba_premultiplied(rgb.r, rgb.g, rgb.b, 255))
-            .collect::<Vec<_>>();
-        self.last_render_tex = Some(tex_allocator.alloc_srgba_premultiplied(
+        self.last_render_tex = Some(alloc_texture(
+            tex_allocator,
             (self.last_render_width, self.last_render_height),
-            &tex_pixels,
+            &self.last_render_pixels,
         ));
     }
 
@@ -95,11 +171,91 @@ impl UiData {
     }
 }
 
+/// A render pinned for comparing later renders against.
+#[derive(Debug)]
+struct ReferenceRender {
+    width: usize,
+    height: usize,
+    pixels: Vec<RGBA8>,
+    tex: TextureId,
+    /// How the displayed render differs from this one, and a texture of the difference heatmap,
+    /// or `None` if they're different sizes
+    diff: Option<(ImageDiff, TextureId)>,
+    /// Whether a different image has been displayed since the diff was worked out
+    diff_outdated: bool,
+}
+
+impl ReferenceRender {
+    fn free_textures(&mut self, tex_allocator: &mut dyn eframe::epi::TextureAllocator) {
+        tex_allocator.free(self.tex);
+        if let Some((_, diff_tex)) = self.diff.take() {
+            tex_allocator.free(diff_tex);
+        }
+    }
+
+    /// Make sure the diff against the displayed render is up to date.
+    fn update_diff(
+        &mut self,
+        data: &UiData,
+        tex_allocator: &mut dyn eframe::epi::TextureAllocator,
+    ) {
+        if !self.diff_outdated {
+            return;
+        }
+        self.diff_outdated = false;
+        if let Some((_, diff_tex)) = self.diff.take() {
+            tex_allocator.free(diff_tex);
+        }
+        if (data.last_render_width, data.last_render_height) == (self.width, self.height) {
+            let diff = crab_tv::diff_images(&self.pixels, &data.last_render_pixels);
+            let tex = alloc_texture(tex_allocator, (self.width, self.height), &diff.heatmap);
+            self.diff = Some((diff, tex));
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct RendererApp {
     config: RenderConfig,
     data: Option<UiData>,
-    cached_model: Option<(PathBuf, Model)>,
+    assets: AssetCache,
+    /// Generation of the asset cache when the last render was started, to re-render when assets
+    /// are reloaded
+    asset_generation: u64,
+    render_job: Option<RenderJob>,
+    /// Whether the camera or light was moved by interacting with the render view
+    view_moved: bool,
+    /// Statistics of the most recently finished renders, oldest first
+    stats_history: VecDeque<SceneStats>,
+    /// Which of the last render's buffers to display
+    buffer_view: BufferView,
+    /// Whether to display buffers of single values in false color rather than grayscale
+    buffer_false_color: bool,
+    /// Pixel of the last render shown in the pixel inspector
+    inspected_pixel: Option<(usize, usize)>,
+    /// Whether the inspected pixel was clicked on, rather than just hovered over
+    inspected_pixel_pinned: bool,
+    /// Render to compare the current render against
+    reference: Option<ReferenceRender>,
+    compare_mode: CompareMode,
+    /// Fraction of the render's width, from the left, that shows the reference in the wipe layout
+    wipe_position: f32,
+    /// Size the in-flight render should be displayed at
+    render_display_size: (usize, usize),
+    /// When to replace the preview being displayed with a full quality render
+    refine_at: Option<Instant>,
+    /// Name of the pass the in-flight render is on, and how far through that pass it is
+    render_progress: Option<(String, f32)>,
+    render_error: Option<String>,
+    animation: AnimationSettings,
+    /// How many frames of the animation being exported have been saved, out of how many
+    animation_progress: Option<(usize, usize)>,
+    /// Where the last animation export was saved
+    animation_saved: Option<PathBuf>,
+    /// Whether the last model export succeeded, and where it was saved or why it failed
+    model_export_result: Option<Result<PathBuf, String>>,
+    /// The model export running on a background thread, which sends back its result
+    model_export: Option<flume::Receiver<Result<PathBuf, String>>>,
 }
 
 impl RendererApp {
@@ -107,15 +263,31 @@ impl RendererApp {
         RendererApp {
             config: Default::default(),
             data: Default::default(),
-            cached_model: None,
+            assets: Default::default(),
+            asset_generation: 0,
+            render_job: None,
+            view_moved: false,
+            stats_history: VecDeque::with_capacity(STATS_HISTORY_LEN),
+            buffer_view: BufferView::default(),
+            buffer_false_color: true,
+            inspected_pixel: None,
+            inspected_pixel_pinned: false,
+            reference: None,
+            compare_mode: CompareMode::default(),
+            wipe_position: 0.5,
+            render_display_size: (0, 0),
+            refine_at: None,
+            render_progress: None,
+            render_error: None,
+            animation: AnimationSettings::default(),
+            animation_progress: None,
+            animation_saved: None,
+            model_export_result: None,
+            model_export: None,
         }
     }
 
-    fn trigger_render(
-        &mut self,
-        input: RenderInput,
-        tex_allocator: &mut dyn eframe::epi::TextureAllocator,
-    ) {
+    fn trigger_render(&mut self, input: RenderInput, frame: &mut epi::Frame<'_>) {
         println!(
             "Triggering render of {width}x{height} image (total {count} pixels)",
             width = self.config.width,
@@ -123,59 +295,463 @@ impl RendererApp {
             count = self.config.image_pixel_count(),
         );
 
-        // reset UI state
-        if let Some(ref mut d) = self.data {
-            d.clear_texture(tex_allocator);
+        self.refine_at = None;
+        self.spawn_render_job(input.clone(), (input.width, input.height), frame);
+    }
+
+    /// Render a quick preview of the input, then render it at full quality once the config has
+    /// stopped changing (or straight away if progressive refinement is off).
+    fn trigger_progressive_render(&mut self, input: RenderInput, frame: &mut epi::Frame<'_>) {
+        let preview = if self.config.progressive_refinement {
+            input.preview(
+                self.config.preview_scale,
+                self.config
+                    .preview_lods
+                    .then_some(self.config.preview_lod_faces),
+            )
+        } else {
+            None
+        };
+        match preview {
+            Some(preview) => {
+                println!(
+                    "Triggering preview render of {width}x{height} image",
+                    width = preview.width,
+                    height = preview.height,
+                );
+                self.spawn_render_job(preview, (input.width, input.height), frame);
+                self.refine_at = Some(Instant::now() + REFINE_DELAY);
+            }
+            None => self.trigger_render(input, frame),
         }
-        self.data = Some(UiData::new(self.config.width, self.config.height));
+    }
 
-        // render new image
-        let mut image = Canvas::new(input.width, input.height);
+    fn spawn_render_job(
+        &mut self,
+        input: RenderInput,
+        display_size: (usize, usize),
+        frame: &mut epi::Frame<'_>,
+    ) {
+        // replacing any in-flight render cancels it, so that we don't have to wait for it
+        self.render_job = Some(RenderJob::spawn(
+            input,
+            self.assets.clone(),
+            frame.repaint_signal(),
+        ));
+        self.render_display_size = display_size;
+        self.render_progress = None;
+        self.render_error = None;
+        self.animation_progress = None;
+    }
 
-        let model_cache = &mut self.cached_model;
-        if let Some((path, _)) = model_cache {
-            if path != input.model_input.path() {
-                model_cache.take();
+    /// What's in the main model's file and any problems with its geometry, e.g. to work out why it
+    /// renders oddly.
+    fn model_report(&mut self, ui: &mut egui::Ui) {
+        let render = match self
+            .data
+            .as_ref()
+            .and_then(|data| data.last_render.as_ref())
+        {
+            Some(render) => render,
+            None => {
+                ui.label("Render the model to see a report on it");
+                return;
             }
+        };
+        let reports = &render.model_reports;
+        for warning in &render.model_file.warnings {
+            ui.colored_label(egui::Color32::YELLOW, warning);
         }
-        if model_cache.is_none() {
-            model_cache.replace((
-                input.model_input.path().to_owned(),
-                Model::load_obj_file(&input.model_input).expect("Failed to load model"),
-            ));
+        for (i, report) in reports.iter().enumerate() {
+            if reports.len() > 1 {
+                ui.label(format!("Model {} of {}", i + 1, reports.len()));
+            }
+            egui::Grid::new(("model_report", i)).show(ui, |ui| {
+                for (label, value, problem) in report.lines() {
+                    ui.label(label);
+                    if problem {
+                        ui.colored_label(egui::Color32::RED, value);
+                    } else {
+                        ui.label(value);
+                    }
+                    ui.end_row();
+                }
+            });
         }
-        let model = &self
-            .cached_model
+    }
+
+    /// Controls for saving the main model's meshes, as they were last drawn at full detail, to an
+    /// OBJ or PLY file.
+    /// Writing the file (and its textures) happens on a background thread.
+    fn model_exporter(&mut self, ui: &mut egui::Ui, frame: &mut epi::Frame<'_>) {
+        if let Some(result) = self
+            .model_export
             .as_ref()
-            .expect("model should be loaded")
-            .1;
-
-        crate::scenes::render_scene(
-            &mut image,
-            &input.scene,
-            model,
-            input.light_dir,
-            input.camera_perspective_dist,
-            input.camera_look_from,
-            input.camera_look_at,
-            input.camera_up,
-            input.phong_lighting_weights,
-            input.use_tangent_space_normal_map,
-            input.shadow_darkness,
-            input.shadow_z_fix,
-            input.ambient_occlusion_passes,
-            input.ambient_occlusion_strength,
-            input.enable_glow_map,
-            input.base_shininess,
-        )
-        .unwrap();
+            .and_then(|export| export.try_recv().ok())
+        {
+            self.model_export = None;
+            self.model_export_result = Some(result);
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Path");
+            ui.add(
+                egui::TextEdit::singleline(&mut self.config.model_export_filename)
+                    .desired_width(200.0),
+            );
+            let render = self
+                .data
+                .as_ref()
+                .and_then(|data| data.last_render.as_ref());
+            if self.model_export.is_some() {
+                ui.label("Exporting...");
+            } else if let Some(render) = render {
+                if ui.button("Export").clicked() {
+                    let path = PathBuf::from(&self.config.model_export_filename);
+                    let models = render.models.clone();
+                    let export_materials = self.config.export_materials;
+                    let repaint_signal = frame.repaint_signal();
+                    let (sender, receiver) = flume::bounded(1);
+                    std::thread::spawn(move || {
+                        let result = crab_tv::export_models(&models, &path, export_materials)
+                            .map(|()| path)
+                            .map_err(|err| format!("{:#}", err));
+                        let _ = sender.send(result);
+                        repaint_signal.request_repaint();
+                    });
+                    self.model_export = Some(receiver);
+                }
+            }
+        });
+        ui.checkbox(
+            &mut self.config.export_materials,
+            "Write a material library and textures alongside OBJ files",
+        );
+        ui.label("Files ending in .ply only keep the geometry and vertex colors");
+        match &self.model_export_result {
+            Some(Ok(path)) => {
+                ui.label(format!("Exported model to {}", path.display()));
+            }
+            Some(Err(err)) => {
+                ui.colored_label(egui::Color32::RED, format!("Export failed: {}", err));
+            }
+            None => (),
+        }
+    }
+
+    /// Render and save every frame of the animation, in place of rendering the current config.
+    fn export_animation(&mut self, frames: Vec<RenderInput>, frame: &mut epi::Frame<'_>) {
+        println!(
+            "Exporting {count} frame animation to {path}",
+            count = frames.len(),
+            path = self.animation.output.display(),
+        );
+        let display_size = frames
+            .first()
+            .map_or((0, 0), |input| (input.width, input.height));
+        let count = frames.len();
+        self.refine_at = None;
+        self.render_job = Some(RenderJob::spawn_animation(
+            self.animation.clone(),
+            frames,
+            self.assets.clone(),
+            frame.repaint_signal(),
+        ));
+        self.render_display_size = display_size;
+        self.render_progress = None;
+        self.render_error = None;
+        self.animation_progress = Some((0, count));
+        self.animation_saved = None;
+    }
+
+    fn cancel_render(&mut self) {
+        println!("Cancelling render");
+        self.render_job = None;
+        self.render_progress = None;
+        self.refine_at = None;
+        self.animation_progress = None;
+    }
+
+    /// Apply any updates sent back by the in-flight render.
+    fn poll_render_job(&mut self, tex_allocator: &mut dyn eframe::epi::TextureAllocator) {
+        let updates = match self.render_job {
+            Some(ref job) => job.poll(),
+            None => return,
+        };
+        for update in updates {
+            match update {
+                RenderUpdate::Progress { pass, fraction } => {
+                    self.render_progress = Some((pass, fraction));
+                }
+                RenderUpdate::Partial {
+                    width,
+                    height,
+                    pixels,
+                } => {
+                    self.show_image(width, height, &pixels, tex_allocator);
+                    if let Some(ref mut data) = self.data {
+                        data.last_render = None;
+                    }
+                }
+                RenderUpdate::Finished(finished) => {
+                    let image = &finished.image;
+                    self.show_image(image.width(), image.height(), image.pixels(), tex_allocator);
+                    if self.stats_history.len() == STATS_HISTORY_LEN {
+                        self.stats_history.pop_front();
+                    }
+                    self.stats_history.push_back(finished.output.stats.clone());
+                    // a clip saved with the config may not be one of this model's, and would keep
+                    // the view re-rendering without anything moving (or any way to stop it)
+                    let clip_count = finished.model_file.animations.len();
+                    if self
+                        .config
+                        .animation_clip
+                        .is_some_and(|clip| clip >= clip_count)
+                    {
+                        self.config.animation_clip = None;
+                    }
+                    if let Some(ref mut data) = self.data {
+                        data.last_render = Some(*finished);
+                    }
+                    self.render_job = None;
+                    self.render_progress = None;
+                }
+                RenderUpdate::FrameSaved {
+                    index,
+                    count,
+                    width,
+                    height,
+                    pixels,
+                } => {
+                    self.show_image(width, height, &pixels, tex_allocator);
+                    if let Some(ref mut data) = self.data {
+                        data.last_render = None;
+                    }
+                    self.animation_progress = Some((index + 1, count));
+                }
+                RenderUpdate::AnimationSaved(path) => {
+                    println!("Saved animation to {}", path.display());
+                    self.animation_saved = Some(path);
+                    self.render_job = None;
+                    self.render_progress = None;
+                    self.animation_progress = None;
+                }
+                RenderUpdate::Failed(err) => {
+                    self.render_error = Some(err);
+                    self.render_job = None;
+                    self.render_progress = None;
+                    self.refine_at = None;
+                    self.animation_progress = None;
+                }
+            }
+        }
+    }
+
+    /// Move the camera (or with shift held, the light) by dragging and scrolling over the render.
+    fn control_view(&mut self, ctx: &egui::CtxRef, response: &egui::Response) {
+        let input = ctx.input();
+        let before = self.config.clone();
+
+        let drag = response.drag_delta();
+        if response.dragged_by(egui::PointerButton::Primary) {
+            let (yaw, pitch) = (drag.x * VIEW_ROTATE_SPEED, drag.y * VIEW_ROTATE_SPEED);
+            if input.modifiers.shift {
+                self.config.auto_rotate_light_speed = 0.0;
+                self.config.rotate_light(yaw, pitch);
+            } else {
+                self.config.auto_rotate_camera_speed = 0.0;
+                self.config.orbit_camera(-yaw, -pitch);
+            }
+        } else if response.dragged_by(egui::PointerButton::Secondary) {
+            // the model fills 3/4 of the render's height when viewed head on, and spans 2 units
+            let units_per_point = 2.0 / (response.rect.height() * 3.0 / 4.0);
+            self.config
+                .pan_camera(-drag.x * units_per_point, drag.y * units_per_point);
+        }
+
+        if response.hovered() && input.scroll_delta.y != 0.0 {
+            self.config
+                .dolly_camera((-input.scroll_delta.y * VIEW_DOLLY_SPEED).exp());
+        }
+
+        if !ctx.wants_keyboard_input() && input.key_pressed(egui::Key::R) {
+            self.config.reset_camera();
+        }
+
+        if self.config != before {
+            self.view_moved = true;
+            ctx.request_repaint();
+        }
+    }
+
+    /// Pick the pixel to show in the pixel inspector: the one under the mouse, or the one last
+    /// clicked on until it is clicked again.
+    fn inspect_view(&mut self, response: &egui::Response, width: usize, height: usize) {
+        let pixel_at = |pos: egui::Pos2| {
+            let rel = (pos - response.rect.min) / response.rect.size();
+            (
+                ((rel.x * width as f32) as usize).min(width - 1),
+                ((rel.y * height as f32) as usize).min(height - 1),
+            )
+        };
+        if response.clicked() {
+            self.inspected_pixel_pinned = !self.inspected_pixel_pinned;
+        }
+        if !self.inspected_pixel_pinned || response.clicked() {
+            if let Some(pos) = response.hover_pos() {
+                self.inspected_pixel = Some(pixel_at(pos));
+            }
+        }
+    }
+
+    fn animation_editor(&mut self, ui: &mut egui::Ui, frame: &mut epi::Frame<'_>) {
+        let animation = &mut self.animation;
+        ui.horizontal(|ui| {
+            ui.label("Animate");
+            for path in AnimationPath::iter() {
+                ui.radio_value(&mut animation.path, path, path.to_string());
+            }
+        });
+        match animation.path {
+            AnimationPath::Turntable => {
+                ui.add(
+                    egui::Slider::new(&mut animation.turntable_frames, 1..=360)
+                        .text("Frames per revolution"),
+                );
+            }
+            AnimationPath::Keyframes => {
+                let mut removed = None;
+                for (i, keyframe) in animation.keyframes.iter_mut().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::DragValue::new(&mut keyframe.time)
+                                .speed(0.1)
+                                .clamp_range(0.0..=600.0)
+                                .suffix("s"),
+                        );
+                        let from = keyframe.camera_look_from;
+                        ui.label(format!(
+                            "from ({:.2}, {:.2}, {:.2})",
+                            from.x, from.y, from.z
+                        ));
+                        if ui.button("View").clicked() {
+                            self.config.camera_look_from = keyframe.camera_look_from;
+                            self.config.camera_look_at = keyframe.camera_look_at;
+                            self.config.light_dir = keyframe.light_dir;
+                        }
+                        if ui.button("Remove").clicked() {
+                            removed = Some(i);
+                        }
+                    });
+                }
+                if let Some(i) = removed {
+                    animation.keyframes.remove(i);
+                }
+                if ui.button("Add keyframe from current view").clicked() {
+                    let time = animation
+                        .keyframes
+                        .iter()
+                        .map(|k| k.time + 1.0)
+                        .fold(0.0, f32::max);
+                    animation
+                        .keyframes
+                        .push(Keyframe::from_config(time, &self.config));
+                }
+            }
+        }
+        ui.add(egui::Slider::new(&mut animation.frames_per_second, 1.0..=60.0).text("FPS"));
+
+        ui.horizontal(|ui| {
+            ui.label("Format");
+            for format in AnimationFormat::iter() {
+                let before = animation.format;
+                ui.radio_value(&mut animation.format, format, format.to_string());
+                if animation.format != before {
+                    animation.output.set_extension(format.extension());
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Path");
+            path_edit_singleline(ui, &mut animation.output);
+        });
+
+        match animation.validate().and_then(|_| self.config.validate()) {
+            Ok(input) => {
+                let frame_count = animation.frame_count();
+                ui.label(format!(
+                    "{} frames of {}x{}, lasting {:.1}s",
+                    frame_count,
+                    input.width,
+                    input.height,
+                    frame_count as f32 / animation.frames_per_second
+                ));
+                if ui.button("Export animation").clicked() {
+                    match animation.frame_inputs(&self.config) {
+                        Ok(frames) => self.export_animation(frames, frame),
+                        Err(err) => self.render_error = Some(format!("{:?}", err)),
+                    }
+                }
+            }
+            Err(err) => {
+                ui.colored_label(egui::Color32::RED, format!("Can't export: {}", err));
+            }
+        }
+        ui.label("Changing the render configuration cancels an export in progress");
+        if let Some(ref path) = self.animation_saved {
+            ui.label(format!("Saved animation to {}", path.display()));
+        }
+    }
+
+    /// Pin the displayed render as the reference to compare later renders against.
+    fn pin_reference(&mut self, tex_allocator: &mut dyn eframe::epi::TextureAllocator) {
+        self.clear_reference(tex_allocator);
+        if let Some(ref data) = self.data {
+            let size = (data.last_render_width, data.last_render_height);
+            self.reference = Some(ReferenceRender {
+                width: size.0,
+                height: size.1,
+                pixels: data.last_render_pixels.clone(),
+                tex: alloc_texture(tex_allocator, size, &data.last_render_pixels),
+                diff: None,
+                diff_outdated: true,
+            });
+        }
+    }
+
+    fn clear_reference(&mut self, tex_allocator: &mut dyn eframe::epi::TextureAllocator) {
+        if let Some(mut reference) = self.reference.take() {
+            reference.free_textures(tex_allocator);
+        }
+    }
+
+    fn show_image(
+        &mut self,
+        width: usize,
+        height: usize,
+        pixels: &[RGBA8],
+        tex_allocator: &mut dyn eframe::epi::TextureAllocator,
+    ) {
+        let same_size = matches!(
+            self.data,
+            Some(ref d) if d.last_render_width == width && d.last_render_height == height
+        );
+        if !same_size {
+            if let Some(ref mut d) = self.data {
+                d.clear_texture(tex_allocator);
+            }
+            self.data = Some(UiData::new(width, height));
+        }
 
         let data = self
             .data
             .as_mut()
             .expect("ui data must be present for storing pixels");
-
-        data.store_image(image.pixels(), tex_allocator);
+        data.last_render_display_size = self.render_display_size;
+        data.store_image(pixels, tex_allocator);
+        if let Some(ref mut reference) = self.reference {
+            reference.diff_outdated = true;
+        }
     }
 }
 
@@ -193,25 +769,53 @@ impl epi::App for RendererApp {
     ) {
         // Load previous app state (if any).
         if let Some(storage) = _storage {
-            self.config = epi::get_value(storage, epi::APP_KEY).unwrap_or_default()
+            self.config = epi::get_value(storage, epi::APP_KEY).unwrap_or_default();
+            self.animation = epi::get_value(storage, ANIMATION_KEY).unwrap_or_default();
         }
 
         if let Ok(input) = self.config.validate() {
-            self.trigger_render(input, frame.tex_allocator());
+            self.trigger_render(input, frame);
         }
     }
 
     /// Called by the frame work to save state before shutdown.
     fn save(&mut self, storage: &mut dyn epi::Storage) {
         epi::set_value(storage, epi::APP_KEY, &self.config);
+        epi::set_value(storage, ANIMATION_KEY, &self.animation);
     }
 
     /// Called each time the UI needs repainting, which may be many times per second.
     /// Put your widgets into a `SidePanel`, `TopPanel`, `CentralPanel`, `Window` or `Area`.
     fn update(&mut self, ctx: &egui::CtxRef, frame: &mut epi::Frame<'_>) {
-        let dt = 1.0 / 60.0; // just hardcode 60hz
+        self.poll_render_job(frame.tex_allocator());
+        if let Some(ref mut data) = self.data {
+            data.update_buffer_texture(
+                self.buffer_view,
+                self.buffer_false_color,
+                frame.tex_allocator(),
+            );
+            if let Some(ref mut reference) = self.reference {
+                reference.update_diff(data, frame.tex_allocator());
+            }
+        }
+
+        // only advance animations once the previous frame has finished rendering, otherwise each
+        // frame would cancel the render of the one before it
+        let dt = if self.render_job.is_some() {
+            0.0
+        } else {
+            1.0 / 60.0 // just hardcode 60hz
+        };
 
-        let mut force_rerender = false;
+        // the side panel checks for config changes before the render view gets to make any, so
+        // changes made by dragging the render are picked up on the following frame
+        let mut force_rerender = std::mem::take(&mut self.view_moved);
+        self.assets.watch(frame.repaint_signal());
+        let asset_generation = self.assets.generation();
+        if asset_generation != self.asset_generation {
+            self.asset_generation = asset_generation;
+            force_rerender = true;
+        }
         egui::SidePanel::left("config_panel")
             // .resizable(false)
             .show(ctx, |ui| {
@@ -262,6 +866,36 @@ impl epi::App for RendererApp {
                             &mut self.config.display_actual_size,
                             "Display render at actual 1:1 size",
                         );
+                        ui.label(
+                            "Drag the render to orbit the camera (or the light while holding \
+                            shift), right-drag to pan, scroll to change the camera distance and \
+                            press R to reset the camera.",
+                        );
+
+                        ui.horizontal(|ui| {
+                            ui.label("Show buffer");
+                            ui.vertical(|ui| {
+                                for view in BufferView::iter() {
+                                    ui.radio_value(&mut self.buffer_view, view, view.label());
+                                }
+                            });
+                        });
+                        ui.checkbox(
+                            &mut self.buffer_false_color,
+                            "Show depth & ambient occlusion in false color",
+                        );
+                        let available = matches!(
+                            self.data,
+                            Some(UiData { buffer_tex: Some((v, _, Some(_))), .. }) if v == self.buffer_view
+                        );
+                        if self.buffer_view != BufferView::Color && !available {
+                            ui.label(if self.buffer_view.needs_fragments() {
+                                "Not available: enable recording pixel details (under Pixel \
+                                inspector) and use a scene that draws the model"
+                            } else {
+                                "Not available for this render, so showing its color instead"
+                            });
+                        }
                     });
 
                     ui.collapsing("Render options", |ui| {
@@ -271,12 +905,10 @@ impl epi::App for RendererApp {
                             ui.label(" or ");
                             if ui.add(egui::widgets::Button::new("Head")).clicked() {
                                 self.config.model = "assets/head.obj".into();
-                                self.cached_model.take();
                                 force_rerender = true;
                             }
                             if ui.add(egui::widgets::Button::new("Diablo")).clicked() {
                                 self.config.model = "assets/diablo.obj".into();
-                                self.cached_model.take();
                                 force_rerender = true;
                             }
                         });
@@ -357,6 +989,79 @@ impl epi::App for RendererApp {
                         );
                         ui.end_row();
 
+                        let model_cameras = self
+                            .data
+                            .as_ref()
+                            .and_then(|data| data.last_render.as_ref())
+                            .map(|render| render.model_file.cameras.clone())
+                            .unwrap_or_default();
+                        if !model_cameras.is_empty() {
+                            ui.horizontal(|ui| {
+                                ui.label("View from the model's camera:");
+                                for camera in &model_cameras {
+                                    if ui.button(&camera.name).clicked() {
+                                        self.config.view_from(camera);
+                                    }
+                                }
+                            });
+                            ui.end_row();
+                        }
+
+                        let model_clips: Vec<(String, f32)> = self
+                            .data
+                            .as_ref()
+                            .and_then(|data| data.last_render.as_ref())
+                            .map(|render| {
+                                render
+                                    .model_file
+                                    .animations
+                                    .iter()
+                                    .map(|clip| (clip.name.clone(), clip.duration))
+                                    .collect()
+                            })
+                            .unwrap_or_default();
+                        if !model_clips.is_empty() {
+                            ui.horizontal(|ui| {
+                                ui.label("Skeletal animation");
+                                ui.radio_value(&mut self.config.animation_clip, None, "Rest pose");
+                                for (i, (name, _)) in model_clips.iter().enumerate() {
+                                    ui.radio_value(&mut self.config.animation_clip, Some(i), name);
+                                }
+                            });
+                            ui.end_row();
+
+                            let duration = self
+                                .config
+                                .animation_clip
+                                .and_then(|clip| model_clips.get(clip))
+                                .map(|&(_, duration)| duration);
+                            if let Some(duration) = duration {
+                                if self.config.is_animating() && duration > 0.0 {
+                                    self.config.animation_time = (self.config.animation_time
+                                        + self.config.animation_speed * dt)
+                                        .rem_euclid(duration);
+                                }
+                                ui.horizontal(|ui| {
+                                    ui.checkbox(&mut self.config.animation_playing, "Play");
+                                    ui.add(
+                                        egui::Slider::new(
+                                            &mut self.config.animation_time,
+                                            0.0..=duration,
+                                        )
+                                        .text("seconds"),
+                                    );
+                                    ui.add(
+                                        egui::Slider::new(
+                                            &mut self.config.animation_speed,
+                                            0.0..=2.0,
+                                        )
+                                        .text("speed"),
+                                    );
+                                });
+                                ui.end_row();
+                            }
+                        }
+
                         ui.add(
                             egui::Slider::new(&mut self.config.phong_lighting_weights.x, 0.0..=3.0)
                                 .text("Phong lighting: Ambient weight"),
@@ -417,6 +1122,181 @@ impl epi::App for RendererApp {
                             "Enable glow map (if available - e.g. for Diablo)",
                         );
                         ui.end_row();
+
+                        ui.add(
+                            egui::Slider::new(&mut self.config.transparency_opacity, 0.0..=1.0)
+                                .text("Transparency: Opacity"),
+                        );
+                        ui.end_row();
+                        ui.horizontal(|ui| {
+                            ui.label("Transparency: Blend mode");
+                            for blend_mode in BlendMode::iter() {
+                                ui.radio_value(
+                                    &mut self.config.transparency_blend_mode,
+                                    blend_mode,
+                                    format!("{}", blend_mode),
+                                );
+                            }
+                        });
+                        ui.end_row();
+
+                        ui.horizontal(|ui| {
+                            ui.label("Subdivision");
+                            for scheme in SubdivisionScheme::iter() {
+                                ui.radio_value(
+                                    &mut self.config.subdivision_scheme,
+                                    scheme,
+                                    format!("{}", scheme),
+                                );
+                            }
+                            if self.config.subdivision_scheme != SubdivisionScheme::Off {
+                                ui.add(
+                                    egui::Slider::new(
+                                        &mut self.config.subdivision_levels,
+                                        1..=MAX_SUBDIVISION_LEVELS,
+                                    )
+                                    .text("levels"),
+                                );
+                            }
+                        });
+                        ui.end_row();
+                        ui.horizontal(|ui| {
+                            ui.label("Face culling");
+                            for cull_mode in CullMode::iter() {
+                                ui.radio_value(
+                                    &mut self.config.cull_mode,
+                                    cull_mode,
+                                    format!("{}", cull_mode),
+                                );
+                            }
+                        });
+                        ui.end_row();
+                        ui.horizontal(|ui| {
+                            ui.label("Anti-aliasing");
+                            for anti_aliasing in AntiAliasing::iter() {
+                                ui.radio_value(
+                                    &mut self.config.anti_aliasing,
+                                    anti_aliasing,
+                                    format!("{}", anti_aliasing),
+                                );
+                            }
+                        });
+                        ui.end_row();
+                        match self.config.anti_aliasing {
+                            AntiAliasing::Off => {}
+                            AntiAliasing::Msaa => {
+                                ui.horizontal(|ui| {
+                                    ui.label("Samples per pixel");
+                                    for samples in [2, 4, 8] {
+                                        ui.radio_value(
+                                            &mut self.config.msaa_samples,
+                                            samples,
+                                            format!("{}x", samples),
+                                        );
+                                    }
+                                });
+                            }
+                            AntiAliasing::Ssaa => {
+                                ui.add(
+                                    egui::Slider::new(&mut self.config.ssaa_factor, 2..=4)
+                                        .suffix("x")
+                                        .text("Supersampling factor"),
+                                );
+                                ui.horizontal(|ui| {
+                                    ui.label("Downsampling filter");
+                                    for filter in ResampleFilter::iter() {
+                                        ui.radio_value(
+                                            &mut self.config.ssaa_filter,
+                                            filter,
+                                            format!("{}", filter),
+                                        );
+                                    }
+                                });
+                            }
+                        }
+                        ui.end_row();
+
+                        ui.horizontal(|ui| {
+                            ui.label("Front face winding");
+                            for winding in Winding::iter() {
+                                ui.radio_value(
+                                    &mut self.config.front_face,
+                                    winding,
+                                    format!("{}", winding),
+                                );
+                            }
+                        });
+                        ui.end_row();
+                    });
+
+                    ui.collapsing("Scene objects", |ui| {
+                        scene_objects_editor(ui, &mut self.config.objects);
+                    });
+
+                    if let Some(stats) = self.stats_history.back() {
+                        ui.collapsing("Render statistics", |ui| {
+                            render_stats(ui, stats);
+                            ui.label(format!(
+                                "Pass times (ms) of the last {} renders",
+                                self.stats_history.len()
+                            ));
+                            stats_history_plot(ui, &self.stats_history);
+                        });
+                    }
+
+                    ui.collapsing("Pixel inspector", |ui| {
+                        ui.checkbox(
+                            &mut self.config.record_fragments,
+                            "Record pixel details while rendering",
+                        );
+                        match (self.inspected_pixel, &self.data) {
+                            (Some(pixel), Some(data)) => {
+                                if self.inspected_pixel_pinned {
+                                    ui.label("Pinned: click the render again to unpin");
+                                } else {
+                                    ui.label("Click the render to pin a pixel");
+                                }
+                                pixel_inspector(ui, data, pixel);
+                            }
+                            _ => {
+                                ui.label("Hover over the render to inspect its pixels");
+                            }
+                        }
+                    });
+
+                    ui.collapsing("Compare with reference", |ui| {
+                        ui.horizontal(|ui| {
+                            if self.data.is_some() && ui.button("Pin current render").clicked() {
+                                self.pin_reference(frame.tex_allocator());
+                            }
+                            if self.reference.is_some() && ui.button("Clear reference").clicked() {
+                                self.clear_reference(frame.tex_allocator());
+                            }
+                        });
+                        ui.horizontal(|ui| {
+                            ui.label("Layout");
+                            for mode in CompareMode::iter() {
+                                ui.radio_value(&mut self.compare_mode, mode, mode.to_string());
+                            }
+                        });
+                        if self.compare_mode == CompareMode::Wipe {
+                            ui.add(
+                                egui::Slider::new(&mut self.wipe_position, 0.0..=1.0)
+                                    .text("Wipe position"),
+                            );
+                        }
+                        match (&self.reference, &self.data) {
+                            (Some(reference), Some(data)) => {
+                                reference_comparison(ui, reference, data, self.inspected_pixel)
+                            }
+                            _ => {
+                                ui.label("Pin a render to compare later renders against it");
+                            }
+                        }
+                    });
+
+                    ui.collapsing("Export animation", |ui| {
+                        self.animation_editor(ui, frame);
                     });
 
                     ui.collapsing("Save render", |ui| {
@@ -436,21 +1316,73 @@ impl epi::App for RendererApp {
                         ui.end_row();
                     });
 
+                    ui.collapsing("Model report", |ui| {
+                        self.model_report(ui);
+                    });
+
+                    ui.collapsing("Export model", |ui| {
+                        self.model_exporter(ui, frame);
+                    });
+
                     ui.checkbox(&mut self.config.auto_rerender, "Re-render on config change");
                     ui.end_row();
 
+                    if self.config.auto_rerender {
+                        ui.horizontal(|ui| {
+                            ui.checkbox(
+                                &mut self.config.progressive_refinement,
+                                "Preview while changing",
+                            );
+                            ui.add(
+                                egui::Slider::new(&mut self.config.preview_scale, 1..=8)
+                                    .prefix("1/")
+                                    .text("resolution"),
+                            );
+                        });
+                        ui.end_row();
+
+                        ui.horizontal(|ui| {
+                            ui.checkbox(&mut self.config.preview_lods, "Simplify models to")
+                                .on_hover_text(
+                                    "Previews draw models with more faces than this with a \
+                                    simplified level of detail, then the full model once the \
+                                    view stops moving",
+                                );
+                            ui.add(
+                                egui::Slider::new(&mut self.config.preview_lod_faces, 500..=20000)
+                                    .logarithmic(true)
+                                    .text("faces"),
+                            );
+                        });
+                        ui.end_row();
+                    }
+
+                    // clips are picked by their index in the model, so they don't carry over
+                    if self.config.model != config_before.model {
+                        self.config.animation_clip = None;
+                    }
+
                     match self.config.validate() {
                         Ok(input) => {
                             if self.config.auto_rerender {
                                 if config_before != self.config || force_rerender {
                                     println!("Configuration change detected - auto-rerendering!");
-                                    self.trigger_render(input, frame.tex_allocator());
+                                    self.trigger_progressive_render(input, frame);
+                                } else if !self.config.always_re_render()
+                                    && self
+                                        .refine_at
+                                        .is_some_and(|refine_at| Instant::now() >= refine_at)
+                                {
+                                    // animations keep changing the config, so only refine once
+                                    // they've been turned off
+                                    println!("Configuration settled - refining preview render");
+                                    self.trigger_render(input, frame);
                                 }
                             } else {
                                 ui.vertical_centered_justified(|ui| {
                                     let button = egui::widgets::Button::new("Re-render image!");
                                     if ui.add(button).clicked() {
-                                        self.trigger_render(input, frame.tex_allocator());
+                                        self.trigger_render(input, frame);
                                     }
                                 });
                             }
@@ -463,6 +1395,31 @@ impl epi::App for RendererApp {
                         }
                     }
                     ui.end_row();
+
+                    if self.render_job.is_some() {
+                        ui.horizontal(|ui| {
+                            let (mut pass, fraction) = self
+                                .render_progress
+                                .clone()
+                                .unwrap_or_else(|| ("Starting".to_owned(), 0.0));
+                            if let Some((saved, count)) = self.animation_progress {
+                                pass = format!("Frame {}/{}, {}", saved + 1, count, pass);
+                            }
+                            ui.add(
+                                egui::ProgressBar::new(fraction)
+                                    .desired_width(200.0)
+                                    .text(format!("{}: {:.0}%", pass, fraction * 100.0)),
+                            );
+                            if ui.button("Cancel").clicked() {
+                                self.cancel_render();
+                            }
+                        });
+                        ui.end_row();
+                    }
+                    if let Some(ref err) = self.render_error {
+                        ui.colored_label(egui::Color32::RED, format!("Rendering failed:\n{}", err));
+                        ui.end_row();
+                    }
                 })
             });
 
@@ -470,33 +1427,410 @@ impl epi::App for RendererApp {
             if let Some(ref mut data) = self.data {
                 let image_sizing = if self.config.display_actual_size {
                     egui::Vec2::new(
-                        data.last_render_width as f32,
-                        data.last_render_height as f32,
+                        data.last_render_display_size.0 as f32,
+                        data.last_render_display_size.1 as f32,
                     )
                 } else {
                     ui.available_size()
                 };
 
-                egui::ScrollArea::auto_sized().show(ui, |ui| {
-                    if let Some(tex_id) = data.last_render_tex {
-                        ui.image(tex_id, image_sizing);
-                    }
+                let image_response = egui::ScrollArea::auto_sized().show(ui, |ui| {
+                    let tex_id = data.displayed_texture(self.buffer_view)?;
+                    let reference = match self.reference {
+                        Some(ref reference) => reference,
+                        None => {
+                            return Some(
+                                ui.add(
+                                    egui::Image::new(tex_id, image_sizing)
+                                        .sense(egui::Sense::click_and_drag()),
+                                ),
+                            )
+                        }
+                    };
+                    let response = match self.compare_mode {
+                        CompareMode::SideBySide => {
+                            let mut sizing = image_sizing;
+                            if !self.config.display_actual_size {
+                                sizing.x = (sizing.x - ui.spacing().item_spacing.x) / 2.0;
+                            }
+                            ui.horizontal(|ui| {
+                                ui.add(egui::Image::new(reference.tex, sizing));
+                                ui.add(
+                                    egui::Image::new(tex_id, sizing)
+                                        .sense(egui::Sense::click_and_drag()),
+                                )
+                            })
+                            .inner
+                        }
+                        CompareMode::Wipe => {
+                            // the wipe handle has to claim drags before the render does
+                            let (rect, _) =
+                                ui.allocate_exact_size(image_sizing, egui::Sense::hover());
+                            let wipe_x = rect.left() + rect.width() * self.wipe_position;
+                            let handle = ui.interact(
+                                egui::Rect::from_x_y_ranges(
+                                    wipe_x - WIPE_HANDLE_WIDTH / 2.0
+                                        ..=wipe_x + WIPE_HANDLE_WIDTH / 2.0,
+                                    rect.y_range(),
+                                ),
+                                ui.id().with("wipe_handle"),
+                                egui::Sense::drag(),
+                            );
+                            let response = ui.interact(
+                                rect,
+                                ui.id().with("wipe_render"),
+                                egui::Sense::click_and_drag(),
+                            );
+                            if let Some(pos) = handle.interact_pointer_pos() {
+                                self.wipe_position =
+                                    ((pos.x - rect.left()) / rect.width()).clamp(0.0, 1.0);
+                            }
+                            let wipe_x = rect.left() + rect.width() * self.wipe_position;
+
+                            egui::Image::new(tex_id, image_sizing).paint_at(ui, rect);
+                            egui::Image::new(reference.tex, image_sizing)
+                                .uv(egui::Rect::from_min_max(
+                                    egui::Pos2::ZERO,
+                                    egui::pos2(self.wipe_position, 1.0),
+                                ))
+                                .paint_at(
+                                    ui,
+                                    egui::Rect::from_x_y_ranges(
+                                        rect.left()..=wipe_x,
+                                        rect.y_range(),
+                                    ),
+                                );
+                            let stroke_width = if handle.hovered() || handle.dragged() {
+                                3.0
+                            } else {
+                                1.5
+                            };
+                            ui.painter().line_segment(
+                                [
+                                    egui::pos2(wipe_x, rect.top()),
+                                    egui::pos2(wipe_x, rect.bottom()),
+                                ],
+                                egui::Stroke::new(stroke_width, egui::Color32::WHITE),
+                            );
+                            response
+                        }
+                        CompareMode::Difference => {
+                            let diff_tex = reference.diff.as_ref().map_or(tex_id, |(_, tex)| *tex);
+                            ui.add(
+                                egui::Image::new(diff_tex, image_sizing)
+                                    .sense(egui::Sense::click_and_drag()),
+                            )
+                        }
+                    };
+                    Some(response)
                 });
+                if let Some(response) = image_response {
+                    let (width, height) = (data.last_render_width, data.last_render_height);
+                    self.control_view(ctx, &response);
+                    self.inspect_view(&response, width, height);
+                }
             }
         });
 
-        if self.config.always_re_render() {
+        if self.config.always_re_render() || self.render_job.is_some() || self.refine_at.is_some() {
             ctx.request_repaint();
         }
     }
 }
 
+/// Describe how the displayed render differs from the reference, overall and at the inspected
+/// pixel.
+fn reference_comparison(
+    ui: &mut egui::Ui,
+    reference: &ReferenceRender,
+    data: &UiData,
+    inspected_pixel: Option<(usize, usize)>,
+) {
+    ui.label(format!(
+        "Reference: {}x{}",
+        reference.width, reference.height
+    ));
+    let diff = match reference.diff {
+        Some((ref diff, _)) => diff,
+        None => {
+            ui.label(format!(
+                "Can't compare against a {}x{} render: sizes differ",
+                data.last_render_width, data.last_render_height
+            ));
+            return;
+        }
+    };
+
+    ui.label(format!(
+        "Pixels differing: {:.2}%",
+        diff.differing_fraction * 100.0
+    ));
+    ui.label(format!(
+        "Mean absolute difference: {:.3}",
+        diff.mean_abs_diff
+    ));
+    ui.label(format!("Max absolute difference: {}", diff.max_abs_diff));
+    ui.label(format!("RMS difference: {:.3}", diff.rms_diff));
+    ui.label(format!("PSNR: {:.2} dB", diff.psnr()));
+
+    if let Some((x, y)) = inspected_pixel {
+        let index = y * reference.width + x;
+        if let (Some(a), Some(b)) = (
+            reference.pixels.get(index),
+            data.last_render_pixels.get(index),
+        ) {
+            ui.label(format!(
+                "At ({}, {}): reference {}, {}, {}; current {}, {}, {}; difference {}, {}, {}",
+                x,
+                y,
+                a.r,
+                a.g,
+                a.b,
+                b.r,
+                b.g,
+                b.b,
+                a.r.abs_diff(b.r),
+                a.g.abs_diff(b.g),
+                a.b.abs_diff(b.b),
+            ));
+        }
+    }
+}
+
+fn render_stats(ui: &mut egui::Ui, stats: &SceneStats) {
+    for (pass, time) in &stats.pass_times {
+        ui.label(format!(
+            "{} pass: {:.1}ms",
+            pass,
+            time.as_secs_f64() * 1000.0
+        ));
+    }
+    ui.label(format!(
+        "Total: {:.1}ms",
+        stats.total_time().as_secs_f64() * 1000.0
+    ));
+
+    let raster = stats.raster;
+    ui.label(format!("Vertices shaded: {}", raster.vertices_shaded));
+    ui.label(format!(
+        "Triangles submitted: {}",
+        raster.triangles_submitted
+    ));
+    ui.label(format!("Triangles culled: {}", raster.triangles_culled));
+    ui.label(format!(
+        "Triangles rasterized: {}",
+        raster.triangles_rasterized
+    ));
+    ui.label(format!("Fragments shaded: {}", raster.fragments_shaded));
+    ui.label(format!(
+        "Fragments depth rejected: {}",
+        raster.fragments_depth_rejected
+    ));
+    ui.label(format!("Overdraw: {:.2}x", stats.overdraw()));
+}
+
+/// Graph the time taken by each pass (and in total) over recent renders.
+fn stats_history_plot(ui: &mut egui::Ui, history: &VecDeque<SceneStats>) {
+    use egui::plot::{Line, Plot, Value, Values};
+
+    let to_ms = |d: Duration| d.as_secs_f64() * 1000.0;
+
+    let mut passes: Vec<&'static str> = Vec::new();
+    for stats in history {
+        for (pass, _) in &stats.pass_times {
+            if !passes.contains(pass) {
+                passes.push(pass);
+            }
+        }
+    }
+
+    let mut plot = Plot::new("render_stats_history")
+        .height(120.0)
+        .include_y(0.0)
+        .allow_drag(false)
+        .allow_zoom(false);
+    for pass in passes {
+        let values = history.iter().enumerate().map(|(i, stats)| {
+            let time = stats
+                .pass_times
+                .iter()
+                .find(|(p, _)| *p == pass)
+                .map_or(0.0, |(_, d)| to_ms(*d));
+            Value::new(i as f64, time)
+        });
+        plot = plot.line(Line::new(Values::from_values_iter(values)).name(pass));
+    }
+    let totals = history
+        .iter()
+        .enumerate()
+        .map(|(i, stats)| Value::new(i as f64, to_ms(stats.total_time())));
+    plot = plot.line(Line::new(Values::from_values_iter(totals)).name("Total"));
+    ui.add(plot);
+}
+
+fn pixel_inspector(ui: &mut egui::Ui, data: &UiData, (x, y): (usize, usize)) {
+    if x >= data.last_render_width || y >= data.last_render_height {
+        return;
+    }
+    ui.label(format!("Pixel: ({}, {})", x, y));
+    let color = data.last_render_pixels[y * data.last_render_width + x];
+    ui.label(format!(
+        "Color: rgba({}, {}, {}, {})",
+        color.r, color.g, color.b, color.a
+    ));
+
+    let (canvas, nodes) = match data.last_render {
+        Some(ref render) => (&render.image, &render.nodes),
+        None => return,
+    };
+    // the pixels of the canvas are flipped once rendering finishes, but its other buffers aren't
+    let (cx, cy) = (x as i32, (canvas.height() - 1 - y) as i32);
+
+    let depth = canvas.z_buffer_at(cx, cy);
+    if depth == f32::NEG_INFINITY {
+        ui.label("Depth: nothing drawn");
+    } else {
+        ui.label(format!("Depth: {:.3} (of {})", depth, DEPTH_MAX));
+    }
+
+    let fragment = match canvas.fragment(cx, cy) {
+        Some(fragment) => fragment,
+        None => {
+            ui.label("No fragment recorded for this pixel");
+            return;
+        }
+    };
+    let bc = fragment.barycentric_coords;
+    ui.label(format!(
+        "Barycentric coords: ({:.3}, {:.3}, {:.3})",
+        bc.x, bc.y, bc.z
+    ));
+    if nodes.len() > 1 {
+        ui.label(format!("Scene node: {}", fragment.node));
+    }
+    if let Some(face_index) = fragment.face {
+        let model = &nodes[fragment.node].model;
+        ui.label(format!(
+            "Face: {} (vertices {:?})",
+            face_index,
+            model.face(face_index)
+        ));
+
+        let vertices = model.face_vertices(face_index);
+        let uv = vertices[0].uv * bc.x + vertices[1].uv * bc.y + vertices[2].uv * bc.z;
+        // the same normal the normals buffer shows, turned the way the node is turned
+        let normal = buffer_view::fragment_normal(nodes, fragment).unwrap_or_default();
+        ui.label(format!("UV: ({:.3}, {:.3})", uv.x, uv.y));
+        ui.label(format!(
+            "Normal: ({:.3}, {:.3}, {:.3})",
+            normal.x, normal.y, normal.z
+        ));
+        if let Some(tangent) = model.tangents.get(face_index) {
+            ui.label(format!(
+                "Tangent: ({:.3}, {:.3}, {:.3})",
+                tangent.x, tangent.y, tangent.z
+            ));
+        }
+    }
+    if let Some(shadowed) = fragment.shadowed {
+        ui.label(format!(
+            "Shadow map: {}",
+            if shadowed { "in shadow" } else { "lit" }
+        ));
+    }
+    if let Some(ambient_occlusion) = fragment.ambient_occlusion {
+        ui.label(format!("Ambient occlusion: {:.3}", ambient_occlusion));
+    }
+}
+
 fn path_edit_singleline(ui: &mut egui::Ui, path_buf: &mut PathBuf) {
     let mut temp = path_buf.to_string_lossy().to_string();
     ui.add(egui::TextEdit::singleline(&mut temp).desired_width(100.0));
     *path_buf = PathBuf::from(temp);
 }
 
+/// Add, remove and edit the models drawn around the main model.
+fn scene_objects_editor(ui: &mut egui::Ui, objects: &mut Vec<SceneObject>) {
+    let mut removed = None;
+    for (i, object) in objects.iter_mut().enumerate() {
+        let name = match &object.model {
+            Some(path) => path.display().to_string(),
+            None => "Ground plane".to_owned(),
+        };
+        egui::CollapsingHeader::new(format!("{}. {}", i + 1, name))
+            .id_source(("scene object", i))
+            .show(ui, |ui| {
+                if let Some(path) = &mut object.model {
+                    ui.horizontal(|ui| {
+                        ui.label("Model path");
+                        path_edit_singleline(ui, path);
+                    });
+                }
+                vec3_editor(ui, "Position", &mut object.transform.translation);
+                vec3_editor(ui, "Rotation (degrees)", &mut object.transform.rotation);
+                vec3_editor(ui, "Scale", &mut object.transform.scale);
+                vec3_editor(ui, "Tint", &mut object.material.tint);
+                optional_slider(
+                    ui,
+                    &mut object.material.shininess,
+                    5.0,
+                    0.1..=10.0,
+                    "Shininess",
+                );
+                optional_slider(ui, &mut object.material.opacity, 1.0, 0.0..=1.0, "Opacity");
+                ui.add(egui::Slider::new(&mut object.columns, 1..=10).text("Copies along x"));
+                ui.add(egui::Slider::new(&mut object.rows, 1..=10).text("Copies along z"));
+                ui.add(egui::Slider::new(&mut object.spacing, 0.1..=5.0).text("Copy spacing"));
+                if ui.button("Remove").clicked() {
+                    removed = Some(i);
+                }
+            });
+    }
+    if let Some(i) = removed {
+        objects.remove(i);
+    }
+
+    ui.horizontal(|ui| {
+        ui.label("Add");
+        if ui.button("Head").clicked() {
+            objects.push(SceneObject::default());
+        }
+        if ui.button("Diablo").clicked() {
+            objects.push(SceneObject {
+                model: Some("assets/diablo.obj".into()),
+                ..SceneObject::default()
+            });
+        }
+        if ui.button("Ground plane").clicked() {
+            objects.push(SceneObject::ground_plane());
+        }
+    });
+}
+
+/// A slider for a setting that is only used when its checkbox is ticked.
+fn optional_slider(
+    ui: &mut egui::Ui,
+    value: &mut Option<f32>,
+    default: f32,
+    range: std::ops::RangeInclusive<f32>,
+    text: &str,
+) {
+    ui.horizontal(|ui| {
+        let mut enabled = value.is_some();
+        ui.checkbox(&mut enabled, "");
+        match (enabled, value.as_mut()) {
+            (true, Some(v)) => {
+                ui.add(egui::Slider::new(v, range).text(text));
+            }
+            (true, None) => *value = Some(default),
+            (false, _) => {
+                *value = None;
+                ui.label(text);
+            }
+        }
+    });
+}
+
 fn vec3_editor(ui: &mut egui::Ui, label: &str, v: &mut Vec3) {
     let speed = 0.01;
 
@@ -511,3 +1845,15 @@ fn vec3_editor(ui: &mut egui::Ui, label: &str, v: &mut Vec3) {
         ui.label(label);
     });
 }
+
+fn alloc_texture(
+    tex_allocator: &mut dyn eframe::epi::TextureAllocator,
+    size: (usize, usize),
+    pixels: &[RGBA8],
+) -> TextureId {
+    let tex_pixels = pixels
+        .iter()
+        .map(|rgb| egui::Color32::from_rgba_premultiplied(rgb.r, rgb.g, rgb.b, 255))
+        .collect::<Vec<_>>();
+    tex_allocator.alloc_srgba_premultiplied(size, &tex_pixels)
+}
@@ -0,0 +1,430 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crab_tv::{
+    Canvas, MeshReport, Model, ModelFile, ProgressSink, SceneNode, SceneOutput, POST_PROCESS_PASS,
+};
+use eframe::epi::RepaintSignal;
+use rgb::RGBA8;
+
+use crate::{
+    animation::{self, AnimationSettings},
+    assets::AssetCache,
+    RenderInput,
+};
+
+/// How often to send a snapshot of the partly rendered image back to the UI.
+const PARTIAL_IMAGE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Messages sent from a render thread back to the UI.
+pub(crate) enum RenderUpdate {
+    Progress {
+        pass: String,
+        fraction: f32,
+    },
+    /// The image as drawn so far, already flipped the right way up
+    Partial {
+        width: usize,
+        height: usize,
+        pixels: Vec<RGBA8>,
+    },
+    Finished(Box<FinishedRender>),
+    /// A frame of an animation being exported has been rendered and saved
+    FrameSaved {
+        index: usize,
+        count: usize,
+        width: usize,
+        height: usize,
+        pixels: Vec<RGBA8>,
+    },
+    /// Every frame of an animation has been saved
+    AnimationSaved(PathBuf),
+    Failed(String),
+}
+
+/// A finished image, along with what was used to draw it so it can be inspected.
+#[derive(Debug)]
+pub(crate) struct FinishedRender {
+    pub(crate) image: Canvas,
+    /// What was drawn, which fragment records refer to by index
+    pub(crate) nodes: Vec<SceneNode>,
+    /// The main model's file, for its saved viewpoints and animations
+    pub(crate) model_file: Arc<ModelFile>,
+    /// The main model file's models at full detail, subdivided if they were drawn that way, for
+    /// exporting
+    pub(crate) models: Vec<Arc<Model>>,
+    /// Reports on each of `models`, so the UI needn't work them out
+    pub(crate) model_reports: Vec<Arc<MeshReport>>,
+    pub(crate) output: SceneOutput,
+}
+
+/// A render running on a background thread. Dropping the job cancels the render.
+#[derive(Debug)]
+pub(crate) struct RenderJob {
+    updates: flume::Receiver<RenderUpdate>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl RenderJob {
+    pub(crate) fn spawn(
+        input: RenderInput,
+        assets: AssetCache,
+        repaint_signal: Arc<dyn RepaintSignal>,
+    ) -> Self {
+        let output_size = (input.width, input.height);
+        Self::spawn_thread(output_size, repaint_signal, move |progress| {
+            match render(&input, &assets, progress, false) {
+                Ok(finished) => RenderUpdate::Finished(Box::new(finished)),
+                Err(err) => RenderUpdate::Failed(format!("{:?}", err)),
+            }
+        })
+    }
+
+    /// Render and save every frame of an animation, sending each frame back as it is saved.
+    pub(crate) fn spawn_animation(
+        settings: AnimationSettings,
+        frames: Vec<RenderInput>,
+        assets: AssetCache,
+        repaint_signal: Arc<dyn RepaintSignal>,
+    ) -> Self {
+        let output_size = frames
+            .first()
+            .map_or((0, 0), |input| (input.width, input.height));
+        Self::spawn_thread(output_size, repaint_signal, move |progress| {
+            let count = frames.len();
+            let sender = progress.sender.clone();
+            let saved = animation::export_animation(
+                &settings,
+                frames,
+                &assets,
+                progress,
+                |index, image| {
+                    let _ = sender.send(RenderUpdate::FrameSaved {
+                        index,
+                        count,
+                        width: image.width(),
+                        height: image.height(),
+                        pixels: image.pixels().to_vec(),
+                    });
+                },
+            );
+            match saved {
+                Ok(()) => RenderUpdate::AnimationSaved(settings.output),
+                Err(err) => RenderUpdate::Failed(format!("{:?}", err)),
+            }
+        })
+    }
+
+    /// Run `work` on a new thread, sending its progress and then its result back to the UI.
+    fn spawn_thread(
+        output_size: (usize, usize),
+        repaint_signal: Arc<dyn RepaintSignal>,
+        work: impl FnOnce(Arc<ChannelProgress>) -> RenderUpdate + Send + 'static,
+    ) -> Self {
+        let (sender, updates) = flume::unbounded();
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        let progress = Arc::new(ChannelProgress {
+            sender: sender.clone(),
+            cancelled: cancelled.clone(),
+            repaint_signal: repaint_signal.clone(),
+            output_size,
+            last_partial: Mutex::new(Instant::now()),
+        });
+
+        std::thread::spawn(move || {
+            let update = work(progress.clone());
+            if !progress.is_cancelled() {
+                // the UI may have already dropped the job, in which case nobody needs the result
+                let _ = sender.send(update);
+                repaint_signal.request_repaint();
+            }
+        });
+
+        RenderJob { updates, cancelled }
+    }
+
+    /// Get all the updates sent by the render thread since this was last called.
+    pub(crate) fn poll(&self) -> Vec<RenderUpdate> {
+        self.updates.try_iter().collect()
+    }
+
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for RenderJob {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Render `input`, drawing models that are still being subdivided as they are unless
+/// `wait_for_subdivision` is set.
+pub(crate) fn render(
+    input: &RenderInput,
+    assets: &AssetCache,
+    progress: Arc<dyn ProgressSink>,
+    wait_for_subdivision: bool,
+) -> anyhow::Result<FinishedRender> {
+    let subdivided = |model: &Arc<Model>| {
+        assets.subdivided(
+            model,
+            input.subdivision_scheme,
+            input.subdivision_levels,
+            wait_for_subdivision,
+        )
+    };
+    let (nodes, model_file) = {
+        let model_inputs: Vec<_> = std::iter::once(&input.model_input)
+            .chain(
+                input
+                    .objects
+                    .iter()
+                    .filter_map(|object| object.model.as_ref()),
+            )
+            .collect();
+        let mut files = assets.models(&model_inputs)?.into_iter();
+        let main_file = files.next().expect("main model should be loaded");
+        // the chosen clip of the main model animates it, along with any other model that has a
+        // clip of the same name
+        let clip_name = input
+            .animation_clip
+            .and_then(|clip| main_file.animations.get(clip))
+            .map(|clip| clip.name.as_str());
+
+        // files with more than one model (i.e. glTF scenes) are drawn as a node per model, each
+        // simplified to fit the face budget if there is one, or otherwise subdivided if asked to
+        // (subdividing a simplified model would blow the budget, and subdivide every level of
+        // detail the preview passes through)
+        let file_nodes = |file: &ModelFile| -> Vec<SceneNode> {
+            let clip = clip_name.and_then(|name| file.animations.iter().find(|c| c.name == name));
+            (0..file.models.len())
+                .map(|i| {
+                    let model = match input.lod_face_budget {
+                        Some(max_faces) => file.model_with_at_most(i, max_faces).clone(),
+                        None => subdivided(&file.models[i]),
+                    };
+                    SceneNode::new(model).posed(clip, input.animation_time)
+                })
+                .collect()
+        };
+        let mut nodes = file_nodes(&main_file);
+        let ground_plane = vec![SceneNode::new(Arc::new(Model::plane()))];
+        for object in &input.objects {
+            let object_nodes = match &object.model {
+                Some(_) => file_nodes(&files.next().expect("object's model should be loaded")),
+                None => ground_plane.clone(),
+            };
+            for &transform in &object.transforms {
+                nodes.extend(object_nodes.iter().map(|node| SceneNode {
+                    transform,
+                    material: object.material,
+                    ..node.clone()
+                }));
+            }
+        }
+        (nodes, main_file)
+    };
+    let models: Vec<_> = model_file.models.iter().map(subdivided).collect();
+    let model_reports = models.iter().map(|model| assets.report(model)).collect();
+
+    let mut image = Canvas::new(
+        input.width * input.ssaa_factor,
+        input.height * input.ssaa_factor,
+    );
+    image.set_culling(input.cull_mode, input.front_face);
+    image.set_msaa_samples(input.msaa_samples);
+    image.set_record_fragments(input.record_fragments);
+    image.set_progress_sink(Some(progress));
+
+    println!("Rendering scene: {}", input.scene);
+    let mut output = crab_tv::render_scene(
+        &mut image,
+        &input.scene,
+        &nodes,
+        input.light_dir,
+        input.camera_perspective_dist,
+        input.camera_look_from,
+        input.camera_look_at,
+        input.camera_up,
+        input.phong_lighting_weights,
+        input.use_tangent_space_normal_map,
+        input.shadow_darkness,
+        input.shadow_z_fix,
+        input.ambient_occlusion_passes,
+        input.ambient_occlusion_strength,
+        input.enable_glow_map,
+        input.base_shininess,
+        input.transparency_opacity,
+        input.transparency_blend_mode,
+    )?;
+    // the sink holds a sender for the channel this image is about to be sent down, so detach it
+    // to let the channel disconnect once the render thread exits
+    image.set_progress_sink(None);
+
+    if input.ssaa_factor > 1 {
+        image = output.stats.time_pass(POST_PROCESS_PASS, || {
+            image.downsample(input.ssaa_factor, input.ssaa_filter)
+        });
+    }
+
+    Ok(FinishedRender {
+        image,
+        nodes,
+        model_file,
+        models,
+        model_reports,
+        output,
+    })
+}
+
+/// Forwards progress from the canvas being rendered to the UI thread.
+struct ChannelProgress {
+    sender: flume::Sender<RenderUpdate>,
+    cancelled: Arc<AtomicBool>,
+    repaint_signal: Arc<dyn RepaintSignal>,
+    output_size: (usize, usize),
+    last_partial: Mutex<Instant>,
+}
+
+impl ProgressSink for ChannelProgress {
+    fn report(&self, pass: &str, fraction: f32, canvas: &Canvas) {
+        let _ = self.sender.send(RenderUpdate::Progress {
+            pass: pass.to_owned(),
+            fraction,
+        });
+
+        // partial images are only useful if they show the final image (so not the shadow buffer,
+        // nor a supersampled image which would need downsampling first)
+        let shows_output =
+            pass != crab_tv::SHADOW_PASS && (canvas.width(), canvas.height()) == self.output_size;
+        let mut last_partial = self.last_partial.lock().expect("lock poisoned");
+        if shows_output && last_partial.elapsed() >= PARTIAL_IMAGE_INTERVAL {
+            *last_partial = Instant::now();
+
+            // the canvas isn't flipped until rendering finishes, so flip the snapshot here
+            let pixels = canvas
+                .pixels()
+                .chunks(canvas.width())
+                .rev()
+                .flatten()
+                .copied()
+                .collect();
+            let _ = self.sender.send(RenderUpdate::Partial {
+                width: canvas.width(),
+                height: canvas.height(),
+                pixels,
+            });
+        }
+
+        self.repaint_signal.request_repaint();
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{animation::NoProgress, RenderConfig};
+    use crab_tv::{RenderScene, SubdivisionScheme};
+
+    struct NoRepaint;
+
+    impl RepaintSignal for NoRepaint {
+        fn request_repaint(&self) {}
+    }
+
+    fn spawn_job(scene: RenderScene) -> RenderJob {
+        let config = RenderConfig {
+            scene,
+            width: 200,
+            height: 200,
+            ..RenderConfig::default()
+        };
+        RenderJob::spawn(
+            config.validate().expect("default config should be valid"),
+            AssetCache::default(),
+            Arc::new(NoRepaint),
+        )
+    }
+
+    #[test]
+    fn render_job_should_report_progress_then_finish() {
+        let job = spawn_job(RenderScene::PhongShader);
+        let updates: Vec<_> = job.updates.iter().collect();
+
+        assert!(updates
+            .iter()
+            .any(|u| matches!(u, RenderUpdate::Progress { pass, .. } if pass == "Main")));
+        match updates.last() {
+            Some(RenderUpdate::Finished(finished)) => {
+                assert_eq!(finished.image.width(), 200);
+                let stats = &finished.output.stats;
+                assert_eq!(stats.pass_times[0].0, "Main");
+                assert!(stats.raster.fragments_shaded >= stats.pixels_covered);
+                assert!(stats.pixels_covered > 0);
+            }
+            _ => panic!("render should finish with the rendered image"),
+        }
+    }
+
+    #[test]
+    fn previews_should_stay_within_their_face_budget_when_subdividing() {
+        const BUDGET: usize = 1000;
+        let config = RenderConfig {
+            width: 200,
+            height: 200,
+            subdivision_scheme: SubdivisionScheme::Loop,
+            subdivision_levels: 1,
+            ..RenderConfig::default()
+        };
+        let input = config.validate().expect("config should be valid");
+        let preview = input.preview(1, Some(BUDGET)).expect("scene has a preview");
+        let assets = AssetCache::default();
+
+        // levels of detail are made in the background once the model has loaded
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while assets
+            .models(&[&input.model_input])
+            .expect("model should load")[0]
+            .lods
+            .is_empty()
+        {
+            assert!(
+                Instant::now() < deadline,
+                "levels of detail were never made"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let render =
+            render(&preview, &assets, Arc::new(NoProgress), true).expect("preview should render");
+        let full_faces = render.model_file.models[0].face_count();
+        assert!(full_faces > BUDGET);
+        assert!(render.nodes[0].model.face_count() <= BUDGET);
+        // the full detail model is still subdivided, ready for exporting
+        assert!(render.models[0].face_count() > full_faces);
+    }
+
+    #[test]
+    fn cancelled_render_job_should_not_finish() {
+        let job = spawn_job(RenderScene::ScreenSpaceAmbientOcclusion);
+        job.cancel();
+        let updates: Vec<_> = job.updates.iter().collect();
+
+        assert!(!updates
+            .iter()
+            .any(|u| matches!(u, RenderUpdate::Finished(_) | RenderUpdate::Failed(_))));
+    }
+}